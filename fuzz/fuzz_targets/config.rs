@@ -0,0 +1,12 @@
+//! Fuzz the deserialization of configuration files (including the quirks).
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use towboot_config::Config;
+
+fuzz_target!(|text: &[u8]| {
+    if let Ok(mut config) = toml::from_slice::<Config>(text) {
+        // this is what towboot does with every config it loaded
+        let _ = config.needed_files();
+    }
+});
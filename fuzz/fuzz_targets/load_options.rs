@@ -0,0 +1,9 @@
+//! Fuzz the parser for the load options.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use towboot_config::parse_load_options;
+
+fuzz_target!(|load_options: &str| {
+    let _ = parse_load_options(load_options, "");
+});
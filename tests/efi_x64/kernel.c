@@ -0,0 +1,275 @@
+/*  kernel.c - check the EFI information passed by the boot loader (64-bit) */
+/*  Copyright (C) 1999, 2010  Free Software Foundation, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+#include "multiboot2.h"
+
+/*  Macros. */
+#define SER(v) __asm__("outb %b0, %w1"::"a" (v), "d"(0x3f8))
+
+/*  "IBI SYST" and "BOOTSERV" */
+#define EFI_SYSTEM_TABLE_SIGNATURE      0x5453595320494249ULL
+#define EFI_BOOT_SERVICES_SIGNATURE     0x56524553544f4f42ULL
+/*  The offset of BootServices in the 64-bit system table. */
+#define EFI64_BOOT_SERVICES_OFFSET      96
+/*  The indices of AllocatePool and FreePool in the 64-bit Boot Services
+   (after the 24 byte header). */
+#define EFI64_ALLOCATE_POOL             5
+#define EFI64_FREE_POOL                 6
+/*  EfiLoaderData */
+#define EFI_LOADER_DATA                 2
+/*  The size of a memory descriptor in UEFI 2.x */
+#define EFI_MEMORY_DESCRIPTOR_SIZE      40
+
+/*  The firmware uses the Microsoft x64 calling convention. */
+typedef multiboot_uint64_t efi64_status_t;
+typedef efi64_status_t (__attribute__ ((ms_abi)) *efi64_allocate_pool_t)
+  (multiboot_uint32_t type, multiboot_uint64_t size, void **buffer);
+typedef efi64_status_t (__attribute__ ((ms_abi)) *efi64_free_pool_t) (void *buffer);
+
+/*  Forward declarations. */
+void cmain (multiboot_uint32_t magic, multiboot_uint64_t addr);
+static void check_boot_services (multiboot_uint64_t system_table);
+static void itoa (char *buf, int base, int d);
+static void putchar (int c);
+#define printf(format, ...) { void* arg[] = {format, __VA_ARGS__}; printf_(arg); }
+void printf_(void* arg[]);
+
+void puts(char* text) {
+  printf(text);
+}
+
+/*  Check if MAGIC is valid and check the EFI tags in the Multiboot
+   information structure pointed by ADDR. */
+void
+cmain (multiboot_uint32_t magic, multiboot_uint64_t addr)
+{
+  struct multiboot_tag *tag;
+  multiboot_uint64_t system_table64 = 0;
+
+  if (magic != MULTIBOOT2_BOOTLOADER_MAGIC)
+    {
+      printf ("Invalid magic number: 0x%x\n", (void *) (multiboot_uint64_t) magic);
+      return;
+    }
+
+  for (tag = (struct multiboot_tag *) (addr + 8);
+       tag->type != MULTIBOOT_TAG_TYPE_END;
+       tag = (struct multiboot_tag *) ((multiboot_uint8_t *) tag
+                                       + ((tag->size + 7) & ~7)))
+    switch (tag->type)
+      {
+      case MULTIBOOT_TAG_TYPE_EFI32:
+        printf ("EFI32 system table at 0x%x\n",
+                (void *) (multiboot_uint64_t) ((struct multiboot_tag_efi32 *) tag)->pointer);
+        break;
+      case MULTIBOOT_TAG_TYPE_EFI64:
+        system_table64 = ((struct multiboot_tag_efi64 *) tag)->pointer;
+        printf ("EFI64 system table at 0x%x%x\n",
+                (void *) (system_table64 >> 32), (void *) (system_table64 & 0xffffffff));
+        break;
+      case MULTIBOOT_TAG_TYPE_EFI32_IH:
+        printf ("EFI32 image handle = 0x%x\n",
+                (void *) (multiboot_uint64_t) ((struct multiboot_tag_efi32_ih *) tag)->pointer);
+        break;
+      case MULTIBOOT_TAG_TYPE_EFI64_IH:
+        {
+          multiboot_uint64_t pointer = ((struct multiboot_tag_efi64_ih *) tag)->pointer;
+
+          printf ("EFI64 image handle = 0x%x%x\n",
+                  (void *) (pointer >> 32), (void *) (pointer & 0xffffffff));
+        }
+        break;
+      case MULTIBOOT_TAG_TYPE_EFI_MMAP:
+        {
+          struct multiboot_tag_efi_mmap *mmap = (struct multiboot_tag_efi_mmap *) tag;
+
+          /*  printf is a block here, so the branches need braces. */
+          if (mmap->descr_size < EFI_MEMORY_DESCRIPTOR_SIZE)
+            {
+              printf ("EFI memory map has too small descriptors\n");
+            }
+          else
+            {
+              printf ("EFI memory map with %u descriptors (version %u)\n",
+                      (void *) (multiboot_uint64_t) ((mmap->size - 16) / mmap->descr_size),
+                      (void *) (multiboot_uint64_t) mmap->descr_vers);
+            }
+        }
+        break;
+      case MULTIBOOT_TAG_TYPE_EFI_BS:
+        printf ("Boot services not terminated\n");
+        break;
+      }
+
+  if (system_table64)
+    check_boot_services (system_table64);
+}
+
+/*  Check the 64-bit system table and call a Boot Services function. */
+static void
+check_boot_services (multiboot_uint64_t system_table)
+{
+  multiboot_uint64_t boot_services;
+  multiboot_uint64_t *functions;
+  void *buffer = 0;
+  efi64_status_t status;
+
+  if (*(multiboot_uint64_t *) system_table != EFI_SYSTEM_TABLE_SIGNATURE)
+    {
+      printf ("Invalid system table signature\n");
+      return;
+    }
+  printf ("System table signature ok\n");
+  boot_services = *(multiboot_uint64_t *) (system_table + EFI64_BOOT_SERVICES_OFFSET);
+  if (*(multiboot_uint64_t *) boot_services != EFI_BOOT_SERVICES_SIGNATURE)
+    {
+      printf ("Invalid Boot Services signature\n");
+      return;
+    }
+  functions = (multiboot_uint64_t *) (boot_services + 24);
+  status = ((efi64_allocate_pool_t) functions[EFI64_ALLOCATE_POOL])
+    (EFI_LOADER_DATA, 64, &buffer);
+  if (status || !buffer)
+    {
+      printf ("AllocatePool failed with 0x%x\n", (void *) status);
+      return;
+    }
+  status = ((efi64_free_pool_t) functions[EFI64_FREE_POOL]) (buffer);
+  if (status)
+    {
+      printf ("FreePool failed with 0x%x\n", (void *) status);
+      return;
+    }
+  printf ("Boot Services call succeeded\n");
+}
+
+/*  Convert the integer D to a string and save the string in BUF. If
+   BASE is equal to ’d’, interpret that D is decimal, and if BASE is
+   equal to ’x’, interpret that D is hexadecimal. */
+static void
+itoa (char *buf, int base, int d)
+{
+  char *p = buf;
+  char *p1, *p2;
+  unsigned long ud = d;
+  int divisor = 10;
+  
+  /*  If %d is specified and D is minus, put ‘-’ in the head. */
+  if (base == 'd' && d < 0)
+    {
+      *p++ = '-';
+      buf++;
+      ud = -d;
+    }
+  else if (base == 'x')
+    divisor = 16;
+
+  /*  Divide UD by DIVISOR until UD == 0. */
+  do
+    {
+      int remainder = ud % divisor;
+      
+      *p++ = (remainder < 10) ? remainder + '0' : remainder + 'a' - 10;
+    }
+  while (ud /= divisor);
+
+  /*  Terminate BUF. */
+  *p = 0;
+  
+  /*  Reverse BUF. */
+  p1 = buf;
+  p2 = p - 1;
+  while (p1 < p2)
+    {
+      char tmp = *p1;
+      *p1 = *p2;
+      *p2 = tmp;
+      p1++;
+      p2--;
+    }
+}
+
+/*  Put the character C on the serial port. */
+static void
+putchar (int c)
+{
+  SER(c & 0xff);
+  if (c == '\n')
+    SER('\r');
+}
+
+/*  Format a string and print it on the screen, just like the libc
+   function printf. */
+void
+printf_(void* arg[])
+{
+  char* format = *(char**)arg++;
+  int c;
+  char buf[20];
+  
+  while ((c = *format++) != 0)
+    {
+      if (c != '%')
+        putchar (c);
+      else
+        {
+          char *p, *p2;
+          int pad0 = 0, pad = 0;
+          
+          c = *format++;
+          if (c == '0')
+            {
+              pad0 = 1;
+              c = *format++;
+            }
+
+          if (c >= '0' && c <= '9')
+            {
+              pad = c - '0';
+              c = *format++;
+            }
+
+          switch (c)
+            {
+            case 'd':
+            case 'u':
+            case 'x':
+              itoa (buf, c, *((int *) arg++));
+              p = buf;
+              goto string;
+              break;
+
+            case 's':
+              p = *arg++;
+              if (! p)
+                p = "(null)";
+
+            string:
+              for (p2 = p; *p2; p2++);
+              for (; p2 < p + pad; p2++)
+                putchar (pad0 ? '0' : ' ');
+              while (*p)
+                putchar (*p++);
+              break;
+
+            default:
+              putchar (*((int *) arg++));
+              break;
+            }
+        }
+    }
+}
@@ -0,0 +1,283 @@
+/*  kernel.c - check the framebuffer passed by the boot loader */
+/*  Copyright (C) 1999, 2010  Free Software Foundation, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+#include "multiboot2.h"
+
+/*  Macros. */
+#define SER(v) __asm__("outb %b0, %w1"::"a" (v), "d"(0x3f8))
+
+/*  The number of vertical stripes in the test pattern. */
+#define STRIPES                 4
+
+/*  Forward declarations. */
+void cmain (unsigned long magic, unsigned long addr);
+static void check_framebuffer (struct multiboot_tag_framebuffer *tagfb);
+static multiboot_uint32_t stripe_color (struct multiboot_tag_framebuffer *tagfb,
+                                        unsigned stripe);
+static void itoa (char *buf, int base, int d);
+static void putchar (int c);
+void printf (const char *format, ...);
+
+/*  Check if MAGIC is valid and check the framebuffer described in the
+   Multiboot information structure pointed by ADDR. */
+void
+cmain (unsigned long magic, unsigned long addr)
+{
+  struct multiboot_tag *tag;
+  int found = 0;
+
+  if (magic != MULTIBOOT2_BOOTLOADER_MAGIC)
+    {
+      printf ("Invalid magic number: 0x%x\n", (unsigned) magic);
+      return;
+    }
+
+  for (tag = (struct multiboot_tag *) (addr + 8);
+       tag->type != MULTIBOOT_TAG_TYPE_END;
+       tag = (struct multiboot_tag *) ((multiboot_uint8_t *) tag
+                                       + ((tag->size + 7) & ~7)))
+    if (tag->type == MULTIBOOT_TAG_TYPE_FRAMEBUFFER)
+      {
+        found = 1;
+        check_framebuffer ((struct multiboot_tag_framebuffer *) tag);
+      }
+
+  if (!found)
+    printf ("No framebuffer\n");
+}
+
+/*  Get the color of the given stripe (red, green, blue, white). */
+static multiboot_uint32_t
+stripe_color (struct multiboot_tag_framebuffer *tagfb, unsigned stripe)
+{
+  multiboot_uint32_t red = ((1 << tagfb->framebuffer_red_mask_size) - 1)
+    << tagfb->framebuffer_red_field_position;
+  multiboot_uint32_t green = ((1 << tagfb->framebuffer_green_mask_size) - 1)
+    << tagfb->framebuffer_green_field_position;
+  multiboot_uint32_t blue = ((1 << tagfb->framebuffer_blue_mask_size) - 1)
+    << tagfb->framebuffer_blue_field_position;
+
+  switch (stripe)
+    {
+    case 0:
+      return red;
+    case 1:
+      return green;
+    case 2:
+      return blue;
+    default:
+      return red | green | blue;
+    }
+}
+
+/*  Print the format of the framebuffer, draw a pattern and read it back. */
+static void
+check_framebuffer (struct multiboot_tag_framebuffer *tagfb)
+{
+  struct multiboot_tag_framebuffer_common *common = &tagfb->common;
+  multiboot_uint8_t *fb = (multiboot_uint8_t *) (unsigned long) common->framebuffer_addr;
+  unsigned bytes = (common->framebuffer_bpp + 7) / 8;
+  unsigned x, y, i;
+
+  printf ("Framebuffer type = %u, bpp = %u\n",
+          (unsigned) common->framebuffer_type, (unsigned) common->framebuffer_bpp);
+  printf ("Resolution = %ux%u, pitch = %u\n",
+          common->framebuffer_width, common->framebuffer_height,
+          common->framebuffer_pitch);
+
+  if (common->framebuffer_type != MULTIBOOT_FRAMEBUFFER_TYPE_RGB)
+    {
+      printf ("Only RGB framebuffers can be checked\n");
+      return;
+    }
+  printf ("red = %u@%u, green = %u@%u, blue = %u@%u\n",
+          (unsigned) tagfb->framebuffer_red_mask_size,
+          (unsigned) tagfb->framebuffer_red_field_position,
+          (unsigned) tagfb->framebuffer_green_mask_size,
+          (unsigned) tagfb->framebuffer_green_field_position,
+          (unsigned) tagfb->framebuffer_blue_mask_size,
+          (unsigned) tagfb->framebuffer_blue_field_position);
+
+  if (common->framebuffer_pitch < common->framebuffer_width * bytes)
+    {
+      printf ("Pitch is too small\n");
+      return;
+    }
+  if (tagfb->framebuffer_red_field_position + tagfb->framebuffer_red_mask_size
+        > common->framebuffer_bpp
+      || tagfb->framebuffer_green_field_position + tagfb->framebuffer_green_mask_size
+        > common->framebuffer_bpp
+      || tagfb->framebuffer_blue_field_position + tagfb->framebuffer_blue_mask_size
+        > common->framebuffer_bpp
+      || (stripe_color (tagfb, 0) & stripe_color (tagfb, 1))
+      || (stripe_color (tagfb, 0) & stripe_color (tagfb, 2))
+      || (stripe_color (tagfb, 1) & stripe_color (tagfb, 2)))
+    {
+      printf ("Color fields are invalid\n");
+      return;
+    }
+  printf ("Format is consistent\n");
+
+  /*  Draw vertical stripes.  */
+  for (y = 0; y < common->framebuffer_height; y++)
+    for (x = 0; x < common->framebuffer_width; x++)
+      {
+        multiboot_uint32_t color
+          = stripe_color (tagfb, x * STRIPES / common->framebuffer_width);
+        multiboot_uint8_t *pixel = fb + common->framebuffer_pitch * y + bytes * x;
+
+        for (i = 0; i < bytes; i++)
+          pixel[i] = (color >> (8 * i)) & 0xff;
+      }
+
+  /*  Read the pattern back: the last pixel of each line is in the last
+     stripe, so a wrong pitch shows up here.  */
+  for (y = 0; y < common->framebuffer_height; y++)
+    for (x = 0; x < common->framebuffer_width; x++)
+      {
+        multiboot_uint32_t color
+          = stripe_color (tagfb, x * STRIPES / common->framebuffer_width);
+        multiboot_uint8_t *pixel = fb + common->framebuffer_pitch * y + bytes * x;
+
+        for (i = 0; i < bytes; i++)
+          if (pixel[i] != ((color >> (8 * i)) & 0xff))
+            {
+              printf ("Pattern mismatch at %u,%u\n", x, y);
+              return;
+            }
+      }
+  printf ("Pattern ok\n");
+}
+
+/*  Convert the integer D to a string and save the string in BUF. If
+   BASE is equal to ’d’, interpret that D is decimal, and if BASE is
+   equal to ’x’, interpret that D is hexadecimal. */
+static void
+itoa (char *buf, int base, int d)
+{
+  char *p = buf;
+  char *p1, *p2;
+  unsigned long ud = d;
+  int divisor = 10;
+  
+  /*  If %d is specified and D is minus, put ‘-’ in the head. */
+  if (base == 'd' && d < 0)
+    {
+      *p++ = '-';
+      buf++;
+      ud = -d;
+    }
+  else if (base == 'x')
+    divisor = 16;
+
+  /*  Divide UD by DIVISOR until UD == 0. */
+  do
+    {
+      int remainder = ud % divisor;
+      
+      *p++ = (remainder < 10) ? remainder + '0' : remainder + 'a' - 10;
+    }
+  while (ud /= divisor);
+
+  /*  Terminate BUF. */
+  *p = 0;
+  
+  /*  Reverse BUF. */
+  p1 = buf;
+  p2 = p - 1;
+  while (p1 < p2)
+    {
+      char tmp = *p1;
+      *p1 = *p2;
+      *p2 = tmp;
+      p1++;
+      p2--;
+    }
+}
+
+/*  Put the character C on the serial port. */
+static void
+putchar (int c)
+{
+  SER(c & 0xff);
+  if (c == '\n')
+    SER('\r');
+}
+
+/*  Format a string and print it on the screen, just like the libc
+   function printf. */
+void
+printf (const char *format, ...)
+{
+  char **arg = (char **) &format;
+  int c;
+  char buf[20];
+
+  arg++;
+  
+  while ((c = *format++) != 0)
+    {
+      if (c != '%')
+        putchar (c);
+      else
+        {
+          char *p, *p2;
+          int pad0 = 0, pad = 0;
+          
+          c = *format++;
+          if (c == '0')
+            {
+              pad0 = 1;
+              c = *format++;
+            }
+
+          if (c >= '0' && c <= '9')
+            {
+              pad = c - '0';
+              c = *format++;
+            }
+
+          switch (c)
+            {
+            case 'd':
+            case 'u':
+            case 'x':
+              itoa (buf, c, *((int *) arg++));
+              p = buf;
+              goto string;
+              break;
+
+            case 's':
+              p = *arg++;
+              if (! p)
+                p = "(null)";
+
+            string:
+              for (p2 = p; *p2; p2++);
+              for (; p2 < p + pad; p2++)
+                putchar (pad0 ? '0' : ' ');
+              while (*p)
+                putchar (*p++);
+              break;
+
+            default:
+              putchar (*((int *) arg++));
+              break;
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Check that the configuration builder catches invalid configurations.
+use towboot_config::{BuildError, Config, Entry, EntryKind, Module};
+
+#[test]
+fn builds_valid_config() {
+    let config = Config::builder()
+        .default("a")
+        .fallback("b")
+        .entry("a", Entry::builder("a.elf").module(Module::new("initrd")).build())
+        .entry("b", Entry::builder("").kind(EntryKind::UefiShell).build())
+        .build()
+        .expect("the configuration should be valid");
+    assert_eq!(config.entries.len(), 2);
+    assert_eq!(config.entries["a"].modules[0].image, "initrd");
+}
+
+#[test]
+fn rejects_duplicate_entries() {
+    let result = Config::builder()
+        .default("a")
+        .entry("a", Entry::builder("a.elf").build())
+        .entry("a", Entry::builder("b.elf").build())
+        .build();
+    assert_eq!(result.unwrap_err(), BuildError::DuplicateEntry("a".to_string()));
+}
+
+#[test]
+fn rejects_missing_default() {
+    let builder = || Config::builder().entry("a", Entry::builder("a.elf").build());
+    assert_eq!(builder().build().unwrap_err(), BuildError::MissingDefault);
+    assert_eq!(
+        builder().default("b").build().unwrap_err(),
+        BuildError::UnknownDefault("b".to_string()),
+    );
+}
+
+#[test]
+fn rejects_incomplete_entries() {
+    let result = Config::builder()
+        .default("a")
+        .entry("a", Entry::builder("").build())
+        .build();
+    assert_eq!(result.unwrap_err(), BuildError::MissingImage("a".to_string()));
+    let result = Config::builder()
+        .default("a")
+        .entry("a", Entry::builder("").kind(EntryKind::BootNext).build())
+        .build();
+    assert_eq!(result.unwrap_err(), BuildError::MissingBootOption("a".to_string()));
+}
+
+#[test]
+fn checks_entries_without_a_default() {
+    let mut config = Config::builder()
+        .default("a")
+        .entry("a", Entry::builder("a.elf").build())
+        .build()
+        .unwrap();
+    config.default = "b".to_string();
+    assert_eq!(config.validate(), Err(BuildError::UnknownDefault("b".to_string())));
+    assert_eq!(config.validate_entries(), Ok(()));
+    config.entries.insert("c".to_string(), Entry::builder("").build());
+    assert_eq!(config.validate_entries(), Err(BuildError::MissingImage("c".to_string())));
+}
+
+#[test]
+fn accepts_renamed_keys() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    std::io::Write::write_all(
+        &mut file, b"default = \"a\"\nloglevel = \"debug\"\n\n[entries.a]\nimage = \"a.elf\"\n",
+    ).unwrap();
+    let config = towbootctl::config::read_file(file.path().to_str().unwrap())
+        .expect("the old name should still be accepted");
+    assert_eq!(config.log_level.as_deref(), Some("debug"));
+}
@@ -0,0 +1,62 @@
+//! Check that broken Multiboot headers are explained.
+use super::header::diagnose;
+
+/// Put the given words at `offset` into an otherwise empty kernel.
+fn kernel(offset: usize, words: &[u32]) -> Vec<u8> {
+    let mut kernel = vec![0; 16 * 1024];
+    for (index, word) in words.iter().enumerate() {
+        kernel[offset + index * 4..offset + index * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    kernel
+}
+
+#[test]
+fn reports_missing_magic() {
+    assert_eq!(
+        diagnose(&kernel(0, &[])),
+        ["there is no Multiboot magic in the first 16384 bytes (is this a Multiboot kernel?)"],
+    );
+}
+
+#[test]
+fn reports_valid_multiboot1_header() {
+    let findings = diagnose(&kernel(0x40, &[0x1BADB002, 0, 0u32.wrapping_sub(0x1BADB002)]));
+    assert_eq!(findings, ["Multiboot1 magic at 0x40 looks like a valid header"]);
+}
+
+#[test]
+fn reports_multiboot1_checksum_and_placement() {
+    let findings = diagnose(&kernel(0x2002, &[0x1BADB002, 0x10000, 0]));
+    assert_eq!(findings, [
+        "Multiboot1 magic at 0x2002 is not 4-byte aligned",
+        "Multiboot1 magic at 0x2002 is not within the first 8 KiB \
+        (see the ExtendedHeaderSearch quirk)",
+        "Multiboot1 magic at 0x2002: the checksum is 0x0, but it should be 0xe4514ffe \
+        (for flags 0x10000)",
+    ]);
+}
+
+#[test]
+fn reports_unknown_multiboot2_tags() {
+    let length = 16 + 16 + 8 + 8;
+    let findings = diagnose(&kernel(0x1000, &[
+        0xE85250D6, 0, length, 0u32.wrapping_sub(0xE85250D6 + length),
+        // a required tag of type 42 (with 8 bytes of data) and an optional one of type 43
+        42, 16, 0, 0,
+        43 | 1 << 16, 8,
+        0, 8,
+    ]));
+    assert_eq!(findings, [
+        "Multiboot2 magic at 0x1000: tag 42 at 0x1010 is required, but unknown",
+        "Multiboot2 magic at 0x1000: tag 43 at 0x1020 is unknown, it is ignored",
+    ]);
+}
+
+#[test]
+fn reports_missing_end_tag() {
+    // just an information request tag
+    let findings = diagnose(&kernel(0, &[
+        0xE85250D6, 0, 32, 0u32.wrapping_sub(0xE85250D6 + 32), 1, 16, 0, 0,
+    ]));
+    assert_eq!(findings, ["Multiboot2 magic at 0x0: there is no end tag"]);
+}
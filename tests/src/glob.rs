@@ -0,0 +1,48 @@
+//! Check the wildcards in module paths.
+use std::fs;
+use std::path::PathBuf;
+
+use tempfile::tempdir;
+use towboot_config::{glob_matches, split_glob};
+use towbootctl::config::copy_destinations;
+
+#[test]
+fn splits_only_patterns() {
+    assert_eq!(split_glob("\\modules\\*.ko"), Some(("\\modules", "*.ko")));
+    assert_eq!(split_glob("modules/net?.ko"), Some(("modules", "net?.ko")));
+    assert_eq!(split_glob("*.ko"), Some(("", "*.ko")));
+    assert_eq!(split_glob("\\modules\\net.ko"), None);
+}
+
+#[test]
+fn matches_names() {
+    assert!(glob_matches("*.ko", "net.ko"));
+    assert!(glob_matches("*.ko", "NET.KO"));
+    assert!(glob_matches("net?.ko", "net1.ko"));
+    assert!(glob_matches("*", ""));
+    assert!(!glob_matches("*.ko", "net.ko.bak"));
+    assert!(!glob_matches("net?.ko", "net.ko"));
+}
+
+#[test]
+fn copies_matches_into_their_own_directory() {
+    let dir = tempdir().unwrap();
+    fs::create_dir(dir.path().join("modules")).unwrap();
+    for name in ["b.ko", "a.ko", "readme.txt"] {
+        fs::write(dir.path().join("modules").join(name), name).unwrap();
+    }
+    let mut directories = vec!["modules".to_string()];
+    let (path, files) = copy_destinations(
+        &dir.path().join("modules").join("*.ko"), &mut directories,
+    ).unwrap();
+    assert_eq!(path, "modules-2\\*.ko");
+    assert_eq!(files, vec![
+        (dir.path().join("modules").join("a.ko"), PathBuf::from("modules-2").join("a.ko")),
+        (dir.path().join("modules").join("b.ko"), PathBuf::from("modules-2").join("b.ko")),
+    ]);
+    let (path, files) = copy_destinations(
+        &dir.path().join("modules").join("readme.txt"), &mut directories,
+    ).unwrap();
+    assert_eq!(path, "readme.txt");
+    assert_eq!(files.len(), 1);
+}
@@ -0,0 +1,336 @@
+//! Compare the Multiboot information towboot generates to known-good output.
+//!
+//! The information is decoded (following the specification) into a textual
+//! description, which is compared to the files in the `golden` folder.
+//! If a change is intentional, run the tests with `TOWBOOT_BLESS=1` to update
+//! them (and check the new files in).
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use multiboot12::header::Header;
+
+use super::info::{BootDevice, Drive, EfiPointers, Supplement, drives_structure, fill};
+
+/// A minimal Multiboot1 header (without any flags)
+pub(super) const MULTIBOOT1_HEADER: [u32; 3] = [0x1BADB002, 0, 0u32.wrapping_sub(0x1BADB002)];
+
+/// A minimal Multiboot2 header (just the end tag)
+pub(super) const MULTIBOOT2_HEADER: [u32; 6] = [
+    0xE85250D6, 0, 24, 0u32.wrapping_sub(0xE85250D6 + 24),
+    0, 8,
+];
+
+/// The offsets of the pointers in the Multiboot1 information struct:
+/// cmdline, mods_addr, mmap_addr, drives_addr and boot_loader_name
+const MULTIBOOT1_POINTERS: [usize; 5] = [16, 24, 48, 56, 64];
+
+/// The flags of the Multiboot1 information for the memory information
+/// (`mem_*` and `mmap_*`), which is only filled in at boot
+const MULTIBOOT1_MEMORY_FLAGS: u32 = 1 << 0 | 1 << 6;
+
+/// The command line used for the golden files
+const COMMAND_LINE: &str = "test of a cmdline";
+
+/// Build the information for a kernel with the given header, using fixed inputs
+/// (except for the command line and the supplement).
+fn build(
+    header: &[u32], efi_pointers: Option<EfiPointers>, command_line: &str,
+    supplement: &Supplement,
+) -> Vec<u8> {
+    let header: Vec<u8> = header.iter().flat_map(|w| w.to_le_bytes()).collect();
+    let header = Header::from_slice(&header).expect("invalid header");
+    let mut info_builder = header.info_builder();
+    fill(
+        &mut info_builder, Some(command_line), Some(0x10_0000),
+        &[(0x20_0000, 0x20_1000, Some("initrd")), (0x30_0000, 0x30_0800, None)], None,
+        "towboot test", efi_pointers, true,
+    );
+    let _efi_mmap = info_builder.allocate_efi_memory_map_vec(0);
+    let _mmap = info_builder.allocate_memory_map_vec(0);
+    info_builder.set_memory_bounds(Some((0, 0)));
+    let (mut info, signature, _) = info_builder.build();
+    supplement.add_to(&mut info, signature);
+    info
+}
+
+/// Make the pointers in Multiboot1 information independent of where it is.
+///
+/// Pointers into the struct itself become offsets, other ones become zero.
+fn normalize_multiboot1(mut info: Vec<u8>) -> Vec<u8> {
+    let base = info.as_ptr() as usize as u32;
+    let len = info.len() as u32;
+    let read = |info: &[u8], offset: usize| u32::from_le_bytes(
+        info[offset..offset + 4].try_into().unwrap()
+    );
+    let normalize = |value: u32| match value.wrapping_sub(base) {
+        offset if offset < len => offset,
+        _ => 0,
+    };
+    // the modules contain pointers to their strings
+    let mods_count = read(&info, 20) as usize;
+    let mods_offset = normalize(read(&info, 24)) as usize;
+    let mut pointers = MULTIBOOT1_POINTERS.to_vec();
+    if mods_offset != 0 {
+        pointers.extend((0..mods_count).map(|i| mods_offset + i * 16 + 8));
+    }
+    for offset in pointers {
+        let value = normalize(read(&info, offset));
+        info[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    info
+}
+
+/// Create a command line that is much longer than what fits into a page.
+fn long_command_line() -> String {
+    (0..10_000).map(|i| format!("arg{i}=value")).collect::<Vec<_>>().join(" ")
+}
+
+/// Get a null-terminated string.
+fn string(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes.split(|b| *b == 0).next().unwrap()).into_owned()
+}
+
+/// Describe Multiboot1 information (leaving out the memory information).
+///
+/// Pointers have to point into the information itself.
+fn describe_multiboot1(info: &[u8]) -> String {
+    let read = |offset: usize| u32::from_le_bytes(info[offset..offset + 4].try_into().unwrap());
+    let base = info.as_ptr() as usize as u32;
+    let offset = |pointer: u32| {
+        let offset = pointer.wrapping_sub(base) as usize;
+        assert!(offset < info.len(), "{pointer:#x} points outside of the information");
+        offset
+    };
+    let pointed_string = |pointer: u32| match pointer {
+        0 => String::new(),
+        pointer => string(&info[offset(pointer)..]),
+    };
+    let flags = read(0);
+    let mut lines = vec![format!("flags: {:#x}", flags & !MULTIBOOT1_MEMORY_FLAGS)];
+    if flags & 1 << 1 != 0 {
+        let [part3, part2, part1, drive] = read(12).to_le_bytes();
+        lines.push(format!(
+            "boot device: drive {drive:#x}, partitions {part1:#x} {part2:#x} {part3:#x}",
+        ));
+    }
+    if flags & 1 << 2 != 0 {
+        lines.push(format!("cmdline: {:?}", pointed_string(read(16))));
+    }
+    if flags & 1 << 3 != 0 && read(20) != 0 {
+        let mods = offset(read(24));
+        for module in (0..read(20) as usize).map(|i| mods + i * 16) {
+            lines.push(format!(
+                "module {:#x}-{:#x}: {:?}",
+                read(module), read(module + 4), pointed_string(read(module + 8)),
+            ));
+        }
+    }
+    // the drives structure is outside of the information
+    if flags & 1 << 7 != 0 {
+        lines.push(format!("drives: {} bytes at {:#x}", read(52), read(56)));
+    }
+    if flags & 1 << 9 != 0 {
+        lines.push(format!("boot loader name: {:?}", pointed_string(read(64))));
+    }
+    lines.join("\n") + "\n"
+}
+
+/// Describe Multiboot2 information (leaving out the memory information).
+///
+/// The tags are sorted by their type, but tags of the same type keep their order.
+fn describe_multiboot2(info: &[u8]) -> String {
+    let read = |offset: usize| u32::from_le_bytes(info[offset..offset + 4].try_into().unwrap());
+    let read64 = |offset: usize| u64::from_le_bytes(
+        info[offset..offset + 8].try_into().unwrap()
+    );
+    let total_size = read(0) as usize;
+    assert!(total_size <= info.len(), "the total size {total_size} is too big");
+    let mut tags = Vec::new();
+    let mut offset = 8;
+    loop {
+        let (tag_type, size) = (read(offset), read(offset + 4) as usize);
+        assert!(
+            size >= 8 && offset + size <= total_size,
+            "tag {tag_type} at {offset:#x} has an invalid size of {size}",
+        );
+        let expect_size = |expected: usize| assert_eq!(
+            size, expected, "tag {tag_type} at {offset:#x} has the wrong size",
+        );
+        let description = match tag_type {
+            0 => {
+                expect_size(8);
+                break;
+            },
+            1 => format!("cmdline: {:?}", string(&info[offset + 8..offset + size])),
+            2 => format!("boot loader name: {:?}", string(&info[offset + 8..offset + size])),
+            3 => format!(
+                "module {:#x}-{:#x}: {:?}",
+                read(offset + 8), read(offset + 12), string(&info[offset + 16..offset + size]),
+            ),
+            // the memory information is only filled in at boot
+            4 | 6 | 17 => String::new(),
+            5 => {
+                expect_size(20);
+                format!(
+                    "boot device: drive {:#x}, partition {:#x}, sub-partition {:#x}",
+                    read(offset + 8), read(offset + 12), read(offset + 16),
+                )
+            },
+            11 => {
+                expect_size(12);
+                format!("EFI 32-bit system table: {:#x}", read(offset + 8))
+            },
+            12 => {
+                expect_size(16);
+                format!("EFI 64-bit system table: {:#x}", read64(offset + 8))
+            },
+            18 => {
+                expect_size(8);
+                "boot services not exited".to_string()
+            },
+            19 => {
+                expect_size(12);
+                format!("EFI 32-bit image handle: {:#x}", read(offset + 8))
+            },
+            20 => {
+                expect_size(16);
+                format!("EFI 64-bit image handle: {:#x}", read64(offset + 8))
+            },
+            16 => format!("network: DHCP ACK of {} bytes", size - 8),
+            21 => {
+                expect_size(12);
+                format!("image load base address: {:#x}", read(offset + 8))
+            },
+            _ => format!("unknown tag {tag_type} of {size} bytes"),
+        };
+        if !description.is_empty() {
+            tags.push((tag_type, description));
+        }
+        offset += size.next_multiple_of(8);
+    }
+    tags.sort_by_key(|(tag_type, _)| *tag_type);
+    tags.into_iter().map(|(_, description)| description + "\n").collect()
+}
+
+/// Compare the description to the golden file with the given name.
+///
+/// A missing file is an error, unless the files are being updated.
+fn compare(name: &str, description: &str) {
+    let mut path = PathBuf::from("golden");
+    path.push(format!("{name}.txt"));
+    if env::var_os("TOWBOOT_BLESS").is_some() {
+        fs::create_dir_all("golden").unwrap();
+        fs::write(&path, description).unwrap();
+        println!("wrote {}", path.display());
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_else(|e| panic!(
+        "failed to read {} ({e}), run with TOWBOOT_BLESS=1 to create it", path.display(),
+    ));
+    assert_eq!(expected, description, "{name} differs from {}", path.display());
+}
+
+#[test]
+fn multiboot1_information() {
+    let info = build(&MULTIBOOT1_HEADER, None, COMMAND_LINE, &Supplement::default());
+    compare("multiboot1", &describe_multiboot1(&info));
+}
+
+#[test]
+fn multiboot2_information_ia32() {
+    let info = build(&MULTIBOOT2_HEADER, Some(EfiPointers::Ia32 {
+        system_table: 0x7F00_0000, image_handle: 0x7E00_0000,
+    }), COMMAND_LINE, &Supplement::default());
+    compare("multiboot2_ia32", &describe_multiboot2(&info));
+}
+
+#[test]
+fn multiboot2_information_x64() {
+    let info = build(&MULTIBOOT2_HEADER, Some(EfiPointers::X64 {
+        system_table: 0x1_7F00_0000, image_handle: 0x1_7E00_0000,
+    }), COMMAND_LINE, &Supplement::default());
+    compare("multiboot2_x64", &describe_multiboot2(&info));
+}
+
+/// Get a supplement with all fields set.
+fn supplement() -> Supplement {
+    Supplement {
+        boot_device: Some(BootDevice { drive: 0x81, partition: Some(1) }),
+        // not a multiple of 8 to check the padding
+        dhcp_ack: Some(vec![0x02; 1466]),
+        drives: Some((0x9_0000, 24)),
+    }
+}
+
+#[test]
+fn multiboot1_supplement() {
+    let supplement = supplement();
+    assert_eq!(supplement.size(false), 0);
+    let plain = build(&MULTIBOOT1_HEADER, None, COMMAND_LINE, &Supplement::default());
+    let info = build(&MULTIBOOT1_HEADER, None, COMMAND_LINE, &supplement);
+    // the information can't grow, as it points to itself
+    assert_eq!(info.len(), plain.len());
+    compare("multiboot1_supplement", &describe_multiboot1(&info));
+}
+
+#[test]
+fn multiboot1_whole_drive() {
+    let supplement = Supplement {
+        boot_device: Some(BootDevice { drive: 0x80, partition: None }),
+        ..Default::default()
+    };
+    let info = build(&MULTIBOOT1_HEADER, None, COMMAND_LINE, &supplement);
+    assert_eq!(u32::from_le_bytes(info[12..16].try_into().unwrap()), 0x80FF_FFFF);
+}
+
+#[test]
+fn multiboot1_drives() {
+    let structure = drives_structure(&[
+        Drive { number: 0x80, sectors: 255 * 63 * 100 + 62 },
+        Drive { number: 0x81, sectors: u64::MAX },
+    ]);
+    assert_eq!(structure, [
+        12, 0, 0, 0, 0x80, 1, 100, 0, 255, 63, 0, 0,
+        12, 0, 0, 0, 0x81, 1, 0xff, 0xff, 255, 63, 0, 0,
+    ]);
+}
+
+#[test]
+fn multiboot2_supplement() {
+    let supplement = supplement();
+    let plain = build(&MULTIBOOT2_HEADER, None, COMMAND_LINE, &Supplement::default());
+    let info = build(&MULTIBOOT2_HEADER, None, COMMAND_LINE, &supplement);
+    assert_eq!(info.len() as u64, plain.len() as u64 + supplement.size(true));
+    assert_eq!(u32::from_le_bytes(info[..4].try_into().unwrap()) as usize, info.len());
+    compare("multiboot2_supplement", &describe_multiboot2(&info));
+}
+
+#[test]
+fn multiboot1_long_command_line() {
+    let command_line = long_command_line();
+    let info = normalize_multiboot1(build(
+        &MULTIBOOT1_HEADER, None, &command_line, &Supplement::default(),
+    ));
+    let offset = u32::from_le_bytes(info[16..20].try_into().unwrap()) as usize;
+    assert_ne!(offset, 0, "the command line is not inside the information");
+    let length = info[offset..].iter().position(|b| *b == 0).unwrap();
+    assert_eq!(&info[offset..offset + length], command_line.as_bytes());
+}
+
+#[test]
+fn multiboot2_long_command_line() {
+    let command_line = long_command_line();
+    let info = build(&MULTIBOOT2_HEADER, None, &command_line, &Supplement::default());
+    let read = |offset: usize| u32::from_le_bytes(info[offset..offset + 4].try_into().unwrap());
+    // the tags start after the fixed part and are aligned to 8 bytes
+    let mut offset = 8;
+    while read(offset) != 1 {
+        assert_ne!(read(offset), 0, "there is no command line tag");
+        offset += (read(offset + 4) as usize).next_multiple_of(8);
+    }
+    let size = read(offset + 4) as usize;
+    assert_eq!(size, 8 + command_line.len() + 1);
+    assert_eq!(&info[offset + 8..offset + size - 1], command_line.as_bytes());
+    assert_eq!(info[offset + size - 1], 0);
+}
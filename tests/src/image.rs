@@ -0,0 +1,166 @@
+//! Check the contents of built images without booting them.
+//!
+//! The images are opened with gpt and fatfs directly, not with towbootctl.
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use fatfs::{FileSystem, FsOptions};
+use fscommon::StreamSlice;
+use gpt::{GptConfig, disk::LogicalBlockSize, mbr::ProtectiveMBR, partition_types};
+use sha2::{Digest, Sha256};
+use tempfile::{NamedTempFile, TempDir, tempdir};
+use towbootctl::{
+    DEFAULT_PARTITION_NAME, DEFAULT_SECTOR_SIZE, IA32_BOOT_PATH, Image, Partition, X64_BOOT_PATH,
+};
+
+use super::{Arch, build_config};
+
+/// The fake kernel and module the configuration refers to
+const KERNEL: &[u8] = b"not really a kernel";
+const MODULE: &[u8] = b"not really a module";
+
+/// Create a configuration whose kernel and module are in subdirectories.
+fn config_dir() -> TempDir {
+    let dir = tempdir().unwrap();
+    fs::create_dir(dir.path().join("kernels")).unwrap();
+    fs::write(dir.path().join("kernels").join("kernel.elf"), KERNEL).unwrap();
+    fs::create_dir(dir.path().join("modules")).unwrap();
+    fs::write(dir.path().join("modules").join("initrd.img"), MODULE).unwrap();
+    fs::write(dir.path().join("towboot.toml"), "\
+        default = \"a\"\n\n\
+        [entries.a]\n\
+        image = \"kernels/kernel.elf\"\n\
+        argv = \"test\"\n\n\
+        [[entries.a.modules]]\n\
+        image = \"modules/initrd.img\"\n\
+        argv = \"initrd\"\n\
+    ").unwrap();
+    dir
+}
+
+/// Open the EFI System Partition of the image, checking the partition table.
+///
+/// This also returns how many partitions there are.
+fn open_esp(image: &Path, sector_size: u64) -> (FileSystem<StreamSlice<File>>, usize) {
+    let disk = GptConfig::new()
+        .writable(false)
+        .logical_block_size(match sector_size {
+            4096 => LogicalBlockSize::Lb4096,
+            _ => LogicalBlockSize::Lb512,
+        })
+        .open(image)
+        .expect("the image should have a GPT");
+    // the EFI System Partition comes first
+    let partition = disk.partitions().values().next().expect("there should be a partition");
+    assert_eq!(partition.part_type_guid, partition_types::EFI);
+    assert_eq!(partition.name, DEFAULT_PARTITION_NAME);
+    assert_eq!(partition.first_lba * sector_size, 1024 * 1024);
+    let part = StreamSlice::new(
+        File::open(image).unwrap(),
+        partition.first_lba * sector_size, (partition.last_lba + 1) * sector_size,
+    ).unwrap();
+    let fs = FileSystem::new(part, FsOptions::new()).expect("the partition should be formatted");
+    (fs, disk.partitions().len())
+}
+
+/// Read a file from the filesystem, if it exists.
+fn read(fs: &FileSystem<StreamSlice<File>>, path: &str) -> Option<Vec<u8>> {
+    let mut file = fs.root_dir().open_file(path).ok()?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).unwrap();
+    Some(buf)
+}
+
+/// Hash the data, so that mismatches don't print whole binaries.
+fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+#[test]
+fn contains_towboot() {
+    let dir = config_dir();
+    let image = build_config(&dir.path().join("towboot.toml"), &[Arch::I686, Arch::X86_64])
+        .expect("failed to build the image");
+    let (fs, partitions) = open_esp(&image, DEFAULT_SECTOR_SIZE);
+    assert_eq!(partitions, 1);
+    assert_eq!(read(&fs, IA32_BOOT_PATH).map(|b| sha256(&b)), Some(sha256(towboot_ia32::TOWBOOT)));
+    assert_eq!(read(&fs, X64_BOOT_PATH).map(|b| sha256(&b)), Some(sha256(towboot_x64::TOWBOOT)));
+}
+
+#[test]
+fn contains_only_the_requested_architectures() {
+    let dir = config_dir();
+    let image = build_config(&dir.path().join("towboot.toml"), &[Arch::X86_64])
+        .expect("failed to build the image");
+    let (fs, _) = open_esp(&image, DEFAULT_SECTOR_SIZE);
+    assert!(read(&fs, X64_BOOT_PATH).is_some());
+    assert!(read(&fs, IA32_BOOT_PATH).is_none());
+}
+
+#[test]
+fn rewrites_the_configuration() {
+    let dir = config_dir();
+    let image = build_config(&dir.path().join("towboot.toml"), &[Arch::X86_64])
+        .expect("failed to build the image");
+    let (fs, _) = open_esp(&image, DEFAULT_SECTOR_SIZE);
+    let mut config_file = NamedTempFile::new().unwrap();
+    config_file.as_file_mut()
+        .write_all(&read(&fs, "towboot.toml").expect("the configuration should be there"))
+        .unwrap();
+    let config = towbootctl::config::read_file(config_file.path().to_str().unwrap())
+        .expect("the configuration should be valid");
+    let entry = &config.entries["a"];
+    assert_eq!(entry.image, "kernel.elf");
+    assert_eq!(entry.argv.as_deref(), Some("test"));
+    assert_eq!(entry.modules[0].image, "initrd.img");
+    assert_eq!(entry.modules[0].argv.as_deref(), Some("initrd"));
+    assert_eq!(read(&fs, "kernel.elf").map(|b| sha256(&b)), Some(sha256(KERNEL)));
+    assert_eq!(read(&fs, "initrd.img").map(|b| sha256(&b)), Some(sha256(MODULE)));
+}
+
+#[test]
+fn fits_the_partitions_onto_the_disk() {
+    for sector_size in [512, 4096] {
+        let path = NamedTempFile::new().unwrap().into_temp_path();
+        let extra: Partition = "name=data,size=1M".parse().unwrap();
+        let mut image = Image::new(
+            &path, 32 * 1024 * 1024, DEFAULT_PARTITION_NAME, None, sector_size, None, None,
+            &[extra],
+        ).expect("failed to create the image");
+        image.add_data(KERNEL, Path::new("kernel.elf")).unwrap();
+        drop(image);
+        let (fs, partitions) = open_esp(&path, sector_size);
+        assert_eq!(partitions, 2);
+        assert_eq!(read(&fs, "kernel.elf").map(|b| sha256(&b)), Some(sha256(KERNEL)));
+    }
+}
+
+#[test]
+fn refuses_an_esp_too_small_for_fat32() {
+    // an empty GPT disk
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let size = 64 * 1024 * 1024;
+    let mut file = Box::new(OpenOptions::new().read(true).write(true).open(&path).unwrap());
+    file.set_len(size).unwrap();
+    ProtectiveMBR::with_lb_size(u32::try_from(size / 512 - 1).unwrap())
+        .overwrite_lba0(&mut file).unwrap();
+    let mut disk = GptConfig::new()
+        .writable(true)
+        .logical_block_size(LogicalBlockSize::Lb512)
+        .create_from_device(file, None)
+        .unwrap();
+    disk.update_partitions(BTreeMap::new()).unwrap();
+    disk.write().unwrap();
+    let mut asked = false;
+    let result = Image::add_esp(
+        &path, 8 * 1024 * 1024, DEFAULT_PARTITION_NAME, None, None, &mut |_| { asked = true; true },
+    );
+    assert!(result.is_err());
+    assert!(!asked, "nothing should be written");
+    let image = Image::add_esp(
+        &path, 40 * 1024 * 1024, DEFAULT_PARTITION_NAME, None, None, &mut |_| true,
+    ).expect("failed to add the ESP");
+    assert!(image.is_some());
+}
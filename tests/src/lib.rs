@@ -2,14 +2,30 @@
 #![cfg(test)]
 #![feature(exit_status_error)]
 use std::error::Error;
+use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::thread::sleep;
 use std::time::Duration;
 
-use tempfile::NamedTempFile;
-use towbootctl::{boot_image, create_image};
+use sha2::{Digest, Sha256};
+use tempfile::{NamedTempFile, TempPath};
+use towbootctl::{
+    BootOptions, DEBUG_EXIT_SUCCESS, DEFAULT_PARTITION_NAME, boot_image, create_image,
+};
+
+// the information and header modules are shared with towboot, which is no_std
+extern crate alloc;
+#[path = "../../towboot/src/boot/info.rs"]
+mod info;
+#[path = "../../towboot/src/boot/header.rs"]
+mod header;
+mod builder;
+mod diagnose;
+mod glob;
+mod golden;
+mod image;
+mod limits;
 
 #[derive(PartialEq, Clone, Copy)]
 enum Arch {
@@ -17,6 +33,15 @@ enum Arch {
     X86_64,
 }
 
+impl From<Arch> for towbootctl::Arch {
+    fn from(arch: Arch) -> Self {
+        match arch {
+            Arch::I686 => Self::I686,
+            Arch::X86_64 => Self::X86_64,
+        }
+    }
+}
+
 #[cfg(test)]
 #[ctor::ctor]
 fn init() {
@@ -26,10 +51,76 @@ fn init() {
     env_logger::init();
 }
 
-/// Builds the given folder as an image and boots it.
-fn build_and_boot(
-    folder: &Path, towboot_arch: Arch, machine_arch: Arch, firmware_arch: Arch,
-) -> Result<String, Box<dyn Error>> {
+/// Where built kernels are cached
+fn kernel_cache() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../target/test-kernels")
+}
+
+/// The folders with the sources other test kernels share (the entry points and
+/// the Multiboot2 definitions)
+const SHARED_KERNEL_SOURCES: [&str; 2] = ["multiboot2", "multiboot2_x64"];
+
+/// Computes the key under which a kernel is cached.
+///
+/// This covers the sources in the folder, the shared sources and the version
+/// of the compiler.
+fn kernel_cache_key(folder: &Path) -> Result<String, Box<dyn Error>> {
+    let mut hasher = Sha256::new();
+    let mut sources = Vec::new();
+    let shared = SHARED_KERNEL_SOURCES.map(|name| folder.with_file_name(name));
+    for folder in std::iter::once(folder.to_path_buf()).chain(shared) {
+        for entry in fs::read_dir(folder)? {
+            sources.push(entry?.path());
+        }
+    }
+    sources.retain(|path| path.file_name().is_some_and(|n| n == "Makefile") || matches!(
+        path.extension().and_then(|e| e.to_str()), Some("c" | "h" | "S"),
+    ));
+    sources.sort();
+    sources.dedup();
+    for source in sources {
+        hasher.update(source.as_os_str().as_encoded_bytes());
+        hasher.update(fs::read(&source)?);
+    }
+    let compiler = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let output = Command::new(compiler).arg("--version").output()?;
+    output.status.exit_ok()?;
+    hasher.update(output.stdout);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Builds the kernel in the given folder, unless it has been built from the
+/// same sources with the same compiler before.
+fn build_kernel(folder: &Path) -> Result<(), Box<dyn Error>> {
+    let cached = kernel_cache()
+        .join(folder.file_name().unwrap())
+        .join(kernel_cache_key(folder)?)
+        .join("kernel");
+    if cached.exists() {
+        fs::copy(&cached, folder.join("kernel"))?;
+        return Ok(());
+    }
+    // the kernel might be newer than the sources, but from different ones
+    Command::new("make")
+        .arg("-B")
+        .current_dir(folder)
+        .status()?.exit_ok()?;
+    fs::create_dir_all(cached.parent().unwrap())?;
+    fs::copy(folder.join("kernel"), &cached)?;
+    Ok(())
+}
+
+/// Builds the given folder as an image (containing towboot for the given
+/// architectures).
+fn build(folder: &Path, towboot_archs: &[Arch]) -> Result<TempPath, Box<dyn Error>> {
+    // make sure that the kernel is built
+    build_kernel(folder)?;
+    build_config(&folder.join("towboot.toml"), towboot_archs)
+}
+
+/// Builds an image with the given configuration (containing towboot for the
+/// given architectures).
+fn build_config(config_path: &Path, towboot_archs: &[Arch]) -> Result<TempPath, Box<dyn Error>> {
     // get towboot
     let mut towboot_temp_ia32 = NamedTempFile::new()?;
     towboot_temp_ia32.as_file_mut().write_all(towboot_ia32::TOWBOOT)?;
@@ -37,55 +128,69 @@ fn build_and_boot(
     towboot_temp_x64.as_file_mut().write_all(towboot_x64::TOWBOOT)?;
     let towboot_temp_ia32_path = towboot_temp_ia32.into_temp_path();
     let towboot_temp_x64_path = towboot_temp_x64.into_temp_path();
-    let i686: Option<&Path> = matches!(towboot_arch, Arch::I686)
+    let i686: Option<&Path> = towboot_archs.contains(&Arch::I686)
         .then_some(&towboot_temp_ia32_path);
-    let x86_64: Option<&Path> = matches!(towboot_arch, Arch::X86_64)
+    let x86_64: Option<&Path> = towboot_archs.contains(&Arch::X86_64)
         .then_some(&towboot_temp_x64_path);
 
-    // make sure that the kernel is built
-    Command::new("make")
-        .current_dir(folder)
-        .status()?.exit_ok()?;
-
     // build the image
     let image_path = NamedTempFile::new()?.into_temp_path();
-    let mut config_path = folder.to_path_buf();
-    config_path.push("towboot.toml");
     create_image(
         &image_path, &[
             "-config".to_string(),
             config_path.to_str().unwrap().to_string(),
-        ], i686.as_deref(), x86_64.as_deref(),
+        ], i686.as_deref(), x86_64.as_deref(), None, DEFAULT_PARTITION_NAME, None, None,
     )?;
+    Ok(image_path)
+}
 
-    // boot it
-    assert!(firmware_arch == machine_arch); // TODO
-    let (mut qemu_command, _temp_files) = boot_image(
-        None,
-        &image_path,
-        matches!(machine_arch, Arch::X86_64),
-        false,
-        true, // the firmware seems to boot only on KVM
-        false,
-    )?;
-    let mut qemu_process = qemu_command
-        .stdin(Stdio::null())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .arg("-display").arg("none")
-        .spawn()?;
-    sleep(Duration::from_secs(5)); // TODO: kernels should probably terminate the VM
-    qemu_process.kill()?; // there's no terminate here
-    let qemu_output = qemu_process.wait_with_output()?;
-    Ok(String::from_utf8(qemu_output.stdout)?)
+/// Boots the given image.
+///
+/// Returns the serial output and the exit code of the kernel (if it exited).
+fn boot(image: &Path, options: &BootOptions) -> Result<(String, Option<i32>), Box<dyn Error>> {
+    boot_with_timeout(image, options, Duration::from_secs(10))
+}
+
+/// Boots the given image, stopping it after the given time.
+///
+/// Returns the serial output and the exit code of the kernel (if it exited).
+fn boot_with_timeout(
+    image: &Path, options: &BootOptions, timeout: Duration,
+) -> Result<(String, Option<i32>), Box<dyn Error>> {
+    let mut vm = boot_image(image, &BootOptions {
+        kvm: true, // the firmware seems to boot only on KVM
+        headless: true,
+        debug_exit: true, // the kernels terminate the VM when they're done
+        ..options.clone()
+    })?;
+    vm.command().stderr(Stdio::inherit());
+    vm.spawn_captured()?;
+    // kernels that don't get booted have to be stopped
+    vm.wait_with_timeout(timeout)?;
+    Ok((vm.output(), vm.debug_exit_code()))
+}
+
+/// Builds the given folder as an image (containing towboot for the given
+/// architectures) and boots it.
+///
+/// Returns the serial output and the exit code of the kernel (if it exited).
+fn build_and_boot(
+    folder: &Path, towboot_archs: &[Arch], machine_arch: Arch, firmware_arch: Arch,
+) -> Result<(String, Option<i32>), Box<dyn Error>> {
+    let image = build(folder, towboot_archs)?;
+    boot(&image, &BootOptions {
+        arch: machine_arch.into(),
+        firmware_arch: Some(firmware_arch.into()),
+        ..Default::default()
+    })
 }
 
 #[test]
 fn multiboot1() {
     for arch in [Arch::I686, Arch::X86_64] {
-        let stdout = build_and_boot(
+        let (stdout, exit_code) = build_and_boot(
             &PathBuf::from("multiboot1"),
-            arch, arch, arch,
+            &[arch], arch, arch,
         ).expect("failed to run");
         println!("{}", stdout);
         assert!(stdout.contains("cmdline = test of a cmdline"));
@@ -93,15 +198,16 @@ fn multiboot1() {
         assert!(stdout.contains("mods_count = 0"));
         assert!(stdout.contains("mem_lower = 640KB"));
         assert!(stdout.ends_with("Halted."));
+        assert_eq!(exit_code, Some(DEBUG_EXIT_SUCCESS));
     }
 }
 
 #[test]
 fn multiboot2() {
     for arch in [Arch::I686, Arch::X86_64] {
-        let stdout = build_and_boot(
+        let (stdout, exit_code) = build_and_boot(
             &PathBuf::from("multiboot2"),
-            arch, arch, arch,
+            &[arch], arch, arch,
         ).expect("failed to run");
         println!("{}", stdout);
         assert!(stdout.contains("Command line = test of a cmdline"));
@@ -109,15 +215,16 @@ fn multiboot2() {
         assert!(!stdout.contains("Module at"));
         assert!(stdout.contains("mem_lower = 640KB"));
         assert!(stdout.ends_with("Halted."));
+        assert_eq!(exit_code, Some(DEBUG_EXIT_SUCCESS));
     }
 }
 
 #[test]
 fn multiboot2_x64() {
     // it should boot on x86_64
-    let stdout = build_and_boot(
+    let (stdout, exit_code) = build_and_boot(
         &PathBuf::from("multiboot2_x64"),
-        Arch::X86_64, Arch::X86_64, Arch::X86_64,
+        &[Arch::X86_64], Arch::X86_64, Arch::X86_64,
     ).expect("failed to run");
     println!("{}", stdout);
     assert!(stdout.contains("Command line = test of a cmdline"));
@@ -125,12 +232,221 @@ fn multiboot2_x64() {
     assert!(!stdout.contains("Module at"));
     assert!(stdout.contains("mem_lower = 640KB"));
     assert!(stdout.ends_with("Halted."));
+    assert_eq!(exit_code, Some(DEBUG_EXIT_SUCCESS));
     // it should not boot on i686
-    let stdout = build_and_boot(
+    let (stdout, exit_code) = build_and_boot(
         &PathBuf::from("multiboot2_x64"),
-        Arch::I686, Arch::I686, Arch::I686,
+        &[Arch::I686], Arch::I686, Arch::I686,
     ).expect("failed to run");
     println!("{}", stdout);
     assert!(stdout.contains("The kernel supports 64-bit UEFI systems, but we're running on 32-bit"));
     assert!(!stdout.contains("Halted."));
+    assert_eq!(exit_code, None);
+}
+
+#[test]
+fn framebuffer() {
+    for arch in [Arch::I686, Arch::X86_64] {
+        let (stdout, exit_code) = build_and_boot(
+            &PathBuf::from("framebuffer"),
+            &[arch], arch, arch,
+        ).expect("failed to run");
+        println!("{}", stdout);
+        // OVMF's QEMU video driver uses 32-bit BGR
+        assert!(stdout.contains("Framebuffer type = 1, bpp = 32"));
+        assert!(stdout.contains("Resolution = 1024x768, pitch = 4096"));
+        assert!(stdout.contains("red = 8@16, green = 8@8, blue = 8@0"));
+        assert!(stdout.contains("Format is consistent"));
+        assert!(stdout.contains("Pattern ok"));
+        assert!(stdout.ends_with("Halted."));
+        assert_eq!(exit_code, Some(DEBUG_EXIT_SUCCESS));
+    }
+}
+
+#[test]
+fn efi_boot_services() {
+    // a 32-bit kernel can't call 64-bit firmware, so x86_64 needs its own kernel
+    for (arch, kernel) in [(Arch::I686, "efi"), (Arch::X86_64, "efi_x64")] {
+        let (stdout, exit_code) = build_and_boot(
+            &PathBuf::from(kernel),
+            &[arch], arch, arch,
+        ).expect("failed to run");
+        println!("{}", stdout);
+        let bits = if arch == Arch::I686 { "EFI32" } else { "EFI64" };
+        assert!(stdout.contains(&format!("{bits} system table at")));
+        assert!(stdout.contains(&format!("{bits} image handle = ")));
+        assert!(!stdout.contains("image handle = 0x0\n"));
+        assert!(stdout.contains("System table signature ok"));
+        assert!(stdout.contains("EFI memory map with"));
+        assert!(stdout.contains("Boot services not terminated"));
+        assert!(stdout.contains("Boot Services call succeeded"));
+        assert!(stdout.ends_with("Halted."));
+        assert_eq!(exit_code, Some(DEBUG_EXIT_SUCCESS));
+    }
+}
+
+#[test]
+fn ia32_firmware_on_x86_64() {
+    // the 32-bit firmware should pick the 32-bit towboot on an x86_64 machine
+    let (stdout, exit_code) = build_and_boot(
+        &PathBuf::from("multiboot2"),
+        &[Arch::I686, Arch::X86_64], Arch::X86_64, Arch::I686,
+    ).expect("failed to run");
+    println!("{}", stdout);
+    assert!(stdout.contains("Command line = test of a cmdline"));
+    assert!(stdout.ends_with("Halted."));
+    assert_eq!(exit_code, Some(DEBUG_EXIT_SUCCESS));
+    // it can't start a 64-bit towboot
+    let (stdout, exit_code) = build_and_boot(
+        &PathBuf::from("multiboot2"),
+        &[Arch::X86_64], Arch::X86_64, Arch::I686,
+    ).expect("failed to run");
+    println!("{}", stdout);
+    assert!(!stdout.contains("Halted."));
+    assert_eq!(exit_code, None);
+}
+
+#[test]
+fn x64_firmware_picks_x64_towboot() {
+    // the 64-bit firmware should pick the 64-bit towboot if both are present
+    let (stdout, exit_code) = build_and_boot(
+        &PathBuf::from("multiboot2_x64"),
+        &[Arch::I686, Arch::X86_64], Arch::X86_64, Arch::X86_64,
+    ).expect("failed to run");
+    println!("{}", stdout);
+    assert!(stdout.contains("Command line = test of a cmdline"));
+    assert!(stdout.ends_with("Halted."));
+    assert_eq!(exit_code, Some(DEBUG_EXIT_SUCCESS));
+    // it can't start a 32-bit towboot
+    let (stdout, exit_code) = build_and_boot(
+        &PathBuf::from("multiboot2_x64"),
+        &[Arch::I686], Arch::X86_64, Arch::X86_64,
+    ).expect("failed to run");
+    println!("{}", stdout);
+    assert!(!stdout.contains("Halted."));
+    assert_eq!(exit_code, None);
+}
+
+#[test]
+fn x64_firmware_on_i686() {
+    // this combination is not possible
+    assert!(build_and_boot(
+        &PathBuf::from("multiboot2"),
+        &[Arch::X86_64], Arch::I686, Arch::X86_64,
+    ).is_err());
+}
+
+/// The sizes of the modules for the stress test: dozens of small ones (most of
+/// them not a multiple of the page size) and one larger than 100 MB
+fn stress_module_sizes() -> Vec<u64> {
+    let mut sizes: Vec<u64> = (0..40).map(|i| 1 + i * 37 * 1024 + i * i).collect();
+    sizes.insert(20, 110 * 1024 * 1024);
+    sizes
+}
+
+/// Boots the multiboot2 kernel with many modules and checks that they arrive
+/// completely, in order, page-aligned, without overlapping and with their
+/// arguments.
+///
+/// Returns the start and end addresses of the modules.
+fn module_stress(quirks: &[&str]) -> Vec<(u64, u64)> {
+    let kernel_folder = PathBuf::from("multiboot2");
+    build_kernel(&kernel_folder).expect("failed to build the kernel");
+    let dir = tempfile::tempdir().expect("failed to create a temporary directory");
+    fs::copy(kernel_folder.join("kernel"), dir.path().join("kernel"))
+        .expect("failed to copy the kernel");
+    let quirks: Vec<String> = quirks.iter().map(|q| format!("\"{q}\"")).collect();
+    let mut config = format!(
+        "default = \"stress\"\ntimeout = 0\nlog_level = \"warn\"\n\n\
+        [entries.stress]\nimage = \"kernel\"\nquirks = [{}]\n",
+        quirks.join(", "),
+    );
+    let sizes = stress_module_sizes();
+    for (index, size) in sizes.iter().enumerate() {
+        let name = format!("module{index:02}");
+        fs::File::create(dir.path().join(&name)).and_then(|f| f.set_len(*size))
+            .expect("failed to create a module");
+        config.push_str(&format!(
+            "\n[[entries.stress.modules]]\nimage = \"{name}\"\nargv = \"module {index}\"\n",
+        ));
+    }
+    let config_path = dir.path().join("towboot.toml");
+    fs::write(&config_path, config).expect("failed to write the configuration");
+    let image = build_config(&config_path, &[Arch::X86_64]).expect("failed to build");
+    let (stdout, exit_code) = boot_with_timeout(&image, &BootOptions {
+        arch: towbootctl::Arch::X86_64,
+        firmware_arch: Some(towbootctl::Arch::X86_64),
+        memory: Some(512),
+        ..Default::default()
+    }, Duration::from_secs(60)).expect("failed to run");
+    println!("{}", stdout);
+    assert!(stdout.ends_with("Halted."));
+    assert_eq!(exit_code, Some(DEBUG_EXIT_SUCCESS));
+
+    let modules: Vec<(u64, u64, &str)> = stdout.lines()
+        .filter_map(|line| line.trim().strip_prefix("Module at 0x"))
+        .map(|line| {
+            let (start, rest) = line.split_once("-0x").unwrap();
+            let (end, argv) = rest.split_once(". Command line ").unwrap();
+            (
+                u64::from_str_radix(start, 16).unwrap(),
+                u64::from_str_radix(end, 16).unwrap(),
+                argv,
+            )
+        })
+        .collect();
+    assert_eq!(modules.len(), sizes.len());
+    for (index, ((start, end, argv), size)) in modules.iter().zip(&sizes).enumerate() {
+        assert_eq!(*argv, format!("module {index}"));
+        assert_eq!(start % 0x1000, 0, "module {index} is not page-aligned");
+        assert_eq!(end - start, *size, "module {index} has the wrong size");
+    }
+    let mut ranges: Vec<(u64, u64)> = modules.iter().map(|(s, e, _)| (*s, *e)).collect();
+    ranges.sort();
+    for pair in ranges.windows(2) {
+        assert!(pair[0].1 <= pair[1].0, "modules overlap: {pair:x?}");
+    }
+    ranges
+}
+
+#[test]
+fn many_modules() {
+    module_stress(&[]);
+}
+
+#[test]
+fn many_modules_below_200mb() {
+    for (_, end) in module_stress(&["ModulesBelow200Mb"]) {
+        assert!(end <= 200 * 1024 * 1024, "a module ends at {end:#x}");
+    }
+}
+
+/// Secure Boot needs a firmware build with SMM and a variable store with
+/// enrolled keys; pass them via `TOWBOOT_SECURE_BOOT_FIRMWARE` and
+/// `TOWBOOT_SECURE_BOOT_VARS` (e.g. `OVMF_CODE.secboot.fd` and
+/// `OVMF_VARS.ms.fd` from your distribution) and run with `--ignored`.
+#[test]
+#[ignore]
+fn secure_boot() {
+    let firmware = std::env::var_os("TOWBOOT_SECURE_BOOT_FIRMWARE")
+        .expect("TOWBOOT_SECURE_BOOT_FIRMWARE is not set");
+    let vars = std::env::var_os("TOWBOOT_SECURE_BOOT_VARS")
+        .expect("TOWBOOT_SECURE_BOOT_VARS is not set");
+    // don't modify the original variable store
+    let nvram = NamedTempFile::new().expect("failed to create temporary file").into_temp_path();
+    std::fs::copy(vars, &nvram).expect("failed to copy the variable store");
+    let image = build(&PathBuf::from("multiboot2_x64"), &[Arch::X86_64])
+        .expect("failed to build");
+    let (stdout, exit_code) = boot(&image, &BootOptions {
+        arch: towbootctl::Arch::X86_64,
+        firmware: towbootctl::Pin::Local(firmware.into()),
+        nvram: Some(nvram.to_path_buf()),
+        secure_boot: true,
+        ..Default::default()
+    }).expect("failed to run");
+    println!("{}", stdout);
+    // towboot isn't signed, so the firmware has to refuse it
+    assert!(!stdout.contains("Boot loader name = towboot"));
+    assert_eq!(exit_code, None);
+    // TODO: check that a signed build boots, once we can sign builds
 }
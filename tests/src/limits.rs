@@ -0,0 +1,80 @@
+//! Check that modules that don't fit into the Multiboot information are rejected.
+use multiboot12::header::Header;
+
+use super::golden::{MULTIBOOT1_HEADER, MULTIBOOT2_HEADER};
+use super::info::{check_modules, size_without_modules};
+
+/// Measure the information for a kernel with the given header (without modules).
+fn base_size(header: &[u32]) -> u64 {
+    let header: Vec<u8> = header.iter().flat_map(|w| w.to_le_bytes()).collect();
+    let header = Header::from_slice(&header).expect("invalid header");
+    size_without_modules(
+        header.info_builder(), Some("test of a cmdline"), None, "towboot test", None, true,
+    )
+}
+
+#[test]
+fn accepts_normal_modules() {
+    let argvs = [Some("initrd"), None, Some("")];
+    assert_eq!(check_modules(&argvs, false, 0, u64::MAX), Ok(()));
+    assert_eq!(check_modules(&argvs, true, 0, u64::MAX), Ok(()));
+}
+
+#[test]
+fn rejects_null_bytes() {
+    assert_eq!(
+        check_modules(&[Some("initrd"), Some("root=\0")], true, 0, u64::MAX),
+        Err("the command line of module 1 contains a null byte".to_string()),
+    );
+}
+
+#[test]
+fn rejects_modules_beyond_the_available_space() {
+    let argvs = [Some("initrd"); 16];
+    assert_eq!(check_modules(&argvs, false, 0, 4096), Ok(()));
+    assert_eq!(
+        check_modules(&argvs, true, 0, 200),
+        Err("16 modules are too many for Multiboot2 (at most 8 fit)".to_string()),
+    );
+    assert_eq!(
+        check_modules(&argvs, false, 0, 300),
+        Err(
+            "the modules and their command lines need 368 bytes, \
+            but there are only 300 bytes left in the Multiboot1 information".to_string()
+        ),
+    );
+}
+
+#[test]
+fn counts_the_rest_of_the_information() {
+    // an empty command line takes 17 bytes for Multiboot1 and 24 for Multiboot2
+    for (header, multiboot2, module) in [
+        (&MULTIBOOT1_HEADER[..], false, 17), (&MULTIBOOT2_HEADER[..], true, 24),
+    ] {
+        let base = base_size(header);
+        assert!(base > 0);
+        let revision = if multiboot2 { 2 } else { 1 };
+        assert_eq!(check_modules(&[Some("")], multiboot2, base, base + module), Ok(()));
+        assert_eq!(
+            check_modules(&[Some(""); 2], multiboot2, base, base + module),
+            Err(format!("2 modules are too many for Multiboot{revision} (at most 1 fit)")),
+        );
+        assert!(check_modules(&[], multiboot2, base, base - 1).is_err());
+    }
+}
+
+#[test]
+fn rejects_modules_beyond_4_gib() {
+    // 1 MiB per command line, so that a few thousand modules cross 4 GiB
+    let argv = "a".repeat(1 << 20);
+    let (base1, base2) = (base_size(&MULTIBOOT1_HEADER), base_size(&MULTIBOOT2_HEADER));
+    assert_eq!(check_modules(&[Some(argv.as_str()); 4000], false, base1, u64::MAX), Ok(()));
+    assert_eq!(
+        check_modules(&[Some(argv.as_str()); 4100], true, base2, u64::MAX),
+        Err(format!(
+            "the modules and their command lines need {} bytes, \
+            but there are only {} bytes left in the Multiboot2 information",
+            4100u64 * (16 + (1 << 20) + 8), u64::from(u32::MAX) - base2,
+        )),
+    );
+}
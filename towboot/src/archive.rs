@@ -0,0 +1,118 @@
+//! This module unpacks archives of modules.
+//!
+//! Only tar archives are supported; they're simple enough to be parsed here.
+use core::ops::Range;
+
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+
+use log::error;
+
+/// The size of headers and blocks in tar archives
+const BLOCK_SIZE: usize = 512;
+
+/// A file inside an archive
+pub(crate) struct Member<'a> {
+    pub name: String,
+    pub data: &'a [u8],
+}
+
+/// Get a null-terminated string from a header field.
+fn field(header: &[u8], range: Range<usize>) -> String {
+    let field = &header[range];
+    let length = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..length]).into_owned()
+}
+
+/// Parse an octal number from a header field.
+fn octal(header: &[u8], range: Range<usize>) -> Option<usize> {
+    let number = field(header, range);
+    let number = number.trim();
+    if number.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(number, 8).ok()
+}
+
+/// Get the name of the file described by the header.
+///
+/// ustar archives may split long names into a prefix and the name.
+fn name(header: &[u8]) -> String {
+    let name = field(header, 0..100);
+    let prefix = field(header, 345..500);
+    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+        format!("{prefix}/{name}")
+    } else {
+        name
+    }
+}
+
+/// Get the path from the records of a PAX extended header.
+///
+/// Each record looks like `<length> <key>=<value>\n`, the length including
+/// itself. This fails if the records are malformed.
+fn pax_path(mut records: &[u8]) -> Result<Option<String>, ()> {
+    let mut path = None;
+    while !records.is_empty() {
+        let space = records.iter().position(|b| *b == b' ').ok_or(())?;
+        let length: usize = core::str::from_utf8(&records[..space]).map_err(|_| ())?
+            .parse().map_err(|_| ())?;
+        let record = records.get(space + 1..length).ok_or(())?;
+        records = &records[length..];
+        let record = record.strip_suffix(b"\n").ok_or(())?;
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(String::from_utf8_lossy(value).into_owned());
+        }
+    }
+    Ok(path)
+}
+
+/// Get all regular files inside a tar archive.
+///
+/// Directories and links are skipped. Long names from GNU (`L`) and PAX (`x`)
+/// headers are applied to the following file; other extended headers are skipped.
+pub(crate) fn tar_members(archive: &[u8]) -> Result<Vec<Member<'_>>, Status> {
+    let mut members = Vec::new();
+    let mut offset: usize = 0;
+    let mut long_name = None;
+    let invalid = |offset: usize| {
+        error!("invalid tar header at offset {offset}");
+        Status::LOAD_ERROR
+    };
+    while let Some(header) = offset.checked_add(BLOCK_SIZE)
+        .and_then(|end| archive.get(offset..end))
+    {
+        // the archive ends with empty blocks
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let size = octal(header, 124..136).ok_or_else(|| invalid(offset))?;
+        let start = offset + BLOCK_SIZE;
+        let data = start.checked_add(size)
+            .and_then(|end| archive.get(start..end))
+            .ok_or_else(|| {
+                error!("the archive is truncated");
+                Status::LOAD_ERROR
+            })?;
+        match header[156] {
+            b'0' | 0 => members.push(Member {
+                name: long_name.take().unwrap_or_else(|| name(header))
+                    .trim_start_matches("./").to_owned(),
+                data,
+            }),
+            b'L' => long_name = Some(field(data, 0..data.len())),
+            b'x' => if let Some(path) = pax_path(data).map_err(|()| invalid(offset))? {
+                long_name = Some(path);
+            },
+            _ => long_name = None,
+        }
+        offset = size.checked_next_multiple_of(BLOCK_SIZE)
+            .and_then(|size| start.checked_add(size))
+            .ok_or_else(|| invalid(offset))?;
+    }
+    Ok(members)
+}
@@ -0,0 +1,235 @@
+//! This module overrides ACPI tables.
+//!
+//! Tables given as modules of the kind `acpi-override` replace the firmware's
+//! tables with the same signature or are added if there is none (SSDTs are
+//! always added). A DSDT replaces the one the FADT points to.
+//! New root tables (an RSDT and an XSDT) are created and installed as
+//! configuration tables for both ACPI 1.0 and 2.0, so both the RSDP tags and
+//! UEFI-aware kernels see the overrides.
+use core::ptr::{self, NonNull};
+use core::slice;
+
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::boot::{
+    AllocateType, MemoryType, allocate_pages, free_pages, install_configuration_table,
+};
+use uefi::system::with_config_table;
+use uefi::table::cfg::{ACPI_GUID, ACPI2_GUID};
+
+use log::{debug, error, info};
+
+use super::super::mem::PAGE_SIZE;
+
+/// The length of the header all tables (except the RSDP) start with
+const HEADER_LENGTH: usize = 36;
+/// The length of the RSDP (version 2)
+const RSDP_LENGTH: usize = 36;
+/// The length of the RSDP (version 1)
+const RSDP_V1_LENGTH: usize = 20;
+/// Where the checksum is in a table's header
+const CHECKSUM_OFFSET: usize = 9;
+/// Where the 32-bit address of the DSDT is in the FADT
+const FADT_DSDT_OFFSET: usize = 40;
+/// Where the 64-bit address of the DSDT is in the FADT (since ACPI 2.0)
+const FADT_X_DSDT_OFFSET: usize = 140;
+
+/// Get a table the firmware provides.
+///
+/// This is unsafe, as the address has to point to a valid table.
+unsafe fn firmware_table(address: u64) -> &'static [u8] {
+    let header = unsafe { slice::from_raw_parts(address as usize as *const u8, HEADER_LENGTH) };
+    let length = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    unsafe { slice::from_raw_parts(address as usize as *const u8, length as usize) }
+}
+
+/// Set the checksum so that all bytes sum up to zero.
+fn fix_checksum(table: &mut [u8], offset: usize) {
+    table[offset] = 0;
+    let sum = table.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    table[offset] = 0u8.wrapping_sub(sum);
+}
+
+/// The tables installed by [`override_tables`]
+///
+/// Dropping this puts the firmware's tables back (and frees the new ones),
+/// so that a failed attempt to boot doesn't affect the next one.
+/// It has to be forgotten when actually booting.
+pub(super) struct Overrides {
+    /// the address of the firmware's ACPI 1.0 RSDP
+    firmware_rsdp_v1: Option<u64>,
+    /// the address of the firmware's ACPI 2.0 RSDP
+    firmware_rsdp_v2: Option<u64>,
+    /// the memory the new tables are in (and the number of pages)
+    allocations: Vec<(NonNull<u8>, usize)>,
+}
+
+impl Overrides {
+    /// Copy a table to memory the kernel keeps around (below 4 GB).
+    fn install(&mut self, table: &[u8]) -> Result<u64, Status> {
+        let pages = table.len().div_ceil(PAGE_SIZE);
+        let ptr: NonNull<u8> = allocate_pages(
+            AllocateType::MaxAddress(u32::MAX.into()), MemoryType::ACPI_RECLAIM, pages,
+        ).map_err(|e| {
+            error!("failed to allocate memory for an ACPI table: {e:?}");
+            e.status()
+        })?;
+        self.allocations.push((ptr, pages));
+        unsafe { slice::from_raw_parts_mut(ptr.as_ptr(), table.len()) }.copy_from_slice(table);
+        Ok(ptr.as_ptr() as usize as u64)
+    }
+}
+
+impl Drop for Overrides {
+    fn drop(&mut self) {
+        debug!("restoring the firmware's ACPI tables");
+        for (guid, address) in [
+            (&ACPI_GUID, self.firmware_rsdp_v1), (&ACPI2_GUID, self.firmware_rsdp_v2),
+        ] {
+            // this removes the entry if the firmware didn't have one
+            let table = address.map_or(ptr::null(), |address| address as usize as *const _);
+            if let Err(e) = unsafe { install_configuration_table(guid, table) } {
+                debug!("failed to restore the ACPI table {guid}: {e:?}");
+            }
+        }
+        for (ptr, pages) in self.allocations.drain(..) {
+            unsafe { free_pages(ptr, pages) }.expect("failed to free the allocated memory");
+        }
+    }
+}
+
+/// Create a root table (RSDT or XSDT) with the given entries.
+///
+/// The rest of the header is taken from the firmware's root table.
+fn root_table<const N: usize>(
+    signature: &[u8; 4], template: &[u8], entries: &[u64], to_bytes: fn(u64) -> [u8; N],
+) -> Vec<u8> {
+    let mut table = template[..HEADER_LENGTH].to_vec();
+    table[0..4].copy_from_slice(signature);
+    for entry in entries {
+        table.extend_from_slice(&to_bytes(*entry));
+    }
+    let length = u32::try_from(table.len()).unwrap();
+    table[4..8].copy_from_slice(&length.to_le_bytes());
+    fix_checksum(&mut table, CHECKSUM_OFFSET);
+    table
+}
+
+/// Point a copy of the FADT to the new DSDT.
+fn patch_fadt(fadt: &[u8], dsdt: u64) -> Vec<u8> {
+    let mut fadt = fadt.to_vec();
+    if let Ok(dsdt) = u32::try_from(dsdt) && fadt.len() >= FADT_DSDT_OFFSET + 4 {
+        fadt[FADT_DSDT_OFFSET..FADT_DSDT_OFFSET + 4].copy_from_slice(&dsdt.to_le_bytes());
+    }
+    if fadt.len() >= FADT_X_DSDT_OFFSET + 8 {
+        fadt[FADT_X_DSDT_OFFSET..FADT_X_DSDT_OFFSET + 8].copy_from_slice(&dsdt.to_le_bytes());
+    }
+    fix_checksum(&mut fadt, CHECKSUM_OFFSET);
+    fadt
+}
+
+/// Install the given tables, replacing the firmware's ones.
+///
+/// Both the ACPI 1.0 and the ACPI 2.0 RSDP are replaced, pointing to a new
+/// RSDT and XSDT respectively. The firmware's tables are restored when the
+/// returned value is dropped.
+pub(super) fn override_tables(tables: &[Vec<u8>]) -> Result<Overrides, Status> {
+    let (firmware_rsdp_v1, firmware_rsdp_v2) = with_config_table(|t| {
+        let find = |guid| t.iter()
+            .find(|entry| entry.guid == guid)
+            .map(|entry| entry.address as usize as u64);
+        (find(ACPI_GUID), find(ACPI2_GUID))
+    });
+    let mut overrides = Overrides {
+        firmware_rsdp_v1, firmware_rsdp_v2, allocations: Vec::new(),
+    };
+    let Some(rsdp_address) = firmware_rsdp_v2.or(firmware_rsdp_v1) else {
+        error!("the firmware doesn't provide ACPI tables, so they can't be overridden");
+        return Err(Status::UNSUPPORTED);
+    };
+    let rsdp = unsafe { slice::from_raw_parts(rsdp_address as usize as *const u8, RSDP_V1_LENGTH) };
+    let revision = rsdp[15];
+    let rsdt_address = u32::from_le_bytes(rsdp[16..20].try_into().unwrap());
+    let xsdt_address = if revision >= 2 {
+        let rsdp = unsafe { slice::from_raw_parts(rsdp.as_ptr(), RSDP_LENGTH) };
+        u64::from_le_bytes(rsdp[24..32].try_into().unwrap())
+    } else {
+        0
+    };
+    // prefer the XSDT, but not every ACPI 2.0 RSDP points to one
+    let (root, mut entries): (&[u8], Vec<u64>) = if xsdt_address != 0 {
+        let xsdt = unsafe { firmware_table(xsdt_address) };
+        (xsdt, xsdt[HEADER_LENGTH..].chunks_exact(8)
+            .map(|entry| u64::from_le_bytes(entry.try_into().unwrap()))
+            .collect())
+    } else if rsdt_address != 0 {
+        let rsdt = unsafe { firmware_table(rsdt_address.into()) };
+        (rsdt, rsdt[HEADER_LENGTH..].chunks_exact(4)
+            .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()).into())
+            .collect())
+    } else {
+        error!("the firmware's RSDP points to neither an XSDT nor an RSDT");
+        return Err(Status::UNSUPPORTED);
+    };
+    let signature = |address: u64| -> [u8; 4] {
+        unsafe { firmware_table(address) }[0..4].try_into().unwrap()
+    };
+    for table in tables {
+        if table.len() < HEADER_LENGTH
+            || u32::from_le_bytes(table[4..8].try_into().unwrap()) as usize != table.len() {
+            error!("an ACPI override is not a valid table");
+            return Err(Status::LOAD_ERROR);
+        }
+        let name = core::str::from_utf8(&table[0..4]).unwrap_or("????");
+        let address = overrides.install(table)?;
+        match &table[0..4] {
+            b"DSDT" => {
+                let Some(fadt) = entries.iter_mut().find(|e| &signature(**e) == b"FACP") else {
+                    error!("there is no FADT to point to the new DSDT");
+                    return Err(Status::NOT_FOUND);
+                };
+                *fadt = overrides.install(&patch_fadt(unsafe { firmware_table(*fadt) }, address))?;
+            },
+            b"SSDT" => entries.push(address),
+            name => match entries.iter_mut().find(|e| &signature(**e) == name) {
+                Some(entry) => *entry = address,
+                None => entries.push(address),
+            },
+        }
+        info!("overriding the ACPI table {name}");
+    }
+    let xsdt = root_table(b"XSDT", root, &entries, u64::to_le_bytes);
+    // the RSDT can only point to tables below 4 GB (the new ones always are)
+    let low_entries: Vec<u64> = entries.iter().copied()
+        .filter(|entry| u32::try_from(*entry).is_ok())
+        .collect();
+    if low_entries.len() != entries.len() {
+        debug!("some tables are above 4 GB, so the RSDT doesn't contain them");
+    }
+    let rsdt = root_table(
+        b"RSDT", root, &low_entries, |entry| u32::try_from(entry).unwrap().to_le_bytes(),
+    );
+    let rsdt_address = u32::try_from(overrides.install(&rsdt)?).unwrap();
+    let xsdt_address = overrides.install(&xsdt)?;
+    let mut new_rsdp = [0; RSDP_LENGTH];
+    new_rsdp[..RSDP_V1_LENGTH].copy_from_slice(rsdp);
+    new_rsdp[15] = 2; // the revision
+    new_rsdp[16..20].copy_from_slice(&rsdt_address.to_le_bytes());
+    new_rsdp[20..24].copy_from_slice(&u32::try_from(RSDP_LENGTH).unwrap().to_le_bytes());
+    new_rsdp[24..32].copy_from_slice(&xsdt_address.to_le_bytes());
+    fix_checksum(&mut new_rsdp[..RSDP_V1_LENGTH], 8);
+    fix_checksum(&mut new_rsdp, 32);
+    let new_rsdp_address = overrides.install(&new_rsdp)?;
+    unsafe {
+        install_configuration_table(&ACPI2_GUID, new_rsdp_address as usize as *const _)
+    }.map_err(|e| e.status())?;
+    let mut rsdp_v1 = new_rsdp[..RSDP_V1_LENGTH].to_vec();
+    rsdp_v1[15] = 0; // the revision
+    fix_checksum(&mut rsdp_v1, 8);
+    let rsdp_v1_address = overrides.install(&rsdp_v1)?;
+    unsafe {
+        install_configuration_table(&ACPI_GUID, rsdp_v1_address as usize as *const _)
+    }.map_err(|e| e.status())?;
+    Ok(overrides)
+}
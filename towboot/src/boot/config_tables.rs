@@ -1,4 +1,5 @@
 //! Handle UEFI config tables.
+use alloc::collections::btree_set::BTreeSet;
 use alloc::slice;
 use alloc::vec::Vec;
 
@@ -6,6 +7,7 @@ use log::{debug, warn};
 use multiboot12::information::InfoBuilder;
 use acpi::rsdp::Rsdp;
 use smbioslib::{SMBiosEntryPoint32, SMBiosEntryPoint64};
+use uefi::{Guid, guid};
 use uefi::system::with_config_table;
 use uefi::table::cfg::{
     ConfigTableEntry, ACPI_GUID, ACPI2_GUID, DEBUG_IMAGE_INFO_GUID,
@@ -14,6 +16,15 @@ use uefi::table::cfg::{
     SMBIOS3_GUID,
 };
 
+use towboot_config::Quirk;
+
+use super::super::mem::Allocation;
+
+/// The configuration table containing a Flattened Device Tree
+const DTB_GUID: Guid = guid!("b1b621d5-f19c-41a5-830b-d9152c69aae0");
+/// The magic number at the start of a Flattened Device Tree
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
 /// Go through all of the configuration tables.
 /// Some of them are interesting for Multiboot2.
 pub(super) fn parse_for_multiboot(info_builder: &mut InfoBuilder) {
@@ -28,6 +39,7 @@ pub(super) fn parse_for_multiboot(info_builder: &mut InfoBuilder) {
             ACPI_GUID => handle_acpi(&table, info_builder),
             ACPI2_GUID => handle_acpi(&table, info_builder),
             DEBUG_IMAGE_INFO_GUID => debug!("ignoring image debug info"),
+            DTB_GUID => debug!("the device tree is passed as a module"),
             DXE_SERVICES_GUID => debug!("ignoring dxe services table"),
             HAND_OFF_BLOCK_LIST_GUID => debug!("ignoring hand-off block list"),
             LZMA_COMPRESS_GUID => debug!("ignoring lzma filesystem"),
@@ -40,6 +52,25 @@ pub(super) fn parse_for_multiboot(info_builder: &mut InfoBuilder) {
     }
 }
 
+/// Copy the device tree, if the firmware provides one.
+///
+/// (The table itself might be in memory the kernel is free to overwrite.)
+pub(super) fn copy_device_tree(quirks: &BTreeSet<Quirk>) -> Option<Allocation> {
+    let table = with_config_table(|s| s.iter().find(|t| t.guid == DTB_GUID).cloned())?;
+    debug!("handling device tree");
+    let header = unsafe { slice::from_raw_parts(table.address as *const u8, 8) };
+    if u32::from_be_bytes(header[0..4].try_into().unwrap()) != FDT_MAGIC {
+        warn!("the device tree is invalid");
+        return None;
+    }
+    let size: usize = u32::from_be_bytes(header[4..8].try_into().unwrap()).try_into().unwrap();
+    let mut allocation = Allocation::new_under_4gb(size, quirks).ok()?;
+    allocation.as_mut_slice()[..size].copy_from_slice(unsafe {
+        slice::from_raw_parts(table.address as *const u8, size)
+    });
+    Some(allocation)
+}
+
 /// Parse the ACPI RSDP and create the Multiboot struct for it.
 fn handle_acpi(table: &ConfigTableEntry, info_builder: &mut InfoBuilder) {
     debug!("handling ACPI RSDP");
@@ -0,0 +1,114 @@
+//! This module translates the disks the firmware knows about into BIOS drive
+//! numbers, as Multiboot expects them.
+//!
+//! There is no BIOS, so these numbers are made up: the disks are simply
+//! counted from `0x80` in the order the firmware lists them.
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::boot::{
+    find_handles, image_handle, open_protocol,
+    OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol,
+};
+use uefi::proto::ProtocolPointer;
+use uefi::proto::device_path::{DevicePath, DevicePathNodeEnum};
+use uefi::proto::media::block::BlockIO;
+
+use log::{debug, info, warn};
+
+use super::info::{BootDevice, Drive};
+
+/// The BIOS drive number of the first hard disk
+const FIRST_DRIVE: u8 = 0x80;
+
+/// Open a protocol without taking it away from anyone else.
+fn open<P: ProtocolPointer + ?Sized>(handle: Handle) -> Option<ScopedProtocol<P>> {
+    // Opening the protocol exclusively would disconnect the drivers.
+    unsafe { open_protocol::<P>(
+        OpenProtocolParams {
+            handle,
+            agent: image_handle(),
+            controller: None,
+        },
+        OpenProtocolAttributes::GetProtocol,
+    ).ok() }
+}
+
+/// Get the whole disks (not their partitions) that contain a medium.
+fn disks() -> Vec<Handle> {
+    find_handles::<BlockIO>().unwrap_or_default().into_iter()
+        .filter(|handle| open::<BlockIO>(*handle).is_some_and(|block_io| {
+            let media = block_io.media();
+            media.is_media_present() && !media.is_logical_partition()
+        }))
+        .collect()
+}
+
+/// Get the drive number of the n-th disk.
+fn drive_number(index: usize) -> Option<u8> {
+    u8::try_from(index).ok().and_then(|index| FIRST_DRIVE.checked_add(index))
+}
+
+/// Describe the disks for the (synthetic) Multiboot1 drives structure.
+pub(super) fn drives() -> Vec<Drive> {
+    disks().into_iter().enumerate()
+        .filter_map(|(index, disk)| {
+            let number = drive_number(index)?;
+            let block_io = open::<BlockIO>(disk)?;
+            let media = block_io.media();
+            let bytes = media.last_block().saturating_add(1)
+                .saturating_mul(media.block_size().into());
+            Some(Drive { number, sectors: bytes / 512 })
+        })
+        .collect()
+}
+
+/// Check whether `path` starts with the nodes of `prefix`.
+fn starts_with(path: &DevicePath, prefix: &DevicePath) -> bool {
+    let mut nodes = path.node_iter();
+    prefix.node_iter().all(|node| nodes.next() == Some(node))
+}
+
+/// Find the drive and the partition of a volume (the one the kernel is on).
+///
+/// The drive is the disk whose device path the one of the volume starts with,
+/// the partition comes from the volume's hard drive node.
+pub(super) fn boot_device(volume: Handle) -> Option<BootDevice> {
+    let volume = open::<DevicePath>(volume)?;
+    let Some(index) = disks().into_iter().position(
+        |disk| open::<DevicePath>(disk).is_some_and(|disk| starts_with(&volume, &disk))
+    ) else {
+        debug!("the kernel has not been loaded from a disk");
+        return None;
+    };
+    let Some(drive) = drive_number(index) else {
+        warn!("the disk the kernel has been loaded from has no drive number");
+        return None;
+    };
+    let hard_drive = volume.node_iter().find_map(|node| match node.as_enum() {
+        Ok(DevicePathNodeEnum::MediaHardDrive(hard_drive)) => Some(hard_drive),
+        _ => None,
+    });
+    let partition = match hard_drive {
+        Some(hard_drive) => {
+            let number = hard_drive.partition_number();
+            info!(
+                "kernel is on partition {number} ({:?}) on drive {drive:#x}",
+                hard_drive.partition_signature(),
+            );
+            // Multiboot counts from 0 and uses 0xff for "no partition".
+            let Some(partition) = number.checked_sub(1)
+                .and_then(|partition| u8::try_from(partition).ok())
+                .filter(|partition| *partition != 0xff) else {
+                warn!("partition {number} can't be passed to the kernel");
+                return None;
+            };
+            Some(partition)
+        },
+        None => {
+            info!("kernel is on drive {drive:#x} (not on a partition)");
+            None
+        },
+    };
+    Some(BootDevice { drive, partition })
+}
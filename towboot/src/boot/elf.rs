@@ -1,34 +1,64 @@
 //! Handling of ELF files
 
-use alloc::collections::btree_map::BTreeMap;
+use core::ops::Range;
+
 use alloc::vec::Vec;
 
 use log::{trace, debug, warn};
 
 use goblin::elf;
+use goblin::elf::program_header::ProgramHeader;
 use goblin::container;
 use scroll::ctx::IntoCtx;
 
 use multiboot12::header::Header;
 use multiboot12::information::Symbols;
 
-use super::super::mem::Allocation;
+use super::super::mem::{Allocation, PAGE_SIZE};
 
 /// Load ELF binaries.
 pub(super) struct OurElfLoader {
-    // maps virtual to physical addresses
-    allocations: BTreeMap<u64, Allocation>,
+    // the physical start addresses of the allocations
+    allocations: Vec<(u64, Allocation)>,
     virtual_entry_point: u64,
     physical_entry_point: Option<usize>,
 }
 
+/// Merge the memory of the segments into page-aligned physical regions.
+///
+/// Segments that overlap, share a page or are adjacent end up in the same
+/// region, so that they can be allocated at once.
+/// This fails if a segment doesn't fit into the address space.
+fn regions(segments: &[&ProgramHeader]) -> Result<Vec<Range<u64>>, &'static str> {
+    let page_size = PAGE_SIZE as u64;
+    let mut ranges: Vec<Range<u64>> = segments.iter()
+        .filter(|s| s.p_memsz > 0)
+        .map(|s| Ok((
+            s.p_paddr / page_size * page_size
+        )..(
+            s.p_paddr.checked_add(s.p_memsz)
+                .and_then(|end| end.checked_next_multiple_of(page_size))
+                .ok_or("a segment ends beyond the address space")?
+        )))
+        .collect::<Result<_, &'static str>>()?;
+    ranges.sort_by_key(|r| r.start);
+    let mut regions: Vec<Range<u64>> = Vec::new();
+    for range in ranges {
+        match regions.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => regions.push(range),
+        }
+    }
+    Ok(regions)
+}
+
 impl OurElfLoader {
     /// Create a new instance.
     ///
     /// The parameter is the virtual address of the entry point.
     pub(super) fn new(entry_point: u64) -> Self {
         OurElfLoader {
-            allocations: BTreeMap::new(),
+            allocations: Vec::new(),
             virtual_entry_point: entry_point,
             physical_entry_point: None,
         }
@@ -36,18 +66,33 @@ impl OurElfLoader {
     
     /// Load an ELF.
     pub(super) fn load_elf(&mut self, binary: &elf::Elf, data: &[u8]) -> Result<(), &'static str> {
-        for program_header in &binary.program_headers {
-            if program_header.p_type == elf::program_header::PT_LOAD {
-                self.allocate(program_header)?;
-                self.load(program_header.p_vaddr, &data[program_header.file_range()])?;
-            }
+        let segments: Vec<&ProgramHeader> = binary.program_headers.iter()
+            .filter(|h| h.p_type == elf::program_header::PT_LOAD)
+            .collect();
+        if segments.iter().any(|s| s.p_filesz > s.p_memsz) {
+            return Err("a segment is larger in the file than in memory");
+        }
+        let regions = regions(&segments)?;
+        debug!("merged {} segments into {} regions", segments.len(), regions.len());
+        for region in regions {
+            self.allocate(region)?;
+        }
+        for segment in segments {
+            trace!("header: {segment:?}");
+            self.find_entry_point(segment);
+            let file_range = usize::try_from(segment.p_offset).ok()
+                .zip(usize::try_from(segment.p_filesz).ok())
+                .and_then(|(start, size)| Some(start..start.checked_add(size)?))
+                .and_then(|range| data.get(range))
+                .ok_or("a segment is outside of the file")?;
+            self.load(segment, file_range)?;
         }
         Ok(())
     }
     
     /// Gets the entry point.
     ///
-    /// We should have found it in `allocate`,
+    /// We should have found it in `load_elf`,
     /// else fall back to the virtual one and hope for the best.
     pub(super) fn entry_point(&self) -> usize {
         if let Some(a) = self.physical_entry_point {
@@ -58,60 +103,61 @@ impl OurElfLoader {
         }
     }
     
-    /// Allocate memory for a segment.
-    fn allocate(&mut self, header: &elf::program_header::ProgramHeader) -> Result<(), &'static str> {
-            trace!("header: {header:?}");
+    /// Allocate memory for a region.
+    fn allocate(&mut self, region: Range<u64>) -> Result<(), &'static str> {
+        debug!("allocating {} bytes at {:#x}", region.end - region.start, region.start);
+        // The physical addresses may be above 4 GiB; this only works on 64-bit firmware.
+        let size = usize::try_from(region.end - region.start)
+            .map_err(|_e| "the kernel is too large for this firmware")?;
+        let mut allocation = Allocation::new_at(region.start, size)
+            .map_err(|_e| "failed to allocate memory for the kernel")?;
+        allocation.as_mut_slice().fill(0);
+        self.allocations.push((region.start, allocation));
+        Ok(())
+    }
+    
+    /// Check whether the segment contains the entry point.
+    fn find_entry_point(&mut self, header: &ProgramHeader) {
+        if header.p_vaddr <= self.virtual_entry_point
+        && header.p_vaddr.saturating_add(header.p_memsz) >= self.virtual_entry_point {
+            // this is addressable, as the segment has been allocated
+            self.physical_entry_point = Some(
+                (header.p_paddr + (self.virtual_entry_point - header.p_vaddr))
+                .try_into().unwrap()
+            );
             debug!(
-                "allocating {} {} bytes at {:#x} for {:#x}",
-                header.p_memsz, header.p_flags, header.p_paddr, header.p_vaddr
+                "(segment {:#x} contains the entry point {:#x} at {:#x})",
+                header.p_vaddr, self.virtual_entry_point, self.physical_entry_point.unwrap(),
             );
-            let mut allocation = Allocation::new_at(
-                header.p_paddr.try_into().unwrap(),
-                header.p_memsz.try_into().unwrap(),
-            ).map_err(|_e| "failed to allocate memory for the kernel")?;
-            let mem_slice = allocation.as_mut_slice();
-            mem_slice.fill(0);
-            self.allocations.insert(header.p_vaddr, allocation);
-            if header.p_vaddr <= self.virtual_entry_point
-            && header.p_vaddr + header.p_memsz >= self.virtual_entry_point {
-                self.physical_entry_point = Some(
-                    (header.p_paddr + self.virtual_entry_point - header.p_vaddr)
-                    .try_into().unwrap()
-                );
-                debug!(
-                    "(this segment will contain the entry point {:#x} at {:#x})",
-                    self.virtual_entry_point, self.physical_entry_point.unwrap(),
-                );
-            }
-        Ok(())
+        }
     }
     
     /// Load a segment.
-    fn load(&mut self, base: u64, region: &[u8]) -> Result<(), &'static str> {
+    fn load(&mut self, header: &ProgramHeader, region: &[u8]) -> Result<(), &'static str> {
         // check whether we actually allocated this
-        match self.allocations.get_mut(&base) {
-            None => panic!("we didn't allocate {base:#x}, but tried to write to it o.O"),
-            Some(alloc) => {
-                assert!(
-                    alloc.len >= region.len(),
-                    "{base:#x} doesn't fit into the memory allocated for it"
-                );
-                let ptr = alloc.as_ptr();
-                debug!(
-                    "load {} bytes into {:#x} (at {:#x})", region.len(), base, ptr as usize
-                );
-                alloc.as_mut_slice()[0..region.len()].clone_from_slice(region);
-                Ok(())
-            },
-        }
+        let Some((start, alloc)) = self.allocations.iter_mut().find(
+            |(start, alloc)| *start <= header.p_paddr
+                && header.p_paddr + region.len() as u64 <= *start + alloc.len as u64
+        ) else {
+            if region.is_empty() {
+                return Ok(());
+            }
+            panic!("we didn't allocate {:#x}, but tried to write to it o.O", header.p_paddr);
+        };
+        let offset: usize = (header.p_paddr - *start).try_into().unwrap();
+        debug!(
+            "load {} bytes into {:#x} (at {:#x})",
+            region.len(), header.p_vaddr, alloc.as_ptr() as usize + offset,
+        );
+        alloc.as_mut_slice()[offset..offset + region.len()].clone_from_slice(region);
+        Ok(())
     }
 }
 
 impl From<OurElfLoader> for Vec<Allocation> {
     /// Gets the allocated memory.
     fn from(loader: OurElfLoader) -> Vec<Allocation> {
-        // using .values() would just borrow the values from the hash map
-        loader.allocations.into_values().collect()
+        loader.allocations.into_iter().map(|(_, allocation)| allocation).collect()
     }
 }
 
@@ -0,0 +1,198 @@
+//! This module explains why a kernel's Multiboot header couldn't be parsed.
+//!
+//! It looks for anything resembling a header and reports what's wrong with it.
+//! It doesn't use anything UEFI-specific, so the tests package can include it.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The magic value at the start of Multiboot1 headers
+pub(super) const MULTIBOOT1_MAGIC: u32 = 0x1BAD_B002;
+
+/// The alignment of Multiboot1 headers
+pub(super) const MULTIBOOT1_ALIGN: usize = 4;
+
+/// Where Multiboot1 headers have to be (from the start of the file)
+pub(super) const MULTIBOOT1_SEARCH_LENGTH: usize = 8 * 1024;
+
+/// The flags of Multiboot1 headers that require features we don't know
+///
+/// Bits 0 to 15 are required features; only 0 to 2 are defined.
+const MULTIBOOT1_UNKNOWN_REQUIRED_FLAGS: u32 = 0xfff8;
+
+/// The magic value at the start of Multiboot2 headers
+pub(super) const MULTIBOOT2_MAGIC: u32 = 0xE852_50D6;
+
+/// The alignment of Multiboot2 headers (and their tags)
+pub(super) const MULTIBOOT2_ALIGN: usize = 8;
+
+/// Where Multiboot2 headers have to be (from the start of the file)
+pub(super) const MULTIBOOT2_SEARCH_LENGTH: usize = 32 * 1024;
+
+/// The highest Multiboot2 header tag type we know (`relocatable`)
+const MULTIBOOT2_LAST_TAG: u16 = 10;
+
+/// Read a little-endian `u16` at the given offset.
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset.checked_add(2)?).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+/// Read a little-endian `u32` at the given offset.
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset.checked_add(4)?)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Check where the header at `offset` is placed.
+fn check_placement(
+    prefix: &str, offset: usize, align: usize, search_length: usize, findings: &mut Vec<String>,
+) {
+    if offset % align != 0 {
+        findings.push(format!("{prefix} is not {align}-byte aligned"));
+    }
+    if offset >= search_length {
+        findings.push(format!(
+            "{prefix} is not within the first {} KiB (see the ExtendedHeaderSearch quirk)",
+            search_length / 1024,
+        ));
+    }
+}
+
+/// Check the Multiboot1 header at `offset`.
+fn diagnose_multiboot1(data: &[u8], offset: usize, findings: &mut Vec<String>) {
+    let prefix = format!("Multiboot1 magic at {offset:#x}");
+    let before = findings.len();
+    check_placement(&prefix, offset, MULTIBOOT1_ALIGN, MULTIBOOT1_SEARCH_LENGTH, findings);
+    let (Some(flags), Some(checksum)) = (read_u32(data, offset + 4), read_u32(data, offset + 8))
+    else {
+        findings.push(format!("{prefix}: the header is cut off"));
+        return;
+    };
+    let expected = 0u32.wrapping_sub(MULTIBOOT1_MAGIC).wrapping_sub(flags);
+    if checksum != expected {
+        findings.push(format!(
+            "{prefix}: the checksum is {checksum:#x}, but it should be {expected:#x} \
+            (for flags {flags:#x})",
+        ));
+    }
+    if flags & MULTIBOOT1_UNKNOWN_REQUIRED_FLAGS != 0 {
+        findings.push(format!(
+            "{prefix}: the flags {flags:#x} require unknown features ({:#x})",
+            flags & MULTIBOOT1_UNKNOWN_REQUIRED_FLAGS,
+        ));
+    }
+    // the address fields (bit 16) and the video mode fields (bit 2) follow the checksum
+    let length = match (flags & 1 << 16 != 0, flags & 1 << 2 != 0) {
+        (_, true) => 48,
+        (true, false) => 32,
+        (false, false) => 12,
+    };
+    if offset + length > data.len() {
+        findings.push(format!("{prefix}: the header ({length} bytes) is cut off"));
+    }
+    if findings.len() == before {
+        findings.push(format!("{prefix} looks like a valid header"));
+    }
+}
+
+/// Check the Multiboot2 header at `offset`, including its tags.
+fn diagnose_multiboot2(data: &[u8], offset: usize, findings: &mut Vec<String>) {
+    let prefix = format!("Multiboot2 magic at {offset:#x}");
+    let before = findings.len();
+    check_placement(&prefix, offset, MULTIBOOT2_ALIGN, MULTIBOOT2_SEARCH_LENGTH, findings);
+    let (Some(architecture), Some(length), Some(checksum)) = (
+        read_u32(data, offset + 4), read_u32(data, offset + 8), read_u32(data, offset + 12),
+    ) else {
+        findings.push(format!("{prefix}: the header is cut off"));
+        return;
+    };
+    let expected = 0u32.wrapping_sub(MULTIBOOT2_MAGIC)
+        .wrapping_sub(architecture).wrapping_sub(length);
+    if checksum != expected {
+        findings.push(format!(
+            "{prefix}: the checksum is {checksum:#x}, but it should be {expected:#x} \
+            (for architecture {architecture} and length {length})",
+        ));
+    }
+    if architecture != 0 {
+        findings.push(format!(
+            "{prefix}: architecture {architecture} is not supported (only 0 for i386 is)",
+        ));
+    }
+    // the header consists of four fields and at least the end tag
+    let length = length as usize;
+    if length < 24 {
+        findings.push(format!("{prefix}: the header length {length} is too short"));
+        return;
+    }
+    let end = offset.saturating_add(length);
+    if end > data.len() {
+        findings.push(format!(
+            "{prefix}: the header ({length} bytes) goes beyond {:#x}", data.len(),
+        ));
+        return;
+    }
+    let mut tag_offset = offset + 16;
+    let mut has_end_tag = false;
+    while !has_end_tag && tag_offset + 8 <= end {
+        // the tag is within the header, so these can be read
+        let tag_type = read_u16(data, tag_offset).unwrap_or_default();
+        let flags = read_u16(data, tag_offset + 2).unwrap_or_default();
+        let size = read_u32(data, tag_offset + 4).unwrap_or_default() as usize;
+        if size < 8 || tag_offset.saturating_add(size) > end {
+            findings.push(format!(
+                "{prefix}: tag {tag_type} at {tag_offset:#x} has an invalid size of {size}",
+            ));
+            return;
+        }
+        match tag_type {
+            0 => {
+                has_end_tag = true;
+                if size != 8 {
+                    findings.push(format!(
+                        "{prefix}: the end tag at {tag_offset:#x} has a size of {size}, \
+                        but it should be 8",
+                    ));
+                }
+            },
+            1..=MULTIBOOT2_LAST_TAG => (),
+            // bit 0 of the flags marks the tag as optional
+            _ if flags & 1 == 0 => findings.push(format!(
+                "{prefix}: tag {tag_type} at {tag_offset:#x} is required, but unknown",
+            )),
+            _ => findings.push(format!(
+                "{prefix}: tag {tag_type} at {tag_offset:#x} is unknown, it is ignored",
+            )),
+        }
+        tag_offset += size.next_multiple_of(MULTIBOOT2_ALIGN);
+    }
+    if !has_end_tag {
+        findings.push(format!("{prefix}: there is no end tag"));
+    }
+    if findings.len() == before {
+        findings.push(format!("{prefix} looks like a valid header"));
+    }
+}
+
+/// Look for anything resembling a Multiboot header at the start of the kernel
+/// and describe what's wrong with it.
+///
+/// This also reports magic values at unaligned offsets or too far into the file,
+/// as those are common mistakes (for example when the linker moves the header).
+pub(super) fn diagnose(kernel_start: &[u8]) -> Vec<String> {
+    let mut findings = Vec::new();
+    for offset in 0..kernel_start.len().saturating_sub(3) {
+        match read_u32(kernel_start, offset) {
+            Some(MULTIBOOT1_MAGIC) => diagnose_multiboot1(kernel_start, offset, &mut findings),
+            Some(MULTIBOOT2_MAGIC) => diagnose_multiboot2(kernel_start, offset, &mut findings),
+            _ => (),
+        }
+    }
+    if findings.is_empty() {
+        findings.push(format!(
+            "there is no Multiboot magic in the first {} bytes (is this a Multiboot kernel?)",
+            kernel_start.len(),
+        ));
+    }
+    findings
+}
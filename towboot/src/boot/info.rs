@@ -0,0 +1,327 @@
+//! This module fills in the parts of the Multiboot information that don't
+//! depend on the firmware.
+//!
+//! It doesn't use anything UEFI-specific, so the tests package can include it
+//! to build the information on the host and compare it to known-good output.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use multiboot12::information::{InfoBuilder, Module, Symbols};
+
+/// Where the System Table and our image handle are
+pub(super) enum EfiPointers {
+    /// on 32-bit firmware
+    Ia32 { system_table: u32, image_handle: u32 },
+    /// on 64-bit firmware
+    X64 { system_table: u64, image_handle: u64 },
+}
+
+/// The size of a module in the Multiboot1 information (without its command line)
+const MULTIBOOT1_MODULE_SIZE: u64 = 16;
+
+/// The size of a module tag in the Multiboot2 information (without its command line)
+const MULTIBOOT2_MODULE_SIZE: u64 = 16;
+
+/// The value a Multiboot1 kernel gets
+const MULTIBOOT1_BOOTLOADER_MAGIC: u32 = 0x2BAD_B002;
+
+/// The value a Multiboot2 kernel gets (instead of the Multiboot1 one)
+const MULTIBOOT2_BOOTLOADER_MAGIC: u32 = 0x36D7_6289;
+
+/// The flag and the offset of `boot_device` in the Multiboot1 information
+const MULTIBOOT1_BOOT_DEVICE: (u32, usize) = (1 << 1, 12);
+
+/// The flag and the offset of `drives_length` (followed by `drives_addr`)
+/// in the Multiboot1 information
+const MULTIBOOT1_DRIVES: (u32, usize) = (1 << 7, 52);
+
+/// The size of a drive in the Multiboot1 drives structure (without any ports)
+const MULTIBOOT1_DRIVE_SIZE: u32 = 12;
+
+/// The mode of drives that are accessed via LBA (and not CHS)
+const MULTIBOOT1_DRIVE_MODE_LBA: u8 = 1;
+
+/// The (made-up) number of heads of the drives
+const DRIVE_HEADS: u8 = 255;
+
+/// The (made-up) number of sectors per track of the drives
+const DRIVE_SECTORS_PER_TRACK: u8 = 63;
+
+/// The type of the Multiboot2 tag containing the boot device
+const MULTIBOOT2_BOOT_DEVICE_TAG: u32 = 5;
+
+/// The size of the data of the Multiboot2 boot device tag
+const MULTIBOOT2_BOOT_DEVICE_SIZE: u64 = 12;
+
+/// The type of the Multiboot2 tag containing the DHCP acknowledgement
+const MULTIBOOT2_NETWORK_TAG: u32 = 16;
+
+/// The drive and the partition on it the kernel has been loaded from
+pub(super) struct BootDevice {
+    /// the BIOS drive number (starting at `0x80` for hard disks)
+    pub drive: u8,
+    /// the partition, counting from 0 (`None` if it's the whole drive)
+    pub partition: Option<u8>,
+}
+
+impl BootDevice {
+    /// Get the value of the Multiboot1 `boot_device` field.
+    ///
+    /// There are no sub-partitions, so `part2` and `part3` are always unused.
+    fn multiboot1(&self) -> u32 {
+        u32::from_be_bytes([self.drive, self.partition.unwrap_or(0xff), 0xff, 0xff])
+    }
+
+    /// Get the data of the Multiboot2 boot device tag.
+    fn multiboot2(&self) -> Vec<u8> {
+        [self.drive.into(), self.partition.map_or(u32::MAX, u32::from), u32::MAX]
+            .iter().flat_map(|value: &u32| value.to_le_bytes()).collect()
+    }
+}
+
+/// A disk, as a BIOS would report it
+pub(super) struct Drive {
+    /// the BIOS drive number (starting at `0x80` for hard disks)
+    pub number: u8,
+    /// the size in 512-byte sectors
+    pub sectors: u64,
+}
+
+/// Build the Multiboot1 drives structure.
+///
+/// This is synthetic: There is no BIOS to ask, so all drives are in LBA mode
+/// and have a made-up geometry (255 heads, 63 sectors per track and as many
+/// cylinders as fit into the size), and their lists of I/O ports are empty.
+pub(super) fn drives_structure(drives: &[Drive]) -> Vec<u8> {
+    let sectors_per_cylinder = u64::from(DRIVE_HEADS) * u64::from(DRIVE_SECTORS_PER_TRACK);
+    let mut structure = Vec::new();
+    for drive in drives {
+        let cylinders = u16::try_from(drive.sectors / sectors_per_cylinder).unwrap_or(u16::MAX);
+        structure.extend_from_slice(&MULTIBOOT1_DRIVE_SIZE.to_le_bytes());
+        structure.push(drive.number);
+        structure.push(MULTIBOOT1_DRIVE_MODE_LBA);
+        structure.extend_from_slice(&cylinders.to_le_bytes());
+        structure.push(DRIVE_HEADS);
+        structure.push(DRIVE_SECTORS_PER_TRACK);
+        // the list of ports is terminated by 0
+        structure.extend_from_slice(&0u16.to_le_bytes());
+    }
+    structure
+}
+
+/// Information that the builder has no setters for.
+///
+/// This is added to the information after it has been built (see [`Supplement::add_to`]).
+#[derive(Default)]
+pub(super) struct Supplement {
+    /// where the kernel has been loaded from
+    pub boot_device: Option<BootDevice>,
+    /// the DHCP acknowledgement towboot has been loaded with (only for Multiboot2)
+    pub dhcp_ack: Option<Vec<u8>>,
+    /// the address and the length of the drives structure (only for Multiboot1)
+    ///
+    /// It has to stay where it is, so it's not part of the information.
+    /// (See [`drives_structure`].)
+    pub drives: Option<(u32, u32)>,
+}
+
+impl Supplement {
+    /// How many bytes this adds to the information.
+    ///
+    /// For Multiboot1, fields in the fixed part are filled in, so this is 0.
+    pub(super) fn size(&self, multiboot2: bool) -> u64 {
+        if !multiboot2 {
+            return 0;
+        }
+        let tag_size = |data_size: u64| (8 + data_size).next_multiple_of(8);
+        self.boot_device.as_ref().map_or(0, |_| tag_size(MULTIBOOT2_BOOT_DEVICE_SIZE))
+            + self.dhcp_ack.as_ref().map_or(0, |dhcp_ack| tag_size(dhcp_ack.len() as u64))
+    }
+
+    /// Add this to the built information.
+    ///
+    /// The signature tells which revision of Multiboot the information is for.
+    /// Multiboot2 tags are inserted before the end tag, so this has to happen
+    /// before the memory information is filled in.
+    /// Multiboot1 information contains pointers to itself, so it never grows.
+    pub(super) fn add_to(&self, info: &mut Vec<u8>, signature: u32) {
+        match signature {
+            MULTIBOOT1_BOOTLOADER_MAGIC => {
+                if let Some(boot_device) = &self.boot_device {
+                    set_multiboot1_fields(info, MULTIBOOT1_BOOT_DEVICE, &[boot_device.multiboot1()]);
+                }
+                if let Some((address, length)) = self.drives {
+                    set_multiboot1_fields(info, MULTIBOOT1_DRIVES, &[length, address]);
+                }
+            },
+            MULTIBOOT2_BOOTLOADER_MAGIC => {
+                if let Some(boot_device) = &self.boot_device {
+                    add_multiboot2_tag(info, MULTIBOOT2_BOOT_DEVICE_TAG, &boot_device.multiboot2());
+                }
+                if let Some(dhcp_ack) = &self.dhcp_ack {
+                    add_multiboot2_tag(info, MULTIBOOT2_NETWORK_TAG, dhcp_ack);
+                }
+            },
+            _ => unreachable!("unknown signature {signature:#x}"),
+        }
+    }
+}
+
+/// Set consecutive fields in built Multiboot1 information and their flag.
+fn set_multiboot1_fields(info: &mut [u8], (flag, offset): (u32, usize), values: &[u32]) {
+    let flags = u32::from_le_bytes(info[..4].try_into().unwrap()) | flag;
+    info[..4].copy_from_slice(&flags.to_le_bytes());
+    for (index, value) in values.iter().enumerate() {
+        let offset = offset + index * 4;
+        info[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Insert a tag into built Multiboot2 information, before the end tag.
+fn add_multiboot2_tag(info: &mut Vec<u8>, tag_type: u32, data: &[u8]) {
+    let end_tag = info.split_off(info.len() - 8);
+    info.extend_from_slice(&tag_type.to_le_bytes());
+    info.extend_from_slice(&u32::try_from(8 + data.len()).unwrap().to_le_bytes());
+    info.extend_from_slice(data);
+    info.resize(info.len().next_multiple_of(8), 0);
+    info.extend_from_slice(&end_tag);
+    let total_size = u32::try_from(info.len()).unwrap();
+    info[..4].copy_from_slice(&total_size.to_le_bytes());
+}
+
+/// Measure the information that [`fill`] builds without any modules.
+///
+/// The modules (and their command lines) are added to this, so it counts
+/// towards their limit. (Symbols and what's only known at boot, like the
+/// memory map, are left out.)
+pub(super) fn size_without_modules(
+    mut info_builder: InfoBuilder, command_line: Option<&str>, load_base_address: Option<u32>,
+    boot_loader_name: &str, efi_pointers: Option<EfiPointers>, boot_services_exited: bool,
+) -> u64 {
+    fill(
+        &mut info_builder, command_line, load_base_address, &[], None, boot_loader_name,
+        efi_pointers, boot_services_exited,
+    );
+    let _efi_mmap = info_builder.allocate_efi_memory_map_vec(0);
+    let _mmap = info_builder.allocate_memory_map_vec(0);
+    info_builder.set_memory_bounds(Some((0, 0)));
+    let (info, _, _) = info_builder.build();
+    info.len() as u64
+}
+
+/// Check that the modules (given as their command lines) can be represented
+/// in the Multiboot information.
+///
+/// Command lines are null-terminated, so they can't contain null bytes.
+/// The modules are added to the rest of the information (which is `base_size`
+/// bytes long, see [`size_without_modules`]), and everything has to fit into
+/// the space `available` for it and into 32 bits: for Multiboot1, the
+/// information (including the module structs and their command lines) has to
+/// be addressable; for Multiboot2, its size is a `u32`.
+pub(super) fn check_modules(
+    argvs: &[Option<&str>], multiboot2: bool, base_size: u64, available: u64,
+) -> Result<(), String> {
+    let (revision, module_size, align) = if multiboot2 {
+        (2, MULTIBOOT2_MODULE_SIZE, 8)
+    } else {
+        (1, MULTIBOOT1_MODULE_SIZE, 1)
+    };
+    let limit = available.min(u32::MAX.into());
+    let Some(space) = limit.checked_sub(base_size) else {
+        return Err(format!(
+            "the Multiboot{revision} information needs {base_size} bytes without any modules, \
+            but there are only {limit} bytes for it",
+        ));
+    };
+    // each module needs at least its struct and an empty command line
+    let max_modules = space / (module_size + 1).next_multiple_of(align);
+    if argvs.len() as u64 > max_modules {
+        return Err(format!(
+            "{} modules are too many for Multiboot{revision} (at most {max_modules} fit)",
+            argvs.len(),
+        ));
+    }
+    let mut size: u64 = 0;
+    for (index, argv) in argvs.iter().enumerate() {
+        let argv = argv.unwrap_or_default();
+        if argv.contains('\0') {
+            return Err(format!("the command line of module {index} contains a null byte"));
+        }
+        // the command line is null-terminated
+        let module = (module_size + argv.len() as u64 + 1).next_multiple_of(align);
+        if module > space {
+            return Err(format!(
+                "the command line of module {index} is {} bytes long, \
+                which is too long for Multiboot{revision}",
+                argv.len(),
+            ));
+        }
+        size += module;
+    }
+    if size > space {
+        return Err(format!(
+            "the modules and their command lines need {size} bytes, \
+            but there are only {space} bytes left in the Multiboot{revision} information",
+        ));
+    }
+    Ok(())
+}
+
+/// Put information about the entry, its modules and the firmware in the
+/// Multiboot information.
+///
+/// The modules are given as their start and end addresses and their command lines.
+#[allow(clippy::too_many_arguments)]
+pub(super) fn fill(
+    info_builder: &mut InfoBuilder, command_line: Option<&str>, load_base_address: Option<u32>,
+    modules: &[(u32, u32, Option<&str>)], symbols: Option<Symbols>, boot_loader_name: &str,
+    efi_pointers: Option<EfiPointers>, boot_services_exited: bool,
+) {
+    // The boot device can't be set here, it's part of the `Supplement`.
+
+    info_builder.set_command_line(command_line);
+    let mb_modules: Vec<Module> = modules.iter().map(
+        |(start, end, argv)| info_builder.new_module(*start, *end, *argv)
+    ).collect();
+    info_builder.set_modules(Some(mb_modules));
+    info_builder.set_symbols(symbols);
+
+    // Passing memory information happens after exiting BootServices,
+    // so we don't accidentally allocate or deallocate, making the data obsolete.
+    // TODO: Do we really need to do this? Our allocations don't matter to the kernel.
+    // TODO: But do they affect the firmware's allocations?
+
+    // We can't ask the BIOS for information about the drives.
+    // The firmware's disks are converted to the legacy BIOS format instead,
+    // but that happens outside of the builder, as part of the `Supplement`.
+
+    // There is no BIOS config table.
+
+    info_builder.set_boot_loader_name(Some(boot_loader_name));
+
+    // There is no APM config table.
+
+    // There is no VBE information.
+
+    // This only has an effect on Multiboot2.
+    match efi_pointers {
+        Some(EfiPointers::Ia32 { system_table, image_handle }) => {
+            info_builder.set_system_table_ia32(Some(system_table));
+            info_builder.set_efi_image_handle32(image_handle);
+        },
+        Some(EfiPointers::X64 { system_table, image_handle }) => {
+            info_builder.set_system_table_x64(Some(system_table));
+            info_builder.set_efi_image_handle64(image_handle);
+        },
+        None => (),
+    }
+
+    if !boot_services_exited {
+        info_builder.set_boot_services_not_exited();
+    }
+
+    if let Some(addr) = load_base_address {
+        info_builder.set_image_load_addr(addr);
+    }
+}
@@ -5,10 +5,11 @@
 use alloc::{
     collections::btree_set::BTreeSet,
     format,
+    string::{String, ToString},
     vec,
     vec::Vec,
 };
-#[cfg(target_arch = "x86_64")]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use x86::{
     dtables::DescriptorTablePointer,
     segmentation::{
@@ -18,9 +19,11 @@ use x86::{
 };
 
 use core::arch::asm;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use core::arch::naked_asm;
 use core::ffi::c_void;
 use core::ptr::NonNull;
+use core::sync::atomic::Ordering;
 use uefi::prelude::*;
 use uefi::boot::{exit_boot_services, image_handle, memory_map, MemoryType, ScopedProtocol};
 use uefi::mem::memory_map::{MemoryMap, MemoryMapMut};
@@ -30,21 +33,45 @@ use uefi::table::system_table_raw;
 use log::{debug, info, error, warn};
 
 use multiboot12::header::Header;
-use multiboot12::information::{
-    Module, InfoBuilder, Symbols
-};
+use multiboot12::information::{InfoBuilder, Symbols};
 
 use goblin::elf::Elf;
 
-use towboot_config::{Entry, Quirk};
-use super::file::File;
-use super::mem::Allocation;
+use towboot_config::{Entry, Module, ModuleKind, Quirk, glob_matches, split_glob};
+use super::archive;
+use super::file::{File, dhcp_ack, list_directory, volume};
+use super::mem::{Allocation, largest_free_block_under_4gb};
 
+mod acpi;
 mod config_tables;
+mod disks;
 mod elf;
+mod header;
+mod info;
 mod video;
 
 use elf::OurElfLoader;
+use header::{
+    MULTIBOOT1_ALIGN, MULTIBOOT1_MAGIC, MULTIBOOT1_SEARCH_LENGTH, MULTIBOOT2_ALIGN,
+    MULTIBOOT2_MAGIC, MULTIBOOT2_SEARCH_LENGTH,
+};
+
+/// The ELF machine type kernels have to be built for
+#[cfg(target_arch = "aarch64")]
+const ELF_MACHINE: u16 = goblin::elf::header::EM_AARCH64;
+#[cfg(target_arch = "riscv64")]
+const ELF_MACHINE: u16 = goblin::elf::header::EM_RISCV;
+
+/// The Multiboot header has to be within this many bytes from the start of
+/// the kernel (8 KiB for Multiboot1 and 32 KiB for Multiboot2).
+const HEADER_SEARCH_LENGTH: usize = 32 * 1024;
+
+/// How long command lines for Multiboot1 kernels should be at most
+/// (including the terminating null byte)
+const MULTIBOOT1_COMMAND_LINE_LIMIT: usize = 4096;
+
+/// How we call ourselves in the Multiboot information
+const BOOT_LOADER_NAME: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
 
 /// A kernel loaded into memory
 struct LoadedKernel {
@@ -58,52 +85,66 @@ struct LoadedKernel {
 impl LoadedKernel {
     /// Load a kernel from a vector.
     /// This requires that the Multiboot header has already been parsed.
+    ///
+    /// `header_offset` is where the data the header has been parsed from starts in the file.
+    /// If `entry_point` is given, it overrides the address from the header (or the ELF file).
     fn new(
-        kernel_vec: Vec<u8>, header: &Header, quirks: &BTreeSet<Quirk>,
+        kernel_file: File, header: &Header, header_offset: usize, quirks: &BTreeSet<Quirk>,
+        entry_point: Option<u64>,
     ) -> Result<Self, Status> {
-        if header.get_load_addresses().is_some() && !quirks.contains(&Quirk::ForceElf) {
-            LoadedKernel::new_multiboot(kernel_vec, header, quirks)
+        let entry_point = entry_point.map(usize::try_from).transpose().map_err(|_| {
+            error!("the entry point is not addressable on this architecture");
+            Status::INVALID_PARAMETER
+        })?;
+        let use_multiboot = header.get_load_addresses().is_some()
+            && !quirks.contains(&Quirk::ForceElf);
+        let kernel = if use_multiboot {
+            LoadedKernel::new_multiboot(kernel_file, header, header_offset, quirks, entry_point)
         } else {
-            LoadedKernel::new_elf(header, kernel_vec, quirks)
+            LoadedKernel::new_elf(header, kernel_file.try_into()?, quirks, entry_point)
+        }?;
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+        if let EntryPoint::Multiboot(_) = kernel.entry_point {
+            error!("the kernel has no UEFI entry point");
+            error!("(There is no Multiboot machine state on this architecture.)");
+            return Err(Status::UNSUPPORTED);
         }
+        Ok(kernel)
     }
     
     /// Load a kernel which has its addresses specified inside the Multiboot header.
     fn new_multiboot(
-        kernel_vec: Vec<u8>, header: &Header, quirks: &BTreeSet<Quirk>,
+        mut kernel_file: File, header: &Header, header_offset: usize, quirks: &BTreeSet<Quirk>,
+        entry_point: Option<usize>,
     ) -> Result<Self, Status> {
         // TODO: Add support for AOut symbols? Do we really know this binary is AOut at this point?
         let addresses = header.get_load_addresses().unwrap();
         
-        // Try to allocate the memory where to load the kernel and move the kernel there.
-        // In the worst case we might have blocked the destination by loading the file there,
-        // but `move_to_where_it_should_be` should fix this later.
-        info!("moving the kernel to its desired location...");
-        let load_offset = addresses.compute_load_offset(header.header_start());
+        // Try to allocate the memory where to load the kernel and read the kernel there.
+        // Only the header has been read so far, so nothing should be in the way;
+        // if the firmware is, `move_to_where_it_should_be` should fix this later.
+        info!("loading the kernel to its desired location...");
+        let header_start = header.header_start() + u32::try_from(header_offset).unwrap();
+        let load_offset: usize = addresses.compute_load_offset(header_start)
+            .try_into().unwrap();
         // allocate
         let kernel_length: usize = addresses.compute_kernel_length(
-            kernel_vec.len().try_into().unwrap()
+            kernel_file.size().try_into().unwrap()
         ).try_into().unwrap();
         let mut allocation = Allocation::new_at(
-            addresses.load_addr().try_into().unwrap(), kernel_length
-        )?;
+            addresses.load_addr().into(), kernel_length
+        )?.zeroize_if_wanted(quirks);
         let kernel_buf = allocation.as_mut_slice();
-        // copy from beginning of text to end of data segment and fill the rest with zeroes
-        kernel_buf.iter_mut().zip(
-            kernel_vec.iter()
-            .skip(load_offset.try_into().unwrap())
-            .take(kernel_length)
-            .chain(core::iter::repeat(&0))
-        )
-        .for_each(|(dst,src)| *dst = *src);
-        // drop the old vector
-        core::mem::drop(kernel_vec);
+        // read from beginning of text to end of data segment and fill the rest with zeroes
+        let file_length = kernel_file.size().saturating_sub(load_offset).min(kernel_length);
+        kernel_file.read_at(load_offset, &mut kernel_buf[..file_length])?;
+        kernel_buf[file_length..].fill(0);
 
-        let entry_point = get_kernel_uefi_entry(header, quirks)
-            .or(header.get_entry_address().map(
-                |e| EntryPoint::Multiboot(e as usize)
-            ))
-            .expect("failed to find an entry point to the kernel");
+        let entry_point = find_entry_point(header, quirks, None, entry_point)
+            .ok_or_else(|| {
+                error!("failed to find an entry point to the kernel");
+                Status::LOAD_ERROR
+            })?;
         let should_exit_boot_services = !quirks.contains(&Quirk::DontExitBootServices) && header.should_exit_boot_services();
         
         Ok(Self {
@@ -118,22 +159,34 @@ impl LoadedKernel {
     /// Load a kernel which uses ELF semantics.
     fn new_elf(
         header: &Header, kernel_vec: Vec<u8>, quirks: &BTreeSet<Quirk>,
+        entry_point: Option<usize>,
     ) -> Result<Self, Status> {
         let mut binary = Elf::parse(kernel_vec.as_slice()).map_err(|msg| {
             error!("failed to parse ELF structure of kernel: {msg}");
             Status::LOAD_ERROR
         })?;
+        // The entry address tag doesn't tell us the architecture, so make sure
+        // we don't jump into x86 code.
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+        if binary.header.e_machine != ELF_MACHINE {
+            error!("the kernel is not built for this architecture");
+            return Err(Status::LOAD_ERROR);
+        }
         let mut loader = OurElfLoader::new(binary.entry);
         loader.load_elf(&binary, kernel_vec.as_slice()).map_err(|msg| {
             error!("failed to load kernel: {msg}");
             Status::LOAD_ERROR
         })?;
         let symbols = Some(elf::symbols(header, &mut binary, kernel_vec.as_slice()));
-        let entry_point = get_kernel_uefi_entry(header, quirks)
-            .or(header.get_entry_address().map(
-                |e| EntryPoint::Multiboot(e as usize)
-            ))
-            .unwrap_or(EntryPoint::Multiboot(loader.entry_point()));
+        let entry_point = find_entry_point(
+            header, quirks, Some(loader.entry_point()), entry_point,
+        ).unwrap();
+        // The segments may be above 4 GiB, but the Multiboot machine state is 32-bit.
+        if let EntryPoint::Multiboot(address) = entry_point
+            && u32::try_from(address).is_err() {
+            error!("the entry point {address:#x} is above 4 GiB, so it can only be a UEFI one");
+            return Err(Status::LOAD_ERROR);
+        }
         let should_exit_boot_services = !quirks.contains(&Quirk::DontExitBootServices) && header.should_exit_boot_services();
         Ok(Self {
             allocations: loader.into(), entry_point, load_base_address: None,
@@ -152,6 +205,30 @@ impl LoadedKernel {
     }
 }
 
+/// Find out where to jump to.
+///
+/// The UEFI entry point is preferred (if it is usable), then the entry address tag,
+/// then the given fallback (e.g. from the ELF file).
+/// An overridden address keeps the kind of entry point, but replaces its address.
+fn find_entry_point(
+    header: &Header, quirks: &BTreeSet<Quirk>,
+    fallback: Option<usize>, override_address: Option<usize>,
+) -> Option<EntryPoint> {
+    let entry_point = get_kernel_uefi_entry(header, quirks)
+        .or(header.get_entry_address().map(
+            |e| EntryPoint::Multiboot(e as usize)
+        ))
+        .or(fallback.map(EntryPoint::Multiboot));
+    let Some(address) = override_address else {
+        return entry_point;
+    };
+    info!("overriding the entry point with {address:#x}");
+    Some(match entry_point {
+        Some(EntryPoint::Uefi(_)) => EntryPoint::Uefi(address),
+        _ => EntryPoint::Multiboot(address),
+    })
+}
+
 /// Check whether the kernel is compatible to the firmware we are running on.
 #[cfg(target_arch = "x86")]
 fn get_kernel_uefi_entry(
@@ -200,54 +277,211 @@ fn get_kernel_uefi_entry(
     }
 }
 
-/// Prepare information for the kernel.
-fn prepare_multiboot_information(
-    entry: &Entry, header: Header, load_base_address: Option<u32>,
-    modules: &[Allocation], symbols: Option<Symbols>,
-    graphics_output: Option<ScopedProtocol<GraphicsOutput>>,
-    boot_services_exited: bool,
-) -> InfoBuilder {
-    let mut info_builder = header.info_builder();
-    
-    // We don't have much information about the partition we loaded the kernel from.
-    // There's the UEFI Handle, but the kernel probably won't understand that.
-    
-    info_builder.set_command_line(entry.argv.as_deref());
-    let mb_modules: Vec<Module> = modules.iter().zip(entry.modules.iter()).map(|(module, module_entry)| {
-        info_builder.new_module(
-            (module.as_ptr() as usize).try_into().unwrap(),
-            (unsafe {
-                module.as_ptr().offset(module.len.try_into().unwrap())
-            } as usize ).try_into().unwrap(),
-            module_entry.argv.as_deref()
-        )
-    }).collect();
-    info_builder.set_modules(Some(mb_modules));
-    info_builder.set_symbols(symbols);
-    
-    // Passing memory information happens after exiting BootServices,
-    // so we don't accidentally allocate or deallocate, making the data obsolete.
-    // TODO: Do we really need to do this? Our allocations don't matter to the kernel.
-    // TODO: But do they affect the firmware's allocations?
-    
-    // We can't ask the BIOS for information about the drives.
-    // (We could ask the firmware and convert it to the legacy BIOS format, however.)
-    
-    // There is no BIOS config table.
-    
-    info_builder.set_boot_loader_name(Some(&format!(
-        "{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")
-    )));
-    
-    // There is no APM config table.
-    
-    // There is no VBE information.
-    
-    if let Some(go) = graphics_output {
-        video::prepare_information(&mut info_builder, go);
+/// Check whether the kernel is compatible to the firmware we are running on.
+///
+/// The Multiboot2 specification only has entry address tags for x86,
+/// so aarch64 and riscv64 kernels use the one for 64-bit UEFI.
+/// As there is no Multiboot machine state here, this is the only way to boot.
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+fn get_kernel_uefi_entry(
+    header: &Header, quirks: &BTreeSet<Quirk>,
+) -> Option<EntryPoint> {
+    if let Some(uefi_entry) = header.get_efi64_entry_address() {
+        if header.should_exit_boot_services() && !quirks.contains(&Quirk::DontExitBootServices) {
+            debug!("The kernel is UEFI-aware but wants us to exit Boot Services.");
+            debug!("(The Boot Services tag is missing.)");
+        }
+        Some(EntryPoint::Uefi(uefi_entry as usize))
+    } else {
+        if header.get_efi32_entry_address().is_some() {
+            warn!("The kernel supports 32-bit UEFI systems, but we're running on 64-bit.");
+        }
+        None
+    }
+}
+
+/// Get the files of a module and their command lines.
+///
+/// If the file name contains wildcards, each matching file becomes a module
+/// (in the order of their names), named like the file.
+fn module_files(
+    module: &Module, image_fs_handle: Handle,
+) -> Result<Vec<(String, Option<String>)>, Status> {
+    let Some((directory, pattern)) = split_glob(&module.image) else {
+        return Ok(vec![(module.image.clone(), module.argv.clone())]);
+    };
+    let directory = if directory.is_empty() { "\\" } else { directory };
+    let names: Vec<String> = list_directory(directory, image_fs_handle)?
+        .into_iter()
+        .filter(|name| glob_matches(pattern, name))
+        .collect();
+    if names.is_empty() {
+        warn!("there are no files matching {}", module.image);
+    } else {
+        info!("{} files match {}", names.len(), module.image);
+    }
+    Ok(names.into_iter().map(|name| (
+        format!("{}\\{name}", directory.trim_end_matches('\\')), Some(name),
+    )).collect())
+}
+
+/// Load a single file of a module.
+///
+/// Archives that should be unpacked result in one module per file inside them,
+/// named like the file.
+fn load_module_file(
+    module: &Module, path: &str, argv: Option<String>, image_fs_handle: Handle,
+    quirks: &BTreeSet<Quirk>,
+) -> Result<Vec<(Allocation, Option<String>)>, Status> {
+    let file = File::open(path, image_fs_handle)?;
+    if !module.unpack {
+        return Ok(vec![(file.try_into_allocation(module, quirks)?, argv)]);
     }
+    let archive: Vec<u8> = file.try_into()?;
+    let members = archive::tar_members(&archive)?;
+    info!("unpacking {} files from {path}", members.len());
+    members.into_iter().map(|member| {
+        let mut allocation = Allocation::for_module(module, member.data.len(), quirks)?;
+        allocation.as_mut_slice()[..member.data.len()].copy_from_slice(member.data);
+        Ok((allocation, Some(member.name)))
+    }).collect()
+}
+
+/// Hide the header of the other Multiboot version if a quirk forces one.
+///
+/// This is done by clearing its magic value, so that only the wanted header is found.
+/// (Multiboot1 headers are 4-byte aligned within the first 8 KiB and
+/// Multiboot2 headers are 8-byte aligned within the first 32 KiB.)
+fn hide_other_header(kernel_start: &mut [u8], quirks: &BTreeSet<Quirk>) -> Result<(), Status> {
+    let (magic, align, limit) = match (
+        quirks.contains(&Quirk::ForceMultiboot1), quirks.contains(&Quirk::ForceMultiboot2),
+    ) {
+        (true, true) => {
+            error!("ForceMultiboot1 and ForceMultiboot2 can't be used together");
+            return Err(Status::INVALID_PARAMETER);
+        },
+        (true, false) => (MULTIBOOT2_MAGIC, MULTIBOOT2_ALIGN, MULTIBOOT2_SEARCH_LENGTH),
+        (false, true) => (MULTIBOOT1_MAGIC, MULTIBOOT1_ALIGN, MULTIBOOT1_SEARCH_LENGTH),
+        (false, false) => return Ok(()),
+    };
+    let limit = if quirks.contains(&Quirk::ExtendedHeaderSearch) {
+        kernel_start.len()
+    } else {
+        kernel_start.len().min(limit)
+    };
+    for offset in (0..limit.saturating_sub(3)).step_by(align) {
+        if kernel_start[offset..offset + 4] == magic.to_le_bytes() {
+            debug!("hiding the header at {offset:#x}");
+            kernel_start[offset..offset + 4].fill(0);
+        }
+    }
+    Ok(())
+}
+
+/// Parse the Multiboot header.
+///
+/// With `extended_search` (the `ExtendedHeaderSearch` quirk), the whole kernel is
+/// searched if there is no header within the limits of the specification.
+/// This returns the header and where the data it has been parsed from starts.
+fn find_header(kernel_start: &[u8], extended_search: bool) -> Option<(Header, usize)> {
+    if let Some(header) = Header::from_slice(kernel_start) {
+        return Some((header, 0));
+    }
+    if !extended_search {
+        return None;
+    }
+    let (offset, header) = (MULTIBOOT1_SEARCH_LENGTH..kernel_start.len().saturating_sub(3))
+        .step_by(MULTIBOOT1_ALIGN)
+        .filter(|offset| {
+            let magic = &kernel_start[*offset..*offset + 4];
+            magic == MULTIBOOT1_MAGIC.to_le_bytes() || (
+                offset % MULTIBOOT2_ALIGN == 0 && magic == MULTIBOOT2_MAGIC.to_le_bytes()
+            )
+        })
+        .find_map(|offset| Header::from_slice(&kernel_start[offset..]).map(|h| (offset, h)))?;
+    warn!("using the Multiboot header at {offset:#x}, beyond the limit of the specification");
+    Some((header, offset))
+}
+
+/// Get the command line of the kernel.
+///
+/// This is `argv`, followed by the contents of `cmdline_file` (if there is one).
+fn command_line(entry: &Entry, image_fs_handle: Handle) -> Result<Option<String>, Status> {
+    let Some(cmdline_file) = &entry.cmdline_file else {
+        return Ok(entry.argv.clone());
+    };
+    let content: Vec<u8> = File::open(cmdline_file, image_fs_handle)?.try_into()?;
+    let content = String::from_utf8(content).map_err(|_| {
+        error!("{cmdline_file} is not valid UTF-8");
+        Status::LOAD_ERROR
+    })?;
+    let from_file = content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join(" ");
+    Ok(Some(match &entry.argv {
+        Some(argv) if !argv.is_empty() => format!("{argv} {from_file}"),
+        _ => from_file,
+    }))
+}
+
+/// Check whether the header is a Multiboot1 header.
+///
+/// `kernel_start` is the data the header has been parsed from.
+fn is_multiboot1(header: &Header, kernel_start: &[u8]) -> bool {
+    let header_start = header.header_start() as usize;
+    kernel_start.get(header_start..header_start + 4)
+        .is_some_and(|magic| magic == MULTIBOOT1_MAGIC.to_le_bytes())
+}
 
-    // This only has an effect on Multiboot2.
+/// Warn if the command line is too long for Multiboot1 kernels.
+///
+/// The specification has no limit, but many kernels (like GRUB Legacy did)
+/// copy it into a buffer of a page.
+fn check_command_line(header: &Header, kernel_start: &[u8], command_line: Option<&str>) {
+    if let Some(command_line) = command_line
+        && is_multiboot1(header, kernel_start)
+        && command_line.len() >= MULTIBOOT1_COMMAND_LINE_LIMIT {
+        warn!(
+            "the command line is {} bytes long, Multiboot1 kernels might only read {} of them",
+            command_line.len(), MULTIBOOT1_COMMAND_LINE_LIMIT - 1,
+        );
+    }
+}
+
+/// Check that the quirks about the descriptor tables make sense for the entry point.
+///
+/// The firmware's GDT and IDT are kept for UEFI entry points (as Boot Services
+/// are still running), so `ForceGdtSetup` can't be used for them.
+/// On x86_64, Multiboot entry points always need our own GDT (to leave long mode)
+/// and IDT (as the firmware's one is invalid in 32-bit mode), so `SkipGdtSetup`
+/// is ignored for them.
+fn check_descriptor_table_quirks(
+    entry_point: &EntryPoint, quirks: &BTreeSet<Quirk>,
+) -> Result<(), Status> {
+    let (force, skip) = (
+        quirks.contains(&Quirk::ForceGdtSetup), quirks.contains(&Quirk::SkipGdtSetup),
+    );
+    if force && skip {
+        error!("ForceGdtSetup and SkipGdtSetup can't be used together");
+        return Err(Status::INVALID_PARAMETER);
+    }
+    match entry_point {
+        EntryPoint::Uefi(_) if force => {
+            error!("ForceGdtSetup can't be used for UEFI entry points");
+            Err(Status::INVALID_PARAMETER)
+        },
+        EntryPoint::Multiboot(_) if skip && cfg!(target_arch = "x86_64") => {
+            warn!("ignoring SkipGdtSetup, as leaving long mode needs a new GDT and IDT");
+            Ok(())
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Get the addresses of the System Table and our image handle for the kernel.
+fn efi_pointers() -> Option<info::EfiPointers> {
     // TODO: Does this stay valid when we exit Boot Services?
     let systab_ptr = system_table_raw()
         .expect("failed to get System Table")
@@ -256,33 +490,67 @@ fn prepare_multiboot_information(
         core::mem::transmute::<Handle, NonNull<c_void>>(image_handle())
     }.as_ptr();
     if cfg!(target_arch = "x86") {
-        info_builder.set_system_table_ia32(Some(
-            (systab_ptr as usize).try_into().unwrap()
-        ));
-        info_builder.set_efi_image_handle32(
-            (image_handle_ptr as usize).try_into().unwrap()
-        );
-    } else if cfg!(target_arch = "x86_64") {
-        info_builder.set_system_table_x64(Some(
-            (systab_ptr as usize).try_into().unwrap()
-        ));
-        info_builder.set_efi_image_handle64(
-            (image_handle_ptr as usize).try_into().unwrap()
-        );
+        Some(info::EfiPointers::Ia32 {
+            system_table: (systab_ptr as usize).try_into().unwrap(),
+            image_handle: (image_handle_ptr as usize).try_into().unwrap(),
+        })
+    } else if cfg!(any(
+        target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64",
+    )) {
+        Some(info::EfiPointers::X64 {
+            system_table: (systab_ptr as usize).try_into().unwrap(),
+            image_handle: (image_handle_ptr as usize).try_into().unwrap(),
+        })
     } else {
         warn!("don't know how to pass the UEFI data on this target");
+        None
     }
+}
 
-    config_tables::parse_for_multiboot(&mut info_builder);
-
-    if !boot_services_exited {
-        info_builder.set_boot_services_not_exited();
+/// Put the (synthetic) Multiboot1 drives structure into memory below 4 GiB.
+///
+/// This returns the memory and the length of the structure, if there are any drives.
+fn allocate_drives(quirks: &BTreeSet<Quirk>) -> Result<Option<(Allocation, u32)>, Status> {
+    let drives = disks::drives();
+    if drives.is_empty() {
+        return Ok(None);
     }
+    info!("passing {} synthetic drives to the kernel", drives.len());
+    let structure = info::drives_structure(&drives);
+    let mut allocation = Allocation::new_under_4gb(structure.len(), quirks)?;
+    allocation.as_mut_slice()[..structure.len()].copy_from_slice(&structure);
+    Ok(Some((allocation, structure.len().try_into().unwrap())))
+}
+
+/// Prepare information for the kernel.
+fn prepare_multiboot_information(
+    command_line: Option<&str>, header: Header, load_base_address: Option<u32>,
+    modules: &[Allocation], module_argvs: &[Option<String>], symbols: Option<Symbols>,
+    graphics_output: Option<ScopedProtocol<GraphicsOutput>>,
+    boot_services_exited: bool,
+) -> InfoBuilder {
+    let mut info_builder = header.info_builder();
+    let module_addresses: Vec<(u32, u32, Option<&str>)> = modules.iter().zip(module_argvs).map(
+        |(module, argv)| (
+            (module.as_ptr() as usize).try_into().unwrap(),
+            (unsafe {
+                module.as_ptr().offset(module.len.try_into().unwrap())
+            } as usize ).try_into().unwrap(),
+            argv.as_deref(),
+        )
+    ).collect();
+
+    info::fill(
+        &mut info_builder, command_line, load_base_address, &module_addresses, symbols,
+        BOOT_LOADER_NAME, efi_pointers(), boot_services_exited,
+    );
 
-    if let Some(addr) = load_base_address {
-        info_builder.set_image_load_addr(addr);
+    if let Some(go) = graphics_output {
+        video::prepare_information(&mut info_builder, go);
     }
-    
+
+    config_tables::parse_for_multiboot(&mut info_builder);
+
     info_builder
 }
 
@@ -295,54 +563,144 @@ pub struct PreparedEntry<'a> {
     loaded_kernel: LoadedKernel,
     multiboot_information: InfoBuilder,
     modules_vec: Vec<Allocation>,
+    acpi_overrides: Option<acpi::Overrides>,
+    drives: Option<Allocation>,
+    supplement: info::Supplement,
 }
 
 impl<'a> PreparedEntry<'a> {
     /// Prepare an entry for boot.
     ///
     /// What this means:
-    /// 1. load the kernel into memory
-    /// 2. try to parse the Multiboot information
-    /// 3. move the kernel to where it wants to be
-    /// 4. load the modules
-    /// 5. make the framebuffer ready
-    /// 6. create the Multiboot information for the kernel
+    /// 1. read the beginning of the kernel
+    /// 2. try to parse the Multiboot header
+    /// 3. load the kernel to where it wants to be
+    /// 4. check and load the modules
+    /// 5. install ACPI overrides
+    /// 6. make the framebuffer ready
+    /// 7. create the Multiboot information for the kernel
     ///
     /// The returned `PreparedEntry` can be used to actually boot.
     /// This is non-destructive and will always return.
+    /// (ACPI overrides are undone when the `PreparedEntry` is dropped.)
     pub(crate) fn new(
         entry: &'a Entry, image_fs_handle: Handle,
     ) -> Result<PreparedEntry<'a>, Status> {
-        let kernel_vec: Vec<u8> = File::open(&entry.image, image_fs_handle)?.try_into()?;
-        let header = Header::from_slice(kernel_vec.as_slice()).ok_or_else(|| {
+        // Only read the beginning of the kernel for now; where the rest goes
+        // depends on the header.
+        let mut kernel_file = File::open(&entry.image, image_fs_handle)?;
+        // (With `ExtendedHeaderSearch`, the header might be anywhere, though.)
+        let extended_search = entry.quirks.contains(&Quirk::ExtendedHeaderSearch);
+        let mut kernel_start = kernel_file.read_start(
+            if extended_search { usize::MAX } else { HEADER_SEARCH_LENGTH }
+        )?;
+        hide_other_header(&mut kernel_start, &entry.quirks)?;
+        let (header, header_offset) = find_header(&kernel_start, extended_search).ok_or_else(|| {
             error!("invalid Multiboot header");
+            for finding in header::diagnose(&kernel_start) {
+                error!("{finding}");
+            }
             Status::LOAD_ERROR
         })?;
-        debug!("loaded kernel {:?} to {:?}", header, kernel_vec.as_ptr());
-        let mut loaded_kernel = LoadedKernel::new(kernel_vec, &header, &entry.quirks)?;
+        debug!("found kernel header {:?}", header);
+        let command_line = command_line(entry, image_fs_handle)?;
+        check_command_line(&header, &kernel_start[header_offset..], command_line.as_deref());
+        let mut loaded_kernel = LoadedKernel::new(
+            kernel_file, &header, header_offset, &entry.quirks, entry.entry_point,
+        )?;
+        check_descriptor_table_quirks(&loaded_kernel.entry_point, &entry.quirks)?;
         info!("kernel is loaded and bootable");
         
+        let multiboot2 = !is_multiboot1(&header, &kernel_start[header_offset..]);
+        let files: Vec<(&Module, String, Option<String>)> = entry.modules.iter()
+            .filter(|module| module.kind != ModuleKind::AcpiOverride)
+            .map(|module| Ok(
+                module_files(module, image_fs_handle)?
+                    .into_iter().map(move |(path, argv)| (module, path, argv))
+            ))
+            .collect::<Result<Vec<_>, Status>>()?
+            .into_iter().flatten().collect();
+        // Check the modules before loading them, so that we don't load lots of
+        // data just to fail afterwards.
+        // (The members of archives are only known after unpacking them.)
+        // The modules are added to the rest of the information, so measure that.
+        // (Some information can't be passed via the builder, it's added later.)
+        // (The drives structure has to stay where it is, so it gets its own memory.)
+        let drives = if multiboot2 { None } else { allocate_drives(&entry.quirks)? };
+        let supplement = info::Supplement {
+            boot_device: volume(&entry.image, image_fs_handle).and_then(disks::boot_device),
+            dhcp_ack: dhcp_ack(image_fs_handle),
+            drives: drives.as_ref().map(|(drives, length)| (
+                (drives.as_ptr() as usize).try_into().unwrap(), *length,
+            )),
+        };
+        let drives = drives.map(|(drives, _)| drives);
+        let boot_services_exited = !entry.quirks.contains(&Quirk::DontExitBootServices);
+        let base_size = info::size_without_modules(
+            header.info_builder(), command_line.as_deref(), loaded_kernel.load_base_address,
+            BOOT_LOADER_NAME, efi_pointers(), boot_services_exited,
+        ) + supplement.size(multiboot2);
+        let argvs: Vec<Option<&str>> = files.iter()
+            .filter(|(module, _, _)| !module.unpack)
+            .map(|(_, _, argv)| argv.as_deref())
+            .collect();
+        info::check_modules(
+            &argvs, multiboot2, base_size, largest_free_block_under_4gb(),
+        ).map_err(|e| {
+            error!("{e}");
+            Status::INVALID_PARAMETER
+        })?;
+
         // Load all modules, fail completely if one fails to load.
         // just always use whole pages, that's easier for us
-        let modules_vec: Vec<Allocation> = entry.modules.iter().map(|module|
-            File::open(&module.image, image_fs_handle)
-            .and_then(|f| f.try_into_allocation(&entry.quirks))
-        ).collect::<Result<Vec<_>, _>>()?;
+        let (mut modules_vec, mut module_argvs): (Vec<Allocation>, Vec<Option<String>>) =
+            files.iter()
+            .map(|(module, path, argv)| load_module_file(
+                module, path, argv.clone(), image_fs_handle, &entry.quirks,
+            ))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter().flatten().unzip();
         info!("loaded {} modules", modules_vec.len());
+        // pass the device tree as an additional module (after the modules of the entry)
+        if let Some(device_tree) = config_tables::copy_device_tree(&entry.quirks) {
+            info!("passing the device tree as module \"dtb\"");
+            modules_vec.push(device_tree);
+            module_argvs.push(Some("dtb".to_string()));
+        }
         for (index, module) in modules_vec.iter().enumerate() {
             debug!("loaded module {} to {:?}", index, module.as_ptr());
         }
+        // fail now instead of passing truncated information to the kernel
+        // (this includes the members of archives and the device tree)
+        let argvs: Vec<Option<&str>> = module_argvs.iter().map(Option::as_deref).collect();
+        info::check_modules(
+            &argvs, multiboot2, base_size, largest_free_block_under_4gb(),
+        ).map_err(|e| {
+            error!("{e}");
+            Status::INVALID_PARAMETER
+        })?;
+        
+        // ACPI overrides are not passed as modules, but replace the firmware's tables.
+        let acpi_tables = entry.modules.iter()
+            .filter(|module| module.kind == ModuleKind::AcpiOverride)
+            .map(|module| File::open(&module.image, image_fs_handle)?.try_into())
+            .collect::<Result<Vec<Vec<u8>>, Status>>()?;
+        let acpi_overrides = if acpi_tables.is_empty() {
+            None
+        } else {
+            Some(acpi::override_tables(&acpi_tables)?)
+        };
         
         let graphics_output = video::setup_video(&header, &entry.quirks);
         
         let multiboot_information = prepare_multiboot_information(
-            entry, header, loaded_kernel.load_base_address, &modules_vec,
-            loaded_kernel.symbols_struct(), graphics_output,
-            !entry.quirks.contains(&Quirk::DontExitBootServices),
+            command_line.as_deref(), header, loaded_kernel.load_base_address, &modules_vec, &module_argvs,
+            loaded_kernel.symbols_struct(), graphics_output, boot_services_exited,
         );
         
         Ok(PreparedEntry {
-            entry, loaded_kernel, multiboot_information, modules_vec,
+            entry, loaded_kernel, multiboot_information, modules_vec, acpi_overrides, drives,
+            supplement,
         })
     }
     
@@ -380,9 +738,11 @@ impl<'a> PreparedEntry<'a> {
         let (
             mut info, signature, update_memory_info,
         ) = self.multiboot_information.build();
+        self.supplement.add_to(&mut info, signature);
         debug!("passing signature {signature:x} to kernel...");
         let mut memory_map = if self.loaded_kernel.should_exit_boot_services {
             info!("exiting boot services...");
+            super::panic::BOOT_SERVICES_EXITED.store(true, Ordering::SeqCst);
             unsafe { exit_boot_services(MemoryType::LOADER_DATA) }
             // now, write! won't work anymore. Also, we can't allocate any memory.
         } else {
@@ -404,6 +764,10 @@ impl<'a> PreparedEntry<'a> {
             unsafe { allocation.move_to_where_it_should_be(
                 &mb_mmap_vec, &self.entry.quirks,
             ) };
+            // We've written the code through the data cache, so make sure
+            // that instruction fetches see it.
+            #[cfg(target_arch = "aarch64")]
+            clean_data_cache(allocation.as_ptr() as usize, allocation.len);
         }
         // The kernel will need its code and data, so make sure it stays around indefinitely.
         core::mem::forget(self.loaded_kernel.allocations);
@@ -411,11 +775,53 @@ impl<'a> PreparedEntry<'a> {
         core::mem::forget(self.modules_vec);
         // The kernel is going to need the section headers and symbols.
         core::mem::forget(self.loaded_kernel.symbols);
+        // The kernel is going to use the overridden ACPI tables.
+        core::mem::forget(self.acpi_overrides);
+        // The kernel may look at the drives.
+        core::mem::forget(self.drives);
         
-        self.loaded_kernel.entry_point.jump(signature, info)
+        self.loaded_kernel.entry_point.jump(signature, info, &self.entry.quirks)
+    }
+}
+
+/// Clean the data cache to the point of unification for the given memory.
+///
+/// This has to be followed by a barrier and invalidating the instruction cache.
+#[cfg(target_arch = "aarch64")]
+fn clean_data_cache(start: usize, len: usize) {
+    let ctr: usize;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack)) };
+    // DminLine is the log2 of the number of words in the smallest cache line.
+    let line_size = 4 << ((ctr >> 16) & 0xf);
+    let mut address = start & !(line_size - 1);
+    while address < start + len {
+        unsafe { asm!("dc cvau, {}", in(reg) address, options(nostack)) };
+        address += line_size;
     }
 }
 
+/// Build a GDT with flat 32-bit code (0x08) and data (0x10) segments.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn flat_gdt() -> [Descriptor; 3] {
+    let code_segment_builder: DescriptorBuilder = SegmentDescriptorBuilder::code_descriptor(
+        0, u32::MAX, CodeSegmentType::ExecuteRead,
+    );
+    let code_segment: Descriptor = code_segment_builder
+        .present()
+        .limit_granularity_4kb()
+        .db() // 32 bit
+        .finish();
+    let data_segment_builder: DescriptorBuilder = SegmentDescriptorBuilder::data_descriptor(
+        0, u32::MAX, DataSegmentType::ReadWrite,
+    );
+    let data_segment: Descriptor = data_segment_builder
+        .present()
+        .limit_granularity_4kb()
+        .db() // 32bit
+        .finish();
+    [Descriptor::NULL, code_segment, data_segment]
+}
+
 /// How to give execution to the kernel
 /// 
 /// Currently, there are two options: UEFI and Multiboot
@@ -433,19 +839,28 @@ enum EntryPoint {
 impl EntryPoint {
     /// Jump to the loaded kernel.
     /// This requires everything else to be ready and won't return.
-    fn jump(self, signature: u32, info: Vec<u8>) -> ! {
-        if let Self::Uefi(entry_address) = self {
-            self.jump_uefi(entry_address, signature, info)
-        } else if let Self::Multiboot(entry_address) = self {
-            self.jump_multiboot(entry_address, signature, info)
-        } else {
-            panic!("invalid entry point")
+    #[cfg_attr(not(any(target_arch = "x86", target_arch = "x86_64")), allow(unused_variables))]
+    fn jump(self, signature: u32, info: Vec<u8>, quirks: &BTreeSet<Quirk>) -> ! {
+        match self {
+            Self::Uefi(entry_address) => self.jump_uefi(entry_address, signature, info),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Self::Multiboot(entry_address) => self.jump_multiboot(
+                entry_address, signature, info, quirks,
+            ),
+            // `LoadedKernel::new` doesn't allow this
+            #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+            Self::Multiboot(_) => panic!("there is no Multiboot machine state here"),
         }
     }
 
     /// Jump to the loaded kernel, UEFI-style, eg. just passing the information.
     /// This requires everything else to be ready and won't return.
+    ///
+    /// Boot Services are still running, so the firmware's GDT and IDT are kept
+    /// (as if `SkipGdtSetup` had been given).
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     fn jump_uefi(self, entry_address: usize, signature: u32, info: Vec<u8>) -> ! {
+        debug!("keeping the firmware's GDT and IDT");
         debug!("jumping to 0x{:x}", entry_address);
         unsafe {
             // TODO: The spec mentions 32 bit registers, even on 64 bit.
@@ -463,9 +878,57 @@ impl EntryPoint {
         }
     }
 
+    /// Jump to the loaded kernel, UEFI-style, eg. just passing the information.
+    /// This requires everything else to be ready and won't return.
+    ///
+    /// The signature is passed in x0 and the address of the information in x1.
+    #[cfg(target_arch = "aarch64")]
+    fn jump_uefi(self, entry_address: usize, signature: u32, info: Vec<u8>) -> ! {
+        debug!("jumping to 0x{:x}", entry_address);
+        unsafe {
+            asm!(
+                // We've copied the kernel around, so make sure that we don't
+                // execute stale instructions.
+                // (`PreparedEntry::boot` has already cleaned the data cache.)
+                "dsb ish",
+                "ic iallu",
+                "dsb ish",
+                "isb",
+                "br {}",
+                in(reg) entry_address,
+                in("x0") signature as usize,
+                in("x1") &info.as_slice()[0],
+                options(noreturn),
+            );
+        }
+    }
+
+    /// Jump to the loaded kernel, UEFI-style, eg. just passing the information.
+    /// This requires everything else to be ready and won't return.
+    ///
+    /// The signature is passed in a0 and the address of the information in a1.
+    #[cfg(target_arch = "riscv64")]
+    fn jump_uefi(self, entry_address: usize, signature: u32, info: Vec<u8>) -> ! {
+        debug!("jumping to 0x{:x}", entry_address);
+        unsafe {
+            asm!(
+                // We've copied the kernel around, so make sure that we don't
+                // execute stale instructions.
+                "fence.i",
+                "jr {}",
+                in(reg) entry_address,
+                in("a0") signature as usize,
+                in("a1") &info.as_slice()[0],
+                options(noreturn),
+            );
+        }
+    }
+
     /// i686-specific part of the Multiboot machine state.
     #[cfg(target_arch = "x86")]
-    fn jump_multiboot(self, entry_address: usize, signature: u32, info: Vec<u8>) -> ! {
+    fn jump_multiboot(
+        self, entry_address: usize, signature: u32, info: Vec<u8>, quirks: &BTreeSet<Quirk>,
+    ) -> ! {
         debug!(
             "preparing machine state and jumping to 0x{:x}", entry_address,
         );
@@ -483,8 +946,34 @@ impl EntryPoint {
         // > 'DS’, 'ES’, ‘FS’, ‘GS’, ‘SS’: Must be a 32-bit read/write data segment with an
         // > offset of ‘0’ and a limit of ‘0xFFFFFFFF’. The exact values are all undefined.
         // We don't set them here as we should already be in the correct state
-        // (as opposed to x86_64).
+        // (as opposed to x86_64), but some firmwares need them to be reloaded.
+        let gdt_array = flat_gdt();
+        if quirks.contains(&Quirk::ForceGdtSetup) {
+            debug!("loading our own GDT");
+            let gdt = DescriptorTablePointer::new_from_slice(&gdt_array);
+            unsafe {
+                x86::dtables::lgdt(&gdt);
+                // This IDT is invalid (but that's no problem as we already
+                // disabled interrupts).
+                x86::dtables::lidt::<u32>(&DescriptorTablePointer::default());
 
+                asm!(
+                    "push 0x08", // code segment
+                    "lea {0}, [2f]",
+                    "push {0}",
+                    // This "return" allows us to overwrite CS.
+                    "retf",
+                    "2:",
+                    "mov {0}, 0x10", // data segment
+                    "mov ds, {0}",
+                    "mov es, {0}",
+                    "mov fs, {0}",
+                    "mov gs, {0}",
+                    "mov ss, {0}",
+                    out(reg) _,
+                );
+            }
+        }
 
         unsafe {
             asm!(
@@ -506,7 +995,9 @@ impl EntryPoint {
 
     /// x86_64-specific part of the Multiboot machine state.
     #[cfg(target_arch = "x86_64")]
-    fn jump_multiboot(self, entry_address: usize, signature: u32, info: Vec<u8>) -> ! {
+    fn jump_multiboot(
+        self, entry_address: usize, signature: u32, info: Vec<u8>, _quirks: &BTreeSet<Quirk>,
+    ) -> ! {
         debug!(
             "preparing machine state and jumping to 0x{:x}", entry_address,
         );
@@ -523,23 +1014,10 @@ impl EntryPoint {
         // > and a limit of ‘0xFFFFFFFF’. The exact value is undefined.
         // To archieve that, we'll have to set a new GDT and reload
         // the code segment.
-        let code_segment_builder: DescriptorBuilder = SegmentDescriptorBuilder::code_descriptor(
-            0, u32::MAX, CodeSegmentType::ExecuteRead,
-        );
-        let code_segment: Descriptor = code_segment_builder
-            .present()
-            .limit_granularity_4kb()
-            .db() // 32 bit
-            .finish();
-        let data_segment_builder: DescriptorBuilder = SegmentDescriptorBuilder::data_descriptor(
-            0, u32::MAX, DataSegmentType::ReadWrite,
-        );
-        let data_segment: Descriptor = data_segment_builder
-            .present()
-            .limit_granularity_4kb()
-            .db() // 32bit
-            .finish();
-        let gdt_array = [Descriptor::NULL, code_segment, data_segment];
+        // (This can't be skipped, as there's no way back to 32 bit without it.
+        // The firmware's IDT has 64-bit gates, so it can't be kept, either.
+        // `check_descriptor_table_quirks` has warned about `SkipGdtSetup`.)
+        let gdt_array = flat_gdt();
         let gdt = DescriptorTablePointer::new_from_slice(&gdt_array);
 
         unsafe {
@@ -586,6 +1064,7 @@ impl EntryPoint {
     }
 
     /// This last part is common for i686 and x86_64.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     #[naked]
     extern "stdcall" fn jump_multiboot_common() {
         unsafe {
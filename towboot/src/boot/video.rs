@@ -113,7 +113,7 @@ pub fn prepare_information(
             8,
             8,
             8,
-            6,
+            16,
             8,
         ),
         PixelFormat::Bgr => multiboot.new_color_info_rgb(
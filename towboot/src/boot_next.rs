@@ -0,0 +1,95 @@
+//! This module hands over to other entries of the firmware's boot manager.
+//!
+//! It sets `BootNext`, so that the firmware boots the given `Boot####` option
+//! exactly once (without touching `BootOrder`), and resets the machine.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::cstr16;
+use uefi::data_types::CString16;
+use uefi::runtime::{
+    ResetType, VariableAttributes, VariableVendor, get_variable_boxed, reset, set_variable,
+};
+
+use log::{error, info};
+
+use towboot_config::Entry;
+
+/// Read a `u16` array from a UEFI variable.
+fn read_u16s(data: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]]))
+}
+
+/// Get the description of the given boot option.
+fn description(number: u16) -> Option<String> {
+    let name = CString16::try_from(format!("Boot{number:04X}").as_str()).ok()?;
+    let (data, _) = get_variable_boxed(&name, &VariableVendor::GLOBAL_VARIABLE).ok()?;
+    // EFI_LOAD_OPTION: attributes (u32), length of the device path (u16),
+    // then the description as a null-terminated UCS-2 string
+    let description: Vec<u16> = read_u16s(data.get(6..)?).take_while(|c| *c != 0).collect();
+    Some(String::from_utf16_lossy(&description))
+}
+
+/// Get the numbers and descriptions of the firmware's boot options in boot order.
+pub(crate) fn boot_options() -> Vec<(u16, String)> {
+    let Ok((order, _)) = get_variable_boxed(
+        cstr16!("BootOrder"), &VariableVendor::GLOBAL_VARIABLE,
+    ) else {
+        return Vec::new();
+    };
+    read_u16s(&order)
+        .filter_map(|number| description(number).map(|d| (number, d)))
+        .collect()
+}
+
+/// Parse the number of a boot option, such as `Boot0003`.
+pub(crate) fn parse_number(name: &str) -> Option<u16> {
+    let number = name.strip_prefix("Boot")?;
+    if number.len() != 4 {
+        return None;
+    }
+    u16::from_str_radix(number, 16).ok()
+}
+
+/// Find a boot option by its number (`Boot0003`) or its description.
+fn find(name: &str) -> Option<u16> {
+    parse_number(name).or_else(|| boot_options().into_iter().find_map(
+        |(number, description)| (description == name).then_some(number)
+    ))
+}
+
+/// Boot the given boot option once.
+///
+/// This only returns if setting `BootNext` fails.
+pub(crate) fn boot_next(number: u16) -> Status {
+    info!("booting Boot{number:04X} next...");
+    if let Err(e) = set_variable(
+        cstr16!("BootNext"), &VariableVendor::GLOBAL_VARIABLE,
+        VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS,
+        &number.to_le_bytes(),
+    ) {
+        error!("failed to set BootNext: {e:?}");
+        return e.status();
+    }
+    reset(ResetType::COLD, Status::SUCCESS, None)
+}
+
+/// Boot the boot option given in the entry once.
+///
+/// This only returns on failure.
+pub(crate) fn start(entry: &Entry) -> Status {
+    let Some(name) = &entry.boot_option else {
+        error!("the entry doesn't say which boot option to boot");
+        return Status::INVALID_PARAMETER;
+    };
+    match find(name) {
+        Some(number) => boot_next(number),
+        None => {
+            error!("there is no boot option {name}");
+            Status::NOT_FOUND
+        },
+    }
+}
@@ -0,0 +1,60 @@
+//! This module decides whether entries should be shown.
+//!
+//! Entries can have a `show_if` condition that is evaluated at boot,
+//! so that a configuration can be shared by different machines.
+use uefi::prelude::*;
+use uefi::cstr16;
+use uefi::runtime::{VariableVendor, get_variable_boxed};
+
+use log::{debug, warn};
+
+use towboot_config::Config;
+
+use super::file::exists;
+
+/// The architecture of the firmware, as used in conditions
+#[cfg(target_arch = "x86")]
+const ARCH: &str = "x86";
+#[cfg(target_arch = "x86_64")]
+const ARCH: &str = "x86_64";
+#[cfg(target_arch = "aarch64")]
+const ARCH: &str = "aarch64";
+#[cfg(target_arch = "riscv64")]
+const ARCH: &str = "riscv64";
+
+/// All architectures that can appear in conditions
+const ARCHES: [&str; 4] = ["x86", "x86_64", "aarch64", "riscv64"];
+
+/// Check whether Secure Boot is enabled.
+fn secure_boot() -> bool {
+    get_variable_boxed(cstr16!("SecureBoot"), &VariableVendor::GLOBAL_VARIABLE)
+        .is_ok_and(|(data, _)| data.first() == Some(&1))
+}
+
+/// Evaluate a condition.
+///
+/// Unknown conditions are treated as true, so that no entry disappears by accident.
+fn holds(condition: &str, image_fs_handle: Handle) -> bool {
+    match condition {
+        "secureboot_on" => secure_boot(),
+        "secureboot_off" => !secure_boot(),
+        arch if ARCHES.contains(&arch) => arch == ARCH,
+        _ => if let Some(path) = condition.strip_prefix("file:") {
+            exists(path, image_fs_handle)
+        } else {
+            warn!("unknown condition '{condition}', ignoring it");
+            true
+        },
+    }
+}
+
+/// Remove the entries whose condition doesn't hold.
+pub(crate) fn filter_entries(config: &mut Config, image_fs_handle: Handle) {
+    config.entries.retain(|key, entry| match entry.show_if.as_deref() {
+        Some(condition) if !holds(condition, image_fs_handle) => {
+            debug!("hiding {key}, as '{condition}' doesn't hold");
+            false
+        },
+        _ => true,
+    });
+}
@@ -9,11 +9,12 @@ use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 
+use log::{debug, error, info};
 use uefi::prelude::*;
 
-use towboot_config::{Config, ConfigSource, parse_load_options};
+use towboot_config::{CONFIG_FILE, Config, ConfigSource, parse_load_options};
 
-use super::file::File;
+use super::file::{File, exists};
 
 /// Generate the output for `-version`.
 fn version_info() -> String {
@@ -41,22 +42,49 @@ fn version_info() -> String {
 /// If we were called with command line options, try them first.
 /// Otherwise, read and parse a configuration file.
 ///
+/// If no configuration file is given, the search path is tried,
+/// followed by the directory towboot has been loaded from (`image_dir`).
+///
 /// Returns None if just a help text has been displayed.
 pub fn get(
-    image_fs_handle: Handle, load_options: &str,
+    image_fs_handle: Handle, load_options: &str, image_dir: Option<&str>,
 ) -> Result<Option<Config>, Status> {
     match parse_load_options(load_options, &version_info()) {
         Ok(Some(ConfigSource::File(s))) => Ok(Some(read_file(image_fs_handle, &s)?)),
+        Ok(Some(ConfigSource::Search(mut paths))) => {
+            if let Some(image_dir) = image_dir {
+                paths.push(format!("{}\\{CONFIG_FILE}", image_dir.trim_end_matches('\\')));
+            }
+            let path = search(image_fs_handle, &paths)?;
+            Ok(Some(read_file(image_fs_handle, &path)?))
+        },
         Ok(Some(ConfigSource::Given(c))) => Ok(Some(c)),
         Ok(None) => Ok(None),
         Err(()) => Err(Status::INVALID_PARAMETER),
     }
 }
 
+/// Find the first of the given files that exists.
+fn search(image_fs_handle: Handle, paths: &[String]) -> Result<String, Status> {
+    for path in paths {
+        if exists(path, image_fs_handle) {
+            info!("using the configuration file at {path}");
+            return Ok(path.clone());
+        }
+        debug!("there is no configuration file at {path}");
+    }
+    error!("failed to find a configuration file, tried {}", paths.join(", "));
+    Err(Status::NOT_FOUND)
+}
+
 /// Try to read and parse the configuration from the given file.
 fn read_file(image_fs_handle: Handle, file_name: &str) -> Result<Config, Status> {
     let text: Vec<u8> = File::open(file_name, image_fs_handle)?.try_into()?;
-    let mut config: Config = toml::from_slice(text.as_slice()).expect("failed to parse config file");
+    let mut config: Config = toml::from_slice(text.as_slice()).map_err(|e| {
+        error!("failed to parse {file_name}: {e}");
+        Status::INVALID_PARAMETER
+    })?;
     config.src = file_name.to_string();
+    config.apply_renamed_keys();
     Ok(config)
 }
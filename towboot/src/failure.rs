@@ -0,0 +1,47 @@
+//! This module decides what happens if an entry fails to boot.
+//!
+//! See `on_failure` and `fallback` in the configuration.
+use core::ptr;
+
+use uefi::Status;
+use uefi::boot::stall;
+
+use log::{error, info, warn};
+
+use towboot_config::{Config, Entry};
+
+/// Find the next entry to try, skipping the ones that have failed already.
+///
+/// These are the ones in `fallback` (in that order) or the ones after the last
+/// failed entry (wrapping around).
+pub(crate) fn next_entry<'a>(config: &'a Config, failed: &[&Entry]) -> Option<&'a Entry> {
+    let has_failed = |entry: &Entry| failed.iter().any(|f| ptr::eq(*f, entry));
+    let next = if config.fallback.is_empty() {
+        let last = failed.last()?;
+        let position = config.entries.values().position(|entry| ptr::eq(*last, entry))?;
+        config.entries.values().cycle().skip(position + 1).take(config.entries.len())
+            .find(|entry| !has_failed(entry))
+    } else {
+        config.fallback.iter().filter_map(|key| {
+            let entry = config.entries.get(key);
+            if entry.is_none() {
+                warn!("the fallback entry {key} does not exist");
+            }
+            entry
+        }).find(|entry| !has_failed(entry))
+    };
+    if let Some(entry) = next {
+        info!("trying {entry} instead");
+    }
+    next
+}
+
+/// Stop here, so that the error stays visible.
+///
+/// (The firmware's watchdog might still reset the machine; set `watchdog = 0` to prevent this.)
+pub(crate) fn halt(status: Status) -> ! {
+    error!("booting failed with {status:?}, halting");
+    loop {
+        stall(1_000_000);
+    }
+}
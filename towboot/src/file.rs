@@ -1,29 +1,218 @@
 //! File handling
+//!
+//! Files are usually read from a volume. If towboot has been loaded via PXE,
+//! the files on the volume it's been loaded from are downloaded via TFTP
+//! from the server it's been loaded from instead.
 
 use alloc::borrow::ToOwned;
 use alloc::collections::btree_set::BTreeSet;
 use alloc::format;
 use alloc::{vec::Vec, vec};
-use alloc::string::ToString;
+use alloc::string::{String, ToString};
 
-use log::{info, error};
+use log::{debug, info, error};
 
 use uefi::prelude::*;
-use uefi::boot::{find_handles, open_protocol_exclusive};
-use uefi::fs::{Path, PathBuf};
-use uefi::data_types::CString16;
+use uefi::boot::{
+    OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol, find_handles, image_handle,
+    open_protocol, open_protocol_exclusive,
+};
+use uefi::fs::{FileSystem, Path, PathBuf};
+use uefi::data_types::{CStr8, CString16};
 use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::proto::network::IpAddress;
+use uefi::proto::network::pxe::{BaseCode, DhcpV4Packet};
 use uefi::proto::media::file::{
     File as UefiFile, FileAttribute, FileInfo, FileMode, FileType, RegularFile
 };
 
-use towboot_config::Quirk;
+use towboot_config::{Module, Quirk};
 use super::mem::Allocation;
 
+/// Find the volume a path is on.
+///
+/// Paths starting with `fs?:` are on the given volume, all others are on the
+/// volume we're loaded from (`image_fs_handle`).
+/// This returns the handle of the volume and the path on it.
+fn resolve(name: &str, image_fs_handle: Handle) -> Result<(Handle, CString16), Status> {
+    let file_name = CString16::try_from(name)
+        .map_err(|e| {
+            error!("filename is invalid because of {e:?}");
+            Status::PROTOCOL_ERROR
+        })?;
+    let file_path = Path::new(&file_name);
+    let mut file_path_components = file_path.components();
+    if let Some(root) = file_path_components.next() && root.to_string().ends_with(':') {
+        if let Some(idx) = root
+            .to_string()
+            .to_lowercase()
+            .strip_suffix(':')
+            .unwrap()
+            .strip_prefix("fs") {
+            let filesystems = find_handles::<SimpleFileSystem>()
+                .map_err(|e| e.status())?;
+            let fs = filesystems.into_iter().nth(
+                idx.parse::<usize>().map_err(|_| {
+                    error!("{idx} is not a number");
+                    Status::INVALID_PARAMETER
+                })?
+            ).ok_or(Status::NOT_FOUND)?;
+            let mut file_path = PathBuf::new();
+            for c in file_path_components {
+                file_path.push(c.as_ref());
+            }
+            Ok((fs, file_path.to_cstr16().to_owned()))
+        } else {
+            error!("don't know how to open {root}");
+            Err(Status::INVALID_PARAMETER)
+        }
+    } else {
+        Ok((image_fs_handle, file_name))
+    }
+}
+
+/// Find the volume a file is on.
+pub(crate) fn volume(name: &str, image_fs_handle: Handle) -> Option<Handle> {
+    resolve(name, image_fs_handle).ok().map(|(handle, _)| handle)
+}
+
+/// Open the PXE Base Code protocol, if the handle is a network device.
+fn base_code(handle: Handle) -> Option<ScopedProtocol<BaseCode>> {
+    // Opening the protocol exclusively would disconnect the network stack.
+    unsafe { open_protocol::<BaseCode>(
+        OpenProtocolParams {
+            handle,
+            agent: image_handle(),
+            controller: None,
+        },
+        OpenProtocolAttributes::GetProtocol,
+    ).ok() }
+}
+
+/// Get the DHCP acknowledgement, if towboot has been loaded via PXE.
+///
+/// This is the whole packet, as the firmware has cached it.
+pub(crate) fn dhcp_ack(image_fs_handle: Handle) -> Option<Vec<u8>> {
+    let base_code = base_code(image_fs_handle)?;
+    let mode = base_code.mode();
+    if !mode.started || !mode.dhcp_ack_received || mode.using_ipv6 {
+        debug!("there is no DHCPv4 acknowledgement to pass on");
+        return None;
+    }
+    let packet = &mode.dhcp_ack;
+    Some(unsafe { core::slice::from_raw_parts(
+        (packet as *const _).cast::<u8>(), core::mem::size_of_val(packet),
+    ) }.to_vec())
+}
+
+/// The TFTP server towboot has been loaded from.
+struct Tftp {
+    base_code: ScopedProtocol<BaseCode>,
+    server: IpAddress,
+}
+
+impl Tftp {
+    /// Find the TFTP server, if the handle is a network device we've been booted from.
+    fn new(handle: Handle) -> Option<Self> {
+        let base_code = base_code(handle)?;
+        let mode = base_code.mode();
+        if !mode.started || !mode.dhcp_ack_received || mode.using_ipv6 {
+            error!("only PXE boot via IPv4 is supported");
+            return None;
+        }
+        let dhcp_ack: &DhcpV4Packet = mode.dhcp_ack.as_ref();
+        let server = IpAddress::new_v4(dhcp_ack.bootp_si_addr);
+        Some(Self { base_code, server })
+    }
+
+    /// Convert a path on the volume to one on the TFTP server.
+    ///
+    /// This is relative to the root of the server and uses forward slashes.
+    fn path(name: &CString16) -> Result<Vec<u8>, Status> {
+        let mut path = name.to_string()
+            .trim_start_matches('\\')
+            .replace('\\', "/")
+            .into_bytes();
+        if !path.is_ascii() || path.contains(&0) {
+            error!("'{name}' can't be loaded via TFTP");
+            return Err(Status::PROTOCOL_ERROR);
+        }
+        path.push(0);
+        Ok(path)
+    }
+
+    /// Get the size of a file, failing if it doesn't exist.
+    fn size(&mut self, path: &[u8]) -> Result<usize, Status> {
+        let file_name = CStr8::from_bytes_with_nul(path).map_err(|_| Status::PROTOCOL_ERROR)?;
+        self.base_code.tftp_get_file_size(&self.server, file_name)
+            .map(|size| size.try_into().unwrap())
+            .map_err(|e| e.status())
+    }
+
+    /// Download a whole file into the buffer.
+    fn read(&mut self, path: &[u8], buf: &mut [u8]) -> Result<(), Status> {
+        let file_name = CStr8::from_bytes_with_nul(path).map_err(|_| Status::PROTOCOL_ERROR)?;
+        self.base_code.tftp_read_file(&self.server, file_name, Some(buf))
+            .map(|_| ())
+            .map_err(|e| e.status())
+    }
+}
+
+/// Check whether a file exists.
+///
+/// The path can be anything [`File::open`] accepts.
+pub(crate) fn exists(name: &str, image_fs_handle: Handle) -> bool {
+    let Ok((fs_handle, file_name)) = resolve(name, image_fs_handle) else {
+        return false;
+    };
+    match open_protocol_exclusive::<SimpleFileSystem>(fs_handle) {
+        Ok(protocol) => FileSystem::new(protocol)
+            .try_exists(&PathBuf::from(file_name))
+            .unwrap_or(false),
+        Err(_) => Tftp::new(fs_handle).is_some_and(|mut tftp| {
+            Tftp::path(&file_name).and_then(|path| tftp.size(&path)).is_ok()
+        }),
+    }
+}
+
+/// List the names of the files in a directory (without subdirectories), sorted by name.
+///
+/// The path can be anything [`File::open`] accepts, but directories can't be
+/// listed via TFTP.
+pub(crate) fn list_directory(name: &str, image_fs_handle: Handle) -> Result<Vec<String>, Status> {
+    let (fs_handle, path) = resolve(name, image_fs_handle)?;
+    let mut fs = FileSystem::new(
+        open_protocol_exclusive::<SimpleFileSystem>(fs_handle).map_err(|e| {
+            error!("can't list the directory '{name}': {e:?}");
+            e.status()
+        })?
+    );
+    let mut names = fs.read_dir(&PathBuf::from(path))
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            error!("failed to list the directory '{name}': {e:?}");
+            Status::NOT_FOUND
+        })?
+        .into_iter()
+        .filter(|info| !info.is_directory())
+        .map(|info| info.file_name().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
+
+/// Where the contents of a file come from.
+enum Source {
+    /// a file on a volume
+    Volume(RegularFile),
+    /// a file on the TFTP server, with its contents once they have been downloaded
+    Tftp(Tftp, Vec<u8>, Option<Vec<u8>>),
+}
+
 /// An opened file.
 pub(crate) struct File<'a> {
     name: &'a str,
-    file: RegularFile,
+    source: Source,
     size: usize,
 }
 
@@ -32,6 +221,7 @@ impl<'a> File<'a> {
     ///
     /// The path can be:
     /// * relative to the volume we're loaded from
+    ///   (or to the TFTP server, if we've been loaded via PXE)
     /// * on a different volume (if it starts with `fs?:`)
     ///
     /// Possible errors:
@@ -41,44 +231,14 @@ impl<'a> File<'a> {
     /// * `Status::UNSUPPORTED`: the given path does exist, but it's a directory
     pub(crate) fn open(name: &'a str, image_fs_handle: Handle) -> Result<Self, Status> {
         info!("loading file '{name}'...");
-        let file_name = CString16::try_from(name)
-            .map_err(|e| {
-                error!("filename is invalid because of {e:?}");
-                Status::PROTOCOL_ERROR
-            })?;
-        let file_path = Path::new(&file_name);
-        let mut file_path_components = file_path.components();
-        let (
-            fs_handle, file_name,
-        ) = if let Some(root) = file_path_components.next() && root.to_string().ends_with(':') {
-            if let Some(idx) = root
-                .to_string()
-                .to_lowercase()
-                .strip_suffix(':')
-                .unwrap()
-                .strip_prefix("fs") {
-                let filesystems = find_handles::<SimpleFileSystem>()
-                    .map_err(|e| e.status())?;
-                let fs = filesystems.into_iter().nth(
-                    idx.parse::<usize>().map_err(|_| {
-                        error!("{idx} is not a number");
-                        Status::INVALID_PARAMETER
-                    })?
-                ).ok_or(Status::NOT_FOUND)?;
-                let mut file_path = PathBuf::new();
-                for c in file_path_components {
-                    file_path.push(c.as_ref());
-                }
-                Ok((fs, file_path.to_cstr16().to_owned()))
-            } else {
-                error!("don't know how to open {root}");
-                Err(Status::INVALID_PARAMETER)
-            }?
-        } else {
-            (image_fs_handle, file_name)
+        let (fs_handle, file_name) = resolve(name, image_fs_handle)?;
+        let mut fs = match open_protocol_exclusive::<SimpleFileSystem>(fs_handle) {
+            Ok(fs) => fs,
+            Err(e) => return match Tftp::new(fs_handle) {
+                Some(tftp) => Self::download(name, &file_name, tftp),
+                None => Err(e.status()),
+            },
         };
-        let mut fs = open_protocol_exclusive::<SimpleFileSystem>(fs_handle)
-            .map_err(|e| e.status())?;
         let file_handle = match fs.open_volume().map_err(|e| e.status())?.open(
             &file_name,
             FileMode::Read,
@@ -111,29 +271,104 @@ impl<'a> File<'a> {
         let size: usize = file.get_info::<FileInfo>(info_vec.as_mut_slice())
         .expect(&format!("Failed to get metadata of file '{name}'"))
         .file_size().try_into().unwrap();
-        Ok(Self { name, file, size })
+        Ok(Self { name, source: Source::Volume(file), size })
     }
-    
-    /// Read a whole file into memory and return the resulting allocation.
+
+    /// Opens a file on the TFTP server.
     ///
-    /// (The difference to `TryInto<Vec<u8>>` is that the allocated memory
-    /// is page-aligned and under 4GB.)
-    pub(crate) fn try_into_allocation(
-        mut self, quirks: &BTreeSet<Quirk>,
-    ) -> Result<Allocation, Status> {
-        let mut allocation = Allocation::new_under_4gb(self.size, quirks)?;
-        let read_size = self.file.read(allocation.as_mut_slice())
+    /// This only gets the size; the contents are downloaded when they're read.
+    fn download(name: &'a str, file_name: &CString16, mut tftp: Tftp) -> Result<Self, Status> {
+        let path = Tftp::path(file_name)?;
+        let size = tftp.size(&path).map_err(|e| {
+            error!("Failed to find file '{name}' on the TFTP server: {e:?}");
+            Status::NOT_FOUND
+        })?;
+        Ok(Self { name, source: Source::Tftp(tftp, path, None), size })
+    }
+    
+    /// Get the size of the file in bytes.
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+    
+    /// Fill the buffer with the contents of the file, starting at the given offset.
+    pub(crate) fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Status> {
+        let file = match &mut self.source {
+            Source::Volume(file) => file,
+            Source::Tftp(tftp, path, content) => return Self::read_downloaded(
+                self.name, self.size, tftp, path, content, offset, buf,
+            ),
+        };
+        file.set_position(offset.try_into().unwrap()).map_err(|e| {
+            error!("Failed to seek in file '{}': {:?}", self.name, e);
+            e.status()
+        })?;
+        let read_size = file.read(buf)
         .map_err(|e| {
             error!("Failed to read from file '{}': {:?}", self.name, e);
             e.status()
         })?;
-        if read_size == self.size {
-            Ok(allocation)
+        if read_size == buf.len() {
+            Ok(())
         } else {
             error!("Failed to fully read from file '{}", self.name);
             Err(Status::END_OF_FILE)
         }
     }
+    
+    /// Fill the buffer from a file on the TFTP server.
+    ///
+    /// TFTP can only download whole files, so reading all of it goes straight
+    /// into the buffer; everything else downloads the file once and keeps it.
+    fn read_downloaded(
+        name: &str, size: usize, tftp: &mut Tftp, path: &[u8], content: &mut Option<Vec<u8>>,
+        offset: usize, buf: &mut [u8],
+    ) -> Result<(), Status> {
+        if content.is_none() && offset == 0 && buf.len() == size {
+            return tftp.read(path, buf).map_err(|e| {
+                error!("Failed to download file '{name}': {e:?}");
+                e
+            });
+        }
+        if content.is_none() {
+            let mut downloaded = vec![0; size];
+            tftp.read(path, &mut downloaded).map_err(|e| {
+                error!("Failed to download file '{name}': {e:?}");
+                e
+            })?;
+            *content = Some(downloaded);
+        }
+        match content.as_deref().and_then(|c| c.get(offset..offset.checked_add(buf.len())?)) {
+            Some(part) => {
+                buf.copy_from_slice(part);
+                Ok(())
+            },
+            None => {
+                error!("Failed to fully read from file '{name}");
+                Err(Status::END_OF_FILE)
+            },
+        }
+    }
+
+    /// Read (at most) the first `len` bytes of the file.
+    pub(crate) fn read_start(&mut self, len: usize) -> Result<Vec<u8>, Status> {
+        let mut start = vec![0; len.min(self.size)];
+        self.read_at(0, &mut start)?;
+        Ok(start)
+    }
+    
+    /// Read a whole module into memory and return the resulting allocation.
+    ///
+    /// (The difference to `TryInto<Vec<u8>>` is that the allocated memory
+    /// is page-aligned and under 4GB. This is also what microcode updates need.)
+    /// The module can ask for a stricter alignment or a lower address.
+    pub(crate) fn try_into_allocation(
+        mut self, module: &Module, quirks: &BTreeSet<Quirk>,
+    ) -> Result<Allocation, Status> {
+        let mut allocation = Allocation::for_module(module, self.size, quirks)?;
+        self.read_at(0, &mut allocation.as_mut_slice()[..self.size])?;
+        Ok(allocation)
+    }
 }
 
 impl TryFrom<File<'_>> for Vec<u8> {
@@ -144,16 +379,7 @@ impl TryFrom<File<'_>> for Vec<u8> {
         // Vec::with_size would allocate enough space, but won't fill it with zeros.
         // file.read seems to need this.
         let mut content_vec = vec![0; file.size];
-        let read_size = file.file.read(content_vec.as_mut_slice())
-        .map_err(|e| {
-            error!("Failed to read from file '{}': {:?}", file.name, e);
-            e.status()
-        })?;
-        if read_size == file.size {
-            Ok(content_vec)
-        } else {
-            error!("Failed to fully read from file '{}", file.name);
-            Err(Status::END_OF_FILE)
-        }
+        file.read_at(0, &mut content_vec)?;
+        Ok(content_vec)
     }
 }
@@ -0,0 +1,55 @@
+//! Translate keys for keyboard layouts other than US.
+//!
+//! Most firmwares report keys as if a US keyboard was attached,
+//! so this maps the characters of the US layout to the ones printed
+//! on the same keys in other layouts.
+//! Dead keys and keys that need AltGr are not supported.
+
+/// A keyboard layout
+pub(crate) struct Keymap(&'static [(char, char)]);
+
+/// Germany (QWERTZ)
+const DE: &[(char, char)] = &[
+    ('y', 'z'), ('z', 'y'), ('Y', 'Z'), ('Z', 'Y'),
+    ('@', '"'), ('#', '§'), ('^', '&'), ('&', '/'), ('*', '('), ('(', ')'), (')', '='),
+    ('-', 'ß'), ('_', '?'),
+    ('[', 'ü'), ('{', 'Ü'), (']', '+'), ('}', '*'),
+    (';', 'ö'), (':', 'Ö'), ('\'', 'ä'), ('"', 'Ä'),
+    ('\\', '#'), ('|', '\''), ('`', '^'), ('~', '°'),
+    ('<', ';'), ('>', ':'), ('/', '-'), ('?', '_'),
+];
+
+/// France (AZERTY)
+const FR: &[(char, char)] = &[
+    ('a', 'q'), ('q', 'a'), ('A', 'Q'), ('Q', 'A'),
+    ('z', 'w'), ('w', 'z'), ('Z', 'W'), ('W', 'Z'),
+    (';', 'm'), (':', 'M'), ('m', ','), ('M', '?'),
+    ('1', '&'), ('2', 'é'), ('3', '"'), ('4', '\''), ('5', '('),
+    ('6', '-'), ('7', 'è'), ('8', '_'), ('9', 'ç'), ('0', 'à'),
+    ('!', '1'), ('@', '2'), ('#', '3'), ('$', '4'), ('%', '5'),
+    ('^', '6'), ('&', '7'), ('*', '8'), ('(', '9'), (')', '0'),
+    ('-', ')'), ('_', '°'), ('\'', 'ù'), ('"', '%'), ('\\', '*'), ('|', 'µ'),
+    (',', ';'), ('<', '.'), ('.', ':'), ('>', '/'), ('/', '!'), ('?', '§'),
+];
+
+impl Keymap {
+    /// the US layout, which doesn't change anything
+    pub(crate) const US: Self = Self(&[]);
+
+    /// Get the layout with the given name (`us`, `de` or `fr`).
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "us" => Some(Self::US),
+            "de" => Some(Self(DE)),
+            "fr" => Some(Self(FR)),
+            _ => None,
+        }
+    }
+
+    /// Translate a character read from the firmware.
+    pub(crate) fn translate(&self, c: char) -> char {
+        self.0.iter()
+            .find(|(from, _)| *from == c)
+            .map_or(c, |(_, to)| *to)
+    }
+}
@@ -0,0 +1,81 @@
+//! This module reads the load options towboot has been started with.
+//!
+//! When started from the UEFI shell, the arguments are taken from the shell,
+//! which already handled its quoting and escaping.
+//! Otherwise, they should be a UCS-2 string, but some firmwares and tools
+//! (such as `efibootmgr` without `-u`) store them as 8-bit strings, so these
+//! are accepted, too.
+use alloc::string::{String, ToString};
+
+use uefi::boot::{image_handle, open_protocol_exclusive};
+use uefi::proto::loaded_image::{LoadedImage, LoadOptionsError};
+use uefi::proto::shell_params::ShellParameters;
+
+use log::{debug, warn};
+
+/// Decode load options that are an ASCII (or UTF-8) string.
+///
+/// A UCS-2 string of ASCII characters has every other byte set to zero,
+/// so load options without any zero bytes (except for the terminator)
+/// can't be UCS-2.
+fn decode_ascii(bytes: &[u8]) -> Option<&str> {
+    let bytes = bytes.strip_suffix(b"\0").unwrap_or(bytes);
+    if bytes.is_empty() || bytes.contains(&0) {
+        return None;
+    }
+    let options = core::str::from_utf8(bytes).ok()?;
+    options.chars().all(|c| !c.is_control() || c.is_whitespace()).then_some(options)
+}
+
+/// Get the arguments from the UEFI shell, if we have been started from there.
+///
+/// They are joined (and quoted, if needed) to look like regular load options.
+fn from_shell() -> Option<String> {
+    let parameters = open_protocol_exclusive::<ShellParameters>(image_handle()).ok()?;
+    let mut options = String::new();
+    for (index, arg) in parameters.args().enumerate() {
+        let arg = arg.to_string();
+        if index > 0 {
+            options.push(' ');
+        }
+        if arg.contains('"') {
+            warn!("quotes inside arguments are not supported: {arg}");
+        }
+        if arg.contains(' ') {
+            options.push('"');
+            options.push_str(&arg);
+            options.push('"');
+        } else {
+            options.push_str(&arg);
+        }
+    }
+    debug!("got arguments from the shell: {options}");
+    Some(options)
+}
+
+/// Get the load options as a string.
+pub(crate) fn get(loaded_image: &LoadedImage) -> Option<String> {
+    if let Some(options) = from_shell() {
+        return Some(options);
+    }
+    if let Some(options) = loaded_image.load_options_as_bytes().and_then(decode_ascii) {
+        warn!("the load options are not UCS-2, treating them as ASCII");
+        debug!("got load options: {options}");
+        return Some(options.to_string());
+    }
+    match loaded_image.load_options_as_cstr16() {
+        Ok(s) => {
+            debug!("got load options: {s:}");
+            Some(s.to_string())
+        }
+        Err(LoadOptionsError::NotSet) => {
+            debug!("got no load options");
+            None
+        }
+        Err(e) => {
+            warn!("failed to get load options: {e:?}");
+            warn!("assuming there were none");
+            None
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! This module finds out where towboot has been loaded from.
+//!
+//! On machines with many disks, it's not always obvious which disk (and which
+//! partition) towboot and its configuration are on.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::boot::{
+    OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol, image_handle, open_protocol,
+};
+use uefi::proto::ProtocolPointer;
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::device_path::text::{AllowShortcuts, DisplayOnly};
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::media::partition::PartitionInfo;
+use uefi::system::{firmware_revision, firmware_vendor, uefi_revision};
+
+/// Open a protocol without taking it away from anyone else.
+fn open<P: ProtocolPointer + ?Sized>(handle: Handle) -> Option<ScopedProtocol<P>> {
+    // Opening a protocol non-exclusively is unsafe, but opening it exclusively
+    // would disconnect the drivers (or fail, as we already have it open).
+    unsafe { open_protocol::<P>(
+        OpenProtocolParams {
+            handle,
+            agent: image_handle(),
+            controller: None,
+        },
+        OpenProtocolAttributes::GetProtocol,
+    ).ok() }
+}
+
+/// Describe a partition.
+fn describe_partition(info: &PartitionInfo) -> Option<String> {
+    if let Some(entry) = info.gpt_partition_entry() {
+        // copy the fields, as the struct might not be aligned
+        let (start, end) = (entry.starting_lba, entry.ending_lba);
+        let (guid, type_guid) = (entry.unique_partition_guid, entry.partition_type_guid.0);
+        let name: String = char::decode_utf16(
+            entry.partition_name.iter().map(|c| u16::from(*c)).take_while(|c| *c != 0)
+        ).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect();
+        Some(format!(
+            "GPT partition '{name}' {guid} (type {type_guid}, LBA {start} to {end})",
+        ))
+    } else {
+        info.mbr_partition_record().map(|record| format!("MBR partition {record:?}"))
+    }
+}
+
+/// Describe the device and the partition towboot has been loaded from.
+pub(crate) fn describe() -> Vec<String> {
+    let mut lines = Vec::new();
+    let Some(device) = open::<LoadedImage>(image_handle()).and_then(|image| image.device()) else {
+        lines.push(String::from("loaded from an unknown device"));
+        return lines;
+    };
+    let path = open::<DevicePath>(device).and_then(
+        |path| path.to_string(DisplayOnly(false), AllowShortcuts(false)).ok()
+    );
+    match path {
+        Some(path) => lines.push(format!("loaded from {path}")),
+        None => lines.push(String::from("loaded from a device without a device path")),
+    }
+    if let Some(partition) = open::<PartitionInfo>(device)
+        .and_then(|info| describe_partition(&info))
+    {
+        lines.push(format!("on {partition}"));
+    }
+    lines
+}
+
+/// Describe the firmware and where towboot has been loaded from.
+pub(crate) fn system_information() -> Vec<String> {
+    let mut lines = Vec::new();
+    lines.push(format!(
+        "firmware: {} (revision {:#x}), UEFI {}",
+        firmware_vendor(), firmware_revision(), uefi_revision(),
+    ));
+    lines.push(format!("towboot {}", env!("CARGO_PKG_VERSION")));
+    lines.extend(describe());
+    lines
+}
@@ -9,20 +9,38 @@ extern crate alloc;
 
 use core::str::FromStr;
 use alloc::string::ToString;
+use alloc::vec::Vec;
 
 use uefi::prelude::*;
-use uefi::boot::{image_handle, open_protocol_exclusive};
+use uefi::boot::{image_handle, open_protocol_exclusive, set_watchdog_timer};
 use uefi::fs::PathBuf;
 use uefi::data_types::CString16;
-use uefi::proto::loaded_image::{LoadedImage, LoadOptionsError};
+use uefi::proto::device_path::text::{AllowShortcuts, DisplayOnly};
+use uefi::proto::loaded_image::LoadedImage;
 
 use log::{debug, info, warn, error};
 
+use towboot_config::{Entry, EntryKind, OnFailure};
+
+mod archive;
 mod boot;
+mod boot_next;
+mod condition;
 mod config;
+mod failure;
 mod file;
+mod keymap;
+mod load_options;
+mod location;
 mod mem;
 mod menu;
+mod panic;
+mod shell;
+
+/// This allows towbootctl to find out which version a binary is.
+/// (The format needs to stay in sync with `towbootctl::VERSION_MARKER`.)
+#[used]
+static VERSION_MARKER: &str = concat!("towboot-version: ", env!("CARGO_PKG_VERSION"), "\0");
 
 #[entry]
 /// This is the main function. Startup happens here.
@@ -36,27 +54,20 @@ fn main() -> Status {
         .expect("Failed to open loaded image protocol");
 
     // get the load options
-    let load_options = match loaded_image.load_options_as_cstr16() {
-        Ok(s) => {
-            debug!("got load options: {s:}");
-            Some(s.to_string())
-        }
-        Err(LoadOptionsError::NotSet) => {
-            debug!("got no load options");
-            None
-        }
-        Err(e) => {
-            warn!("failed to get load options: {e:?}");
-            warn!("assuming there were none");
-            None
-        }
-    };
+    let load_options = load_options::get(&loaded_image);
 
     // get the filesystem
     let image_fs_handle = loaded_image.device().expect("the image to be loaded from a device");
 
+    // get the directory we were loaded from
+    let image_dir = loaded_image.file_path()
+        .and_then(|p| p.to_string(DisplayOnly(false), AllowShortcuts(false)).ok())
+        .and_then(|p| PathBuf::from(p).parent())
+        .map(|p| p.to_string());
+    debug!("we were loaded from {image_dir:?}");
+
     let mut config = match config::get(
-        image_fs_handle, load_options.as_deref().unwrap_or_default(),
+        image_fs_handle, load_options.as_deref().unwrap_or_default(), image_dir.as_deref(),
     ) {
         Ok(Some(c)) => c,
         Ok(None) => return Status::SUCCESS,
@@ -72,6 +83,18 @@ fn main() -> Status {
             warn!("'{level}' is not a valid log level, using default");
         }
     }
+    // (This is logged only now, so that the log level from the configuration applies.)
+    for line in location::describe() {
+        debug!("{line}");
+    }
+    if let Some(watchdog) = config.watchdog {
+        // codes below 0x10000 are reserved for the firmware
+        match set_watchdog_timer(watchdog as usize, 0x10000, None) {
+            Ok(()) if watchdog == 0 => debug!("disabled the watchdog"),
+            Ok(()) => debug!("set the watchdog to {watchdog} seconds"),
+            Err(e) => warn!("failed to set the watchdog: {e:?}"),
+        }
+    }
     // resolve paths relative to the config file itself
     if let Some(config_parent) = PathBuf::from(
         CString16::try_from(config.src.as_str())
@@ -88,20 +111,68 @@ fn main() -> Status {
             *path = buf.to_string();
         }
     }
+    config.microcode_first();
+    condition::filter_entries(&mut config, image_fs_handle);
+    if config.entries.is_empty() {
+        error!("there are no entries to show on this machine");
+        return Status::NOT_FOUND;
+    }
+    // the image is optional for some kinds of entries, so check that it's there
+    if let Err(e) = config.validate_entries() {
+        error!("{} is invalid: {e}", config.src);
+        return Status::INVALID_PARAMETER;
+    }
     debug!("config: {config:?}");
-    let entry_to_boot = menu::choose(&config);
+    let mut entry_to_boot = menu::choose(&config);
+    let mut failed = Vec::new();
+    loop {
+        let status = start(entry_to_boot, image_fs_handle);
+        if status.is_success() {
+            return status;
+        }
+        failed.push(entry_to_boot);
+        entry_to_boot = match config.on_failure {
+            None => return status, // give up
+            Some(OnFailure::Halt) => failure::halt(status),
+            Some(OnFailure::Menu) => menu::choose_again(&config),
+            Some(OnFailure::Next) => match failure::next_entry(&config, &failed) {
+                Some(entry) => entry,
+                None => {
+                    error!("there are no more entries to try");
+                    return status;
+                },
+            },
+        };
+    }
+}
+
+/// Start an entry.
+///
+/// This only returns if it failed (or if it was the UEFI shell that exited).
+fn start(entry_to_boot: &Entry, image_fs_handle: Handle) -> Status {
     debug!("okay, trying to load {entry_to_boot:?}");
-    info!("loading {entry_to_boot}...");
+    // (`towbootctl bench` looks for this and the message before booting.)
+    info!("loading entry '{entry_to_boot}'...");
     
+    match entry_to_boot.kind {
+        EntryKind::Multiboot => (),
+        EntryKind::UefiShell => return match shell::start(entry_to_boot, image_fs_handle) {
+            Ok(()) => Status::SUCCESS,
+            Err(e) => {
+                error!("failed to start the shell: {e:?}");
+                e
+            },
+        },
+        EntryKind::BootNext => return boot_next::start(entry_to_boot),
+    }
     match boot::PreparedEntry::new(entry_to_boot, image_fs_handle) {
         Ok(e) => {
-            info!("booting {entry_to_boot}...");
+            info!("booting entry '{entry_to_boot}'...");
             e.boot();
         },
         Err(e) => {
             error!("failed to prepare the entry: {e:?}");
-            e // give up
-            // TODO: perhaps redisplay the menu or something like that
+            e
         },
     }
 }
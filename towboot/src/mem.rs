@@ -20,7 +20,7 @@ use uefi::mem::memory_map::{MemoryDescriptor, MemoryMap, MemoryMapMut, MemoryTyp
 
 use log::{debug, warn, error};
 
-use towboot_config::Quirk;
+use towboot_config::{Module, Quirk};
 
 // no multiboot import here as some of the types have the same name as the UEFI ones
 
@@ -59,21 +59,34 @@ impl Allocation {
     /// [`move_to_where_it_should_be`], but please keep its safety implications in mind.
     ///
     /// [`move_to_where_it_should_be`]: struct.Allocation.html#method.move_to_where_it_should_be
-    pub(crate) fn new_at(address: usize, size: usize) -> Result<Self, Status>{
+    ///
+    /// The address may be above 4 GiB (on 64-bit firmware).
+    pub(crate) fn new_at(address: u64, size: usize) -> Result<Self, Status>{
         let count_pages = Self::calculate_page_count(size);
+        let Some(end) = address.checked_add(size as u64) else {
+            error!("{address:#x} + {size:#x} is beyond the address space");
+            return Err(Status::INVALID_PARAMETER);
+        };
+        if usize::try_from(end).is_err() {
+            error!("{address:#x} is not addressable by this firmware");
+            return Err(Status::UNSUPPORTED);
+        }
         match allocate_pages(
-            AllocateType::Address(address.try_into().unwrap()),
+            AllocateType::Address(address),
             MemoryType::LOADER_DATA,
             count_pages
         ) {
             Ok(ptr) => Ok(Allocation { ptr, len: size, pages: count_pages, should_be_at: None }),
             Err(e) => {
                 warn!("failed to allocate {size} bytes of memory at {address:x}: {e:?}");
+                if !is_in_memory_map(address, size) {
+                    warn!("this machine doesn't have memory at {address:#x}");
+                }
                 dump_memory_map();
                 warn!("going to allocate it somewhere else and try to move it later");
                 warn!("this might fail without notice");
                 Self::new_under_4gb(size, &BTreeSet::default()).map(|mut allocation| {
-                    allocation.should_be_at = Some(address.try_into().unwrap());
+                    allocation.should_be_at = Some(address);
                     allocation
                 })
             }
@@ -84,22 +97,83 @@ impl Allocation {
     ///
     /// Note: This will round up to whole pages.
     pub(crate) fn new_under_4gb(size: usize, quirks: &BTreeSet<Quirk>) -> Result<Self, Status> {
+        Self::new_under(size, Self::max_address(quirks), PAGE_SIZE)
+            .map(|allocation| allocation.zeroize_if_wanted(quirks))
+    }
+
+    /// Fill the memory with zeroes if the `ZeroizeBeforeLoad` quirk is set.
+    pub(crate) fn zeroize_if_wanted(mut self, quirks: &BTreeSet<Quirk>) -> Self {
+        if quirks.contains(&Quirk::ZeroizeBeforeLoad) {
+            self.as_mut_slice().fill(0);
+        }
+        self
+    }
+
+    /// Get the highest address allocations below 4GB may use.
+    pub(crate) fn max_address(quirks: &BTreeSet<Quirk>) -> u64 {
+        if quirks.contains(&Quirk::ModulesBelow200Mb) {
+            200 * 1024 * 1024
+        } else {
+            u32::MAX.into()
+        }
+    }
+
+    /// Allocate memory for a module, honoring its alignment and maximum address.
+    ///
+    /// Note: This will round up to whole pages.
+    pub(crate) fn for_module(
+        module: &Module, size: usize, quirks: &BTreeSet<Quirk>,
+    ) -> Result<Self, Status> {
+        let max_address = module.max_address.map_or(
+            Self::max_address(quirks),
+            |max_address| max_address.min(Self::max_address(quirks)),
+        );
+        let align = match module.align.map(usize::try_from) {
+            Some(Ok(align)) if align.is_power_of_two() => align,
+            Some(_) => {
+                error!("the alignment of {} has to be a power of two", module.image);
+                return Err(Status::INVALID_PARAMETER);
+            },
+            None => PAGE_SIZE,
+        };
+        Self::new_under(size, max_address, align)
+            .map(|allocation| allocation.zeroize_if_wanted(quirks))
+    }
+
+    /// Allocate memory below the given address with the given alignment.
+    ///
+    /// The alignment has to be a power of two; everything up to a page is always satisfied.
+    ///
+    /// Note: This will round up to whole pages.
+    pub(crate) fn new_under(size: usize, max_address: u64, align: usize) -> Result<Self, Status> {
         let count_pages = Self::calculate_page_count(size);
+        // allocate a bit more, so that there is an aligned start, and free the rest later
+        let align = align.max(PAGE_SIZE);
+        let total_pages = count_pages + align / PAGE_SIZE - 1;
         let ptr = allocate_pages(
-                AllocateType::MaxAddress(if quirks.contains(&Quirk::ModulesBelow200Mb) {
-                    200 * 1024 * 1024
-                } else {
-                    u32::MAX.into()
-                }),
+                AllocateType::MaxAddress(max_address),
                 MemoryType::LOADER_DATA,
-                count_pages
+                total_pages
             )
             .map_err(|e| {
-                error!("failed to allocate {size} bytes of memory: {e:?}");
+                error!("failed to allocate {size} bytes of memory below {max_address:x}: {e:?}");
                 dump_memory_map();
                 Status::LOAD_ERROR
             })?;
-        Ok(Allocation { ptr, len:size, pages: count_pages, should_be_at: None })
+        let start = (ptr.as_ptr() as usize).next_multiple_of(align);
+        let head_pages = (start - ptr.as_ptr() as usize) / PAGE_SIZE;
+        let tail_pages = total_pages - head_pages - count_pages;
+        if head_pages > 0 {
+            unsafe { free_pages(ptr, head_pages) }.expect("failed to free the allocated memory");
+        }
+        if tail_pages > 0 {
+            let tail = NonNull::new((start + count_pages * PAGE_SIZE) as *mut u8).unwrap();
+            unsafe { free_pages(tail, tail_pages) }.expect("failed to free the allocated memory");
+        }
+        Ok(Allocation {
+            ptr: NonNull::new(start as *mut u8).unwrap(), len: size, pages: count_pages,
+            should_be_at: None,
+        })
     }
     
     /// Calculate how many pages to allocate for the given amount of bytes.
@@ -147,6 +221,19 @@ impl Allocation {
     }
 }
 
+/// Check whether the firmware's memory map covers the given range.
+fn is_in_memory_map(address: u64, size: usize) -> bool {
+    let Ok(memory_map) = memory_map(MemoryType::LOADER_DATA) else {
+        return false;
+    };
+    let Some(end) = address.checked_add(size as u64) else {
+        return false;
+    };
+    memory_map.entries().any(|descriptor| descriptor.phys_start <= address
+        && descriptor.phys_start.saturating_add(descriptor.page_count * PAGE_SIZE as u64) >= end
+    )
+}
+
 /// Show the current memory map.
 fn dump_memory_map() {
     debug!("memory map:");
@@ -158,6 +245,25 @@ fn dump_memory_map() {
     }
 }
 
+/// Get the size of the largest block of free memory below 4GB.
+///
+/// The Multiboot information (including the module structs) has to fit there.
+/// If there is no memory map, this assumes that all of it is free.
+pub(crate) fn largest_free_block_under_4gb() -> u64 {
+    let limit = u64::from(u32::MAX) + 1;
+    let Ok(map) = memory_map(MemoryType::LOADER_DATA) else {
+        warn!("failed to get the memory map, assuming there is enough free memory");
+        return u32::MAX.into();
+    };
+    map.entries()
+        .filter(|entry| entry.ty == MemoryType::CONVENTIONAL && entry.phys_start < limit)
+        .map(|entry| {
+            let end = entry.phys_start + entry.page_count * PAGE_SIZE as u64;
+            end.min(limit) - entry.phys_start
+        })
+        .max()
+        .unwrap_or_default()
+}
 
 /// Pass the memory map to the kernel.
 ///
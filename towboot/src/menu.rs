@@ -3,34 +3,152 @@ use core::fmt::Write;
 use alloc::collections::btree_map::BTreeMap;
 use alloc::string::String;
 
+use uefi::{CStr16, cstr16, guid};
 use uefi::prelude::*;
 use uefi::boot::{EventType, TimerTrigger, Tpl, create_event, set_timer, wait_for_event};
-use uefi::proto::console::text::{Key, ScanCode};
+use uefi::proto::console::text::{Color as UefiColor, Key};
+use uefi::runtime::{
+    VariableAttributes, VariableVendor, delete_variable, get_variable_boxed, set_variable,
+};
 use uefi::system::{with_stdin, with_stdout};
 
-use log::{error, warn};
+use log::{debug, error, warn};
 
-use towboot_config::{Config, Entry};
+use towboot_config::{Color, ColorPair, Config, Entry, Theme};
+
+use super::{boot_next, location};
+use super::keymap::Keymap;
+
+/// The vendor of our UEFI variables
+const VENDOR: VariableVendor = VariableVendor(guid!("b5f4a7c2-3e0d-4b6a-9c1e-7d2f8a6b5c40"));
+/// The UEFI variable that contains the key of the entry chosen as default
+const DEFAULT_VARIABLE: &CStr16 = cstr16!("TowbootDefault");
+
+/// The firmware's default colors
+const DEFAULT_COLORS: ColorPair = ColorPair {
+    foreground: Color::LightGray, background: Color::Black,
+};
+
+/// Convert a color of the configuration to one of the firmware.
+fn uefi_color(color: Color) -> UefiColor {
+    match color {
+        Color::Black => UefiColor::Black,
+        Color::Blue => UefiColor::Blue,
+        Color::Green => UefiColor::Green,
+        Color::Cyan => UefiColor::Cyan,
+        Color::Red => UefiColor::Red,
+        Color::Magenta => UefiColor::Magenta,
+        Color::Brown => UefiColor::Brown,
+        Color::LightGray => UefiColor::LightGray,
+        Color::DarkGray => UefiColor::DarkGray,
+        Color::LightBlue => UefiColor::LightBlue,
+        Color::LightGreen => UefiColor::LightGreen,
+        Color::LightCyan => UefiColor::LightCyan,
+        Color::LightRed => UefiColor::LightRed,
+        Color::LightMagenta => UefiColor::LightMagenta,
+        Color::Yellow => UefiColor::Yellow,
+        Color::White => UefiColor::White,
+    }
+}
+
+/// Switch to the colors of the theme for a part of the menu.
+///
+/// Parts without colors use the normal ones; without a theme, nothing changes.
+fn set_colors(theme: Option<&Theme>, part: fn(&Theme) -> Option<ColorPair>) {
+    let Some(theme) = theme else {
+        return;
+    };
+    let mut colors = part(theme).or(theme.normal).unwrap_or(DEFAULT_COLORS);
+    // the console only supports dark background colors
+    if colors.background as u8 > Color::LightGray as u8 {
+        warn!("{:?} is not supported as a background color", colors.background);
+        colors.background = DEFAULT_COLORS.background;
+    }
+    if let Err(e) = with_stdout(|stdout| stdout.set_color(
+        uefi_color(colors.foreground), uefi_color(colors.background),
+    )) {
+        warn!("failed to set the colors: {e:?}");
+    }
+}
+
+/// Get the key of the entry that has been chosen as the default in the menu (if any).
+fn saved_default() -> Option<String> {
+    let (data, _) = get_variable_boxed(DEFAULT_VARIABLE, &VENDOR).ok()?;
+    String::from_utf8(data.into_vec()).ok()
+}
+
+/// Remember the key of the entry to boot by default.
+fn save_default(key: &str) -> uefi::Result {
+    set_variable(
+        DEFAULT_VARIABLE, &VENDOR,
+        VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS,
+        key.as_bytes(),
+    )
+}
+
+/// Forget the entry that has been chosen as the default in the menu (if any).
+fn clear_default() {
+    match delete_variable(DEFAULT_VARIABLE, &VENDOR) {
+        Ok(()) => with_stdout(|stdout| writeln!(
+            stdout, "the default entry of the configuration will be used again",
+        )).unwrap(),
+        Err(e) if e.status() == Status::NOT_FOUND => with_stdout(|stdout| writeln!(
+            stdout, "no entry has been made the default",
+        )).unwrap(),
+        Err(e) => error!("failed to clear the default entry: {e:?}"),
+    }
+}
 
 /// Choose an entry to boot.
 ///
 /// Pass in a parsed config, get out the entry portion that was selected.
-/// This will print a message and then wait for the timeout or for the escape key to be pressed.
-/// On timeout, it will boot the default entry.
-/// On escape, it will list the available entries and ask which one to boot.
+/// This will print a message and then wait for the timeout or for a key to be pressed.
+/// On timeout or enter, it will boot the default entry.
+/// On any other key, it will list the available entries and ask which one to boot.
 ///
+/// If an entry has been made the default in the menu before, it is used
+/// instead of the one in the configuration.
 /// If the default entry is missing, it will try to use the first one instead.
 /// If there are no entries, it will panic.
 // TODO: perhaps this should return a Result?
 pub fn choose(config: &Config) -> &Entry {
-    let default_entry = config.entries.get(&config.default).unwrap_or_else(|| {
+    choose_with_timeout(config, config.timeout)
+}
+
+/// Choose an entry to boot, listing them right away (without a timeout).
+///
+/// This is used when an entry failed to boot.
+pub fn choose_again(config: &Config) -> &Entry {
+    choose_with_timeout(config, None)
+}
+
+/// Choose an entry to boot, booting the default one after the given timeout.
+fn choose_with_timeout(config: &Config, timeout: Option<u8>) -> &Entry {
+    let default_key = match saved_default() {
+        Some(key) if config.entries.contains_key(&key) => {
+            debug!("using {key} as the default, as it has been chosen before");
+            key
+        },
+        _ => config.default.clone(),
+    };
+    let default_entry = config.entries.get(&default_key).unwrap_or_else(|| {
         warn!("default entry is missing, trying the first one");
         config.entries.values().next().expect("no entries")
     });
-    if let Some(0) = config.timeout {
+    if let Some(0) = timeout {
         return default_entry
     }
-    match display_menu(config, default_entry) {
+    let keymap = config.keymap.as_deref().map_or(Keymap::US, |name| {
+        Keymap::from_name(name).unwrap_or_else(|| {
+            warn!("unknown keymap '{name}', using us");
+            Keymap::US
+        })
+    });
+    let result = display_menu(config, timeout, &default_key, default_entry, &keymap);
+    // go back to the firmware's colors for the log
+    set_colors(config.theme.as_ref(), |_| Some(DEFAULT_COLORS));
+    match result {
         Ok(entry) => entry,
         Err(err) => {
             error!("failed to display menu: {err:?}");
@@ -42,14 +160,23 @@ pub fn choose(config: &Config) -> &Entry {
 
 /// Display the menu. This can fail.
 fn display_menu<'a>(
-    config: &'a Config, default_entry: &'a Entry,
+    config: &'a Config, timeout: Option<u8>, default_key: &str, default_entry: &'a Entry,
+    keymap: &Keymap,
 ) -> uefi::Result<&'a Entry> {
-    if let Some(timeout) = config.timeout {
+    let theme = config.theme.as_ref();
+    set_colors(theme, |theme| theme.normal);
+    if let Some(timeout) = timeout {
+        set_colors(theme, |theme| theme.countdown);
         with_stdout(|stdout | writeln!(
             stdout,
-            "towboot: booting {} ({}) in {} seconds... (press ESC to change)",
-            config.default, default_entry.name.as_ref().unwrap_or(&config.default), timeout,
+            "towboot: booting {} ({}) in {} seconds... \
+            (press Enter to boot, any other key to change)",
+            default_key, default_entry.name.as_deref().unwrap_or(default_key), timeout,
         )).unwrap();
+        if let Some(description) = &default_entry.description {
+            with_stdout(|stdout| writeln!(stdout, "    {description}")).unwrap();
+        }
+        set_colors(theme, |theme| theme.normal);
         // This is safe because there is no callback.
         let timer = unsafe { create_event(
             EventType::TIMER, Tpl::APPLICATION, None, None
@@ -69,8 +196,14 @@ fn display_menu<'a>(
             ).discard_errdata()? {
                 // key
                 0 => match with_stdin(|stdin| stdin.read_key())? {
-                    Some(Key::Special(ScanCode::ESCAPE)) => break,
-                    _ => (),
+                    // enter boots the default entry right away
+                    Some(Key::Printable(c)) if char::from(c) == '\r' => {
+                        set_timer(&timer, TimerTrigger::Cancel)?;
+                        return Ok(default_entry);
+                    },
+                    // any other key opens the menu
+                    Some(_) => break,
+                    None => (),
                 },
                 // timer
                 1 => return Ok(default_entry),
@@ -79,24 +212,88 @@ fn display_menu<'a>(
         }
         set_timer(&timer, TimerTrigger::Cancel)?;
     }
-    with_stdout(|stdout| {
-        writeln!(stdout, "available entries:").unwrap();
-        for (index, (key, entry)) in config.entries.iter().enumerate() {
+    with_stdout(|stdout| writeln!(stdout, "available entries:")).unwrap();
+    for (index, (key, entry)) in config.entries.iter().enumerate() {
+        if key == default_key {
+            set_colors(theme, |theme| theme.highlight);
+        }
+        with_stdout(|stdout| {
             writeln!(stdout, "{index}. [{key}] {entry}").unwrap();
+            if let Some(description) = &entry.description {
+                writeln!(stdout, "    {description}").unwrap();
+            }
+        });
+        if key == default_key {
+            set_colors(theme, |theme| theme.normal);
+        }
+    }
+    with_stdout(|stdout| {
+        let boot_options = boot_next::boot_options();
+        if !boot_options.is_empty() {
+            writeln!(stdout, "firmware boot options (type the name to boot one once):").unwrap();
+            for (number, description) in boot_options {
+                writeln!(stdout, "Boot{number:04X}. {description}").unwrap();
+            }
         }
+        writeln!(stdout, "(type info to show information about this system)").unwrap();
+        writeln!(stdout, "(type reset to forget the default chosen here)").unwrap();
     });
-    loop {
-        match select_entry(&config.entries) {
-            Ok(entry) => return Ok(entry),
+    let (key, entry) = loop {
+        match select_entry(&config.entries, keymap) {
+            Ok(choice) => break choice,
             Err(err) => {
+                set_colors(theme, |theme| theme.error);
                 with_stdout(|stdout| writeln!(stdout, "invalid choice: {err:?}")).unwrap();
+                set_colors(theme, |theme| theme.normal);
             }
         }
+    };
+    if key != default_key && let Err(e) = offer_to_save_default(key, keymap) {
+        // we have a choice, so don't fall back to the default entry
+        error!("failed to ask whether to make {key} the default: {e:?}");
+    }
+    Ok(entry)
+}
+
+/// Ask whether the chosen entry should become the default and save it if so.
+///
+/// Failing to save it is only logged.
+fn offer_to_save_default(key: &str, keymap: &Keymap) -> uefi::Result {
+    with_stdout(|stdout| writeln!(
+        stdout, "press D to make {key} the default, any other key to continue",
+    )).unwrap();
+    let key_event = with_stdin(|stdin| stdin.wait_for_key_event())
+        .expect("to be able to wait for key events");
+    wait_for_event(
+        // this is safe because we're never calling close_event
+        &mut [unsafe { key_event.unsafe_clone() }]
+    ).discard_errdata()?;
+    if let Some(Key::Printable(c)) = with_stdin(|stdin| stdin.read_key())?
+        && matches!(keymap.translate(char::from(c)), 'd' | 'D')
+    {
+        match save_default(key) {
+            Ok(()) => with_stdout(|stdout| writeln!(stdout, "{key} is now the default")).unwrap(),
+            Err(e) => error!("failed to save the default entry: {e:?}"),
+        }
     }
+    Ok(())
+}
+
+/// Show where we have been loaded from and so on.
+fn show_system_information() {
+    let lines = location::system_information();
+    with_stdout(|stdout| {
+        writeln!(stdout).unwrap();
+        for line in lines {
+            writeln!(stdout, "{line}").unwrap();
+        }
+    });
 }
 
-/// Try to select an entry.
-fn select_entry(entries: &BTreeMap<String, Entry>) -> uefi::Result<&Entry> {
+/// Try to select an entry, returning its key and the entry itself.
+fn select_entry<'a>(
+    entries: &'a BTreeMap<String, Entry>, keymap: &Keymap,
+) -> uefi::Result<(&'a String, &'a Entry)> {
     let mut value = String::new();
     let key_event = with_stdin(|stdin| stdin.wait_for_key_event())
         .expect("to be able to wait for key events");
@@ -112,16 +309,29 @@ fn select_entry(entries: &BTreeMap<String, Entry>) -> uefi::Result<&Entry> {
             |stdin| stdin.read_key()
         )? {
             match c.into() {
+                '\r' if value == "info" => {
+                    show_system_information();
+                    value.clear();
+                },
+                '\r' if value == "reset" => {
+                    with_stdout(|stdout| writeln!(stdout)).unwrap();
+                    clear_default();
+                    value.clear();
+                },
                 '\r' => break, // enter
                 '\u{8}' => {value.pop();}, // backspace
-                chr => value.push(chr),
+                chr => value.push(keymap.translate(chr)),
             }
         }
     }
     with_stdout(|stdout| writeln!(stdout,)).unwrap();
+    // this only returns if it fails
+    if let Some(number) = boot_next::parse_number(&value) {
+        return Err(boot_next::boot_next(number).into());
+    }
     // support lookup by both index and key
     match value.parse::<usize>() {
-        Ok(index) => entries.values().nth(index),
-        Err(_) => entries.get(&value),
+        Ok(index) => entries.iter().nth(index),
+        Err(_) => entries.get_key_value(&value),
     }.ok_or(Status::INVALID_PARAMETER.into())
 }
@@ -0,0 +1,180 @@
+//! This module reports panics.
+//!
+//! The report goes to the console and to all serial ports, so that it isn't lost
+//! on headless machines. Then towboot waits for a key (or a minute) and resets.
+//! If Boot Services have already been exited, it can only use the first serial port
+//! (on x86) and halts instead.
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use uefi::{Identify, Status};
+use uefi::boot::{
+    MemoryType, OpenProtocolAttributes, OpenProtocolParams, SearchType, image_handle,
+    locate_handle_buffer, memory_map, open_protocol, stall,
+};
+use uefi::mem::memory_map::MemoryMap;
+use uefi::proto::console::serial::Serial;
+use uefi::runtime::{ResetType, reset};
+use uefi::system::{with_stdin, with_stdout};
+
+/// Whether Boot Services have been exited
+pub(crate) static BOOT_SERVICES_EXITED: AtomicBool = AtomicBool::new(false);
+
+/// Whether we're already panicking
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// How long to wait for a key before resetting (in seconds)
+const RESET_TIMEOUT: usize = 60;
+
+/// The I/O port of the first serial port
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const COM1: u16 = 0x3F8;
+
+/// Get the current stack pointer.
+fn stack_pointer() -> usize {
+    let sp: usize;
+    #[cfg(target_arch = "x86")]
+    unsafe { asm!("mov {}, esp", out(reg) sp) };
+    #[cfg(target_arch = "x86_64")]
+    unsafe { asm!("mov {}, rsp", out(reg) sp) };
+    #[cfg(target_arch = "aarch64")]
+    unsafe { asm!("mov {}, sp", out(reg) sp) };
+    #[cfg(target_arch = "riscv64")]
+    unsafe { asm!("mv {}, sp", out(reg) sp) };
+    sp
+}
+
+/// Write a short summary of the machine's state.
+fn write_state(writer: &mut impl Write, boot_services: bool) -> fmt::Result {
+    writeln!(writer, "stack pointer: {:#x}", stack_pointer())?;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    unsafe {
+        writeln!(
+            writer, "CR0: {:#x}, CR3: {:#x}, CR4: {:#x}",
+            x86::controlregs::cr0().bits(), x86::controlregs::cr3(),
+            x86::controlregs::cr4().bits(),
+        )?;
+    }
+    if boot_services && let Ok(map) = memory_map(MemoryType::LOADER_DATA) {
+        let free_pages: u64 = map.entries()
+            .filter(|entry| entry.ty == MemoryType::CONVENTIONAL)
+            .map(|entry| entry.page_count)
+            .sum();
+        writeln!(writer, "free memory: {} KiB", free_pages * 4)?;
+    }
+    Ok(())
+}
+
+/// Write the report.
+fn write_report(writer: &mut impl Write, info: &PanicInfo, boot_services: bool) -> fmt::Result {
+    writeln!(writer)?;
+    writeln!(writer, "towboot {} panicked", env!("CARGO_PKG_VERSION"))?;
+    if let Some(location) = info.location() {
+        writeln!(writer, "at {location}:")?;
+    }
+    writeln!(writer, "{}", info.message())?;
+    write_state(writer, boot_services)
+}
+
+/// Writes to a serial port (that might also be used by the console).
+struct SerialWriter<'a>(&'a mut Serial);
+
+impl Write for SerialWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (idx, line) in s.split('\n').enumerate() {
+            if idx > 0 {
+                self.0.write(b"\r\n").map_err(|_| fmt::Error)?;
+            }
+            self.0.write(line.as_bytes()).map_err(|_| fmt::Error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Send the report to the console and all serial ports.
+///
+/// This doesn't allocate, as the allocator might be what's broken.
+fn report_with_boot_services(info: &PanicInfo) {
+    with_stdout(|stdout| {
+        let _ = write_report(stdout, info, true);
+    });
+    // (the firmware allocates the buffer)
+    let Ok(handles) = locate_handle_buffer(SearchType::ByProtocol(&Serial::GUID)) else {
+        return;
+    };
+    for handle in handles.iter() {
+        // Opening the protocol exclusively would disconnect the console.
+        let serial = unsafe { open_protocol::<Serial>(
+            OpenProtocolParams {
+                handle: *handle,
+                agent: image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        ) };
+        if let Ok(mut serial) = serial {
+            let _ = write_report(&mut SerialWriter(&mut serial), info, true);
+        }
+    }
+}
+
+/// Wait for a key to be pressed (but not longer than `RESET_TIMEOUT`).
+fn wait_for_key() {
+    with_stdout(|stdout| {
+        let _ = writeln!(stdout, "press any key to reset (or wait {RESET_TIMEOUT} seconds)");
+    });
+    for _ in 0..RESET_TIMEOUT * 10 {
+        if let Ok(Some(_)) = with_stdin(|stdin| stdin.read_key()) {
+            return;
+        }
+        stall(100_000);
+    }
+}
+
+/// Writes directly to the first serial port.
+///
+/// This works without Boot Services.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+struct RawSerial;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl Write for RawSerial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_str("\r")?;
+            }
+            unsafe {
+                // wait until the transmitter is empty
+                while x86::io::inb(COM1 + 5) & 0x20 == 0 {}
+                x86::io::outb(COM1, byte);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stop for good.
+fn halt() -> ! {
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        // there's not much we can do if reporting the panic panics
+        halt();
+    }
+    if BOOT_SERVICES_EXITED.load(Ordering::SeqCst) {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        let _ = write_report(&mut RawSerial, info, false);
+        halt();
+    }
+    report_with_boot_services(info);
+    wait_for_key();
+    reset(ResetType::COLD, Status::ABORTED, None)
+}
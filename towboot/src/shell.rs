@@ -0,0 +1,103 @@
+//! This module starts the UEFI shell.
+//!
+//! The shell is either given as the image of the entry, found on the volume
+//! towboot has been loaded from or (with the `embedded-shell` feature)
+//! bundled into towboot itself.
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use uefi::prelude::*;
+use uefi::boot::{LoadImageSource, image_handle, load_image, open_protocol_exclusive, start_image};
+use uefi::data_types::CString16;
+use uefi::proto::loaded_image::LoadedImage;
+
+use log::{debug, error, info};
+
+use towboot_config::Entry;
+
+use super::file::{File, exists};
+
+/// How the shell is called for this architecture
+#[cfg(target_arch = "x86")]
+const ARCH: &str = "ia32";
+#[cfg(target_arch = "x86_64")]
+const ARCH: &str = "x64";
+#[cfg(target_arch = "aarch64")]
+const ARCH: &str = "aa64";
+#[cfg(target_arch = "riscv64")]
+const ARCH: &str = "riscv64";
+
+/// Where to look for the shell on the volume we're loaded from
+fn search_paths() -> [String; 4] {
+    [
+        format!("\\EFI\\tools\\shell{ARCH}.efi"),
+        format!("\\shell{ARCH}.efi"),
+        "\\EFI\\Shell\\Shell.efi".to_string(),
+        "\\Shell.efi".to_string(),
+    ]
+}
+
+/// Search the volume we're loaded from for the shell.
+fn search(image_fs_handle: Handle) -> Result<Option<Vec<u8>>, Status> {
+    for path in search_paths() {
+        if exists(&path, image_fs_handle) {
+            info!("found the shell at {path}");
+            return File::open(&path, image_fs_handle)?.try_into().map(Some);
+        }
+        debug!("the shell is not at {path}");
+    }
+    Ok(None)
+}
+
+/// Get the shell that is bundled into towboot.
+#[cfg(feature = "embedded-shell")]
+fn embedded() -> Result<Vec<u8>, Status> {
+    info!("using the embedded shell");
+    Ok(include_bytes!(env!("TOWBOOT_SHELL")).to_vec())
+}
+
+/// Get the shell that is bundled into towboot.
+#[cfg(not(feature = "embedded-shell"))]
+fn embedded() -> Result<Vec<u8>, Status> {
+    error!("failed to find the UEFI shell");
+    Err(Status::NOT_FOUND)
+}
+
+/// Start the UEFI shell, returning once it exits.
+///
+/// The arguments of the entry are passed to the shell.
+pub(crate) fn start(entry: &Entry, image_fs_handle: Handle) -> Result<(), Status> {
+    let shell = if !entry.image.is_empty() {
+        File::open(&entry.image, image_fs_handle)?.try_into()?
+    } else if let Some(shell) = search(image_fs_handle)? {
+        shell
+    } else {
+        embedded()?
+    };
+    let handle = load_image(image_handle(), LoadImageSource::FromBuffer {
+        buffer: &shell, file_path: None,
+    }).map_err(|e| {
+        error!("failed to load the shell: {e:?}");
+        e.status()
+    })?;
+    // The shell expects its own name as the first argument.
+    // This has to stay around until the shell exits.
+    let load_options = entry.argv.as_deref().map(
+        |argv| CString16::try_from(format!("shell.efi {argv}").as_str())
+    ).transpose().map_err(|e| {
+        error!("the arguments are invalid because of {e:?}");
+        Status::INVALID_PARAMETER
+    })?;
+    if let Some(load_options) = &load_options {
+        let mut loaded_image = open_protocol_exclusive::<LoadedImage>(handle)
+            .map_err(|e| e.status())?;
+        unsafe {
+            loaded_image.set_load_options(
+                load_options.as_ptr().cast(), load_options.num_bytes().try_into().unwrap(),
+            );
+        }
+    }
+    info!("starting the shell...");
+    start_image(handle).map_err(|e| e.status())
+}
@@ -0,0 +1,314 @@
+//! This module contains builders to create configurations in code.
+//!
+//! This is meant for build scripts of operating systems, so that they don't
+//! need to format TOML themselves.
+use core::fmt;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::{Config, Entry, EntryKind, Module, ModuleKind, OnFailure, Quirk, Theme};
+
+/// Why a configuration couldn't be built
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// There are no entries.
+    NoEntries,
+    /// The entry with this key has been added twice.
+    DuplicateEntry(String),
+    /// The default entry hasn't been set.
+    MissingDefault,
+    /// The default entry doesn't exist.
+    UnknownDefault(String),
+    /// An entry in the fallback list doesn't exist.
+    UnknownFallback(String),
+    /// The Multiboot entry with this key has no image.
+    MissingImage(String),
+    /// The `boot-next` entry with this key has no boot option.
+    MissingBootOption(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoEntries => write!(f, "there are no entries"),
+            Self::DuplicateEntry(key) => write!(f, "entry {key} is defined twice"),
+            Self::MissingDefault => write!(f, "there is no default entry"),
+            Self::UnknownDefault(key) => write!(f, "the default entry {key} does not exist"),
+            Self::UnknownFallback(key) => write!(f, "the fallback entry {key} does not exist"),
+            Self::MissingImage(key) => write!(f, "entry {key} has no image"),
+            Self::MissingBootOption(key) => write!(f, "entry {key} has no boot option"),
+        }
+    }
+}
+
+impl core::error::Error for BuildError {}
+
+/// A builder for a [`Config`].
+///
+/// ```
+/// # use towboot_config::{Config, Entry, Module};
+/// let config = Config::builder()
+///     .default("myos")
+///     .timeout(5)
+///     .entry("myos", Entry::builder("kernel.elf")
+///         .name("My OS")
+///         .argv("--verbose")
+///         .module(Module::new("initrd.img"))
+///         .build())
+///     .build()?;
+/// # Ok::<(), towboot_config::BuildError>(())
+/// ```
+pub struct ConfigBuilder {
+    config: Config,
+    duplicate: Option<String>,
+}
+
+impl Config {
+    /// Start building a new configuration.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config {
+                default: String::new(),
+                timeout: None,
+                log_level: None,
+                watchdog: None,
+                keymap: None,
+                on_failure: None,
+                fallback: Vec::new(),
+                entries: BTreeMap::new(),
+                theme: None,
+                loglevel: None,
+                src: ".".to_string(),
+            },
+            duplicate: None,
+        }
+    }
+
+    /// Check that the configuration makes sense.
+    ///
+    /// This is done when building it, but parsed configurations can be checked, too.
+    pub fn validate(&self) -> Result<(), BuildError> {
+        if self.entries.is_empty() {
+            return Err(BuildError::NoEntries);
+        }
+        if self.default.is_empty() {
+            return Err(BuildError::MissingDefault);
+        }
+        if !self.entries.contains_key(&self.default) {
+            return Err(BuildError::UnknownDefault(self.default.clone()));
+        }
+        if let Some(key) = self.fallback.iter().find(|k| !self.entries.contains_key(*k)) {
+            return Err(BuildError::UnknownFallback(key.clone()));
+        }
+        self.validate_entries()
+    }
+
+    /// Check that each entry has what its kind needs to boot.
+    ///
+    /// This is part of [`Config::validate`], but it doesn't care about the
+    /// default and fallback entries, so it can be checked at boot.
+    pub fn validate_entries(&self) -> Result<(), BuildError> {
+        for (key, entry) in &self.entries {
+            match entry.kind {
+                EntryKind::Multiboot if entry.image.is_empty() => {
+                    return Err(BuildError::MissingImage(key.clone()));
+                },
+                EntryKind::BootNext if entry.boot_option.is_none() => {
+                    return Err(BuildError::MissingBootOption(key.clone()));
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ConfigBuilder {
+    /// Set the key of the entry to boot by default.
+    pub fn default(mut self, key: &str) -> Self {
+        self.config.default = key.to_string();
+        self
+    }
+
+    /// Set how long to show the menu (in seconds).
+    pub fn timeout(mut self, timeout: u8) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Set the log level.
+    pub fn log_level(mut self, log_level: &str) -> Self {
+        self.config.log_level = Some(log_level.to_string());
+        self
+    }
+
+    /// Set the timeout of the firmware's watchdog (in seconds, 0 disables it).
+    pub fn watchdog(mut self, watchdog: u32) -> Self {
+        self.config.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Set the keyboard layout of the menu.
+    pub fn keymap(mut self, keymap: &str) -> Self {
+        self.config.keymap = Some(keymap.to_string());
+        self
+    }
+
+    /// Set what to do if the chosen entry fails to boot.
+    pub fn on_failure(mut self, on_failure: OnFailure) -> Self {
+        self.config.on_failure = Some(on_failure);
+        self
+    }
+
+    /// Set the colors of the menu.
+    pub fn theme(mut self, theme: Theme) -> Self {
+        self.config.theme = Some(theme);
+        self
+    }
+
+    /// Add an entry to try if another one fails.
+    pub fn fallback(mut self, key: &str) -> Self {
+        self.config.fallback.push(key.to_string());
+        self
+    }
+
+    /// Set the path of the configuration file.
+    ///
+    /// The paths of images are relative to it.
+    pub fn src(mut self, src: &str) -> Self {
+        self.config.src = src.to_string();
+        self
+    }
+
+    /// Add an entry with the given key.
+    pub fn entry(mut self, key: &str, entry: Entry) -> Self {
+        if self.config.entries.insert(key.to_string(), entry).is_some() {
+            self.duplicate.get_or_insert_with(|| key.to_string());
+        }
+        self
+    }
+
+    /// Check the configuration and return it.
+    pub fn build(self) -> Result<Config, BuildError> {
+        let config = self.config;
+        if let Some(key) = self.duplicate {
+            return Err(BuildError::DuplicateEntry(key));
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// A builder for an [`Entry`].
+pub struct EntryBuilder {
+    entry: Entry,
+}
+
+impl Entry {
+    /// Start building a new entry booting the given image.
+    ///
+    /// The image may be empty for entries that don't need one.
+    pub fn builder(image: &str) -> EntryBuilder {
+        EntryBuilder {
+            entry: Entry {
+                argv: None,
+                image: image.to_string(),
+                name: None,
+                kind: EntryKind::default(),
+                boot_option: None,
+                cmdline_file: None,
+                show_if: None,
+                description: None,
+                entry_point: None,
+                quirks: BTreeSet::new(),
+                modules: Vec::new(),
+            },
+        }
+    }
+}
+
+impl EntryBuilder {
+    /// Set the arguments to pass to the kernel.
+    pub fn argv(mut self, argv: &str) -> Self {
+        self.entry.argv = Some(argv.to_string());
+        self
+    }
+
+    /// Set the name to show in the menu.
+    pub fn name(mut self, name: &str) -> Self {
+        self.entry.name = Some(name.to_string());
+        self
+    }
+
+    /// Set what to do with the entry.
+    pub fn kind(mut self, kind: EntryKind) -> Self {
+        self.entry.kind = kind;
+        self
+    }
+
+    /// Set the firmware's boot option to boot (for `boot-next` entries).
+    pub fn boot_option(mut self, boot_option: &str) -> Self {
+        self.entry.boot_option = Some(boot_option.to_string());
+        self
+    }
+
+    /// Set a file to read further arguments for the kernel from.
+    pub fn cmdline_file(mut self, cmdline_file: &str) -> Self {
+        self.entry.cmdline_file = Some(cmdline_file.to_string());
+        self
+    }
+
+    /// Only show the entry if the condition holds at boot.
+    pub fn show_if(mut self, condition: &str) -> Self {
+        self.entry.show_if = Some(condition.to_string());
+        self
+    }
+
+    /// Set a longer explanation to show in the menu.
+    pub fn description(mut self, description: &str) -> Self {
+        self.entry.description = Some(description.to_string());
+        self
+    }
+
+    /// Jump to the given address instead of the kernel's entry point.
+    pub fn entry_point(mut self, entry_point: u64) -> Self {
+        self.entry.entry_point = Some(entry_point);
+        self
+    }
+
+    /// Add a quirk.
+    pub fn quirk(mut self, quirk: Quirk) -> Self {
+        self.entry.quirks.insert(quirk);
+        self
+    }
+
+    /// Add a module.
+    pub fn module(mut self, module: Module) -> Self {
+        self.entry.modules.push(module);
+        self
+    }
+
+    /// Return the entry.
+    ///
+    /// It is checked when the configuration is built.
+    pub fn build(self) -> Entry {
+        self.entry
+    }
+}
+
+impl Module {
+    /// Create a normal module with the given image and no arguments.
+    ///
+    /// The other fields can be set afterwards.
+    pub fn new(image: &str) -> Self {
+        Self {
+            argv: None,
+            image: image.to_string(),
+            kind: ModuleKind::default(),
+            align: None,
+            max_address: None,
+            unpack: false,
+        }
+    }
+}
@@ -3,26 +3,73 @@ use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::vec::Vec;
 use alloc::string::String;
 
+use log::warn;
 use serde::{Deserialize, Serialize};
 
 /// The main configuration struct
 #[derive(Deserialize, Debug, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Config {
     pub default: String,
     pub timeout: Option<u8>,
     pub log_level: Option<String>,
+    /// the timeout of the firmware's watchdog in seconds (0 disables it)
+    ///
+    /// If this is not set, the firmware's default (usually 5 minutes) is kept.
+    pub watchdog: Option<u32>,
+    /// the keyboard layout to use in the menu (`us`, `de` or `fr`)
+    pub keymap: Option<String>,
+    /// what to do if the chosen entry fails to boot
+    ///
+    /// If this is not set, towboot exits and the firmware takes over.
+    pub on_failure: Option<OnFailure>,
+    /// which entries to try (in this order) if one fails and `on_failure` is `next`
+    ///
+    /// By default, all entries are tried in order.
+    #[serde(default)]
+    pub fallback: Vec<String>,
     pub entries: BTreeMap<String, Entry>,
+    /// the colors of the menu
+    pub theme: Option<Theme>,
+    /// the old name of `log_level` (see [`Config::apply_renamed_keys`])
+    #[serde(default, skip_serializing)]
+    #[cfg_attr(feature = "schema", schemars(skip))]
+    pub(crate) loglevel: Option<String>,
     #[serde(skip)]
     /// the path of the configuration file itself
     pub src: String,
 }
 
 impl Config {
+    /// Move the values of renamed keys to their current names, warning about each one.
+    ///
+    /// The old names are still accepted when parsing, so that existing configuration
+    /// files keep working. If both names are used, the current one wins.
+    pub fn apply_renamed_keys(&mut self) {
+        if let Some(log_level) = self.loglevel.take() {
+            warn!("{}: 'loglevel' has been renamed to 'log_level'", self.src);
+            self.log_level.get_or_insert(log_level);
+        }
+    }
+
+    /// Move microcode modules to the front of each entry (keeping the order otherwise).
+    pub fn microcode_first(self: &mut Config) {
+        for entry in self.entries.values_mut() {
+            entry.modules.sort_by_key(|module| module.kind != ModuleKind::Microcode);
+        }
+    }
+
     /// Determine which files are referenced in the configuration.
     pub fn needed_files(self: &mut Config) -> Vec<&mut String> {
         let mut files = Vec::new();
         for (_name, entry) in self.entries.iter_mut() {
-            files.push(&mut entry.image);
+            // the UEFI shell doesn't need to have an image
+            if !entry.image.is_empty() {
+                files.push(&mut entry.image);
+            }
+            if let Some(cmdline_file) = &mut entry.cmdline_file {
+                files.push(cmdline_file);
+            }
             for module in &mut entry.modules {
                 files.push(&mut module.image);
             }
@@ -33,11 +80,33 @@ impl Config {
 
 /// A menu entry -- an operating system to be booted.
 #[derive(Deserialize, Debug, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Entry {
     pub argv: Option<String>,
+    #[serde(default)]
     pub image: String,
     pub name: Option<String>,
     #[serde(default)]
+    pub kind: EntryKind,
+    /// the firmware's boot option to boot (for `boot-next` entries),
+    /// either its number (`Boot0003`) or its description
+    pub boot_option: Option<String>,
+    /// a text file to read further arguments for the kernel from
+    ///
+    /// Its lines are appended to `argv` (separated by spaces);
+    /// empty lines and lines starting with `#` are skipped.
+    pub cmdline_file: Option<String>,
+    /// only show the entry if this condition holds at boot:
+    /// the firmware's architecture (`x86`, `x86_64`, `aarch64`, `riscv64`),
+    /// `secureboot_on`, `secureboot_off` or `file:<path>`
+    pub show_if: Option<String>,
+    /// a longer explanation of the entry, shown below it in the menu
+    pub description: Option<String>,
+    /// the address to jump to instead of the one from the Multiboot header (or the ELF file)
+    ///
+    /// Whether the kernel is entered in the Multiboot or the UEFI machine state doesn't change.
+    pub entry_point: Option<u64>,
+    #[serde(default)]
     pub quirks: BTreeSet<Quirk>,
     #[serde(default)]
     pub modules: Vec<Module>,
@@ -45,27 +114,154 @@ pub struct Entry {
 
 impl fmt::Display for Entry {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.name.as_ref().unwrap_or(&self.image))
+        match (&self.name, self.kind) {
+            (Some(name), _) => write!(f, "{name}"),
+            (None, EntryKind::UefiShell) if self.image.is_empty() => write!(f, "UEFI shell"),
+            (None, EntryKind::BootNext) => write!(
+                f, "{}", self.boot_option.as_deref().unwrap_or("firmware boot option"),
+            ),
+            (None, _) => write!(f, "{}", self.image),
+        }
     }
 }
 
+/// What to do if an entry fails to boot
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum OnFailure {
+    /// Try the next entry (see `fallback`).
+    Next,
+    /// Show the menu (without a timeout).
+    Menu,
+    /// Stop, so that the error stays on the screen.
+    Halt,
+}
+
+/// The colors of the (text) menu
+///
+/// Parts without colors use the `normal` ones (or the firmware's defaults).
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Theme {
+    /// the colors of the menu's text
+    pub normal: Option<ColorPair>,
+    /// the colors of the default entry in the list of entries
+    pub highlight: Option<ColorPair>,
+    /// the colors of the countdown before booting the default entry
+    pub countdown: Option<ColorPair>,
+    /// the colors of error messages
+    pub error: Option<ColorPair>,
+}
+
+/// A foreground and a background color
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ColorPair {
+    pub foreground: Color,
+    /// the background color (only `black` to `light-gray` are supported)
+    pub background: Color,
+}
+
+/// A color of the firmware's text console
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum Color {
+    Black,
+    Blue,
+    Green,
+    Cyan,
+    Red,
+    Magenta,
+    Brown,
+    LightGray,
+    DarkGray,
+    LightBlue,
+    LightGreen,
+    LightCyan,
+    LightRed,
+    LightMagenta,
+    Yellow,
+    White,
+}
+
+/// What to do with an entry
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryKind {
+    /// Load a Multiboot kernel and its modules.
+    #[default]
+    Multiboot,
+    /// Start the UEFI shell.
+    /// If the image is not set, it is searched for on the ESP.
+    UefiShell,
+    /// Boot one of the firmware's boot options once.
+    BootNext,
+}
+
 /// Information about a module
 #[derive(Deserialize, Debug, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Module {
     pub argv: Option<String>,
+    /// the file to load
+    ///
+    /// Its name may contain the wildcards `*` and `?` to load each matching file
+    /// as its own module (in the order of their names, named like the file).
     pub image: String,
+    #[serde(default)]
+    pub kind: ModuleKind,
+    /// the alignment of the module in bytes (a power of two, at least a page by default)
+    pub align: Option<u64>,
+    /// the highest address the module may end at (below 4 GiB by default)
+    pub max_address: Option<u64>,
+    /// pass each file inside the (tar) archive as its own module, named like the file
+    #[serde(default)]
+    pub unpack: bool,
+}
+
+/// What a module contains
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum ModuleKind {
+    /// Anything the kernel wants.
+    #[default]
+    Normal,
+    /// A CPU microcode update.
+    /// It is placed below 4 GiB, 16-byte aligned and passed as the first module,
+    /// so that the kernel can apply it early.
+    Microcode,
+    /// An ACPI table (such as an SSDT or a DSDT).
+    /// It is not passed as a module, but replaces the firmware's table with the
+    /// same signature (SSDTs are added) in the tables the kernel gets.
+    AcpiOverride,
 }
 
 /// Runtime options to override information in kernel images.
 #[derive(Deserialize, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum Quirk {
     /// Do not exit Boot Services.
     /// This starts the kernel with more privileges and less available memory.
     /// In some cases this might also display more helpful error messages.
     DontExitBootServices,
+    /// Search the whole kernel for the Multiboot header if it isn't within
+    /// the first 8 KiB (Multiboot1) or 32 KiB (Multiboot2).
+    ExtendedHeaderSearch,
     /// Treat the kernel always as an ELF file.
     /// This ignores bit 16 of the kernel's Multiboot header.
     ForceElf,
+    /// Use the Multiboot1 header if the kernel has headers for both versions.
+    ForceMultiboot1,
+    /// Use the Multiboot2 header if the kernel has headers for both versions.
+    ForceMultiboot2,
+    /// Load a flat GDT and an empty IDT before jumping to a Multiboot entry,
+    /// even on i686 (where the firmware's segments are used otherwise).
+    /// (UEFI entries always keep the firmware's tables.)
+    ForceGdtSetup,
     /// Ignore the memory map when loading the kernel.
     /// This might damage your hardware!
     ForceOverwrite,
@@ -73,4 +269,12 @@ pub enum Quirk {
     KeepResolution,
     /// Place modules below 200 MB.
     ModulesBelow200Mb,
+    /// Leave the firmware's GDT and IDT alone before jumping to the kernel.
+    /// This is what happens for UEFI entries and on i686 anyway;
+    /// it is ignored for Multiboot entries on x86_64, as leaving long mode
+    /// needs a new GDT (and the firmware's IDT is invalid in 32-bit mode).
+    SkipGdtSetup,
+    /// Fill all memory with zeroes before the kernel and modules are read into it.
+    /// This includes the padding after modules (up to the end of the page).
+    ZeroizeBeforeLoad,
 }
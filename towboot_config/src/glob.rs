@@ -0,0 +1,33 @@
+//! Wildcards in the paths of modules
+//!
+//! Only the file name (the last part of a path) may contain wildcards:
+//! `*` matches any number of characters and `?` matches exactly one.
+use alloc::vec::Vec;
+
+/// Split a path into its directory and its file name, if the latter contains wildcards.
+///
+/// Both `\` and `/` separate directories.
+pub fn split_glob(path: &str) -> Option<(&str, &str)> {
+    let (directory, name) = path.rsplit_once(['\\', '/']).unwrap_or(("", path));
+    name.contains(['*', '?']).then_some((directory, name))
+}
+
+/// Check whether a file name matches a pattern.
+///
+/// This ignores case, just like FAT does.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().flat_map(char::to_lowercase).collect();
+    let name: Vec<char> = name.chars().flat_map(char::to_lowercase).collect();
+    matches(&pattern, &name)
+}
+
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => matches(&pattern[1..], name)
+            || (!name.is_empty() && matches(pattern, &name[1..])),
+        (Some('?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => matches(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
@@ -4,11 +4,26 @@
 //! The command line options take precedence if they are specified.
 #![no_std]
 extern crate alloc;
+#[cfg(feature = "schema")]
+extern crate std;
 
+mod builder;
 mod config;
-pub use config::{Config, Entry, Module, Quirk};
+mod glob;
+pub use builder::{BuildError, ConfigBuilder, EntryBuilder};
+pub use config::{
+    Color, ColorPair, Config, Entry, EntryKind, Module, ModuleKind, OnFailure, Quirk, Theme,
+};
+pub use glob::{glob_matches, split_glob};
 
 #[cfg(feature = "options")]
 mod options;
 #[cfg(feature = "options")]
-pub use options::{CONFIG_FILE, ConfigSource, LoadOptionKey, parse_load_options};
+pub use options::{
+    CONFIG_FILE, CONFIG_SEARCH_PATH, ConfigSource, LoadOptionKey, parse_load_options,
+};
+
+#[cfg(feature = "schema")]
+mod schema;
+#[cfg(feature = "schema")]
+pub use schema::json_schema;
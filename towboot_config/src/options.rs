@@ -1,4 +1,4 @@
-use alloc::collections::{btree_map::BTreeMap, btree_set::BTreeSet};
+use alloc::collections::btree_set::BTreeSet;
 use alloc::{fmt, format};
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
@@ -13,10 +13,18 @@ use super::{Config, Entry, Module, Quirk};
 /// The default path to the configuration file.
 pub const CONFIG_FILE: &str = "towboot.toml";
 
+/// Where to look for the configuration file if none is given.
+///
+/// towboot additionally looks in the directory it has been loaded from
+/// (usually `\EFI\<name>\`).
+pub const CONFIG_SEARCH_PATH: [&str; 2] = ["\\towboot.toml", "\\boot\\towboot.toml"];
+
 /// Where to load the configuration from
 pub enum ConfigSource {
     /// Load the configuration from a file
     File(String),
+    /// Load the configuration from the first of these files that exists
+    Search(Vec<String>),
     /// Use the configuration specified in here
     Given(Config),
 }
@@ -26,6 +34,8 @@ pub enum ConfigSource {
 pub enum LoadOptionKey {
     /// Load the specified configuration file instead of the default one.
     Config,
+    /// Also look for a configuration file here. Can be specified multiple times.
+    ConfigSearch,
     /// Don't load a configuration file, instead boot the specified kernel.
     Kernel,
     /// Set the log level. (This only applies if `-kernel` is specified.)
@@ -61,6 +71,7 @@ pub fn parse_load_options(
 ) -> Result<Option<ConfigSource>, ()> {
     let options = LoadOptionKey::parse(load_options);
     let mut config_file = None;
+    let mut config_search = Vec::<&str>::new();
     let mut kernel = None;
     let mut log_level = None;
     let mut modules = Vec::<&str>::new();
@@ -71,6 +82,7 @@ pub fn parse_load_options(
                 trace!("option: {key} => {value}");
                 match key {
                     LoadOptionKey::Config => config_file = Some(value),
+                    LoadOptionKey::ConfigSearch => config_search.push(value),
                     LoadOptionKey::Kernel => kernel = Some(value),
                     LoadOptionKey::LogLevel => log_level = Some(value),
                     LoadOptionKey::Module => modules.push(value),
@@ -103,32 +115,34 @@ pub fn parse_load_options(
         }
     }
     if let Some(kernel) = kernel {
-        let modules = modules.iter().map(|m| {
-            let (image, argv) = m.split_once(' ').unwrap_or((m, ""));
-            Module {
-                image: image.to_string(),
-                argv: Some(argv.to_string()),
-            }
-        }).collect();
         let (kernel_image, kernel_argv) = kernel.split_once(' ').unwrap_or((kernel, ""));
-        let mut entries = BTreeMap::new();
-        entries.insert("cli".to_string(), Entry {
-            argv: Some(kernel_argv.to_string()),
-            image: kernel_image.to_string(),
-            name: None,
-            quirks,
-            modules,
-        });
-        Ok(Some(ConfigSource::Given(Config {
-            default: "cli".to_string(),
-            timeout: Some(0),
-            log_level: log_level.map(ToString::to_string),
-            entries,
-            src: ".".to_string(), // TODO: put the CWD here
-        })))
+        let mut entry = Entry::builder(kernel_image).argv(kernel_argv);
+        for quirk in quirks {
+            entry = entry.quirk(quirk);
+        }
+        for module in modules {
+            let (image, argv) = module.split_once(' ').unwrap_or((module, ""));
+            entry = entry.module(Module { argv: Some(argv.to_string()), ..Module::new(image) });
+        }
+        let mut config = Config::builder().default("cli").timeout(0).entry("cli", entry.build());
+        if let Some(log_level) = log_level {
+            config = config.log_level(log_level);
+        }
+        // TODO: put the CWD in src
+        match config.build() {
+            Ok(config) => Ok(Some(ConfigSource::Given(config))),
+            Err(e) => {
+                error!("invalid parameters: {e}");
+                Err(())
+            },
+        }
     } else if let Some(c) = config_file {
         Ok(Some(ConfigSource::File(c.to_string())))
     } else {
-        Ok(Some(ConfigSource::File(CONFIG_FILE.to_string())))
+        // the additional paths are tried first
+        Ok(Some(ConfigSource::Search(
+            config_search.into_iter().chain(CONFIG_SEARCH_PATH)
+                .map(ToString::to_string).collect()
+        )))
     }
 }
@@ -0,0 +1,14 @@
+//! This module describes the configuration file as a JSON Schema.
+//!
+//! Editors can use it for autocompletion and CI can validate configurations
+//! with it (after converting them to JSON).
+use alloc::string::String;
+
+use schemars::schema_for;
+
+use super::Config;
+
+/// Get the JSON Schema of the configuration file.
+pub fn json_schema() -> String {
+    serde_json::to_string_pretty(&schema_for!(Config)).unwrap()
+}
@@ -1,10 +1,49 @@
 //! Our build script.
 //!
 //! It makes certain compile-time information visible to the application using built.
+//! With the `embedded-firmware` feature, it also provides the firmware builds to embed.
 use std::env;
 
+/// Where the latest builds of the firmware are (see `src/firmware.rs`)
+#[cfg(feature = "embedded-firmware")]
+const OVMF_LATEST_URL: &str = "https://retrage.github.io/edk2-nightly/bin";
+
+/// The firmware builds to embed, by the names of their architectures
+#[cfg(feature = "embedded-firmware")]
+const EMBEDDED_FIRMWARE: [(&str, &str); 4] = [
+    ("IA32", "RELEASEIa32_OVMF.fd"),
+    ("X64", "RELEASEX64_OVMF.fd"),
+    ("AA64", "RELEASEAARCH64_QEMU_EFI.fd"),
+    ("RISCV64", "RELEASERISCV64_VIRT_CODE.fd"),
+];
+
+/// Download the firmware builds (or take the local ones given at build time)
+/// and tell the compiler where they are.
+#[cfg(feature = "embedded-firmware")]
+fn embed_firmware() {
+    use std::path::PathBuf;
+
+    let cache = cached_path::Cache::builder()
+        .dir(PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("firmware"))
+        .build()
+        .expect("failed to create the firmware cache");
+    for (arch, file_name) in EMBEDDED_FIRMWARE {
+        let variable = format!("TOWBOOTCTL_FIRMWARE_{arch}");
+        println!("cargo:rerun-if-env-changed={variable}");
+        let path = match env::var_os(&variable) {
+            Some(path) => PathBuf::from(path),
+            None => cache.cached_path(&format!("{OVMF_LATEST_URL}/{file_name}"))
+                .expect("failed to download the firmware"),
+        };
+        println!("cargo:rerun-if-changed={}", path.display());
+        println!("cargo:rustc-env=TOWBOOTCTL_EMBEDDED_FIRMWARE_{arch}={}", path.display());
+    }
+}
+
 fn main() {
     if env::var("CARGO_FEATURE_BINARY").is_ok() {
         built::write_built_file().expect("Failed to acquire build-time information");
     }
+    #[cfg(feature = "embedded-firmware")]
+    embed_firmware();
 }
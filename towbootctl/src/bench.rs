@@ -0,0 +1,103 @@
+//! This module measures how long booting takes.
+//!
+//! An image is booted several times; the time from starting the virtual
+//! machine until certain markers appear on the serial port is recorded.
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use super::{BootOptions, Error, boot_image};
+
+/// The markers to use if none are given: towboot starts loading the
+/// selected entry and hands over to the kernel
+///
+/// (These need to stay in sync with what towboot's `start` logs; other
+/// messages, such as the ones about loading files, must not match.)
+pub const DEFAULT_MARKERS: [&str; 2] = ["loading entry '", "booting entry '"];
+
+/// How often to check the output
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Statistics about how long it took until a marker appeared
+#[derive(Debug, Clone)]
+pub struct Statistics {
+    /// the text that was searched for
+    pub marker: String,
+    /// in how many runs it appeared
+    pub count: usize,
+    /// the fastest run
+    pub min: Duration,
+    /// the slowest run
+    pub max: Duration,
+    /// the average of all runs
+    pub mean: Duration,
+    /// the run in the middle
+    pub median: Duration,
+}
+
+/// Boot the image once, returning when each of the markers appeared.
+///
+/// The markers are expected in the given order; the virtual machine is stopped
+/// after the last one has appeared or the timeout has been reached.
+pub fn run(
+    image: &Path, options: &BootOptions, markers: &[&str], timeout: Duration,
+) -> Result<Vec<Option<Duration>>, Error> {
+    let mut vm = boot_image(image, &BootOptions {
+        headless: true,
+        ..options.clone()
+    })?;
+    let start = Instant::now();
+    vm.spawn_captured()?;
+    let mut times = Vec::new();
+    let mut position = 0;
+    for marker in markers {
+        let found = loop {
+            let output = vm.output();
+            // the output might end with an incomplete character
+            if let Some(index) = output.get(position..).and_then(|o| o.find(marker)) {
+                position += index + marker.len();
+                break Some(start.elapsed());
+            }
+            if start.elapsed() > timeout {
+                warn!("{marker:?} didn't appear in time");
+                break None;
+            }
+            sleep(POLL_INTERVAL);
+        };
+        times.push(found);
+        if found.is_none() {
+            break;
+        }
+    }
+    times.resize(markers.len(), None);
+    vm.kill()?;
+    Ok(times)
+}
+
+/// Boot the image the given number of times and compute statistics for each marker.
+pub fn bench(
+    image: &Path, options: &BootOptions, runs: usize, markers: &[&str], timeout: Duration,
+) -> Result<Vec<Statistics>, Error> {
+    let mut results: Vec<Vec<Duration>> = vec![Vec::new(); markers.len()];
+    for i in 0..runs {
+        info!("run {} of {runs}", i + 1);
+        for (result, time) in results.iter_mut().zip(run(image, options, markers, timeout)?) {
+            result.extend(time);
+        }
+    }
+    Ok(markers.iter().zip(results).filter(|(_, times)| !times.is_empty()).map(
+        |(marker, mut times)| {
+            times.sort();
+            Statistics {
+                marker: marker.to_string(),
+                count: times.len(),
+                min: times[0],
+                max: times[times.len() - 1],
+                mean: times.iter().sum::<Duration>() / times.len() as u32,
+                median: times[times.len() / 2],
+            }
+        }
+    ).collect())
+}
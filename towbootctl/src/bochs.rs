@@ -1,22 +1,141 @@
 //! This module allows booting with Bochs.
+use std::env;
+use std::fs;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use log::{info, warn};
 use tempfile::NamedTempFile;
 
+use super::{BootOptions, firmware};
+
+/// Settings that only apply to Bochs
+#[derive(Debug, Default, Clone)]
+pub struct BochsOptions {
+    /// the display library to use (such as `x`, `sdl2` or `win32`)
+    ///
+    /// This defaults to the usual one for the operating system.
+    pub display_library: Option<String>,
+    /// the VGA BIOS to use instead of the one shipped with Bochs
+    pub vga_rom: Option<PathBuf>,
+    /// write Bochs' own log to this file instead of the terminal
+    pub log: Option<PathBuf>,
+}
+
+/// The display library Bochs usually has on this operating system
+fn default_display_library() -> &'static str {
+    if cfg!(target_os = "windows") {
+        "win32"
+    } else if cfg!(target_os = "macos") {
+        "sdl2"
+    } else {
+        "x"
+    }
+}
+
+/// The names the VGA BIOS has in different versions and distributions
+const VGA_ROM_NAMES: [&str; 3] = ["VGABIOS-lgpl-latest", "VGABIOS-lgpl-latest.bin", "vgabios.bin"];
+
+/// Where Bochs (or the VGA BIOS) is usually installed
+fn vga_rom_directories() -> Vec<PathBuf> {
+    let mut directories = Vec::new();
+    // Bochs itself looks there
+    if let Some(share) = env::var_os("BXSHARE") {
+        directories.push(PathBuf::from(share));
+    }
+    for directory in [
+        "/usr/share/bochs", "/usr/local/share/bochs", // Linux and BSD
+        "/usr/share/vgabios", // Debian's vgabios package
+        "/opt/homebrew/share/bochs", "/opt/local/share/bochs", // Homebrew and MacPorts
+    ] {
+        directories.push(PathBuf::from(directory));
+    }
+    // the Windows installer creates a folder per version
+    if let Some(entries) = env::var_os("ProgramFiles").and_then(|p| fs::read_dir(p).ok()) {
+        directories.extend(entries.filter_map(Result::ok).map(|e| e.path()).filter(
+            |path| path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("Bochs"))
+        ));
+    }
+    directories
+}
+
+/// Find the VGA BIOS shipped with Bochs, downloading it if it isn't installed.
+fn find_vga_rom() -> Option<PathBuf> {
+    let found = vga_rom_directories().into_iter()
+        .flat_map(|directory| VGA_ROM_NAMES.map(|name| directory.join(name)))
+        .find(|path| path.is_file());
+    if found.is_some() {
+        return found;
+    }
+    info!("didn't find the VGA BIOS, downloading it");
+    firmware::vga_bios().inspect_err(|e| warn!("failed to download the VGA BIOS: {e}")).ok()
+}
+
+/// Check whether a program is in the `PATH`.
+pub(crate) fn in_path(program: &str) -> bool {
+    env::var_os("PATH").is_some_and(|path| env::split_paths(&path).any(|dir| {
+        dir.join(program).is_file() || dir.join(format!("{program}.exe")).is_file()
+    }))
+}
+
 /// Generate a appropriate bochrs file.
-pub fn bochsrc(ovmf: &Path, image: &Path, gdb: bool) -> Result<NamedTempFile> {
+///
+/// This fails with a list of everything that's missing to run Bochs.
+pub fn bochsrc(ovmf: &Path, image: &Path, options: &BootOptions) -> Result<NamedTempFile> {
+    let mut missing = Vec::new();
+    if !in_path("bochs") {
+        missing.push("the bochs executable (in the PATH)".to_string());
+    }
+    let vga_rom = match &options.bochs.vga_rom {
+        Some(path) if path.exists() => Some(path.clone()),
+        Some(path) => {
+            missing.push(format!("the VGA BIOS at {}", path.display()));
+            None
+        },
+        None => find_vga_rom().or_else(|| {
+            missing.push("the VGA BIOS (set BXSHARE or pass it explicitly)".to_string());
+            None
+        }),
+    };
+    if !ovmf.exists() {
+        missing.push(format!("the firmware at {}", ovmf.display()));
+    }
+    if !missing.is_empty() {
+        return Err(anyhow!("can't start Bochs, missing: {}", missing.join(", ")));
+    }
+    let vga_rom = vga_rom.unwrap();
+    let vga_rom = vga_rom.display();
     let ovmf = ovmf.display();
     let image = image.display();
-    let gdb: u8 = gdb.into();
+    let gdb: u8 = options.gdb.into();
+    let memory = options.memory();
+    let cpus = options.cpus();
+    let display_library = if options.headless {
+        "nogui"
+    } else {
+        options.bochs.display_library.as_deref().unwrap_or(default_display_library())
+    };
+    let com1 = match &options.serial_log {
+        Some(path) => format!("mode=file, dev=\"{}\"", path.display()),
+        None => "mode=null".to_string(),
+    };
+    // there's nobody to ask without a window
+    let panic = if options.headless { "fatal" } else { "ask" };
+    let log = match &options.bochs.log {
+        Some(path) => path.display().to_string(),
+        None => "-".to_string(),
+    };
     let mut file = NamedTempFile::new()?;
     write!(file.as_file_mut(), "
 # partly taken from https://forum.osdev.org/viewtopic.php?f=1&t=33440
-display_library: x
-megs: 768
+display_library: {display_library}
+megs: {memory}
+cpu: count={cpus}
 romimage: file=\"{ovmf}\", address=0x0, options=none
-vgaromimage: file=\"/usr/share/bochs/VGABIOS-lgpl-latest\"
+vgaromimage: file=\"{vga_rom}\"
 ata0: enabled=1, ioaddr1=0x1f0, ioaddr2=0x3f0, irq=14
 ata0-master: type=disk, path=\"{image}\", mode=flat, cylinders=0, heads=0, spt=0, sect_size=512, model=\"Generic 1234\", biosdetect=auto, translation=auto
 ata0-slave: type=none
@@ -26,18 +145,17 @@ print_timestamps: enabled=0
 port_e9_hack: enabled=0
 private_colormap: enabled=0
 clock: sync=none, time0=local, rtc_sync=0
-log: -
+log: {log}
 logprefix: %t%e%d
 debug: action=ignore
 info: action=report
 error: action=report
-panic: action=ask
+panic: action={panic}
 keyboard: type=mf, serial_delay=250, paste_delay=100000, user_shortcut=none
 mouse: type=ps2, enabled=0, toggle=ctrl+mbutton
-sound: waveoutdrv=win, waveout=none, waveindrv=win, wavein=none, midioutdrv=win, midiout=none
-speaker: enabled=1, mode=sound
+speaker: enabled=0
 parport1: enabled=1, file=none
-com1: enabled=1, mode=null
+com1: enabled=1, {com1}
 gdbstub: enabled={gdb}, port=1234, text_base=0, data_base=0, bss_base=0
 ")?;
     Ok(file)
@@ -0,0 +1,97 @@
+//! This module registers installs with the firmware's boot manager.
+//!
+//! It creates (and removes) `Boot####` entries via efibootmgr.
+//! Load options are stored as the entry's optional data, where towboot
+//! picks them up just like arguments given in the UEFI shell.
+use std::fs;
+use std::path::{Component, Path};
+use std::process::Command;
+
+use anyhow::{Result, anyhow};
+use log::info;
+
+use super::esp::run;
+
+/// Fail if boot entries can't be managed on this operating system.
+fn check_supported() -> Result<()> {
+    if cfg!(target_os = "linux") {
+        Ok(())
+    } else {
+        Err(anyhow!("managing boot entries is not supported on this operating system"))
+    }
+}
+
+/// Find the disk and the partition number of the filesystem mounted at the given path.
+fn disk_and_partition(path: &Path) -> Result<(String, u32)> {
+    let output = run(Command::new("findmnt")
+        .args(["--noheadings", "--output", "SOURCE", "--target"]).arg(path))?;
+    let partition = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let output = run(Command::new("lsblk")
+        .args(["--noheadings", "--paths", "--output", "PKNAME"]).arg(&partition))?;
+    let disk = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let name = Path::new(&partition).file_name()
+        .ok_or_else(|| anyhow!("{partition} is not a partition"))?;
+    let number = fs::read_to_string(Path::new("/sys/class/block").join(name).join("partition"))
+        .map_err(|_| anyhow!("{partition} is not a partition"))?
+        .trim().parse()?;
+    Ok((disk, number))
+}
+
+/// Convert a path on the ESP to the form UEFI expects (`\EFI\yourOS`).
+fn uefi_path(esp_path: &Path, path: &Path) -> Result<String> {
+    let mut uefi_path = String::new();
+    for component in path.strip_prefix(esp_path)?.components() {
+        let Component::Normal(component) = component else {
+            return Err(anyhow!("{} is not a plain path", path.display()));
+        };
+        uefi_path.push('\\');
+        uefi_path.push_str(&component.to_string_lossy());
+    }
+    Ok(uefi_path)
+}
+
+/// Create a boot entry for the towboot binary at `loader` (on the ESP).
+///
+/// `load_options` (as created by [`super::runtime_args_to_load_options`])
+/// are stored in the entry, so that towboot can be configured without a
+/// configuration file on the ESP.
+pub fn register(
+    esp_path: &Path, loader: &Path, label: &str, load_options: Option<&str>,
+) -> Result<()> {
+    check_supported()?;
+    let (disk, partition) = disk_and_partition(esp_path)?;
+    let loader = uefi_path(esp_path, loader)?;
+    info!("registering {loader} as '{label}'");
+    let mut command = Command::new("efibootmgr");
+    command
+        .arg("--create")
+        .arg("--disk").arg(&disk)
+        .arg("--part").arg(partition.to_string())
+        .arg("--label").arg(label)
+        .arg("--loader").arg(&loader);
+    if let Some(load_options) = load_options {
+        // towboot expects UCS-2, just like the shell passes it
+        command.arg("--unicode").arg(load_options);
+    }
+    run(&mut command)?;
+    Ok(())
+}
+
+/// Remove all boot entries with the given label.
+pub fn unregister(label: &str) -> Result<()> {
+    check_supported()?;
+    // the lines look like this: `Boot0003* yourOS	HD(1,GPT,...)/File(...)`
+    let output = run(&mut Command::new("efibootmgr"))?;
+    let numbers: Vec<String> = String::from_utf8_lossy(&output.stdout).lines()
+        .filter_map(|line| line.strip_prefix("Boot"))
+        .filter_map(|line| line.split_once(' '))
+        .filter(|(_, rest)| rest.split('\t').next() == Some(label))
+        .map(|(number, _)| number.trim_end_matches('*').to_string())
+        .filter(|number| number.len() == 4)
+        .collect();
+    for number in numbers {
+        info!("removing the boot entry Boot{number}");
+        run(Command::new("efibootmgr").args(["--delete-bootnum", "--bootnum", &number]))?;
+    }
+    Ok(())
+}
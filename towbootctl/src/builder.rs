@@ -0,0 +1,335 @@
+//! This module contains a builder for images.
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use log::{debug, info};
+use uuid::Uuid;
+
+use towboot_config::Config;
+
+use super::{
+    AA64_BOOT_PATH, DEFAULT_ESP_SIZE, DEFAULT_PARTITION_NAME, DEFAULT_SECTOR_SIZE, Error,
+    IA32_BOOT_PATH, Image, Partition, Progress, RISCV64_BOOT_PATH, X64_BOOT_PATH, config,
+    runtime_args_to_load_options,
+};
+use super::error::Result;
+
+/// How much space to reserve for the partition table and the filesystem
+const OVERHEAD: u64 = 0x00_20_00_00;
+
+/// How much space to reserve for aligning each additional partition
+const PARTITION_OVERHEAD: u64 = 0x00_10_00_00;
+
+/// Where a file in the image comes from
+enum Source {
+    /// a file on the local filesystem
+    File(PathBuf),
+    /// data in memory
+    Data(Vec<u8>),
+}
+
+/// Get the source and destination paths of all files referenced in the config.
+///
+/// This also rewrites the paths in the config to point to the copies.
+/// Paths with wildcards include all matching files (see [`config::copy_destinations`]).
+fn get_config_files(config: &mut Config) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut paths = Vec::<(PathBuf, PathBuf)>::new();
+    let mut directories = Vec::new();
+    let mut config_path = PathBuf::from(config.src.clone());
+    config_path.pop();
+
+    // go through all needed files; including them (but without the original path)
+    for src_file in config.needed_files() {
+        let src_path = config_path.join(PathBuf::from(&src_file));
+        let (dst_file, files) = config::copy_destinations(&src_path, &mut directories)
+            .map_err(|e| Error::file(&src_path, e))?;
+        paths.extend(files);
+        *src_file = dst_file;
+    }
+
+    Ok(paths)
+}
+
+/// A builder for an [`Image`].
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use towbootctl::Image;
+/// let image = Image::builder()
+///     .label("MYOS")
+///     .add_towboot_x64(Path::new("towboot.efi"))
+///     .add_file(Path::new("kernel.elf"), Path::new("kernel.elf"))
+///     .build(Path::new("image.img"))?;
+/// # Ok::<(), towbootctl::Error>(())
+/// ```
+pub struct ImageBuilder {
+    size: Option<u64>,
+    sector_size: u64,
+    partition_name: String,
+    volume_label: Option<String>,
+    disk_guid: Option<Uuid>,
+    partition_guid: Option<Uuid>,
+    config: Option<Config>,
+    files: Vec<(Source, PathBuf)>,
+    partitions: Vec<Partition>,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self {
+            size: None,
+            sector_size: DEFAULT_SECTOR_SIZE,
+            partition_name: DEFAULT_PARTITION_NAME.to_string(),
+            volume_label: None,
+            disk_guid: None,
+            partition_guid: None,
+            config: None,
+            files: Vec::new(),
+            partitions: Vec::new(),
+        }
+    }
+}
+
+impl Image {
+    /// Start building a new image.
+    pub fn builder() -> ImageBuilder {
+        ImageBuilder::default()
+    }
+
+    /// Create an image at the given location containing the given directory tree.
+    /// If the file exists already, it will be overwritten.
+    pub fn from_directory(source: &Path, target: &Path) -> Result<Image> {
+        Self::builder().add_directory(source, Path::new(""))?.build(target)
+    }
+}
+
+impl ImageBuilder {
+    /// Set the size of the image.
+    ///
+    /// By default, the image is just big enough for its contents.
+    pub fn size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Set the logical sector size (512 or 4096 bytes).
+    ///
+    /// By default, the image has 512 byte sectors.
+    pub fn sector_size(mut self, sector_size: u64) -> Self {
+        self.sector_size = sector_size;
+        self
+    }
+
+    /// Set the name of the partition.
+    pub fn partition_name(mut self, name: &str) -> Self {
+        self.partition_name = name.to_string();
+        self
+    }
+
+    /// Set the label of the filesystem.
+    pub fn label(mut self, label: &str) -> Self {
+        self.volume_label = Some(label.to_string());
+        self
+    }
+
+    /// Set the GUID of the disk.
+    ///
+    /// By default, it is random.
+    pub fn disk_guid(mut self, guid: Uuid) -> Self {
+        self.disk_guid = Some(guid);
+        self
+    }
+
+    /// Set the unique GUID of the partition (as in `PARTUUID`).
+    ///
+    /// By default, it is random.
+    pub fn partition_guid(mut self, guid: Uuid) -> Self {
+        self.partition_guid = Some(guid);
+        self
+    }
+
+    /// Add a file from the local filesystem.
+    pub fn add_file(mut self, source: &Path, dest: &Path) -> Self {
+        self.files.push((Source::File(source.to_path_buf()), dest.to_path_buf()));
+        self
+    }
+
+    /// Add a file with the given contents.
+    pub fn add_data(mut self, data: &[u8], dest: &Path) -> Self {
+        self.files.push((Source::Data(data.to_vec()), dest.to_path_buf()));
+        self
+    }
+
+    /// Add all files in a directory on the local filesystem (recursively),
+    /// keeping their layout.
+    ///
+    /// Empty directories are skipped.
+    pub fn add_directory(mut self, source: &Path, dest: &Path) -> Result<Self> {
+        for entry in fs::read_dir(source).map_err(|e| Error::file(source, e))? {
+            let entry = entry?;
+            let dest = dest.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                self = self.add_directory(&entry.path(), &dest)?;
+            } else {
+                self = self.add_file(&entry.path(), &dest);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Add the 32-bit build of towboot.
+    pub fn add_towboot_ia32(self, source: &Path) -> Self {
+        self.add_file(source, Path::new(IA32_BOOT_PATH))
+    }
+
+    /// Add the 64-bit build of towboot.
+    pub fn add_towboot_x64(self, source: &Path) -> Self {
+        self.add_file(source, Path::new(X64_BOOT_PATH))
+    }
+
+    /// Add the aarch64 build of towboot.
+    pub fn add_towboot_aa64(self, source: &Path) -> Self {
+        self.add_file(source, Path::new(AA64_BOOT_PATH))
+    }
+
+    /// Add the riscv64 build of towboot.
+    pub fn add_towboot_riscv64(self, source: &Path) -> Self {
+        self.add_file(source, Path::new(RISCV64_BOOT_PATH))
+    }
+
+    /// Add another partition after the EFI System Partition.
+    pub fn add_partition(mut self, partition: Partition) -> Self {
+        self.partitions.push(partition);
+        self
+    }
+
+    /// Add a configuration.
+    ///
+    /// All kernels and modules it references are added, too.
+    /// They're placed next to the configuration file, so their paths inside
+    /// the configuration get rewritten.
+    pub fn add_config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Add the configuration given by towboot's runtime options.
+    ///
+    /// This does nothing if they don't contain a configuration.
+    pub fn add_runtime_args(self, runtime_args: &[String]) -> Result<Self> {
+        let load_options = runtime_args_to_load_options(runtime_args);
+        Ok(match config::get(&load_options).map_err(Error::config)? {
+            Some(config) => self.add_config(config),
+            None => self,
+        })
+    }
+
+    /// Create the image at the given location.
+    /// If the file exists already, it will be overwritten.
+    pub fn build(self, target: &Path) -> Result<Image> {
+        self.build_with_progress(target, &mut |_| ())
+    }
+
+    /// Create the image at the given location, calling the callback for each step.
+    /// If the file exists already, it will be overwritten.
+    pub fn build_with_progress(
+        self, target: &Path, progress: &mut dyn FnMut(Progress),
+    ) -> Result<Image> {
+        let builder = self.add_config_files()?;
+        let size = match builder.size {
+            Some(size) => size,
+            None => {
+                info!("calculating image size");
+                progress(Progress::CalculatingSize);
+                let mut size = OVERHEAD;
+                for (source, _) in builder.files.iter() {
+                    size += match source {
+                        Source::File(path) => fs::metadata(path)
+                            .map_err(|e| Error::file(path, e))?.len(),
+                        Source::Data(data) => data.len() as u64,
+                    };
+                }
+                for partition in builder.partitions.iter() {
+                    size += partition.size()? + PARTITION_OVERHEAD;
+                }
+                size
+            },
+        };
+
+        info!("creating image at {} (size: {} MiB)", target.display(), size / 1024 / 1024);
+        progress(Progress::CreatingImage);
+        let image = Image::new(
+            target, size, &builder.partition_name, builder.volume_label.as_deref(),
+            builder.sector_size, builder.disk_guid, builder.partition_guid, &builder.partitions,
+        )?;
+        builder.fill(image, progress)
+    }
+
+    /// Add an EFI System Partition to an existing GPT disk and put the files there.
+    ///
+    /// The partition is placed in free space and gets the size that has been
+    /// set (or [`DEFAULT_ESP_SIZE`]); see [`Image::add_esp`].
+    /// The sector size, the disk GUID and additional partitions don't apply here.
+    ///
+    /// `confirm` gets a description of the changes to the disk; if it returns
+    /// false, nothing is written and this returns None.
+    pub fn build_on_disk(
+        self, device: &Path, confirm: &mut dyn FnMut(&str) -> bool,
+        progress: &mut dyn FnMut(Progress),
+    ) -> Result<Option<Image>> {
+        if !self.partitions.is_empty() {
+            return Err(Error::image("additional partitions can't be added to an existing disk"));
+        }
+        let builder = self.add_config_files()?;
+        progress(Progress::CreatingImage);
+        let Some(image) = Image::add_esp(
+            device, builder.size.unwrap_or(DEFAULT_ESP_SIZE), &builder.partition_name,
+            builder.volume_label.as_deref(), builder.partition_guid, confirm,
+        )? else {
+            return Ok(None);
+        };
+        info!("created an EFI System Partition on {}", device.display());
+        builder.fill(image, progress).map(Some)
+    }
+
+    /// Turn the configuration into files to add.
+    fn add_config_files(mut self) -> Result<Self> {
+        if let Some(mut config) = self.config.take() {
+            // this also sets the correct config file paths inside the image
+            for (src, dst) in get_config_files(&mut config)? {
+                self = self.add_file(&src, &dst);
+            }
+            let config = config::to_string(&config).map_err(Error::config)?;
+            self = self.add_data(config.as_bytes(), Path::new("towboot.toml"));
+        }
+        Ok(self)
+    }
+
+    /// Write the files into the image.
+    fn fill(self, mut image: Image, progress: &mut dyn FnMut(Progress)) -> Result<Image> {
+        for (source, dest) in self.files {
+            let (mut reader, total): (Box<dyn Read>, u64) = match source {
+                Source::File(path) => {
+                    debug!("adding {} as {}", path.display(), dest.display());
+                    let file = File::open(&path).map_err(|e| Error::file(&path, e))?;
+                    let total = file.metadata()?.len();
+                    (Box::new(file), total)
+                },
+                Source::Data(data) => {
+                    let total = data.len() as u64;
+                    (Box::new(Cursor::new(data)), total)
+                },
+            };
+            let start = Instant::now();
+            image.write_file(&mut reader, &dest, &mut |done| progress(Progress::Copying {
+                path: &dest, done, total,
+            }))?;
+            let duration_ms = start.elapsed().as_millis() as u64;
+            info!(file:% = dest.display(), bytes = total, duration_ms; "added {}", dest.display());
+        }
+        progress(Progress::Done);
+        Ok(image)
+    }
+}
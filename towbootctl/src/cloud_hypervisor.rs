@@ -0,0 +1,33 @@
+//! This module allows booting with cloud-hypervisor.
+//!
+//! It needs a special build of edk2, see [`super::firmware::cloud_hypervisor`].
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+/// Get the command to boot the image with cloud-hypervisor.
+///
+/// The memory is given in MiB.
+pub fn cloud_hypervisor(
+    firmware: &Path, image: &Path, serial_log: Option<&Path>, memory: u32, cpus: u32,
+) -> Command {
+    let mut disk = OsString::from("path=");
+    disk.push(image);
+    let serial = match serial_log {
+        Some(path) => {
+            let mut serial = OsString::from("file=");
+            serial.push(path);
+            serial
+        },
+        None => OsString::from("tty"),
+    };
+    let mut command = Command::new("cloud-hypervisor");
+    command
+        .arg("--firmware").arg(firmware)
+        .arg("--disk").arg(disk)
+        .arg("--cpus").arg(format!("boot={cpus}"))
+        .arg("--memory").arg(format!("size={memory}M"))
+        .arg("--serial").arg(serial)
+        .arg("--console").arg("off");
+    command
+}
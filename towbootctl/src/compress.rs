@@ -0,0 +1,85 @@
+//! This module compresses built images.
+//!
+//! Most of an image is empty space, so it compresses very well.
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::Result;
+use log::info;
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+/// The compression level for zstd
+const ZSTD_LEVEL: i32 = 19;
+
+/// The compression level for xz
+const XZ_LEVEL: u32 = 9;
+
+/// How to compress an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Xz,
+    Zip,
+}
+
+impl Compression {
+    /// The extension that gets appended to the image's file name.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Zstd => "zst",
+            Self::Xz => "xz",
+            Self::Zip => "zip",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "zstd" | "zst" => Ok(Self::Zstd),
+            "xz" => Ok(Self::Xz),
+            "zip" => Ok(Self::Zip),
+            _ => Err(format!("unknown compression: {s}")),
+        }
+    }
+}
+
+/// Compress the image, placing the result next to it.
+///
+/// Returns the path of the compressed file.
+pub fn compress(image: &Path, compression: Compression) -> Result<PathBuf> {
+    let mut target = image.as_os_str().to_owned();
+    target.push(".");
+    target.push(compression.extension());
+    let target = PathBuf::from(target);
+    info!("compressing {} to {}", image.display(), target.display());
+    let mut source = BufReader::new(File::open(image)?);
+    let file = BufWriter::new(File::create(&target)?);
+    match compression {
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(file, ZSTD_LEVEL)?;
+            io::copy(&mut source, &mut encoder)?;
+            encoder.finish()?.flush()?;
+        },
+        Compression::Xz => {
+            let mut encoder = xz2::write::XzEncoder::new(file, XZ_LEVEL);
+            io::copy(&mut source, &mut encoder)?;
+            encoder.finish()?.flush()?;
+        },
+        Compression::Zip => {
+            let mut zip = ZipWriter::new(file);
+            // images might be larger than 4 GiB
+            let options = SimpleFileOptions::default().large_file(true);
+            let name = image.file_name().unwrap_or(image.as_os_str()).to_string_lossy();
+            zip.start_file(name, options)?;
+            io::copy(&mut source, &mut zip)?;
+            zip.finish()?.flush()?;
+        },
+    }
+    Ok(target)
+}
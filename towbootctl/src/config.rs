@@ -5,11 +5,17 @@
 //!
 //! Most of the actual structs can be found in the [`towboot_config`] crate.
 //! The towboot package has its own config.rs.
-use std::fs::read_to_string;
+use std::collections::BTreeMap;
+use std::fs::{read_dir, read_to_string};
+use std::io;
+use std::path::{MAIN_SEPARATOR_STR, Path, PathBuf, absolute};
 
 use anyhow::{Result, anyhow};
+use toml_edit::{DocumentMut, Item, TableLike, Value};
 
-use towboot_config::{Config, ConfigSource, parse_load_options};
+use towboot_config::{
+    Config, ConfigSource, Entry, Module, glob_matches, parse_load_options, split_glob,
+};
 
 /// Get the config.
 /// If there are command line options, try them first.
@@ -19,16 +25,198 @@ use towboot_config::{Config, ConfigSource, parse_load_options};
 pub fn get(load_options: &str) -> Result<Option<Config>> {
     match parse_load_options(load_options, "") {
         Ok(Some(ConfigSource::File(s))) => Ok(Some(read_file(&s)?)),
+        Ok(Some(ConfigSource::Search(paths))) => Ok(Some(read_file(&search(&paths)?)?)),
         Ok(Some(ConfigSource::Given(c))) => Ok(Some(c)),
         Ok(None) => Ok(None),
         Err(()) => Err(anyhow!("invalid parameters")),
     }
 }
 
+/// Find the first of the given files that exists.
+///
+/// The paths are meant for the ESP, so they're looked up relative to the
+/// current directory.
+fn search(paths: &[String]) -> Result<String> {
+    paths.iter()
+        .map(|p| p.trim_start_matches('\\').replace('\\', "/"))
+        .find(|p| Path::new(p).is_file())
+        .ok_or_else(|| anyhow!("failed to find a configuration file, tried {}", paths.join(", ")))
+}
+
+/// Split a definition of the form `name: image argv` into its parts.
+///
+/// The image is made absolute, so that the resulting configuration does not
+/// depend on where it is stored.
+fn split_definition(definition: &str) -> Result<(String, String, String)> {
+    let (name, rest) = definition.split_once(':')
+        .ok_or_else(|| anyhow!("{definition} is missing a name"))?;
+    let rest = rest.trim();
+    let (image, argv) = rest.split_once(' ').unwrap_or((rest, ""));
+    if image.is_empty() {
+        return Err(anyhow!("{definition} is missing an image"));
+    }
+    Ok((
+        name.trim().to_string(),
+        absolute(image)?.to_str().ok_or_else(|| anyhow!("invalid path {image}"))?.to_string(),
+        argv.trim().to_string(),
+    ))
+}
+
+/// Build a configuration from entries of the form `name: kernel argv` and
+/// modules of the form `name: module argv`.
+///
+/// The first entry is the default one.
+pub fn from_entries(entries: &[String], modules: &[String]) -> Result<Config> {
+    let mut modules_by_entry = BTreeMap::<String, Vec<Module>>::new();
+    for definition in modules {
+        let (name, image, argv) = split_definition(definition)?;
+        modules_by_entry.entry(name).or_default()
+            .push(Module { argv: Some(argv), ..Module::new(&image) });
+    }
+    let mut config = Config::builder();
+    for (index, definition) in entries.iter().enumerate() {
+        let (name, image, argv) = split_definition(definition)?;
+        if index == 0 {
+            config = config.default(&name);
+        }
+        let mut entry = Entry::builder(&image).name(&name).argv(&argv);
+        for module in modules_by_entry.remove(&name).unwrap_or_default() {
+            entry = entry.module(module);
+        }
+        config = config.entry(&name, entry.build());
+    }
+    if let Some((name, modules)) = modules_by_entry.first_key_value() {
+        return Err(anyhow!("there is no entry {name} for module {}", modules[0].image));
+    }
+    Ok(config.build()?)
+}
+
 /// Try to read and parse the configuration from the given file.
-fn read_file(file_name: &str) -> Result<Config> {
+pub fn read_file(file_name: &str) -> Result<Config> {
     let text = read_to_string(file_name)?;
-    let mut config: Config = toml::from_str(&text).expect("failed to parse config file");
+    let mut config: Config = toml::from_str(&text)
+        .map_err(|e| anyhow!("failed to parse {file_name}: {e}"))?;
     config.src = file_name.to_string();
+    config.apply_renamed_keys();
     Ok(config)
 }
+
+/// Find the files a path from a configuration refers to.
+///
+/// If its file name contains wildcards, these are all matching files in its
+/// directory, sorted by name (just like towboot does). Otherwise, it's just the path.
+pub fn expand_path(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let Some((directory, pattern)) = path.to_str().and_then(split_glob) else {
+        return Ok(vec![path.to_path_buf()]);
+    };
+    // the directory may be separated by backslashes, as towboot expects
+    let directory = match directory {
+        "" => PathBuf::from("."),
+        directory => PathBuf::from(directory.replace(['\\', '/'], MAIN_SEPARATOR_STR)),
+    };
+    let mut files = Vec::new();
+    for entry in read_dir(directory)? {
+        let entry = entry?;
+        let matches = entry.file_name().to_str().is_some_and(|name| glob_matches(pattern, name));
+        if matches && entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Find out where to copy the files a path from a configuration refers to.
+///
+/// Files are copied next to the configuration, keeping just their names.
+/// The matches of a path with wildcards get a directory of their own (named
+/// like the one they come from), so that the pattern can't match anything else;
+/// `directories` are the ones that have already been taken.
+///
+/// Returns the path to put into the configuration and the files to copy
+/// (with their destinations relative to the configuration).
+pub fn copy_destinations(
+    path: &Path, directories: &mut Vec<String>,
+) -> io::Result<(String, Vec<(PathBuf, PathBuf)>)> {
+    let Some((directory, pattern)) = path.to_str().and_then(split_glob) else {
+        let name = path.file_name().unwrap();
+        return Ok((
+            name.to_str().unwrap().to_string(), vec![(path.to_path_buf(), PathBuf::from(name))],
+        ));
+    };
+    let base = directory.rsplit(['\\', '/']).next()
+        .filter(|name| !name.is_empty() && *name != "." && *name != "..")
+        .unwrap_or("modules");
+    let mut name = base.to_string();
+    for i in 2.. {
+        if !directories.contains(&name) {
+            break;
+        }
+        name = format!("{base}-{i}");
+    }
+    directories.push(name.clone());
+    let files = expand_path(path)?.into_iter()
+        .map(|file| {
+            let destination = Path::new(&name).join(file.file_name().unwrap());
+            (file, destination)
+        })
+        .collect();
+    Ok((format!("{name}\\{pattern}"), files))
+}
+
+/// Replace a string in a table, keeping the comments around it.
+fn replace_string(table: &mut dyn TableLike, key: &str, new: &str) {
+    match table.get_mut(key).and_then(Item::as_value_mut) {
+        Some(value) => {
+            let decor = value.decor().clone();
+            *value = new.into();
+            *value.decor_mut() = decor;
+        },
+        None => {
+            table.insert(key, toml_edit::value(new));
+        },
+    }
+}
+
+/// Serialize the configuration, keeping the comments and the layout of the
+/// file it has been read from.
+///
+/// Only the paths of images, command line files and modules are taken from
+/// `config`, as these are what towbootctl changes when copying the files.
+/// If the configuration doesn't come from a file, it is just serialized.
+pub fn to_string(config: &Config) -> Result<String> {
+    let Ok(source) = read_to_string(&config.src) else {
+        return Ok(toml::to_string(config)?);
+    };
+    let mut document: DocumentMut = source.parse()
+        .map_err(|e| anyhow!("failed to parse {}: {e}", config.src))?;
+    let entries = document.get_mut("entries").and_then(Item::as_table_like_mut)
+        .ok_or_else(|| anyhow!("{} has no entries", config.src))?;
+    for (key, entry) in &config.entries {
+        let table = entries.get_mut(key).and_then(Item::as_table_like_mut)
+            .ok_or_else(|| anyhow!("{} has no entry {key}", config.src))?;
+        if !entry.image.is_empty() {
+            replace_string(table, "image", &entry.image);
+        }
+        if let Some(cmdline_file) = &entry.cmdline_file {
+            replace_string(table, "cmdline_file", cmdline_file);
+        }
+        match table.get_mut("modules") {
+            None => (),
+            Some(Item::ArrayOfTables(modules)) => {
+                for (table, module) in modules.iter_mut().zip(&entry.modules) {
+                    replace_string(table, "image", &module.image);
+                }
+            },
+            Some(Item::Value(Value::Array(modules))) => {
+                for (value, module) in modules.iter_mut().zip(&entry.modules) {
+                    if let Some(table) = value.as_inline_table_mut() {
+                        replace_string(table, "image", &module.image);
+                    }
+                }
+            },
+            Some(_) => return Err(anyhow!("the modules of {key} are not a list")),
+        }
+    }
+    Ok(document.to_string())
+}
@@ -0,0 +1,31 @@
+//! This module allows booting with crosvm.
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+/// Get the command to boot the image with crosvm.
+///
+/// The memory is given in MiB.
+pub fn crosvm(
+    firmware: &Path, image: &Path, serial_log: Option<&Path>, memory: u32, cpus: u32,
+) -> Command {
+    let mut disk = OsString::from("path=");
+    disk.push(image);
+    let serial = match serial_log {
+        Some(path) => {
+            let mut serial = OsString::from("type=file,hardware=serial,num=1,path=");
+            serial.push(path);
+            serial
+        },
+        None => OsString::from("type=stdout,hardware=serial,num=1"),
+    };
+    let mut command = Command::new("crosvm");
+    command
+        .arg("run")
+        .arg("--mem").arg(memory.to_string())
+        .arg("--cpus").arg(cpus.to_string())
+        .arg("--bios").arg(firmware)
+        .arg("--block").arg(disk)
+        .arg("--serial").arg(serial);
+    command
+}
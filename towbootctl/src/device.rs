@@ -0,0 +1,100 @@
+//! This module writes images to block devices (such as USB sticks).
+//!
+//! Devices are given as `/dev/sdX` (Linux), `/dev/diskN` (macOS) or
+//! `\\.\PhysicalDriveN` (Windows).
+//! The device must not be mounted.
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+
+/// How much to copy at once
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Determine the size of a device (or file).
+///
+/// This returns None if the size can't be determined.
+pub fn size(device: &Path) -> Result<Option<u64>> {
+    let mut file = File::open(device)?;
+    // The metadata of block devices has a length of 0, but seeking works.
+    Ok(file.seek(SeekFrom::End(0)).ok().filter(|size| *size > 0))
+}
+
+/// Fail if the device (or one of its partitions) is mounted.
+///
+/// This can only be checked on Linux; elsewhere, it's up to the user.
+pub fn check_not_mounted(device: &Path) -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        return Ok(());
+    }
+    let Ok(mounts) = fs::read_to_string("/proc/self/mounts") else {
+        warn!("failed to check whether {} is mounted", device.display());
+        return Ok(());
+    };
+    let device = device.canonicalize()?;
+    let Some(name) = device.file_name() else {
+        return Ok(());
+    };
+    for line in mounts.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(source), Some(target)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        // pseudo filesystems have names like "proc" as their source
+        if !source.starts_with('/') {
+            continue;
+        }
+        let Ok(source) = Path::new(source).canonicalize() else {
+            continue;
+        };
+        // the partitions of a disk are listed below it in sysfs
+        let is_partition = source.file_name().is_some_and(
+            |partition| Path::new("/sys/class/block").join(name).join(partition).exists()
+        );
+        if source == device || is_partition {
+            return Err(anyhow!(
+                "{} is mounted at {target}, please unmount it first", source.display(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Write the image to the device, overwriting everything on it.
+///
+/// The image must fit on the device; if it is smaller, the rest is left as is.
+pub fn write(image: &Path, device: &Path) -> Result<()> {
+    check_not_mounted(device)?;
+    let mut source = File::open(image)?;
+    let image_size = source.metadata()?.len();
+    match size(device)? {
+        Some(device_size) if device_size < image_size => return Err(anyhow!(
+            "{} is too small ({} MiB) for the image ({} MiB)",
+            device.display(), device_size / 1024 / 1024, image_size / 1024 / 1024,
+        )),
+        Some(_) => (),
+        None => warn!("failed to determine the size of {}", device.display()),
+    }
+    let mut dest = OpenOptions::new().write(true).open(device)?;
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut written = 0;
+    loop {
+        let length = source.read(&mut buf)?;
+        if length == 0 {
+            break;
+        }
+        dest.write_all(&buf[..length])?;
+        written += length as u64;
+        if written % (16 * CHUNK_SIZE as u64) == 0 || written == image_size {
+            info!(
+                "written {} of {} MiB ({}%)",
+                written / 1024 / 1024, image_size / 1024 / 1024, written * 100 / image_size,
+            );
+        }
+    }
+    info!("flushing {}", device.display());
+    dest.sync_all()?;
+    Ok(())
+}
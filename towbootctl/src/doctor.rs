@@ -0,0 +1,185 @@
+//! This module checks whether the host can build and boot images.
+//!
+//! Most problems on the first run come from the environment (a missing QEMU,
+//! no access to KVM, no network to download the firmware), so every check
+//! comes with a suggestion how to fix it.
+use std::env;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::process::Command;
+
+use super::bochs::in_path;
+use super::{Arch, Pin, firmware};
+
+/// How much free space there should be at least (in bytes)
+const MIN_FREE_SPACE: u64 = 1024 * 1024 * 1024;
+
+/// The outcome of a check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// everything is fine
+    Ok,
+    /// something optional is missing
+    Warning,
+    /// something is missing that towbootctl needs
+    Error,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ok => "ok",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        })
+    }
+}
+
+/// A single check of the environment
+#[derive(Debug)]
+pub struct Check {
+    /// what has been checked
+    pub name: String,
+    pub status: Status,
+    /// what has been found
+    pub detail: String,
+    /// how to fix a problem
+    pub suggestion: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &str, detail: String) -> Self {
+        Self { name: name.to_string(), status: Status::Ok, detail, suggestion: None }
+    }
+
+    fn problem(name: &str, status: Status, detail: String, suggestion: &str) -> Self {
+        Self {
+            name: name.to_string(), status, detail, suggestion: Some(suggestion.to_string()),
+        }
+    }
+}
+
+/// Get the first line a program prints when called with `--version`.
+fn version(program: &str) -> Option<String> {
+    let output = Command::new(program).arg("--version").output().ok()?;
+    String::from_utf8_lossy(&output.stdout).lines().next().map(str::to_string)
+}
+
+/// Check that QEMU for the given system is there.
+fn check_qemu(system: &str, status: Status) -> Check {
+    let program = format!("qemu-system-{system}");
+    match version(&program) {
+        Some(version) => Check::ok(&program, version),
+        None => Check::problem(
+            &program, status, "not found".to_string(),
+            "install QEMU (e.g. the package qemu-system-x86 or qemu-full)",
+        ),
+    }
+}
+
+/// Check whether KVM can be used.
+fn check_kvm() -> Check {
+    if !cfg!(target_os = "linux") {
+        return Check::problem(
+            "KVM", Status::Warning, "only available on Linux".to_string(),
+            "booting works without --kvm, just slower",
+        );
+    }
+    let kvm = Path::new("/dev/kvm");
+    if !kvm.exists() {
+        return Check::problem(
+            "KVM", Status::Warning, "/dev/kvm does not exist".to_string(),
+            "enable virtualization in the firmware settings and load the kvm module; \
+            booting still works without --kvm, just slower",
+        );
+    }
+    match OpenOptions::new().read(true).write(true).open(kvm) {
+        Ok(_) => Check::ok("KVM", "/dev/kvm is usable".to_string()),
+        Err(e) => Check::problem(
+            "KVM", Status::Warning, format!("/dev/kvm can't be opened: {e}"),
+            "add yourself to the kvm group (and log in again)",
+        ),
+    }
+}
+
+/// Check whether Bochs is there.
+fn check_bochs() -> Check {
+    if in_path("bochs") {
+        Check::ok("Bochs", "found in the PATH".to_string())
+    } else {
+        Check::problem(
+            "Bochs", Status::Warning, "not found".to_string(),
+            "install Bochs to use --hypervisor bochs",
+        )
+    }
+}
+
+/// Check whether mtools are there.
+fn check_mtools() -> Check {
+    if in_path("mdir") {
+        Check::ok("mtools", "found in the PATH".to_string())
+    } else {
+        Check::problem(
+            "mtools", Status::Warning, "not found".to_string(),
+            "install mtools to look into images with mdir and mcopy \
+            (towbootctl doesn't need them itself)",
+        )
+    }
+}
+
+/// Check whether the firmware is installed or can be downloaded (or at least is cached).
+fn check_firmware(arch: Arch) -> Check {
+    let name = format!("firmware for {arch:?}");
+    match firmware::firmware(arch, &Pin::Latest) {
+        Ok(path) => Check::ok(&name, path.display().to_string()),
+        Err(e) => Check::problem(
+            &name, Status::Error, e.to_string(),
+            "install OVMF, check the network connection \
+            or pass a local build (see `towbootctl firmware`)",
+        ),
+    }
+}
+
+/// Get the free space on the filesystem the path is on (in bytes).
+fn free_space(path: &Path) -> Option<u64> {
+    let output = Command::new("df").arg("-Pk").arg(path).output().ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let available: u64 = output.lines().nth(1)?.split_whitespace().nth(3)?.parse().ok()?;
+    Some(available * 1024)
+}
+
+/// Check whether there is enough free space in the given directory.
+fn check_free_space(name: &str, path: &Path) -> Check {
+    match free_space(path) {
+        Some(free) if free >= MIN_FREE_SPACE => Check::ok(
+            name, format!("{} MiB free in {}", free / 1024 / 1024, path.display()),
+        ),
+        Some(free) => Check::problem(
+            name, Status::Warning,
+            format!("only {} MiB free in {}", free / 1024 / 1024, path.display()),
+            "free some space, images and firmware builds need a few hundred MiB",
+        ),
+        None => Check::problem(
+            name, Status::Warning, format!("can't determine the free space in {}", path.display()),
+            "make sure there is some space left",
+        ),
+    }
+}
+
+/// Run all checks.
+///
+/// This downloads the firmware, if it isn't cached yet.
+pub fn run() -> Vec<Check> {
+    vec![
+        check_qemu("i386", Status::Error),
+        check_qemu("x86_64", Status::Error),
+        check_kvm(),
+        check_bochs(),
+        check_mtools(),
+        check_firmware(Arch::I686),
+        check_firmware(Arch::X86_64),
+        check_free_space("free space (current directory)", Path::new(".")),
+        check_free_space("free space (temporary files)", &env::temp_dir()),
+    ]
+}
@@ -0,0 +1,60 @@
+//! This module contains the errors the library returns.
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+/// Something went wrong.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The configuration could not be loaded or is invalid.
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    /// A file (such as a kernel or a module) does not exist.
+    #[error("{} does not exist", .0.display())]
+    MissingFile(PathBuf),
+    /// The image could not be created, read or written.
+    #[error("failed to access the image: {0}")]
+    Image(String),
+    /// The firmware could not be found or downloaded.
+    #[error("failed to get the firmware: {0}")]
+    Firmware(String),
+    /// The virtual machine could not be launched.
+    #[error("failed to launch the virtual machine: {0}")]
+    Hypervisor(String),
+    /// Something else failed while accessing files.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl Error {
+    pub(crate) fn config(e: impl Display) -> Self {
+        Self::Config(e.to_string())
+    }
+
+    pub(crate) fn image(e: impl Display) -> Self {
+        Self::Image(e.to_string())
+    }
+
+    pub(crate) fn firmware(e: impl Display) -> Self {
+        Self::Firmware(e.to_string())
+    }
+
+    pub(crate) fn hypervisor(e: impl Display) -> Self {
+        Self::Hypervisor(e.to_string())
+    }
+
+    /// Convert an error that happened while accessing the given file.
+    pub(crate) fn file(path: &Path, e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::NotFound {
+            Self::MissingFile(path.to_path_buf())
+        } else {
+            Self::Io(e)
+        }
+    }
+}
+
+/// A result with our [`Error`]
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,148 @@
+//! This module finds the EFI System Partition of the running system.
+//!
+//! If it is not mounted, yet, it gets mounted until the [`Esp`] is dropped.
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use anyhow::{Result, anyhow};
+use log::{debug, info, warn};
+use tempfile::TempDir;
+
+/// The partition type GUID of an EFI System Partition
+#[cfg(target_os = "linux")]
+const ESP_GUID: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+
+/// The mounted EFI System Partition.
+pub struct Esp {
+    path: PathBuf,
+    /// how to unmount it again, if we mounted it
+    unmount: Option<Command>,
+    /// the mount point, if we created one
+    _dir: Option<TempDir>,
+}
+
+impl Esp {
+    /// Find the EFI System Partition and mount it if necessary.
+    pub fn find() -> Result<Self> {
+        let esp = find()?;
+        info!("found the ESP at {}", esp.path.display());
+        Ok(esp)
+    }
+
+    /// Get the path the ESP is mounted at.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Esp {
+    fn drop(&mut self) {
+        if let Some(mut unmount) = self.unmount.take() {
+            info!("unmounting {}", self.path.display());
+            if let Err(e) = run(&mut unmount) {
+                warn!("failed to unmount {}: {e}", self.path.display());
+            }
+        }
+    }
+}
+
+/// Run a command, failing if it does not succeed.
+pub(crate) fn run(command: &mut Command) -> Result<Output> {
+    debug!("running {command:?}");
+    let output = command.output()?;
+    if output.status.success() {
+        Ok(output)
+    } else {
+        Err(anyhow!(
+            "{command:?} failed with {}: {}",
+            output.status, String::from_utf8_lossy(&output.stderr).trim(),
+        ))
+    }
+}
+
+/// Find the ESP via lsblk and mount it to a temporary directory if necessary.
+#[cfg(target_os = "linux")]
+fn find() -> Result<Esp> {
+    use serde_json::Value;
+
+    /// Search the device tree for the first ESP.
+    fn search(devices: &[Value]) -> Option<&Value> {
+        devices.iter().find_map(|device| {
+            if device["parttype"].as_str().is_some_and(|t| t.eq_ignore_ascii_case(ESP_GUID)) {
+                Some(device)
+            } else {
+                search(device["children"].as_array()?)
+            }
+        })
+    }
+
+    let output = run(Command::new("lsblk").args([
+        "--json", "--paths", "--output", "PATH,PARTTYPE,MOUNTPOINT",
+    ]))?;
+    let tree: Value = serde_json::from_slice(&output.stdout)?;
+    let device = tree["blockdevices"].as_array()
+        .and_then(|devices| search(devices))
+        .ok_or_else(|| anyhow!("failed to find an EFI System Partition"))?;
+    let device_path = device["path"].as_str()
+        .ok_or_else(|| anyhow!("invalid output of lsblk"))?;
+    if let Some(mount_point) = device["mountpoint"].as_str() {
+        return Ok(Esp { path: PathBuf::from(mount_point), unmount: None, _dir: None });
+    }
+    let dir = TempDir::new()?;
+    info!("mounting {device_path} at {}", dir.path().display());
+    run(Command::new("mount").arg(device_path).arg(dir.path()))?;
+    let mut unmount = Command::new("umount");
+    unmount.arg(dir.path());
+    Ok(Esp { path: dir.path().to_path_buf(), unmount: Some(unmount), _dir: Some(dir) })
+}
+
+/// Mount the ESP to a free drive letter via mountvol.
+#[cfg(target_os = "windows")]
+fn find() -> Result<Esp> {
+    let letter = ('D'..='Z').rev()
+        .find(|l| !Path::new(&format!("{l}:\\")).exists())
+        .ok_or_else(|| anyhow!("there is no free drive letter to mount the ESP to"))?;
+    let drive = format!("{letter}:");
+    info!("mounting the ESP at {drive}");
+    run(Command::new("mountvol").args([drive.as_str(), "/S"]))?;
+    let mut unmount = Command::new("mountvol");
+    unmount.args([drive.as_str(), "/D"]);
+    Ok(Esp { path: PathBuf::from(format!("{drive}\\")), unmount: Some(unmount), _dir: None })
+}
+
+/// Find the ESP via diskutil and mount it if necessary.
+#[cfg(target_os = "macos")]
+fn find() -> Result<Esp> {
+    /// Get the mount point of a partition, if it is mounted.
+    fn mount_point(identifier: &str) -> Result<Option<PathBuf>> {
+        let output = run(Command::new("diskutil").args(["info", identifier]))?;
+        Ok(String::from_utf8_lossy(&output.stdout).lines()
+            .filter_map(|line| line.trim().strip_prefix("Mount Point:"))
+            .map(str::trim)
+            .find(|mount_point| !mount_point.is_empty())
+            .map(PathBuf::from))
+    }
+
+    // the lines look like this: `1: EFI EFI 209.7 MB disk0s1`
+    let output = run(Command::new("diskutil").arg("list"))?;
+    let identifier = String::from_utf8_lossy(&output.stdout).lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|columns| columns.get(1) == Some(&"EFI"))
+        .and_then(|columns| columns.last().map(|c| c.to_string()))
+        .ok_or_else(|| anyhow!("failed to find an EFI System Partition"))?;
+    if let Some(path) = mount_point(&identifier)? {
+        return Ok(Esp { path, unmount: None, _dir: None });
+    }
+    info!("mounting {identifier}");
+    run(Command::new("diskutil").args(["mount", identifier.as_str()]))?;
+    let mut unmount = Command::new("diskutil");
+    unmount.args(["unmount", identifier.as_str()]);
+    let path = mount_point(&identifier)?
+        .ok_or_else(|| anyhow!("{identifier} has been mounted, but has no mount point"))?;
+    Ok(Esp { path, unmount: Some(unmount), _dir: None })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+fn find() -> Result<Esp> {
+    Err(anyhow!("finding the ESP is not supported on this operating system"))
+}
@@ -1,37 +1,359 @@
 //! This module downloads and provides current builds of OVMF.
-//! 
+//!
 //! It uses [retrage/edk2-nightly](https://retrage.github.io/edk2-nightly/),
-//! as this provides builds for both x64 and ia32 as single files.
+//! as this provides builds for x64, ia32, aarch64 and riscv64 as single files.
 //! When <https://github.com/epwalsh/rust-cached-path/pull/74> is merged,
 //! we might want to switch back to the Arch Linux builds.
+//!
+//! Instead of the latest build, a local one can be used by setting
+//! `TOWBOOTCTL_FIRMWARE_IA32`, `TOWBOOTCTL_FIRMWARE_X64`,
+//! `TOWBOOTCTL_FIRMWARE_AA64` or `TOWBOOTCTL_FIRMWARE_RISCV64` to its path.
+//! Otherwise, a build installed on the system (by the distribution or with QEMU)
+//! is preferred over downloading one, unless `TOWBOOTCTL_NO_SYSTEM_FIRMWARE` is set.
+//!
+//! With the `embedded-firmware` feature, the latest builds (at compile time)
+//! are part of towbootctl itself. They are written to the cache directory on
+//! first use and used instead of downloading them.
 
-use std::path::PathBuf;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use cached_path::Cache;
 use directories::ProjectDirs;
+use log::{debug, info, warn};
 
-const OVMF_X64_URL: &str = "https://retrage.github.io/edk2-nightly/bin/RELEASEX64_OVMF.fd";
-const OVMF_IA32_URL: &str = "https://retrage.github.io/edk2-nightly/bin/RELEASEIa32_OVMF.fd";
+use super::Arch;
+use super::manifest::sha256;
+
+/// Where the latest builds are
+const OVMF_LATEST_URL: &str = "https://retrage.github.io/edk2-nightly/bin";
+/// Where the builds of a specific revision of the repository are
+const OVMF_REVISION_URL: &str = "https://raw.githubusercontent.com/retrage/edk2-nightly";
+const OVMF_X64_FILE: &str = "RELEASEX64_OVMF.fd";
+const OVMF_IA32_FILE: &str = "RELEASEIa32_OVMF.fd";
+const OVMF_X64_CODE_FILE: &str = "RELEASEX64_OVMF_CODE.fd";
+const OVMF_X64_VARS_FILE: &str = "RELEASEX64_OVMF_VARS.fd";
+const OVMF_IA32_CODE_FILE: &str = "RELEASEIa32_OVMF_CODE.fd";
+const OVMF_IA32_VARS_FILE: &str = "RELEASEIa32_OVMF_VARS.fd";
+const AAVMF_FILE: &str = "RELEASEAARCH64_QEMU_EFI.fd";
+const RISCV64_FILE: &str = "RELEASERISCV64_VIRT_CODE.fd";
+/// Where the edk2 builds for cloud-hypervisor are
+const CLOUDHV_RELEASES_URL: &str = "https://github.com/cloud-hypervisor/edk2/releases";
+const CLOUDHV_FILE: &str = "CLOUDHV_EFI.fd";
+/// Where distributions install firmware builds (as single files, usable with `-bios`)
+const SYSTEM_OVMF_X64_FILES: &[&str] = &[
+    "/usr/share/ovmf/OVMF.fd", // Debian, Ubuntu
+    "/usr/share/OVMF/OVMF.fd",
+    "/usr/share/edk2/x64/OVMF.fd", // Arch Linux
+    "/usr/share/edk2-ovmf/x64/OVMF.fd", // older Arch Linux
+    "/usr/share/edk2/ovmf/OVMF_CODE.fd", // Fedora
+];
+const SYSTEM_OVMF_IA32_FILES: &[&str] = &[
+    "/usr/share/edk2/ia32/OVMF.fd", // Arch Linux
+    "/usr/share/edk2-ovmf/ia32/OVMF.fd", // older Arch Linux
+    "/usr/share/edk2/ovmf-ia32/OVMF_CODE.fd", // Fedora
+];
+const SYSTEM_AAVMF_FILES: &[&str] = &[
+    "/usr/share/qemu-efi-aarch64/QEMU_EFI.fd", // Debian, Ubuntu
+    "/usr/share/edk2/aarch64/QEMU_EFI.fd", // Arch Linux, Fedora
+    "/usr/share/AAVMF/AAVMF_CODE.fd",
+];
+const SYSTEM_RISCV64_FILES: &[&str] = &[
+    "/usr/share/qemu-efi-riscv64/RISCV_VIRT_CODE.fd", // Debian, Ubuntu
+    "/usr/share/edk2/riscv/RISCV_VIRT_CODE.fd", // Fedora
+];
+/// Where QEMU installs its data files (including builds of edk2)
+const QEMU_DATA_DIRS: &[&str] = &[
+    "/usr/share/qemu",
+    "/opt/homebrew/share/qemu", // Homebrew on Apple silicon
+    "/usr/local/share/qemu", // Homebrew on Intel
+];
+/// Where the VGA BIOS for Bochs is
+const VGABIOS_URL: &str =
+    "https://raw.githubusercontent.com/bochs-emu/Bochs/master/bochs/bios/VGABIOS-lgpl-latest.bin";
+const VGABIOS_FILE: &str = "VGABIOS-lgpl-latest.bin";
+
+/// Which build of the firmware to use
+#[derive(Debug, Clone, Default)]
+pub enum Pin {
+    /// the latest nightly build
+    #[default]
+    Latest,
+    /// the nightly build from the given commit of retrage/edk2-nightly
+    Revision(String),
+    /// download the firmware from the given URL
+    Url(String),
+    /// use a local file
+    Local(PathBuf),
+}
+
+/// Get the cache, optionally without network access.
+fn cache(offline: bool) -> Result<Cache> {
+    let mut builder = Cache::builder().offline(offline);
+    if let Some(dirs) = ProjectDirs::from_path("towbootctl".into()) {
+        builder = builder.dir(dirs.cache_dir().to_path_buf());
+    };
+    Ok(builder.build()?)
+}
 
 /// Download the firmware and provide a path to it.
 /// It is cached to prevent unneccessary downloads.
-fn get_firmware(url: &str) -> Result<PathBuf> {
-    let mut cache = Cache::new()?;
-    if let Some(dirs) = ProjectDirs::from_path("towbootctl".into()) {
-        cache.dir = dirs.cache_dir().to_path_buf();
+///
+/// If the download fails, this falls back to a previously cached version.
+fn get_firmware(file_name: &str, pin: &Pin) -> Result<PathBuf> {
+    let url = match pin {
+        Pin::Latest => format!("{OVMF_LATEST_URL}/{file_name}"),
+        Pin::Revision(revision) => format!("{OVMF_REVISION_URL}/{revision}/bin/{file_name}"),
+        Pin::Url(url) => url.clone(),
+        Pin::Local(path) => return if path.exists() {
+            Ok(path.clone())
+        } else {
+            Err(anyhow!("{} does not exist", path.display()))
+        },
+    };
+    match cache(false)?.cached_path(&url) {
+        Ok(path) => Ok(path),
+        Err(e) => {
+            warn!("failed to download {url}: {e}");
+            warn!("trying to use a cached version instead");
+            Ok(cache(true)?.cached_path(&url)?)
+        },
+    }
+}
+
+/// Get the paths a firmware build for the given architecture might be installed at.
+///
+/// These are the packages of common distributions and the files QEMU ships.
+fn system_firmware_candidates(arch: Arch) -> Vec<PathBuf> {
+    let (files, qemu_file) = match arch {
+        Arch::I686 => (SYSTEM_OVMF_IA32_FILES, "edk2-i386-code.fd"),
+        Arch::X86_64 => (SYSTEM_OVMF_X64_FILES, "edk2-x86_64-code.fd"),
+        Arch::Aarch64 => (SYSTEM_AAVMF_FILES, "edk2-aarch64-code.fd"),
+        Arch::Riscv64 => (SYSTEM_RISCV64_FILES, "edk2-riscv-code.fd"),
     };
-    Ok(
-        cache.cached_path(url)?
-    )
+    let mut qemu_dirs: Vec<PathBuf> = QEMU_DATA_DIRS.iter().map(PathBuf::from).collect();
+    // QEMU for Windows puts them next to its binaries
+    if let Some(program_files) = env::var_os("ProgramFiles") {
+        qemu_dirs.push(Path::new(&program_files).join("qemu").join("share"));
+    }
+    files.iter().map(PathBuf::from)
+        .chain(qemu_dirs.into_iter().map(|dir| dir.join(qemu_file)))
+        .collect()
+}
+
+/// Find a firmware build for the given architecture that is installed on the system.
+pub fn system_firmware(arch: Arch) -> Option<PathBuf> {
+    system_firmware_candidates(arch).into_iter().find(|path| path.is_file())
+}
+
+/// Get the firmware build for the given architecture that is embedded into towbootctl.
+#[cfg(feature = "embedded-firmware")]
+fn embedded_firmware(arch: Arch) -> Option<&'static [u8]> {
+    let data: &'static [u8] = match arch {
+        Arch::I686 => include_bytes!(env!("TOWBOOTCTL_EMBEDDED_FIRMWARE_IA32")),
+        Arch::X86_64 => include_bytes!(env!("TOWBOOTCTL_EMBEDDED_FIRMWARE_X64")),
+        Arch::Aarch64 => include_bytes!(env!("TOWBOOTCTL_EMBEDDED_FIRMWARE_AA64")),
+        Arch::Riscv64 => include_bytes!(env!("TOWBOOTCTL_EMBEDDED_FIRMWARE_RISCV64")),
+    };
+    Some(data)
+}
+
+/// Without the `embedded-firmware` feature, there are no embedded builds.
+#[cfg(not(feature = "embedded-firmware"))]
+fn embedded_firmware(_arch: Arch) -> Option<&'static [u8]> {
+    None
+}
+
+/// Write the embedded firmware build to the cache directory and provide a path to it.
+///
+/// It is only written if it isn't there yet (or differs, after an update).
+fn extract_embedded_firmware(file_name: &str, arch: Arch) -> Result<Option<PathBuf>> {
+    let Some(data) = embedded_firmware(arch) else {
+        return Ok(None);
+    };
+    let dir = cache(true)?.dir.join("embedded");
+    let path = dir.join(file_name);
+    if fs::read(&path).ok().as_deref() != Some(data) {
+        info!("extracting the embedded firmware to {}", path.display());
+        fs::create_dir_all(&dir)?;
+        fs::write(&path, data)?;
+    }
+    Ok(Some(path))
+}
+
+/// Use the local override from the given environment variable instead of the latest build.
+///
+/// Without one, this prefers a build installed on the system
+/// and then the embedded one over downloading it.
+fn get_firmware_or_override(
+    file_name: &str, variable: &str, arch: Arch, pin: &Pin,
+) -> Result<PathBuf> {
+    match (pin, env::var_os(variable)) {
+        (Pin::Latest, Some(path)) => {
+            debug!("using {} from {variable}", Path::new(&path).display());
+            get_firmware(file_name, &Pin::Local(PathBuf::from(path)))
+        },
+        (Pin::Latest, None) => {
+            let system = env::var_os("TOWBOOTCTL_NO_SYSTEM_FIRMWARE").is_none()
+                .then(|| system_firmware(arch)).flatten();
+            if let Some(path) = system {
+                debug!("using the firmware installed at {}", path.display());
+                return Ok(path);
+            }
+            match extract_embedded_firmware(file_name, arch)? {
+                Some(path) => Ok(path),
+                None => get_firmware(file_name, pin),
+            }
+        },
+        _ => get_firmware(file_name, pin),
+    }
+}
+
+/// Get the firmware for the given architecture.
+pub fn firmware(arch: Arch, pin: &Pin) -> Result<PathBuf> {
+    match arch {
+        Arch::I686 => ia32(pin),
+        Arch::X86_64 => x64(pin),
+        Arch::Aarch64 => aa64(pin),
+        Arch::Riscv64 => riscv64(pin),
+    }
+}
+
+/// Get the firmware for the given architecture, making sure it has the given SHA-256.
+pub fn firmware_checked(arch: Arch, pin: &Pin, checksum: &str) -> Result<PathBuf> {
+    let path = firmware(arch, pin)?;
+    verify(&path, checksum)?;
+    Ok(path)
+}
+
+/// Check that the file has the given SHA-256 (as a hex string).
+pub fn verify(path: &Path, checksum: &str) -> Result<()> {
+    let actual = sha256(&fs::read(path)?);
+    if actual.eq_ignore_ascii_case(checksum) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} has the checksum {actual}, but {checksum} was expected", path.display(),
+        ))
+    }
 }
 
 /// Get OVMF for x64.
-pub fn x64() -> Result<PathBuf> {
-    get_firmware(OVMF_X64_URL)
+pub fn x64(pin: &Pin) -> Result<PathBuf> {
+    get_firmware_or_override(OVMF_X64_FILE, "TOWBOOTCTL_FIRMWARE_X64", Arch::X86_64, pin)
 }
 
 /// Get OVMF for ia32.
-pub fn ia32() -> Result<PathBuf> {
-    get_firmware(OVMF_IA32_URL)
+pub fn ia32(pin: &Pin) -> Result<PathBuf> {
+    get_firmware_or_override(OVMF_IA32_FILE, "TOWBOOTCTL_FIRMWARE_IA32", Arch::I686, pin)
+}
+
+/// Get edk2 for aarch64 (also known as AAVMF).
+pub fn aa64(pin: &Pin) -> Result<PathBuf> {
+    get_firmware_or_override(AAVMF_FILE, "TOWBOOTCTL_FIRMWARE_AA64", Arch::Aarch64, pin)
+}
+
+/// Get edk2 for riscv64.
+///
+/// This is meant to be loaded as flash, OpenSBI still has to be the BIOS.
+pub fn riscv64(pin: &Pin) -> Result<PathBuf> {
+    get_firmware_or_override(RISCV64_FILE, "TOWBOOTCTL_FIRMWARE_RISCV64", Arch::Riscv64, pin)
+}
+
+/// Get edk2 for cloud-hypervisor.
+///
+/// A pinned revision refers to a release tag of cloud-hypervisor/edk2 here.
+pub fn cloud_hypervisor(pin: &Pin) -> Result<PathBuf> {
+    match pin {
+        Pin::Latest => get_firmware(
+            CLOUDHV_FILE,
+            &Pin::Url(format!("{CLOUDHV_RELEASES_URL}/latest/download/{CLOUDHV_FILE}")),
+        ),
+        Pin::Revision(tag) => get_firmware(
+            CLOUDHV_FILE,
+            &Pin::Url(format!("{CLOUDHV_RELEASES_URL}/download/{tag}/{CLOUDHV_FILE}")),
+        ),
+        _ => get_firmware(CLOUDHV_FILE, pin),
+    }
+}
+
+/// Get the LGPL VGA BIOS for Bochs from its repository.
+pub fn vga_bios() -> Result<PathBuf> {
+    get_firmware(VGABIOS_FILE, &Pin::Url(VGABIOS_URL.to_string()))
+}
+
+/// Get the code and the variable store template of a split firmware build.
+fn get_split_firmware(
+    code_file_name: &str, vars_file_name: &str, pin: &Pin,
+) -> Result<(PathBuf, PathBuf)> {
+    match pin {
+        Pin::Latest | Pin::Revision(_) => Ok((
+            get_firmware(code_file_name, pin)?, get_firmware(vars_file_name, pin)?,
+        )),
+        _ => Err(anyhow!("split firmware builds are only available from edk2-nightly")),
+    }
+}
+
+/// Get OVMF for x64, split into code and variable store template.
+pub fn x64_split(pin: &Pin) -> Result<(PathBuf, PathBuf)> {
+    get_split_firmware(OVMF_X64_CODE_FILE, OVMF_X64_VARS_FILE, pin)
+}
+
+/// Get OVMF for ia32, split into code and variable store template.
+pub fn ia32_split(pin: &Pin) -> Result<(PathBuf, PathBuf)> {
+    get_split_firmware(OVMF_IA32_CODE_FILE, OVMF_IA32_VARS_FILE, pin)
+}
+
+/// Create a variable store with the default keys enrolled and Secure Boot enabled.
+///
+/// This uses `virt-fw-vars` from [virt-firmware](https://gitlab.com/kraxel/virt-firmware),
+/// which enrolls the Microsoft keys and a test platform key.
+pub fn enroll_keys(template: &Path, target: &Path) -> Result<()> {
+    let status = Command::new("virt-fw-vars")
+        .arg("--input").arg(template)
+        .arg("--output").arg(target)
+        .arg("--enroll-redhat")
+        .arg("--secure-boot")
+        .status()
+        .map_err(|e| anyhow!("failed to run virt-fw-vars (is virt-firmware installed?): {e}"))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("virt-fw-vars failed with {status}"))
+    }
+}
+
+/// List all cached firmware builds, returning their URLs and paths.
+pub fn list_cached() -> Result<Vec<(String, PathBuf)>> {
+    let dir = cache(true)?.dir;
+    let mut builds = Vec::new();
+    if !dir.exists() {
+        return Ok(builds);
+    }
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "meta") {
+            let meta: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+            if let (Some(resource), Some(resource_path)) = (
+                meta["resource"].as_str(), meta["resource_path"].as_str(),
+            ) {
+                builds.push((resource.to_string(), PathBuf::from(resource_path)));
+            }
+        }
+    }
+    builds.sort();
+    Ok(builds)
+}
+
+/// Delete all cached firmware builds.
+pub fn purge() -> Result<()> {
+    let dir = cache(true)?.dir;
+    if dir.exists() {
+        info!("removing {}", dir.display());
+        fs::remove_dir_all(&dir)?;
+    }
+    Ok(())
 }
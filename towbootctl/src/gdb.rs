@@ -0,0 +1,101 @@
+//! This module helps debugging towboot with GDB.
+//!
+//! The firmware's debug log (see [`super::BootOptions::firmware_log`]) tells
+//! where towboot has been loaded, which is needed to load its symbols.
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+
+use super::Arch;
+
+/// The port QEMU and Bochs listen on for GDB
+pub const GDB_PORT: u16 = 1234;
+
+/// How long to wait for the firmware to load towboot
+///
+/// This is generous, as the virtual machine waits for GDB to continue it.
+pub const LOAD_BASE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Find the address towboot has been loaded at in the firmware's debug log.
+///
+/// edk2 logs lines like
+/// `Loading driver at 0x0003E6AA000 EntryPoint=0x0003E6AB0F0 towboot.efi`.
+/// (Removable media use `bootx64.efi` etc. instead.)
+pub fn find_load_base(log: &str) -> Option<u64> {
+    log.lines()
+        .filter(|line| {
+            let line = line.trim_end().to_lowercase();
+            ["towboot.efi", "bootia32.efi", "bootx64.efi", "bootaa64.efi", "bootriscv64.efi"]
+                .iter().any(|name| line.ends_with(name))
+        })
+        .filter_map(|line| line.split_once("Loading driver at 0x"))
+        .filter_map(|(_, rest)| rest.split_whitespace().next())
+        .filter_map(|address| u64::from_str_radix(address, 16).ok())
+        .last()
+}
+
+/// Wait until the firmware's debug log shows where towboot has been loaded.
+///
+/// This blocks until it has been found or the timeout has passed.
+/// Only DEBUG builds of the firmware write a debug log.
+pub fn wait_for_load_base(log: &Path, timeout: Duration) -> Result<u64> {
+    let start = Instant::now();
+    loop {
+        let content = fs::read(log).unwrap_or_default();
+        if let Some(base) = find_load_base(&String::from_utf8_lossy(&content)) {
+            return Ok(base);
+        }
+        if start.elapsed() >= timeout {
+            return Err(if content.is_empty() {
+                anyhow!(
+                    "the firmware didn't write a debug log within {}s; \
+                    finding towboot needs a DEBUG build of OVMF (pass one via --firmware)",
+                    timeout.as_secs(),
+                )
+            } else {
+                anyhow!("the firmware didn't load towboot within {}s", timeout.as_secs())
+            });
+        }
+        sleep(Duration::from_millis(100));
+    }
+}
+
+/// Create commands to load the symbols of towboot at the given address.
+///
+/// The code starts one page after the image base.
+pub fn symbol_script(symbols: &Path, base: u64) -> String {
+    format!("add-symbol-file {} {:#x}\n", symbols.display(), base + 0x1000)
+}
+
+/// Create a GDB script that connects to the virtual machine.
+///
+/// It defines `towboot-symbols` to load the symbols from `symbol_script`
+/// (once towboot has been loaded) and helpers to switch the architecture,
+/// as the firmware and the kernel might run in different modes.
+pub fn script(arch: Arch, symbol_script: &Path) -> String {
+    let mut script = String::new();
+    writeln!(script, "set architecture {}", gdb_arch(arch)).unwrap();
+    writeln!(script, "target remote localhost:{GDB_PORT}").unwrap();
+    writeln!(script, "define towboot-symbols\n  source {}\nend", symbol_script.display()).unwrap();
+    for (name, gdb_arch) in [
+        ("arch-i386", "i386"), ("arch-x86-64", "i386:x86-64"),
+        ("arch-aarch64", "aarch64"), ("arch-riscv64", "riscv:rv64"),
+    ] {
+        writeln!(script, "define {name}\n  set architecture {gdb_arch}\nend").unwrap();
+    }
+    script
+}
+
+/// How GDB calls the architecture.
+fn gdb_arch(arch: Arch) -> &'static str {
+    match arch {
+        Arch::I686 => "i386",
+        Arch::X86_64 => "i386:x86-64",
+        Arch::Aarch64 => "aarch64",
+        Arch::Riscv64 => "riscv:rv64",
+    }
+}
@@ -1,14 +1,69 @@
 //! This module contains functionality to work with images.
-use std::error::Error;
 use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
-use std::io::{Write, Read};
+use std::io::{self, ErrorKind, Seek, SeekFrom, Write, Read};
 use std::path::Path;
+use std::time::Instant;
 
 use fscommon::StreamSlice;
 use gpt::{GptConfig, disk::LogicalBlockSize, mbr::ProtectiveMBR, partition_types};
 use log::debug;
-use fatfs::{FileSystem, format_volume, FormatVolumeOptions, FsOptions};
+use uuid::Uuid;
+use fatfs::{Dir, FatType, FileSystem, format_volume, FormatVolumeOptions, FsOptions};
+
+use super::{Error, Partition, device};
+use super::error::Result;
+
+/// How much to write at once (between progress reports)
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The name of the partition if none is given
+pub const DEFAULT_PARTITION_NAME: &str = "towboot";
+
+/// The logical sector size if none is given
+pub const DEFAULT_SECTOR_SIZE: u64 = 512;
+
+/// Where the first partition starts (and what partitions are aligned to)
+const PARTITION_ALIGNMENT: u64 = 1024 * 1024;
+
+/// The size of an EFI System Partition added to an existing disk if none is given
+pub const DEFAULT_ESP_SIZE: u64 = 512 * 1024 * 1024;
+
+/// How many clusters a FAT32 volume needs at least
+const MIN_FAT32_CLUSTERS: u64 = 65525;
+
+/// Get the size a partition needs to be formatted with FAT32.
+///
+/// Small volumes get one sector per cluster; the FATs (two of them, with four
+/// bytes per cluster) and the reserved sectors come on top.
+fn min_fat32_size(sector_size: u64) -> u64 {
+    let data = MIN_FAT32_CLUSTERS * sector_size;
+    let overhead = 2 * MIN_FAT32_CLUSTERS * 4 + 32 * sector_size;
+    (data + overhead).next_multiple_of(PARTITION_ALIGNMENT)
+}
+
+/// Get the logical block size for the given sector size.
+fn logical_block_size(sector_size: u64) -> Result<LogicalBlockSize> {
+    match sector_size {
+        512 => Ok(LogicalBlockSize::Lb512),
+        4096 => Ok(LogicalBlockSize::Lb4096),
+        _ => Err(Error::Image(format!(
+            "invalid sector size {sector_size}: it must be 512 or 4096",
+        ))),
+    }
+}
+
+/// Convert a volume label to the padded form FAT expects.
+fn fat_volume_label(label: &str) -> Result<[u8; 11]> {
+    if !label.is_ascii() || label.len() > 11 {
+        return Err(Error::Image(format!(
+            "invalid volume label {label}: it must consist of at most 11 ASCII characters",
+        )));
+    }
+    let mut padded = [b' '; 11];
+    padded[..label.len()].copy_from_slice(label.to_ascii_uppercase().as_bytes());
+    Ok(padded)
+}
 
 /// An image that is currently being constructed.
 pub struct Image {
@@ -18,7 +73,33 @@ pub struct Image {
 impl Image {
     /// Create a new image at the given location with the given size.
     /// If the file exists already, it will be overwritten.
-    pub fn new(path: &Path, size: u64) -> Result<Self, Box<dyn Error>> {
+    ///
+    /// The partition gets the given name and the filesystem the given label
+    /// (if there is one).
+    /// The sector size is either 512 or 4096 bytes.
+    /// The disk and the partition get the given GUIDs (or random ones).
+    ///
+    /// The additional partitions are placed after the EFI System Partition
+    /// (which gets the remaining space).
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        path: &Path, size: u64, partition_name: &str, volume_label: Option<&str>,
+        sector_size: u64, disk_guid: Option<Uuid>, partition_guid: Option<Uuid>,
+        partitions: &[Partition],
+    ) -> Result<Self> {
+        let block_size = logical_block_size(sector_size)?;
+        // the disk has to consist of whole sectors
+        let size = size.div_ceil(sector_size) * sector_size;
+        if partition_name.encode_utf16().count() > 36 {
+            return Err(Error::Image(format!(
+                "invalid partition name {partition_name}: it must be at most 36 characters long",
+            )));
+        }
+        let mut format_options = FormatVolumeOptions::new()
+            .bytes_per_sector(sector_size as u16);
+        if let Some(volume_label) = volume_label {
+            format_options = format_options.volume_label(fat_volume_label(volume_label)?);
+        }
         debug!("creating disk image");
         let mut file = Box::new(OpenOptions::new()
             .read(true)
@@ -29,31 +110,219 @@ impl Image {
         file.set_len(size)?;
         // protective MBR
         let mbr = ProtectiveMBR::with_lb_size(
-            u32::try_from((size / 512) - 1).unwrap_or(0xFF_FF_FF_FF)
+            u32::try_from((size / sector_size) - 1).unwrap_or(0xFF_FF_FF_FF)
         );
-        mbr.overwrite_lba0(&mut file)?;
+        mbr.overwrite_lba0(&mut file).map_err(Error::image)?;
         let mut disk = GptConfig::new()
             .writable(true)
-            .logical_block_size(LogicalBlockSize::Lb512)
-            .create_from_device(file, None)?;
-        disk.update_partitions(BTreeMap::new())?;
+            .logical_block_size(block_size)
+            .create_from_device(file, disk_guid)
+            .map_err(Error::image)?;
+        disk.update_partitions(BTreeMap::new()).map_err(Error::image)?;
+        let mut extra_sizes = Vec::new();
+        for partition in partitions {
+            extra_sizes.push(partition.size()?.div_ceil(sector_size) * sector_size);
+        }
+        // Leave space for the alignment of each partition and for the backup GPT
+        // at the end of the disk.
+        let esp_size = extra_sizes.iter()
+            .map(|s| s + PARTITION_ALIGNMENT)
+            .try_fold(size, u64::checked_sub)
+            .and_then(|rest| rest.checked_sub(2 * PARTITION_ALIGNMENT))
+            .ok_or_else(|| Error::image("the image is too small for the partitions"))?;
         debug!("creating partition");
-        disk.add_partition("towboot", size - 1024 * 1024, partition_types::EFI, 0, None)?;
-        let partitions = disk.partitions().clone();
-        let (_, partition) = partitions.iter().next().unwrap();
-        let file = disk.write()?;
+        let esp_id = disk.add_partition(
+            partition_name, esp_size, partition_types::EFI, 0,
+            Some(PARTITION_ALIGNMENT / sector_size),
+        ).map_err(Error::image)?;
+        let mut extra_ids = Vec::new();
+        for (partition, size) in partitions.iter().zip(extra_sizes) {
+            debug!("creating partition {}", partition.name);
+            extra_ids.push(disk.add_partition(
+                &partition.name, size, partition.part_type.clone(), 0,
+                Some(PARTITION_ALIGNMENT / sector_size),
+            ).map_err(Error::image)?);
+        }
+        // the GUIDs of the partitions are random unless they're given
+        let mut created = disk.partitions().clone();
+        if let Some(guid) = partition_guid {
+            created.get_mut(&esp_id).unwrap().part_guid = guid;
+        }
+        for (partition, id) in partitions.iter().zip(&extra_ids) {
+            if let Some(guid) = partition.guid {
+                created.get_mut(id).unwrap().part_guid = guid;
+            }
+        }
+        disk.update_partitions(created.clone()).map_err(Error::image)?;
+        let partition = &created[&esp_id];
+        let mut file = disk.write().map_err(Error::image)?;
+        for (extra, id) in partitions.iter().zip(extra_ids) {
+            if let Some(from) = &extra.from {
+                let target = &created[&id];
+                debug!("copying {} to {}", from.display(), target);
+                let mut source = File::open(from).map_err(|e| Error::file(from, e))?;
+                let available = (target.last_lba - target.first_lba + 1) * sector_size;
+                if source.metadata()?.len() > available {
+                    return Err(Error::Image(format!(
+                        "{} does not fit into partition {}", from.display(), extra.name,
+                    )));
+                }
+                file.seek(SeekFrom::Start(target.first_lba * sector_size))?;
+                io::copy(&mut source, &mut file)?;
+            }
+        }
         let mut part = StreamSlice::new(
-            file, partition.first_lba * 512, partition.last_lba * 512,
+            file, partition.first_lba * sector_size, (partition.last_lba + 1) * sector_size,
         )?;
         debug!("formatting {}", partition);
-        format_volume(&mut part, FormatVolumeOptions::new())?;
+        format_volume(&mut part, format_options)?;
         Ok(Self { fs: FileSystem::new(part, FsOptions::new())? })
     }
 
+    /// Open an existing image at the given location.
+    /// This uses the first EFI System Partition inside it.
+    ///
+    /// Both 512 and 4096 byte sectors are supported.
+    pub fn open(path: &Path) -> Result<Self> {
+        debug!("opening disk image");
+        let (disk, sector_size) = match GptConfig::new()
+            .writable(false)
+            .logical_block_size(LogicalBlockSize::Lb512)
+            .open(path)
+        {
+            Ok(disk) => (disk, 512),
+            Err(e) => {
+                debug!("failed to open the image with 512 byte sectors: {e}");
+                let disk = GptConfig::new()
+                    .writable(false)
+                    .logical_block_size(LogicalBlockSize::Lb4096)
+                    .open(path)
+                    .map_err(Error::image)?;
+                (disk, 4096)
+            },
+        };
+        let partition = disk.partitions().values()
+            .find(|p| p.part_type_guid == partition_types::EFI)
+            .ok_or_else(|| Error::image("the image does not contain an EFI System Partition"))?;
+        debug!("using {}", partition);
+        let file = Box::new(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?);
+        let part = StreamSlice::new(
+            file, partition.first_lba * sector_size, (partition.last_lba + 1) * sector_size,
+        )?;
+        Ok(Self { fs: FileSystem::new(part, FsOptions::new())? })
+    }
+
+    /// Add an EFI System Partition to an existing GPT disk (or image) and format it.
+    ///
+    /// The partition is placed in free space; existing partitions are not
+    /// touched (or shrunk), so there has to be enough space for the given size
+    /// (which has to be enough for FAT32).
+    /// Devices must not be mounted; on Linux, this is checked.
+    ///
+    /// Nothing is written until `confirm` (which gets a description of the
+    /// changes) returns true; otherwise, this returns None.
+    pub fn add_esp(
+        path: &Path, size: u64, partition_name: &str, volume_label: Option<&str>,
+        partition_guid: Option<Uuid>, confirm: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<Option<Self>> {
+        device::check_not_mounted(path).map_err(Error::image)?;
+        debug!("opening disk");
+        let mut opened = None;
+        for (block_size, sector_size) in [
+            (LogicalBlockSize::Lb512, 512), (LogicalBlockSize::Lb4096, 4096),
+        ] {
+            let file = Box::new(OpenOptions::new().read(true).write(true).open(path)?);
+            match GptConfig::new()
+                .writable(true)
+                .logical_block_size(block_size)
+                .open_from_device(file)
+            {
+                Ok(disk) => {
+                    opened = Some((disk, sector_size));
+                    break;
+                },
+                Err(e) => debug!("failed to open the disk with {sector_size} byte sectors: {e}"),
+            }
+        }
+        let (mut disk, sector_size) = opened.ok_or_else(|| Error::Image(format!(
+            "{} does not contain a GPT", path.display(),
+        )))?;
+        if let Some(esp) = disk.partitions().values()
+            .find(|p| p.part_type_guid == partition_types::EFI)
+        {
+            return Err(Error::Image(format!(
+                "{} already contains an EFI System Partition ({esp})", path.display(),
+            )));
+        }
+        let mut format_options = FormatVolumeOptions::new()
+            .bytes_per_sector(sector_size as u16)
+            .fat_type(FatType::Fat32);
+        if let Some(volume_label) = volume_label {
+            format_options = format_options.volume_label(fat_volume_label(volume_label)?);
+        }
+        let size = size.div_ceil(sector_size) * sector_size;
+        // fatfs would happily create a FAT32 volume that's too small to be valid
+        let min_size = min_fat32_size(sector_size);
+        if size < min_size {
+            return Err(Error::Image(format!(
+                "an EFI System Partition with {sector_size} byte sectors needs at least {} MiB",
+                min_size / 1024 / 1024,
+            )));
+        }
+        let id = disk.add_partition(
+            partition_name, size, partition_types::EFI, 0,
+            Some(PARTITION_ALIGNMENT / sector_size),
+        ).map_err(|e| Error::Image(format!(
+            "there is not enough free space on {} for {} MiB: {e}",
+            path.display(), size / 1024 / 1024,
+        )))?;
+        let mut partitions = disk.partitions().clone();
+        if let Some(guid) = partition_guid {
+            partitions.get_mut(&id).unwrap().part_guid = guid;
+        }
+        disk.update_partitions(partitions.clone()).map_err(Error::image)?;
+        let partition = &partitions[&id];
+        if !confirm(&format!(
+            "create an EFI System Partition of {} MiB (sectors {} to {}) on {} \
+            and format it with FAT32",
+            size / 1024 / 1024, partition.first_lba, partition.last_lba, path.display(),
+        )) {
+            return Ok(None);
+        }
+        debug!("writing the partition table");
+        let file = disk.write().map_err(Error::image)?;
+        let mut part = StreamSlice::new(
+            file, partition.first_lba * sector_size, (partition.last_lba + 1) * sector_size,
+        )?;
+        debug!("formatting {}", partition);
+        format_volume(&mut part, format_options)?;
+        Ok(Some(Self { fs: FileSystem::new(part, FsOptions::new())? }))
+    }
+
     /// Copy a file from the local filesystem to the image.
-    pub fn add_file(&mut self, source: &Path, dest: &Path) -> Result<(), Box<dyn Error>> {
+    pub fn add_file(&mut self, source: &Path, dest: &Path) -> Result<()> {
         debug!("adding {} as {}", source.display(), dest.display());
-        let mut source_file = File::open(source)?;
+        let mut source_file = File::open(source).map_err(|e| Error::file(source, e))?;
+        self.write_file(&mut source_file, dest, &mut |_| ())
+    }
+
+    /// Write the given data to a file in the image.
+    pub fn add_data(&mut self, data: &[u8], dest: &Path) -> Result<()> {
+        debug!("adding {} bytes as {}", data.len(), dest.display());
+        self.write_file(&mut &data[..], dest, &mut |_| ())
+    }
+
+    /// Create (or overwrite) a file in the image, including its parent directories.
+    ///
+    /// The contents are read from the source in chunks, so they don't have to
+    /// fit into memory at once.
+    /// The callback gets called with the number of bytes written so far.
+    pub(crate) fn write_file(
+        &mut self, source: &mut dyn Read, dest: &Path, progress: &mut dyn FnMut(u64),
+    ) -> Result<()> {
         let mut dir = self.fs.root_dir();
         let components: Vec<_> = dest.components().collect();
         let (file_name, dir_names) = components.split_last().unwrap();
@@ -63,9 +332,74 @@ impl Image {
         let mut dest_file = dir.create_file(
             file_name.as_os_str().to_str().unwrap()
         )?;
-        let mut buf = Vec::new();
-        source_file.read_to_end(&mut buf)?;
-        dest_file.write_all(&buf)?;
+        progress(0);
+        let start = Instant::now();
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut written = 0;
+        loop {
+            let len = match source.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            dest_file.write_all(&buf[..len])?;
+            written += len as u64;
+            progress(written);
+        }
+        // the file might have existed before and been longer
+        dest_file.truncate()?;
+        let elapsed = start.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            debug!(
+                "wrote {written} bytes in {elapsed:.2}s ({:.1} MiB/s)",
+                written as f64 / elapsed / 1024.0 / 1024.0,
+            );
+        }
         Ok(())
     }
+
+    /// List all files in the image (with their full paths).
+    pub fn files(&self) -> Result<Vec<String>> {
+        /// Walk a directory recursively.
+        fn walk(
+            dir: &Dir<StreamSlice<Box<File>>>, prefix: &str, files: &mut Vec<String>,
+        ) -> Result<()> {
+            for entry in dir.iter() {
+                let entry = entry?;
+                let name = entry.file_name();
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let path = format!("{prefix}{name}");
+                if entry.is_dir() {
+                    walk(&entry.to_dir(), &format!("{path}/"), files)?;
+                } else {
+                    files.push(path);
+                }
+            }
+            Ok(())
+        }
+        let mut files = Vec::new();
+        walk(&self.fs.root_dir(), "", &mut files)?;
+        files.sort();
+        Ok(files)
+    }
+
+    /// Read a file from the image.
+    ///
+    /// Returns None if the file does not exist.
+    pub fn read_file(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        let path: Vec<_> = path.components()
+            .map(|c| c.as_os_str().to_str().unwrap())
+            .collect();
+        let mut file = match self.fs.root_dir().open_file(&path.join("/")) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        Ok(Some(buf))
+    }
 }
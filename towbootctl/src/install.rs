@@ -0,0 +1,261 @@
+//! This module installs towboot to an ESP.
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+
+use log::info;
+
+use towboot_config::Config;
+
+use super::{Arch, Progress, config};
+
+/// The file name of towboot in installs that are not removable
+pub const INSTALLED_BINARY: &str = "towboot.efi";
+
+/// How much to copy at once
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Get the folder the towboot binaries are placed in.
+pub fn get_install_path(esp_path: &Path, removable: bool, name: Option<&str>) -> PathBuf {
+    let mut install_path = esp_path.to_path_buf();
+    install_path.push("EFI");
+    install_path.push(if removable {
+        "BOOT"
+    } else {
+        name.expect("non-removable installs must have a name")
+    });
+    install_path
+}
+
+/// Get the file name of towboot for the given architecture in an install.
+///
+/// Removable installs use the name the firmware looks for (such as `BOOTX64.efi`);
+/// other installs are started via their boot option, so they just use [`INSTALLED_BINARY`].
+pub fn binary_name(arch: Arch, removable: bool) -> &'static str {
+    if !removable {
+        return INSTALLED_BINARY;
+    }
+    match arch {
+        Arch::I686 => "BOOTIA32.efi",
+        Arch::X86_64 => "BOOTX64.efi",
+        Arch::Aarch64 => "BOOTAA64.efi",
+        Arch::Riscv64 => "BOOTRISCV64.efi",
+    }
+}
+
+/// Find out which architecture the firmware of this machine has.
+///
+/// 64-bit x86 machines might still come with 32-bit firmware; this is only
+/// detected on Linux. Elsewhere, the architecture of towbootctl is assumed.
+pub fn firmware_arch() -> Result<Arch, Box<dyn Error>> {
+    let platform_size = fs::read_to_string("/sys/firmware/efi/fw_platform_size").ok();
+    match (std::env::consts::ARCH, platform_size.as_deref().map(str::trim)) {
+        ("x86" | "x86_64", Some("32")) | ("x86", _) => Ok(Arch::I686),
+        ("x86_64", _) => Ok(Arch::X86_64),
+        ("aarch64", _) => Ok(Arch::Aarch64),
+        ("riscv64", _) => Ok(Arch::Riscv64),
+        (arch, _) => Err(format!("there is no towboot binary for {arch}").into()),
+    }
+}
+
+/// Get the folder the configuration, kernels and modules are placed in.
+pub fn get_config_path(esp_path: &Path, install_path: &Path, removable: bool) -> PathBuf {
+    if removable {
+        esp_path.to_path_buf()
+    } else {
+        install_path.to_path_buf()
+    }
+}
+
+/// Copies of the files an install overwrote.
+///
+/// They are kept in /EFI/towboot-backup/<install folder>/<timestamp>/,
+/// mirroring their original location on the ESP.
+pub struct Backup<'a> {
+    esp_path: &'a Path,
+    /// the folder containing all backups of this install
+    path: PathBuf,
+    /// when this backup was started (in seconds since the Unix epoch)
+    timestamp: u64,
+}
+
+impl<'a> Backup<'a> {
+    pub fn new(esp_path: &'a Path, install_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut path = esp_path.to_path_buf();
+        path.push("EFI");
+        path.push("towboot-backup");
+        path.push(install_path.file_name().unwrap());
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        Ok(Self { esp_path, path, timestamp })
+    }
+
+    /// Copy the file to the backup if it exists (because it's about to be overwritten).
+    pub fn save(&self, file: &Path) -> Result<(), Box<dyn Error>> {
+        if file.exists() {
+            let mut dest = self.path.join(self.timestamp.to_string());
+            dest.push(file.strip_prefix(self.esp_path)?);
+            info!("backing up {} to {}", file.display(), dest.display());
+            fs::create_dir_all(dest.parent().unwrap())?;
+            fs::copy(file, &dest)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the newest backup and remove it.
+    pub fn restore(&self) -> Result<(), Box<dyn Error>> {
+        let newest = if self.path.exists() {
+            fs::read_dir(&self.path)?
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u64>().ok())
+                .max()
+        } else {
+            None
+        }.ok_or("there is no backup to restore")?;
+        let backup_path = self.path.join(newest.to_string());
+        info!("restoring {}", backup_path.display());
+        Self::copy_dir(&backup_path, self.esp_path)?;
+        fs::remove_dir_all(&backup_path)?;
+        Ok(())
+    }
+
+    /// Copy the contents of a folder recursively.
+    fn copy_dir(source: &Path, dest: &Path) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(dest)?;
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            let dest = dest.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                Self::copy_dir(&entry.path(), &dest)?;
+            } else {
+                info!("restoring {}", dest.display());
+                fs::copy(entry.path(), &dest)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Copy a file, reporting the progress.
+fn copy(
+    source: &Path, dest: &Path, progress: &mut dyn FnMut(Progress),
+) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    let mut source_file = File::open(source)?;
+    let total = source_file.metadata()?.len();
+    let mut dest_file = File::create(dest)?;
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut done = 0;
+    progress(Progress::Copying { path: dest, done, total });
+    loop {
+        let length = source_file.read(&mut buf)?;
+        if length == 0 {
+            break;
+        }
+        dest_file.write_all(&buf[..length])?;
+        done += length as u64;
+        progress(Progress::Copying { path: dest, done, total });
+    }
+    let duration_ms = start.elapsed().as_millis() as u64;
+    info!(
+        file:% = dest.display(), bytes = total, duration_ms;
+        "copied {} to {}", source.display(), dest.display(),
+    );
+    Ok(())
+}
+
+/// Copy a folder recursively, backing up everything that gets overwritten.
+fn copy_tree(
+    source: &Path, dest: &Path, backup: &Backup, progress: &mut dyn FnMut(Progress),
+) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let dest = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_tree(&entry.path(), &dest, backup, progress)?;
+        } else {
+            progress(Progress::BackingUp(&dest));
+            backup.save(&dest)?;
+            copy(&entry.path(), &dest, progress)?;
+        }
+    }
+    Ok(())
+}
+
+/// Get the path of a file relative to the assets, as towboot expects it.
+///
+/// This is `None` if the file is not part of the assets.
+fn asset_path(assets: &Path, file: &Path) -> Option<String> {
+    let relative = file.canonicalize().ok()?
+        .strip_prefix(assets.canonicalize().ok()?).ok()?
+        .to_path_buf();
+    let components: Vec<_> = relative.iter().map(|c| c.to_string_lossy()).collect();
+    Some(components.join("\\"))
+}
+
+/// Install towboot to the ESP.
+///
+/// This copies the configuration (if there is one) with its kernels and modules
+/// and the given towboot binaries (with their file names) to the ESP.
+/// The contents of `assets` are copied next to the configuration, keeping their
+/// structure; references to files in there are rewritten to point to the copies.
+/// Everything that gets overwritten is backed up first (see [`Backup`]).
+pub fn install(
+    esp_path: &Path, removable: bool, name: Option<&str>, config: Option<Config>,
+    assets: Option<&Path>, binaries: &[(&str, &[u8])], progress: &mut dyn FnMut(Progress),
+) -> Result<(), Box<dyn Error>> {
+    let install_path = get_install_path(esp_path, removable, name);
+    let config_dir = get_config_path(esp_path, &install_path, removable);
+    let backup = Backup::new(esp_path, &install_path)?;
+    if !install_path.exists() {
+        fs::create_dir_all(&install_path)?;
+    }
+    info!("installing to {}", install_path.display());
+    if let Some(assets) = assets {
+        info!("copying the assets from {}", assets.display());
+        copy_tree(assets, &config_dir, &backup, progress)?;
+    }
+    if let Some(mut config) = config {
+        // Write the given configuration to the ESP.
+        let mut config_path = PathBuf::from(config.src.clone());
+        config_path.pop();
+        let mut directories = Vec::new();
+        // go through all needed files; including them (but without the original path)
+        for src_file in config.needed_files() {
+            let src_path = config_path.join(PathBuf::from(&src_file));
+            // files from the assets have already been copied
+            if let Some(path) = assets.and_then(|assets| asset_path(assets, &src_path)) {
+                *src_file = path;
+                continue;
+            }
+            // the matches of paths with wildcards get their own directory
+            let (dst_file, files) = config::copy_destinations(&src_path, &mut directories)?;
+            for (file, dst) in files {
+                let dst_path = config_dir.join(dst);
+                progress(Progress::BackingUp(&dst_path));
+                backup.save(&dst_path)?;
+                fs::create_dir_all(dst_path.parent().unwrap())?;
+                copy(&file, &dst_path, progress)?;
+            }
+            *src_file = dst_file;
+        }
+        // write the configuration itself
+        let config_path = config_dir.join("towboot.toml");
+        progress(Progress::BackingUp(&config_path));
+        backup.save(&config_path)?;
+        fs::write(&config_path, config::to_string(&config)?)?;
+    }
+    // add towboot itself (see `binary_name` for the file names)
+    for (file, binary) in binaries {
+        let path = Path::join(&install_path, file);
+        progress(Progress::BackingUp(&path));
+        backup.save(&path)?;
+        let total = binary.len() as u64;
+        progress(Progress::Copying { path: &path, done: 0, total });
+        fs::write(&path, binary)?;
+        progress(Progress::Copying { path: &path, done: total, total });
+    }
+    progress(Progress::Done);
+    Ok(())
+}
@@ -1,25 +1,69 @@
 //! This crate offers functionality to use towboot for your own operating system.
-#![cfg_attr(feature = "args", feature(exit_status_error))]
-use std::error::Error;
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::error::Error as StdError;
+#[cfg(feature = "args")]
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+#[cfg(feature = "args")]
+use std::process::Stdio;
+use std::process::{Child, Command, ExitStatus};
+use std::str::FromStr;
+#[cfg(feature = "args")]
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
 #[cfg(feature = "args")]
 use argh::FromArgs;
 use log::info;
-use tempfile::{NamedTempFile, TempPath};
-
-use towboot_config::Config;
+#[cfg(feature = "args")]
+use log::warn;
 
+pub mod bench;
 mod bochs;
+pub mod boot_entry;
+mod builder;
+mod cloud_hypervisor;
+#[cfg(feature = "compress")]
+pub mod compress;
 pub mod config;
-mod firmware;
+mod crosvm;
+pub mod device;
+pub mod doctor;
+mod error;
+mod esp;
+pub mod firmware;
+pub mod gdb;
 mod image;
+pub mod install;
+pub mod manifest;
+mod partition;
+mod qemu;
+mod qmp;
+pub mod release;
+mod swtpm;
+mod virtualbox;
+mod vm;
+mod vmware;
+pub use bochs::BochsOptions;
 use bochs::bochsrc;
-use image::Image;
+pub use builder::ImageBuilder;
+pub use towboot_config::Config;
+use cloud_hypervisor::cloud_hypervisor;
+use crosvm::crosvm;
+pub use error::Error;
+pub use esp::Esp;
+pub use firmware::Pin;
+pub use image::{DEFAULT_ESP_SIZE, DEFAULT_PARTITION_NAME, DEFAULT_SECTOR_SIZE, Image};
+use qemu::qemu;
+use swtpm::Swtpm;
+pub use partition::Partition;
+pub use qmp::Qmp;
+pub use uuid::Uuid;
+use virtualbox::virtualbox;
+pub use vm::{BootedVm, DEBUG_EXIT_SUCCESS};
+use vmware::vmware;
 
 /// How big the image should be
 pub const DEFAULT_IMAGE_SIZE: u64 = 50*1024*1024;
@@ -30,26 +74,14 @@ pub const IA32_BOOT_PATH: &str = "EFI/Boot/bootia32.efi";
 /// Where to place the 64-bit EFI file
 pub const X64_BOOT_PATH: &str = "EFI/Boot/bootx64.efi";
 
-/// Get the source and destination paths of all files referenced in the config.
-fn get_config_files(
-    config: &mut Config,
-) -> Result<Vec<(PathBuf, PathBuf)>, Box<dyn Error>> {
-    let mut paths = Vec::<(PathBuf, PathBuf)>::new();
-    let mut config_path = PathBuf::from(config.src.clone());
-    config_path.pop();
+/// Where to place the EFI file for aarch64
+pub const AA64_BOOT_PATH: &str = "EFI/Boot/bootaa64.efi";
 
-    // go through all needed files; including them (but without the original path)
-    for src_file in config.needed_files() {
-        let src_path = config_path.join(PathBuf::from(&src_file));
-        let dst_file = src_path.file_name().unwrap();
-        let dst_path = PathBuf::from(&dst_file);
-        src_file.clear();
-        src_file.push_str(dst_file.to_str().unwrap());
-        paths.push((src_path, dst_path));
-    }
+/// Where to place the EFI file for riscv64
+pub const RISCV64_BOOT_PATH: &str = "EFI/Boot/bootriscv64.efi";
 
-    Ok(paths)
-}
+/// towboot binaries contain this, followed by their version
+pub const VERSION_MARKER: &[u8] = b"towboot-version: ";
 
 /// Joins a slice of strings.
 pub fn runtime_args_to_load_options(runtime_args: &[String]) -> String {
@@ -67,98 +99,431 @@ pub fn runtime_args_to_load_options(runtime_args: &[String]) -> String {
     load_options
 }
 
+/// Find out which version of towboot the given binary is.
+pub fn get_towboot_version(binary: &[u8]) -> Option<String> {
+    let start = binary.windows(VERSION_MARKER.len())
+        .position(|w| w == VERSION_MARKER)? + VERSION_MARKER.len();
+    let length = binary[start..].iter().position(|b| *b == 0)?;
+    String::from_utf8(binary[start..start + length].to_vec()).ok()
+}
+
+/// Find out which architecture the given EFI binary is built for.
+pub fn get_binary_arch(binary: &[u8]) -> Option<Arch> {
+    // the offset of the PE header is at 0x3c; the machine type follows its signature
+    let offset = u32::from_le_bytes(binary.get(0x3c..0x40)?.try_into().ok()?) as usize;
+    if binary.get(offset..offset.checked_add(4)?)? != b"PE\0\0" {
+        return None;
+    }
+    match u16::from_le_bytes(binary.get(offset + 4..offset + 6)?.try_into().ok()?) {
+        0x014c => Some(Arch::I686),
+        0x8664 => Some(Arch::X86_64),
+        0xaa64 => Some(Arch::Aarch64),
+        0x5064 => Some(Arch::Riscv64),
+        _ => None,
+    }
+}
+
+/// What is happening while creating an image or installing
+#[derive(Debug, Clone, Copy)]
+pub enum Progress<'a> {
+    /// the size of the image is being calculated
+    CalculatingSize,
+    /// the image is being created and formatted
+    CreatingImage,
+    /// the file at the given path is about to be backed up
+    BackingUp(&'a Path),
+    /// `done` of `total` bytes of the file have been copied to the given path
+    Copying { path: &'a Path, done: u64, total: u64 },
+    /// everything is done
+    Done,
+}
+
 /// Create an image, containing a configuration file, kernels, modules and towboot.
+///
+/// The partition and filesystem are named as given (see [`Image::new`]).
+/// If given, the progress callback is called for each step.
+/// Use [`Image::builder`] for more control over the image's contents.
 pub fn create_image(
     target: &Path, runtime_args: &[String], i686: Option<&Path>, x86_64: Option<&Path>,
-) -> Result<Image, Box<dyn Error>> {
-    info!("calculating image size");
-    let mut paths = Vec::<(PathBuf, PathBuf)>::new();
+    aarch64: Option<&Path>, partition_name: &str, volume_label: Option<&str>,
+    progress: Option<&mut dyn FnMut(Progress)>,
+) -> Result<Image, Error> {
+    let mut builder = Image::builder().partition_name(partition_name);
+    if let Some(volume_label) = volume_label {
+        builder = builder.label(volume_label);
+    }
 
     // generate a configuration file from the load options
-    let load_options = runtime_args_to_load_options(runtime_args);
-    let mut config_file = NamedTempFile::new()?;
-    if let Some(mut config) = config::get(&load_options)? {
-        // get paths to all files referenced by config
-        // this also sets the correct config file paths inside the image
-        let mut config_paths = get_config_files(&mut config)?;
-        paths.append(&mut config_paths);
-
-        // generate temp config file
-        config_file.as_file_mut().write_all(
-            toml::to_string(&config)?.as_bytes()
-        )?;
-        paths.push((PathBuf::from(config_file.path()), PathBuf::from("towboot.toml")));
-    }
+    builder = builder.add_runtime_args(runtime_args)?;
 
     // add towboot itself
     if let Some(src) = i686 {
-        paths.push((PathBuf::from(src), PathBuf::from(IA32_BOOT_PATH)));
+        builder = builder.add_towboot_ia32(src);
     }
     if let Some(src) = x86_64 {
-        paths.push((PathBuf::from(src), PathBuf::from(X64_BOOT_PATH)));
+        builder = builder.add_towboot_x64(src);
+    }
+    if let Some(src) = aarch64 {
+        builder = builder.add_towboot_aa64(src);
     }
 
-    let mut image_size = 0x00_20_00_00;
-    for pair in paths.iter() {
-        let file = OpenOptions::new()
-            .read(true)
-            .open(PathBuf::from(&pair.0))?;
-        image_size += file.metadata()?.len();
+    match progress {
+        Some(progress) => builder.build_with_progress(target, progress),
+        None => builder.build(target),
     }
+}
+
+/// Which architecture to boot.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    #[default]
+    I686,
+    X86_64,
+    Aarch64,
+    Riscv64,
+}
 
-    info!("creating image at {} (size: {} MiB)", target.display(), image_size / 1024 / 1024);
-    let mut image = Image::new(target, image_size)?;
-    for pair in paths {
-        image.add_file(pair.0.as_path(), pair.1.as_path())?
+impl Arch {
+    /// Whether this is i686 or x86_64.
+    pub(crate) fn is_x86(self) -> bool {
+        matches!(self, Self::I686 | Self::X86_64)
     }
+}
+
+impl FromStr for Arch {
+    type Err = String;
 
-    Ok(image)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "i686" | "ia32" | "x86" => Ok(Self::I686),
+            "x86_64" | "x64" => Ok(Self::X86_64),
+            "aarch64" | "aa64" => Ok(Self::Aarch64),
+            "riscv64" => Ok(Self::Riscv64),
+            _ => Err(format!("unknown architecture: {s}")),
+        }
+    }
 }
 
-/// Boot a built image, returning the running process.
-pub fn boot_image(
-    firmware: Option<&Path>, image: &Path, is_x86_64: bool, use_bochs: bool,
-    use_kvm: bool, use_gdb: bool,
-) -> Result<(Command, Vec<TempPath>), Box<dyn Error>> {
-    info!("getting firmware");
-    let firmware_path = if let Some(path) = firmware {
-        assert!(path.exists());
-        path.to_path_buf()
-    } else {
-        match is_x86_64 {
-            false => firmware::ia32()?,
-            true => firmware::x64()?,
+/// Which virtual machine to use.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Hypervisor {
+    #[default]
+    Qemu,
+    Bochs,
+    VirtualBox,
+    VMware,
+    CloudHypervisor,
+    Crosvm,
+}
+
+impl FromStr for Hypervisor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "qemu" => Ok(Self::Qemu),
+            "bochs" => Ok(Self::Bochs),
+            "virtualbox" => Ok(Self::VirtualBox),
+            "vmware" => Ok(Self::VMware),
+            "cloud-hypervisor" => Ok(Self::CloudHypervisor),
+            "crosvm" => Ok(Self::Crosvm),
+            _ => Err(format!("unknown hypervisor: {s}")),
         }
+    }
+}
+
+/// How the disk is attached to the virtual machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskBus {
+    Ide,
+    Ahci,
+    Virtio,
+    Nvme,
+    Usb,
+}
+
+impl FromStr for DiskBus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ide" => Ok(Self::Ide),
+            "ahci" | "sata" => Ok(Self::Ahci),
+            "virtio" => Ok(Self::Virtio),
+            "nvme" => Ok(Self::Nvme),
+            "usb" => Ok(Self::Usb),
+            _ => Err(format!("unknown disk bus: {s}")),
+        }
+    }
+}
+
+/// How to boot an image.
+#[derive(Debug, Default, Clone)]
+pub struct BootOptions {
+    /// which firmware to use
+    ///
+    /// (This doesn't apply to VirtualBox and VMware; they bring their own.)
+    pub firmware: Pin,
+    /// which architecture to boot
+    pub arch: Arch,
+    /// use the firmware for this architecture instead of the one for `arch`
+    ///
+    /// (This is only supported by QEMU for 32-bit firmware on x86_64.)
+    pub firmware_arch: Option<Arch>,
+    /// which virtual machine to use
+    pub hypervisor: Hypervisor,
+    /// enable KVM
+    pub kvm: bool,
+    /// wait for GDB to attach
+    pub gdb: bool,
+    /// persist UEFI variables in this file
+    ///
+    /// It gets created from the firmware's template if it does not exist, yet.
+    pub nvram: Option<PathBuf>,
+    /// don't show a window
+    pub headless: bool,
+    /// additionally write the serial output to this file
+    pub serial_log: Option<PathBuf>,
+    /// open a QMP socket on this port on localhost
+    ///
+    /// Use [`Qmp::connect`] to control the virtual machine after spawning it.
+    pub qmp: Option<u16>,
+    /// boot with Secure Boot enabled (QEMU on x86 only)
+    ///
+    /// Either pass a local firmware build with SMM support as `firmware` and
+    /// a variable store with the enrolled keys as `nvram`, or use a build from
+    /// edk2-nightly and let `virt-fw-vars` enroll the default keys
+    /// (in `nvram` if it doesn't exist, yet, or in a temporary file).
+    pub secure_boot: bool,
+    /// add QEMU's isa-debug-exit device at port 0xf4
+    ///
+    /// The guest can then terminate the virtual machine by writing its exit
+    /// code to the port; see [`BootedVm::debug_exit_code`].
+    pub debug_exit: bool,
+    /// write the firmware's debug output to this file (QEMU on x86 only)
+    ///
+    /// [`gdb::find_load_base`] can find out where towboot has been loaded from it.
+    pub firmware_log: Option<PathBuf>,
+    /// how much memory the virtual machine gets in MiB
+    ///
+    /// (This defaults to 256 MiB, or 768 MiB in Bochs.)
+    pub memory: Option<u32>,
+    /// how many CPUs the virtual machine gets (1 by default)
+    pub smp: Option<u32>,
+    /// attach the image via this bus (QEMU only)
+    ///
+    /// By default, it's IDE on x86 and virtio elsewhere.
+    pub disk_bus: Option<DiskBus>,
+    /// add a TPM 2.0 emulated by swtpm (QEMU on x86 and aarch64 only)
+    pub tpm: bool,
+    /// don't attach the image, but serve this directory via TFTP (QEMU only)
+    ///
+    /// It should contain towboot at the usual place (such as [`X64_BOOT_PATH`]).
+    pub netboot: Option<PathBuf>,
+    /// settings that only apply to Bochs
+    pub bochs: BochsOptions,
+}
+
+impl BootOptions {
+    /// Get the architecture of the firmware.
+    pub(crate) fn firmware_arch(&self) -> Arch {
+        self.firmware_arch.unwrap_or(self.arch)
+    }
+
+    /// Get the amount of memory in MiB.
+    pub(crate) fn memory(&self) -> u32 {
+        self.memory.unwrap_or(match self.hypervisor {
+            // the firmware needs more memory in Bochs
+            Hypervisor::Bochs => 768,
+            _ => 256,
+        })
+    }
+
+    /// Get the number of CPUs.
+    pub(crate) fn cpus(&self) -> u32 {
+        self.smp.unwrap_or(1)
+    }
+}
+
+/// Check whether the hypervisor supports the given options.
+fn check_boot_options(options: &BootOptions) -> anyhow::Result<()> {
+    if options.firmware_arch() != options.arch {
+        if options.hypervisor != Hypervisor::Qemu {
+            return Err(anyhow!(
+                "can't use a different firmware architecture in {:?}", options.hypervisor,
+            ));
+        }
+        if (options.arch, options.firmware_arch()) != (Arch::X86_64, Arch::I686) {
+            return Err(anyhow!(
+                "can't boot {:?} firmware on {:?}", options.firmware_arch(), options.arch,
+            ));
+        }
+    }
+    // cloud-hypervisor and crosvm always use KVM
+    if options.kvm && matches!(
+        options.hypervisor,
+        Hypervisor::Bochs | Hypervisor::VirtualBox | Hypervisor::VMware,
+    ) {
+        return Err(anyhow!("can't do KVM in {:?}", options.hypervisor));
+    }
+    if options.nvram.is_some() && options.hypervisor != Hypervisor::Qemu {
+        return Err(anyhow!(
+            "can't persist UEFI variables in {:?}", options.hypervisor,
+        ));
+    }
+    if options.qmp.is_some() && options.hypervisor != Hypervisor::Qemu {
+        return Err(anyhow!("can't open a QMP socket in {:?}", options.hypervisor));
+    }
+    if options.secure_boot {
+        if options.hypervisor != Hypervisor::Qemu || !options.arch.is_x86() {
+            return Err(anyhow!("Secure Boot is only available in QEMU on x86"));
+        }
+        if options.nvram.is_none() && matches!(options.firmware, Pin::Local(_)) {
+            return Err(anyhow!(
+                "Secure Boot with a local firmware build needs a variable store with enrolled keys",
+            ));
+        }
+    }
+    if options.debug_exit && (
+        options.hypervisor != Hypervisor::Qemu || !options.arch.is_x86()
+    ) {
+        return Err(anyhow!("isa-debug-exit is only available in QEMU on x86"));
+    }
+    if options.disk_bus.is_some() && options.hypervisor != Hypervisor::Qemu {
+        return Err(anyhow!("can't choose the disk bus in {:?}", options.hypervisor));
+    }
+    if options.disk_bus == Some(DiskBus::Ide) && !options.arch.is_x86() {
+        return Err(anyhow!("there is no IDE controller on {:?}", options.arch));
+    }
+    if options.tpm && (
+        options.hypervisor != Hypervisor::Qemu || options.arch == Arch::Riscv64
+    ) {
+        return Err(anyhow!("a TPM is only available in QEMU on x86 and aarch64"));
+    }
+    if options.netboot.is_some() {
+        if options.hypervisor != Hypervisor::Qemu {
+            return Err(anyhow!("can't boot from the network in {:?}", options.hypervisor));
+        }
+        if options.disk_bus.is_some() {
+            return Err(anyhow!("there is no disk when booting from the network"));
+        }
+    }
+    if options.memory == Some(0) || options.smp == Some(0) {
+        return Err(anyhow!("the virtual machine needs memory and at least one CPU"));
+    }
+    if options.firmware_log.is_some() && (
+        options.hypervisor != Hypervisor::Qemu || !options.arch.is_x86()
+    ) {
+        return Err(anyhow!("the firmware's debug output is only available in QEMU on x86"));
+    }
+    if options.gdb && !matches!(options.hypervisor, Hypervisor::Qemu | Hypervisor::Bochs) {
+        return Err(anyhow!("can't wait for GDB in {:?}", options.hypervisor));
+    }
+    if options.arch != Arch::X86_64 && matches!(
+        options.hypervisor, Hypervisor::CloudHypervisor | Hypervisor::Crosvm,
+    ) {
+        return Err(anyhow!("{:?} only supports x86_64", options.hypervisor));
+    }
+    if !options.arch.is_x86() && options.hypervisor != Hypervisor::Qemu {
+        return Err(anyhow!("{:?} doesn't support {:?}", options.hypervisor, options.arch));
+    }
+    Ok(())
+}
+
+/// Prepare booting a built image, returning a handle to the virtual machine.
+///
+/// Use [`BootedVm::spawn`] or [`BootedVm::spawn_captured`] to actually start it.
+pub fn boot_image(image: &Path, options: &BootOptions) -> Result<BootedVm, Error> {
+    check_boot_options(options).map_err(Error::hypervisor)?;
+    let swtpm = options.tpm.then(|| {
+        info!("spawning swtpm");
+        Swtpm::spawn()
+    }).transpose().map_err(Error::hypervisor)?;
+    let (command, temp_files) = match options.hypervisor {
+        Hypervisor::Qemu => {
+            info!("spawning QEMU");
+            let tpm_socket = swtpm.as_ref().map(Swtpm::socket);
+            qemu(image, options, tpm_socket.as_deref()).map_err(Error::hypervisor)?
+        },
+        Hypervisor::Bochs => {
+            info!("getting firmware");
+            let firmware_path = match options.arch {
+                Arch::X86_64 => firmware::x64(&options.firmware),
+                _ => firmware::ia32(&options.firmware),
+            }.map_err(Error::firmware)?;
+            info!("spawning Bochs");
+            let config = bochsrc(&firmware_path, image, options)
+                .map_err(Error::hypervisor)?.into_temp_path();
+            let mut bochs = Command::new("bochs");
+            bochs.arg("-qf").arg(config.as_os_str());
+            (bochs, vec![config])
+        },
+        Hypervisor::VirtualBox => {
+            info!("spawning VirtualBox");
+            (virtualbox(
+                image, options.arch == Arch::X86_64, options.headless,
+                options.serial_log.as_deref(), options.memory(), options.cpus(),
+            ).map_err(Error::hypervisor)?, vec![])
+        },
+        Hypervisor::VMware => {
+            info!("spawning VMware");
+            (vmware(
+                image, options.arch == Arch::X86_64, options.headless,
+                options.serial_log.as_deref(), options.memory(), options.cpus(),
+            ).map_err(Error::hypervisor)?, vec![])
+        },
+        Hypervisor::CloudHypervisor => {
+            info!("getting firmware");
+            let firmware_path = firmware::cloud_hypervisor(&options.firmware)
+                .map_err(Error::firmware)?;
+            info!("spawning cloud-hypervisor");
+            (cloud_hypervisor(
+                &firmware_path, image, options.serial_log.as_deref(),
+                options.memory(), options.cpus(),
+            ), vec![])
+        },
+        Hypervisor::Crosvm => {
+            info!("getting firmware");
+            let firmware_path = firmware::x64(&options.firmware).map_err(Error::firmware)?;
+            info!("spawning crosvm");
+            (crosvm(
+                &firmware_path, image, options.serial_log.as_deref(),
+                options.memory(), options.cpus(),
+            ), vec![])
+        },
     };
-    Ok(if use_bochs {
-        info!("spawning Bochs");
-        if use_kvm {
-            return Err(anyhow!("can't do KVM in Bochs").into());
-        }
-        let config = bochsrc(&firmware_path, image, use_gdb)?.into_temp_path();
-        let mut bochs = Command::new("bochs");
-        bochs.arg("-qf").arg(config.as_os_str());
-        (bochs, vec![config])
+    Ok(BootedVm::new(command, temp_files).with_swtpm(swtpm))
+}
+
+/// Turn an unsuccessful exit status into an error.
+///
+/// (This does the same as `ExitStatus::exit_ok`, which is not stable, yet.)
+pub fn exit_ok(status: ExitStatus) -> io::Result<()> {
+    if status.success() {
+        Ok(())
     } else {
-        info!("spawning QEMU");
-        let mut qemu = Command::new(match is_x86_64 {
-            false => "qemu-system-i386",
-            true => "qemu-system-x86_64",
-        });
-        qemu
-            .arg("-m").arg("256")
-            .arg("-hda").arg(image)
-            .arg("-serial").arg("stdio")
-            .arg("-bios").arg(firmware_path);
-        if use_kvm {
-            qemu.arg("-machine").arg("pc,accel=kvm");
+        Err(io::Error::other(format!("process exited unsuccessfully: {status}")))
+    }
+}
+
+/// Wait for a process to exit, killing it when the timeout is reached.
+///
+/// Returns None if the process had to be killed.
+pub fn wait_with_timeout(
+    process: &mut Child, timeout: Duration,
+) -> io::Result<Option<ExitStatus>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = process.try_wait()? {
+            return Ok(Some(status));
         }
-        if use_gdb {
-            info!("The machine starts paused, waiting for GDB to attach to localhost:1234.");
-            qemu.arg("-s").arg("-S");
+        if start.elapsed() >= timeout {
+            process.kill()?;
+            process.wait()?;
+            return Ok(None);
         }
-        (qemu, vec![])
-    })
+        sleep(Duration::from_millis(100));
+    }
 }
 
 #[cfg(feature = "args")]
@@ -174,22 +539,119 @@ pub struct BootImageCommand {
     #[argh(switch)]
     x86_64: bool,
 
+    /// use aarch64 instead of i686
+    #[argh(switch)]
+    aarch64: bool,
+
+    /// use riscv64 instead of i686
+    #[argh(switch)]
+    riscv64: bool,
+
+    /// use the 32-bit firmware (together with --x86-64)
+    #[argh(switch)]
+    ia32_firmware: bool,
+
     /// enable KVM
     #[argh(switch)]
     kvm: bool,
 
-    /// use Bochs instead of QEMU
-    #[argh(switch)]
-    bochs: bool,
+    /// which virtual machine to use: qemu (default), bochs, virtualbox, vmware,
+    /// cloud-hypervisor or crosvm
+    #[argh(option, default = "Hypervisor::Qemu")]
+    hypervisor: Hypervisor,
 
-    /// wait for GDB to attach
+    /// wait for GDB to attach and write a script for it (see --gdb-script)
     #[argh(switch)]
     gdb: bool,
 
+    /// where to write the GDB script (together with --gdb)
+    #[argh(option, default = "PathBuf::from(\"towboot.gdb\")")]
+    gdb_script: PathBuf,
+
+    /// the towboot binary to load the symbols from (together with --gdb)
+    #[argh(option)]
+    symbols: Option<PathBuf>,
+
+    /// start GDB with the script (together with --gdb)
+    #[argh(switch)]
+    launch_gdb: bool,
+
     /// use the specified firmware instead of OVMF
     #[argh(option)]
     firmware: Option<PathBuf>,
 
+    /// use the OVMF build from this revision of edk2-nightly instead of the latest one
+    #[argh(option)]
+    firmware_revision: Option<String>,
+
+    /// download the firmware from this URL instead
+    #[argh(option)]
+    firmware_url: Option<String>,
+
+    /// persist UEFI variables in this file (it is created if it does not exist)
+    #[argh(option)]
+    nvram: Option<PathBuf>,
+
+    /// don't show a window
+    #[argh(switch)]
+    headless: bool,
+
+    /// additionally write the serial output to this file
+    #[argh(option)]
+    serial_log: Option<PathBuf>,
+
+    /// stop the virtual machine after this many seconds
+    #[argh(option)]
+    timeout: Option<u64>,
+
+    /// open a QMP socket on this port on localhost (QEMU only)
+    #[argh(option)]
+    qmp: Option<u16>,
+
+    /// enable Secure Boot with the default keys (QEMU on x86 only, needs virt-fw-vars)
+    #[argh(switch)]
+    secure_boot: bool,
+
+    /// let the guest exit by writing to port 0xf4, 0x10 means success (QEMU on x86 only)
+    #[argh(switch)]
+    debug_exit: bool,
+
+    /// how much memory the virtual machine gets in MiB (256 by default)
+    #[argh(option)]
+    memory: Option<u32>,
+
+    /// how many CPUs the virtual machine gets (1 by default)
+    #[argh(option)]
+    smp: Option<u32>,
+
+    /// attach the image via ide, ahci, virtio, nvme or usb (QEMU only)
+    #[argh(option)]
+    disk_bus: Option<DiskBus>,
+
+    /// attach the image as a removable USB stick (short for --disk-bus usb)
+    #[argh(switch)]
+    usb: bool,
+
+    /// add a TPM 2.0 emulated by swtpm (QEMU on x86 and aarch64 only)
+    #[argh(switch)]
+    tpm: bool,
+
+    /// boot from the network, serving this directory via TFTP instead of using the image
+    #[argh(option)]
+    netboot: Option<PathBuf>,
+
+    /// the display library Bochs should use (defaults to the usual one for this OS)
+    #[argh(option)]
+    bochs_display: Option<String>,
+
+    /// the VGA BIOS Bochs should use (it is searched for by default)
+    #[argh(option)]
+    bochs_vga_rom: Option<PathBuf>,
+
+    /// write the log of Bochs to this file
+    #[argh(option)]
+    bochs_log: Option<PathBuf>,
+
     /// additional arguments to pass to the hypervisor
     #[argh(positional, greedy)]
     args: Vec<String>,
@@ -197,15 +659,236 @@ pub struct BootImageCommand {
 
 #[cfg(feature = "args")]
 impl BootImageCommand {
-    pub fn r#do(&self) -> Result<(), Box<dyn Error>> {
-        let (mut process, _temp_files) = boot_image(
-            self.firmware.as_deref(), &self.image, self.x86_64, self.bochs,
-            self.kvm, self.gdb,
-        )?;
-        process
-            .args(&self.args)
-            .status()?
-            .exit_ok()?;
+    pub fn r#do(&self) -> Result<(), Box<dyn StdError>> {
+        let disk_bus = match (self.usb, self.disk_bus) {
+            (true, Some(bus)) if bus != DiskBus::Usb => {
+                return Err("--usb can't be combined with another --disk-bus".into());
+            },
+            (true, _) => Some(DiskBus::Usb),
+            (false, bus) => bus,
+        };
+        let mut vm = boot_image(&self.image, &BootOptions {
+            firmware: firmware_pin(
+                self.firmware.as_deref(), self.firmware_revision.as_deref(),
+                self.firmware_url.as_deref(),
+            ),
+            arch: arch(self.x86_64, self.aarch64, self.riscv64)?,
+            firmware_arch: self.ia32_firmware.then_some(Arch::I686),
+            hypervisor: self.hypervisor,
+            kvm: self.kvm,
+            gdb: self.gdb,
+            nvram: self.nvram.clone(),
+            headless: self.headless,
+            serial_log: self.serial_log.clone(),
+            qmp: self.qmp,
+            secure_boot: self.secure_boot,
+            debug_exit: self.debug_exit,
+            firmware_log: self.firmware_log(),
+            memory: self.memory,
+            smp: self.smp,
+            disk_bus,
+            tpm: self.tpm,
+            netboot: self.netboot.clone(),
+            bochs: BochsOptions {
+                display_library: self.bochs_display.clone(),
+                vga_rom: self.bochs_vga_rom.clone(),
+                log: self.bochs_log.clone(),
+            },
+        })?;
+        vm.command().args(&self.args);
+        if self.gdb {
+            self.prepare_gdb()?;
+        }
+        let mut gdb = (self.gdb && self.launch_gdb).then(|| {
+            let mut gdb = Command::new("gdb");
+            gdb.arg("-x").arg(&self.gdb_script);
+            gdb
+        });
+        if gdb.is_some() {
+            // GDB needs the terminal for itself
+            vm.command().stdin(Stdio::null());
+        }
+        vm.spawn()?;
+        if let Some(gdb) = &mut gdb {
+            gdb.spawn()?;
+        }
+        let status = if let Some(timeout) = self.timeout {
+            match vm.wait_with_timeout(Duration::from_secs(timeout))? {
+                Some(status) => status,
+                None => {
+                    info!("stopped the virtual machine after {timeout} seconds");
+                    return Ok(());
+                },
+            }
+        } else {
+            vm.wait()?
+        };
+        match vm.debug_exit_code().filter(|_| self.debug_exit) {
+            Some(DEBUG_EXIT_SUCCESS) => Ok(()),
+            // QEMU exits with 1 on its own errors, too
+            Some(0) => Err(anyhow!("QEMU failed (or the guest exited with code 0)").into()),
+            Some(code) => Err(anyhow!("the guest exited with code {code}").into()),
+            None => Ok(exit_ok(status)?),
+        }
+    }
+
+    /// Where to write the firmware's debug output to find towboot's load address.
+    fn firmware_log(&self) -> Option<PathBuf> {
+        (
+            self.gdb && self.hypervisor == Hypervisor::Qemu
+            && !self.aarch64 && !self.riscv64
+        ).then(|| self.gdb_script.with_extension("log"))
+    }
+
+    /// Write the GDB script and load the symbols once towboot has been loaded.
+    fn prepare_gdb(&self) -> Result<(), Box<dyn StdError>> {
+        let symbol_script = self.gdb_script.with_extension("symbols.gdb");
+        fs::write(&self.gdb_script, gdb::script(
+            arch(self.x86_64, self.aarch64, self.riscv64)?, &symbol_script,
+        ))?;
+        info!("wrote a GDB script to {}", self.gdb_script.display());
+        let Some(firmware_log) = self.firmware_log() else {
+            return Ok(());
+        };
+        // the log might be left over from a previous run
+        let _ = fs::remove_file(&firmware_log);
+        let symbols = self.symbols.clone();
+        thread::spawn(move || {
+            let base = match gdb::wait_for_load_base(&firmware_log, gdb::LOAD_BASE_TIMEOUT) {
+                Ok(base) => base,
+                Err(e) => {
+                    warn!("failed to find out where towboot has been loaded: {e}");
+                    let script = format!("echo failed to find towboot: {e}\\n\n");
+                    if let Err(e) = fs::write(&symbol_script, script) {
+                        warn!("failed to write {}: {e}", symbol_script.display());
+                    }
+                    return;
+                },
+            };
+            info!("towboot has been loaded at {base:#x}");
+            let script = match &symbols {
+                Some(symbols) => gdb::symbol_script(symbols, base),
+                None => format!("# towboot has been loaded at {base:#x}, pass --symbols\n"),
+            };
+            match fs::write(&symbol_script, script) {
+                Ok(()) => info!("run towboot-symbols in GDB to load the symbols"),
+                Err(e) => warn!("failed to write {}: {e}", symbol_script.display()),
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Determine which architecture to use from the command line options.
+#[cfg(feature = "args")]
+fn arch(x86_64: bool, aarch64: bool, riscv64: bool) -> Result<Arch, Box<dyn StdError>> {
+    match (x86_64, aarch64, riscv64) {
+        (false, false, false) => Ok(Arch::I686),
+        (true, false, false) => Ok(Arch::X86_64),
+        (false, true, false) => Ok(Arch::Aarch64),
+        (false, false, true) => Ok(Arch::Riscv64),
+        _ => Err(anyhow!("only one of --x86-64, --aarch64 and --riscv64 can be used").into()),
+    }
+}
+
+/// Determine which firmware to use from the command line options.
+///
+/// A local file takes precedence over a URL which takes precedence over a revision.
+#[cfg(feature = "args")]
+fn firmware_pin(file: Option<&Path>, revision: Option<&str>, url: Option<&str>) -> Pin {
+    if let Some(file) = file {
+        Pin::Local(file.to_path_buf())
+    } else if let Some(url) = url {
+        Pin::Url(url.to_string())
+    } else if let Some(revision) = revision {
+        Pin::Revision(revision.to_string())
+    } else {
+        Pin::Latest
+    }
+}
+
+#[cfg(feature = "args")]
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "firmware")]
+/// Manage the cached firmware builds.
+pub struct FirmwareCommand {
+    #[argh(subcommand)]
+    command: FirmwareSubcommand,
+}
+
+#[cfg(feature = "args")]
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum FirmwareSubcommand {
+    Fetch(FirmwareFetchCommand),
+    List(FirmwareListCommand),
+    Purge(FirmwarePurgeCommand),
+}
+
+#[cfg(feature = "args")]
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "fetch")]
+/// Download a firmware build (if it isn't cached yet) and print its path.
+struct FirmwareFetchCommand {
+    /// get the firmware for x86_64 instead of i686
+    #[argh(switch)]
+    x86_64: bool,
+
+    /// get the firmware for aarch64 instead of i686
+    #[argh(switch)]
+    aarch64: bool,
+
+    /// get the firmware for riscv64 instead of i686
+    #[argh(switch)]
+    riscv64: bool,
+
+    /// get the OVMF build from this revision of edk2-nightly instead of the latest one
+    #[argh(option)]
+    revision: Option<String>,
+
+    /// download the firmware from this URL instead
+    #[argh(option)]
+    url: Option<String>,
+
+    /// make sure the firmware has this SHA-256
+    #[argh(option)]
+    sha256: Option<String>,
+}
+
+#[cfg(feature = "args")]
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "list")]
+/// List the cached firmware builds.
+struct FirmwareListCommand {}
+
+#[cfg(feature = "args")]
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "purge")]
+/// Delete all cached firmware builds.
+struct FirmwarePurgeCommand {}
+
+#[cfg(feature = "args")]
+impl FirmwareCommand {
+    pub fn r#do(&self) -> Result<(), Box<dyn StdError>> {
+        match &self.command {
+            FirmwareSubcommand::Fetch(fetch) => {
+                let pin = firmware_pin(
+                    None, fetch.revision.as_deref(), fetch.url.as_deref(),
+                );
+                let arch = arch(fetch.x86_64, fetch.aarch64, fetch.riscv64)?;
+                let path = match &fetch.sha256 {
+                    Some(checksum) => firmware::firmware_checked(arch, &pin, checksum)?,
+                    None => firmware::firmware(arch, &pin)?,
+                };
+                println!("{}", path.display());
+            },
+            FirmwareSubcommand::List(_) => {
+                for (url, path) in firmware::list_cached()? {
+                    println!("{url}\n    {}", path.display());
+                }
+            },
+            FirmwareSubcommand::Purge(_) => firmware::purge()?,
+        }
         Ok(())
     }
 }
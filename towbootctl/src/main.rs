@@ -2,14 +2,27 @@
 use std::error::Error;
 use std::fs;
 use std::env;
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
 use argh::{FromArgs, from_env};
-use log::info;
-use tempfile::NamedTempFile;
+use log::{info, warn};
+use log::kv::{self, Key, Value, VisitSource};
+use tempfile::{NamedTempFile, TempPath};
 
-use towbootctl::{BootImageCommand, create_image, config, runtime_args_to_load_options};
+use towbootctl::{
+    AA64_BOOT_PATH, Arch, BootImageCommand, BootOptions, DEFAULT_ESP_SIZE,
+    DEFAULT_PARTITION_NAME, DEFAULT_SECTOR_SIZE, Esp, FirmwareCommand, IA32_BOOT_PATH, Image,
+    Partition, Uuid, X64_BOOT_PATH, bench, boot_entry, config, doctor, get_binary_arch,
+    get_towboot_version, manifest, release, runtime_args_to_load_options,
+};
+use towbootctl::compress::{Compression, compress};
+use towbootctl::install::{
+    Backup, INSTALLED_BINARY, binary_name, firmware_arch, get_config_path, get_install_path,
+    install,
+};
 
 #[allow(dead_code)]
 mod built_info {
@@ -19,19 +32,155 @@ mod built_info {
 #[derive(Debug, FromArgs)]
 /// Top-level command.
 struct Cli {
+    /// how to format the log: text (the default) or json (one object per line)
+    #[argh(option, default = "LogFormat::Text")]
+    log_format: LogFormat,
+
     #[argh(subcommand)]
     command: Command,
 }
 
+/// How to format the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    /// human-readable lines
+    Text,
+    /// one JSON object per line, for CI systems
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format: {s}")),
+        }
+    }
+}
+
 #[derive(Debug, FromArgs)]
 #[argh(subcommand)]
 enum Command {
+    Bench(BenchCommand),
     BootImage(BootImageCommand),
+    Doctor(DoctorCommand),
+    Firmware(FirmwareCommand),
     Image(ImageCommand),
+    Inspect(InspectCommand),
     Install(InstallCommand),
+    Partition(PartitionCommand),
+    Schema(SchemaCommand),
+    Uninstall(UninstallCommand),
+    Update(UpdateCommand),
+    Validate(ValidateCommand),
+    Verify(VerifyCommand),
     Version(VersionCommand),
 }
 
+impl Command {
+    /// Get the name of the subcommand (for the log).
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Bench(_) => "bench",
+            Command::BootImage(_) => "boot-image",
+            Command::Doctor(_) => "doctor",
+            Command::Firmware(_) => "firmware",
+            Command::Image(_) => "image",
+            Command::Inspect(_) => "inspect",
+            Command::Install(_) => "install",
+            Command::Partition(_) => "partition",
+            Command::Schema(_) => "schema",
+            Command::Uninstall(_) => "uninstall",
+            Command::Update(_) => "update",
+            Command::Validate(_) => "validate",
+            Command::Verify(_) => "verify",
+            Command::Version(_) => "version",
+        }
+    }
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "bench")]
+/// Boot an image several times and measure how long it takes.
+struct BenchCommand {
+    /// what image to boot
+    #[argh(option, default = "PathBuf::from(\"image.img\")")]
+    image: PathBuf,
+
+    /// use x86_64 instead of i686
+    #[argh(switch)]
+    x86_64: bool,
+
+    /// enable KVM
+    #[argh(switch)]
+    kvm: bool,
+
+    /// how often to boot
+    #[argh(option, default = "10")]
+    runs: usize,
+
+    /// measure the time until this text appears on the serial port, can be repeated
+    /// (default: when towboot starts loading and when it hands over to the kernel)
+    #[argh(option)]
+    marker: Vec<String>,
+
+    /// give up on a run after this many seconds
+    #[argh(option, default = "30")]
+    timeout: u64,
+}
+
+impl BenchCommand {
+    fn r#do(&self) -> Result<(), Box<dyn Error>> {
+        let markers: Vec<&str> = if self.marker.is_empty() {
+            bench::DEFAULT_MARKERS.to_vec()
+        } else {
+            self.marker.iter().map(String::as_str).collect()
+        };
+        let statistics = bench::bench(&self.image, &BootOptions {
+            arch: if self.x86_64 { Arch::X86_64 } else { Arch::I686 },
+            kvm: self.kvm,
+            ..Default::default()
+        }, self.runs, &markers, Duration::from_secs(self.timeout))?;
+        println!(
+            "{:<24} {:>5} {:>9} {:>9} {:>9} {:>9}",
+            "marker", "runs", "min", "median", "mean", "max",
+        );
+        for s in statistics {
+            println!(
+                "{:<24} {:>5} {:>7}ms {:>7}ms {:>7}ms {:>7}ms",
+                format!("{:?}", s.marker), s.count, s.min.as_millis(), s.median.as_millis(),
+                s.mean.as_millis(), s.max.as_millis(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "doctor")]
+/// Check whether everything needed to build and boot images is there.
+struct DoctorCommand {}
+
+impl DoctorCommand {
+    fn r#do(&self) -> Result<(), Box<dyn Error>> {
+        let checks = doctor::run();
+        for check in &checks {
+            println!("[{}] {}: {}", check.status, check.name, check.detail);
+            if let Some(suggestion) = &check.suggestion {
+                println!("    -> {suggestion}");
+            }
+        }
+        if checks.iter().any(|check| check.status == doctor::Status::Error) {
+            Err("some checks failed".into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
 #[derive(Debug, FromArgs)]
 #[argh(subcommand, name = "image")]
 /// Build a bootable image containing towboot, kernels and their modules.
@@ -40,29 +189,203 @@ struct ImageCommand {
     #[argh(option, default = "PathBuf::from(\"image.img\")")]
     target: PathBuf,
 
+    /// write the image directly to this device (such as /dev/sdX or \\.\PhysicalDriveN)
+    /// instead of a file, erasing everything on it
+    #[argh(option)]
+    device: Option<PathBuf>,
+
+    /// don't ask before overwriting the device
+    #[argh(switch)]
+    yes: bool,
+
+    /// the label of the FAT filesystem (at most 11 characters)
+    #[argh(option)]
+    volume_label: Option<String>,
+
+    /// the name of the GPT partition
+    #[argh(option, default = "DEFAULT_PARTITION_NAME.to_string()")]
+    partition_name: String,
+
+    /// the GUID of the disk (random by default)
+    #[argh(option)]
+    disk_guid: Option<Uuid>,
+
+    /// the unique GUID of the partition, as in PARTUUID (random by default)
+    #[argh(option)]
+    part_guid: Option<Uuid>,
+
+    /// copy the contents of this directory into the image (keeping the layout)
+    #[argh(option)]
+    from_dir: Option<PathBuf>,
+
+    /// add a partition after the ESP ("name=data,type=linux,size=1G,from=./rootfs.img"),
+    /// can be repeated
+    #[argh(option)]
+    extra_part: Vec<Partition>,
+
+    /// the logical sector size of the image (512 or 4096)
+    #[argh(option, default = "DEFAULT_SECTOR_SIZE")]
+    sector_size: u64,
+
+    /// add a menu entry ("name: kernel args"), can be repeated
+    /// The first one is the default.
+    #[argh(option)]
+    entry: Vec<String>,
+
+    /// add a module to a menu entry ("name: module args"), can be repeated
+    #[argh(option)]
+    entry_module: Vec<String>,
+
+    /// add a manifest with the checksums of all files (inside the image and next to it)
+    #[argh(switch)]
+    manifest: bool,
+
+    /// compress the image (zstd, xz or zip), appending the extension to its name
+    #[argh(option)]
+    compress: Option<Compression>,
+
+    /// keep the uncompressed image when compressing it
+    #[argh(switch)]
+    keep_raw: bool,
+
     /// runtime options to pass to towboot
     #[argh(positional, greedy)]
     runtime_args: Vec<String>,
 }
 
+/// Ask the user a yes/no question on the command line.
+fn confirm(question: &str) -> Result<bool, Box<dyn Error>> {
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Turn the menu entries given via `--entry` and `--entry-module` into runtime
+/// options pointing to a generated configuration file.
+///
+/// The returned path needs to be kept as long as the runtime options are used.
+fn entries_to_runtime_args(
+    entries: &[String], modules: &[String], runtime_args: &[String],
+) -> Result<(Vec<String>, Option<TempPath>), Box<dyn Error>> {
+    if entries.is_empty() {
+        if !modules.is_empty() {
+            return Err("--entry-module needs an --entry".into());
+        }
+        return Ok((runtime_args.to_vec(), None));
+    }
+    if !runtime_args.is_empty() {
+        return Err("--entry can't be combined with runtime options".into());
+    }
+    let config = config::from_entries(entries, modules)?;
+    let mut config_file = NamedTempFile::new()?;
+    config_file.as_file_mut().write_all(config::to_string(&config)?.as_bytes())?;
+    let config_path = config_file.into_temp_path();
+    Ok((
+        vec!["-config".to_string(), config_path.to_str().unwrap().to_string()],
+        Some(config_path),
+    ))
+}
+
 impl ImageCommand {
     fn r#do(&self) -> Result<(), Box<dyn Error>> {
+        let (runtime_args, _config_path) = entries_to_runtime_args(
+            &self.entry, &self.entry_module, &self.runtime_args,
+        )?;
+        if self.compress.is_some() && self.device.is_some() {
+            return Err("--compress can't be combined with --device".into());
+        }
         let mut towboot_temp_ia32 = NamedTempFile::new()?;
         towboot_temp_ia32.as_file_mut().write_all(towboot_ia32::TOWBOOT)?;
         let mut towboot_temp_x64 = NamedTempFile::new()?;
         towboot_temp_x64.as_file_mut().write_all(towboot_x64::TOWBOOT)?;
+        let mut towboot_temp_aa64 = NamedTempFile::new()?;
+        towboot_temp_aa64.as_file_mut().write_all(towboot_aa64::TOWBOOT)?;
 
-        create_image(
-            &self.target,
-            &self.runtime_args,
-            Some(&towboot_temp_ia32.into_temp_path()),
-            Some(&towboot_temp_x64.into_temp_path()),
-        )?;
+        // when writing to a device, build the image in a temporary file first
+        let device_image = self.device.as_ref()
+            .map(|_| NamedTempFile::new().map(NamedTempFile::into_temp_path))
+            .transpose()?;
+        let target = device_image.as_deref().unwrap_or(&self.target);
+        let towboot_temp_ia32 = towboot_temp_ia32.into_temp_path();
+        let towboot_temp_x64 = towboot_temp_x64.into_temp_path();
+        let towboot_temp_aa64 = towboot_temp_aa64.into_temp_path();
+        let mut builder = Image::builder()
+            .partition_name(&self.partition_name)
+            .sector_size(self.sector_size)
+            .add_runtime_args(&runtime_args)?
+            .add_towboot_ia32(&towboot_temp_ia32)
+            .add_towboot_x64(&towboot_temp_x64)
+            .add_towboot_aa64(&towboot_temp_aa64);
+        // files from the directory take precedence over the bundled ones
+        if let Some(from_dir) = &self.from_dir {
+            builder = builder.add_directory(from_dir, Path::new(""))?;
+        }
+        for partition in &self.extra_part {
+            builder = builder.add_partition(partition.clone());
+        }
+        if let Some(volume_label) = &self.volume_label {
+            builder = builder.label(volume_label);
+        }
+        if let Some(disk_guid) = self.disk_guid {
+            builder = builder.disk_guid(disk_guid);
+        }
+        if let Some(part_guid) = self.part_guid {
+            builder = builder.partition_guid(part_guid);
+        }
+        let mut image = builder.build(target)?;
+        let release = release::create(&image, &build_info())?;
+        image.add_data(release.as_bytes(), Path::new(release::RELEASE_PATH))?;
+
+        if self.manifest {
+            let manifest = manifest::create(&image)?;
+            let mut manifest_path = self.target.as_os_str().to_owned();
+            manifest_path.push(".manifest.json");
+            info!("writing manifest to {}", Path::new(&manifest_path).display());
+            fs::write(&manifest_path, &manifest)?;
+            image.add_data(manifest.as_bytes(), Path::new(manifest::MANIFEST_PATH))?;
+        }
+        drop(image);
+
+        if let Some(compression) = self.compress {
+            compress(&self.target, compression)?;
+            if !self.keep_raw {
+                fs::remove_file(&self.target)?;
+            }
+        }
+
+        if let (Some(device), Some(device_image)) = (&self.device, &device_image) {
+            if !self.yes && !confirm(&format!(
+                "All data on {} will be lost. Continue?", device.display(),
+            ))? {
+                return Err("aborted".into());
+            }
+            info!("writing image to {}", device.display());
+            towbootctl::device::write(device_image, device)?;
+        }
 
         Ok(())
     }
 }
 
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "inspect")]
+/// Show which build of towboot is inside an image.
+struct InspectCommand {
+    #[argh(positional)]
+    /// the image to inspect
+    image: PathBuf,
+}
+
+impl InspectCommand {
+    fn r#do(&self) -> Result<(), Box<dyn Error>> {
+        let image = Image::open(&self.image)?;
+        print!("{}", release::describe(&image)?);
+        Ok(())
+    }
+}
+
 #[derive(Debug, FromArgs)]
 #[argh(subcommand, name = "install")]
 /// Install towboot, the configuration file, its kernels and modules to a disk.
@@ -75,6 +398,41 @@ struct InstallCommand {
     #[argh(switch)]
     register: bool,
 
+    /// the architecture to install towboot for, can be repeated for removable installs
+    /// (i686, x86_64 or aarch64; defaults to all for removable installs and to the
+    /// architecture of this machine's firmware otherwise)
+    #[argh(option)]
+    arch: Vec<Arch>,
+
+    /// runtime option to store in the registered boot entry, can be repeated
+    /// towboot parses these just like arguments passed to it in the shell,
+    /// so paths are relative to the ESP.
+    #[argh(option)]
+    load_option: Vec<String>,
+
+    /// find (and mount) the ESP automatically
+    /// All positional arguments are passed to towboot then.
+    #[argh(switch)]
+    auto_esp: bool,
+
+    /// restore the files the last install overwrote instead of installing
+    #[argh(switch)]
+    rollback: bool,
+
+    /// add a menu entry ("name: kernel args"), can be repeated
+    /// The first one is the default.
+    #[argh(option)]
+    entry: Vec<String>,
+
+    /// add a module to a menu entry ("name: module args"), can be repeated
+    #[argh(option)]
+    entry_module: Vec<String>,
+
+    /// a folder to copy next to the configuration (such as themes or extra files)
+    /// Kernels and modules from there keep their place in it.
+    #[argh(option)]
+    assets: Option<PathBuf>,
+
     /// the operating system's name
     /// This is being used as the folder name inside /EFI and as the name for
     /// the boot entry.
@@ -90,68 +448,449 @@ struct InstallCommand {
     runtime_args: Vec<String>,
 }
 
+/// Check that the options describing an install fit together.
+///
+/// Removable installs can't be registered with the firmware (as `register`
+/// would do), all other installs need a name.
+fn check_install_kind(
+    removable: bool, register: Option<&str>, name: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    match (removable, register, name) {
+        (true, Some(register), _) => Err(
+            format!("{register} can't be used with --removable").into()
+        ),
+        (false, _, None) => Err("--name is needed unless --removable is given".into()),
+        _ => Ok(()),
+    }
+}
+
 impl InstallCommand {
     fn r#do(&self) -> Result<(), Box<dyn Error>> {
-        assert!(self.esp_path.is_dir());
-        let mut install_path = self.esp_path.clone();
-        install_path.push("EFI");
-        if !install_path.exists() {
-            fs::create_dir(&install_path)?;
+        check_install_kind(
+            self.removable, self.register.then_some("--register"), self.name.as_deref(),
+        )?;
+        let esp = self.auto_esp.then(Esp::find).transpose()?;
+        let (esp_path, runtime_args) = match &esp {
+            Some(esp) => {
+                // the first positional argument is actually for towboot
+                let mut runtime_args = vec![self.esp_path.to_string_lossy().into_owned()];
+                runtime_args.extend(self.runtime_args.iter().cloned());
+                (esp.path(), runtime_args)
+            },
+            None => (self.esp_path.as_path(), self.runtime_args.clone()),
+        };
+        let (runtime_args, _config_path) = entries_to_runtime_args(
+            &self.entry, &self.entry_module, &runtime_args,
+        )?;
+        assert!(esp_path.is_dir());
+        if !self.register && !self.load_option.is_empty() {
+            return Err("load options can only be stored when registering the install".into());
+        }
+        if self.rollback {
+            let install_path = get_install_path(
+                esp_path, self.removable, self.name.as_deref(),
+            );
+            return Backup::new(esp_path, &install_path)?.restore();
         }
-        install_path.push(if self.removable {
-            "BOOT"
+        let config = if !runtime_args.is_empty() {
+            let load_options = runtime_args_to_load_options(&runtime_args);
+            match config::get(&load_options)? {
+                Some(config) => Some(config),
+                // Exit if the options were just -help.
+                None => return Ok(()),
+            }
         } else {
-            self.name.as_ref().expect("non-removable installs must have a name")
-        });
-        if !install_path.exists() {
-            fs::create_dir(&install_path)?;
-        }
-        info!("installing to {}", install_path.display());
-        if !self.runtime_args.is_empty() {
-            let load_options = runtime_args_to_load_options(&self.runtime_args);
-            if let Some(mut config) = config::get(&load_options)? {
-                // Write the given configuration to the ESP.
-                let mut config_path = PathBuf::from(config.src.clone());
-                config_path.pop();
-                // go through all needed files; including them (but without the original path)
-                for src_file in config.needed_files() {
-                    let src_path = config_path.join(PathBuf::from(&src_file));
-                    let dst_file = src_path.file_name().unwrap();
-                    let mut dst_path = if self.removable {
-                        self.esp_path.clone()
-                    } else {
-                        install_path.clone()
-                    };
-                    dst_path.push(dst_file);
-                    src_file.clear();
-                    src_file.push_str(dst_file.to_str().unwrap());
-                    fs::copy(&src_path, &dst_path)?;
+            None
+        };
+        if self.assets.as_ref().is_some_and(|assets| !assets.is_dir()) {
+            return Err("the assets have to be a folder".into());
+        }
+        let arches = match (self.arch.as_slice(), self.removable) {
+            ([], true) => vec![Arch::I686, Arch::X86_64, Arch::Aarch64],
+            ([], false) => vec![firmware_arch()?],
+            ([_, _, ..], false) => return Err(
+                "only removable installs can contain multiple architectures".into()
+            ),
+            (arches, _) => arches.to_vec(),
+        };
+        let binaries = arches.iter()
+            .map(|arch| Ok((binary_name(*arch, self.removable), bundled_binary(*arch)?)))
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        install(
+            esp_path, self.removable, self.name.as_deref(), config, self.assets.as_deref(),
+            &binaries, &mut |_| (),
+        )?;
+        if let Some(name) = self.name.as_deref().filter(|_| self.register) {
+            let load_options = (!self.load_option.is_empty())
+                .then(|| runtime_args_to_load_options(&self.load_option));
+            boot_entry::register(
+                esp_path, &get_install_path(esp_path, false, Some(name)).join(INSTALLED_BINARY),
+                name, load_options.as_deref(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "partition")]
+/// Create an EFI System Partition in the free space of a GPT disk and install towboot there.
+struct PartitionCommand {
+    /// the size of the new partition in MiB (512 by default)
+    #[argh(option, default = "DEFAULT_ESP_SIZE / 1024 / 1024")]
+    size: u64,
+
+    /// the label of the FAT filesystem (at most 11 characters)
+    #[argh(option)]
+    volume_label: Option<String>,
+
+    /// the name of the GPT partition
+    #[argh(option, default = "DEFAULT_PARTITION_NAME.to_string()")]
+    partition_name: String,
+
+    /// don't ask before changing the disk
+    #[argh(switch)]
+    yes: bool,
+
+    /// add a menu entry ("name: kernel args"), can be repeated
+    /// The first one is the default.
+    #[argh(option)]
+    entry: Vec<String>,
+
+    /// add a module to a menu entry ("name: module args"), can be repeated
+    #[argh(option)]
+    entry_module: Vec<String>,
+
+    #[argh(positional)]
+    /// the disk (such as /dev/sdX or \\.\PhysicalDriveN); it must not be mounted
+    device: PathBuf,
+
+    /// runtime options to pass to towboot
+    #[argh(positional, greedy)]
+    runtime_args: Vec<String>,
+}
+
+impl PartitionCommand {
+    fn r#do(&self) -> Result<(), Box<dyn Error>> {
+        let (runtime_args, _config_path) = entries_to_runtime_args(
+            &self.entry, &self.entry_module, &self.runtime_args,
+        )?;
+        let mut towboot_temp_ia32 = NamedTempFile::new()?;
+        towboot_temp_ia32.as_file_mut().write_all(towboot_ia32::TOWBOOT)?;
+        let mut towboot_temp_x64 = NamedTempFile::new()?;
+        towboot_temp_x64.as_file_mut().write_all(towboot_x64::TOWBOOT)?;
+        let mut towboot_temp_aa64 = NamedTempFile::new()?;
+        towboot_temp_aa64.as_file_mut().write_all(towboot_aa64::TOWBOOT)?;
+        let towboot_temp_ia32 = towboot_temp_ia32.into_temp_path();
+        let towboot_temp_x64 = towboot_temp_x64.into_temp_path();
+        let towboot_temp_aa64 = towboot_temp_aa64.into_temp_path();
+        let mut builder = Image::builder()
+            .size(self.size * 1024 * 1024)
+            .partition_name(&self.partition_name)
+            .add_runtime_args(&runtime_args)?
+            .add_towboot_ia32(&towboot_temp_ia32)
+            .add_towboot_x64(&towboot_temp_x64)
+            .add_towboot_aa64(&towboot_temp_aa64);
+        if let Some(volume_label) = &self.volume_label {
+            builder = builder.label(volume_label);
+        }
+        let mut confirm_changes = |changes: &str| self.yes || confirm(&format!(
+            "This will {changes}. Continue?",
+        )).unwrap_or(false);
+        let Some(mut image) = builder.build_on_disk(
+            &self.device, &mut confirm_changes, &mut |_| (),
+        )? else {
+            return Err("aborted".into());
+        };
+        let release = release::create(&image, &build_info())?;
+        image.add_data(release.as_bytes(), Path::new(release::RELEASE_PATH))?;
+        info!("installed towboot to {}", self.device.display());
+        Ok(())
+    }
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "schema")]
+/// Print the JSON Schema of the configuration file.
+struct SchemaCommand {
+    /// write the schema to this file instead
+    #[argh(option, short = 'o')]
+    output: Option<PathBuf>,
+}
+
+impl SchemaCommand {
+    fn r#do(&self) -> Result<(), Box<dyn Error>> {
+        let schema = towboot_config::json_schema();
+        match &self.output {
+            Some(output) => fs::write(output, schema)?,
+            None => println!("{schema}"),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "uninstall")]
+/// Remove towboot, the configuration file, its kernels and modules from a disk.
+struct UninstallCommand {
+    /// whether to remove a removable install (meaning /EFI/BOOT/)
+    #[argh(switch)]
+    removable: bool,
+
+    /// whether to remove the boot entry the install has been registered with
+    #[argh(switch)]
+    unregister: bool,
+
+    /// the operating system's name
+    /// This is the folder name inside /EFI that was used when installing.
+    #[argh(option)]
+    name: Option<String>,
+
+    #[argh(positional)]
+    /// the root of the mounted ESP
+    esp_path: PathBuf,
+}
+
+impl UninstallCommand {
+    fn r#do(&self) -> Result<(), Box<dyn Error>> {
+        check_install_kind(
+            self.removable, self.unregister.then_some("--unregister"), self.name.as_deref(),
+        )?;
+        assert!(self.esp_path.is_dir());
+        let install_path = get_install_path(
+            &self.esp_path, self.removable, self.name.as_deref(),
+        );
+        let config_dir = get_config_path(&self.esp_path, &install_path, self.removable);
+        info!("uninstalling from {}", install_path.display());
+        let config_path = Path::join(&config_dir, "towboot.toml");
+        if config_path.exists() {
+            // The installed configuration only references files next to it
+            // (or in their own directory, for paths with wildcards).
+            let mut config = config::read_file(config_path.to_str().unwrap())?;
+            for file in config.needed_files() {
+                let pattern = Path::join(&config_dir, file.as_str());
+                let matches = config::expand_path(&pattern)?;
+                for path in &matches {
+                    if path.exists() {
+                        info!("removing {}", path.display());
+                        fs::remove_file(path)?;
+                    }
+                }
+                // remove the directory of the matches, if nothing else is in there
+                let directory = matches.first().and_then(|path| path.parent());
+                if let Some(directory) = directory.filter(|directory| *directory != config_dir) {
+                    let _ = fs::remove_dir(directory);
                 }
-                // write the configuration itself
-                let mut config_path = if self.removable {
-                    self.esp_path.clone()
-                } else {
-                    install_path.clone()
+            }
+            fs::remove_file(&config_path)?;
+        }
+        // older versions used the removable names for all installs
+        for file in ["BOOTIA32.efi", "BOOTX64.efi", "BOOTAA64.efi", INSTALLED_BINARY] {
+            let path = Path::join(&install_path, file);
+            if path.exists() {
+                info!("removing {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+        if let Some(name) = self.name.as_deref().filter(|_| self.unregister) {
+            boot_entry::unregister(name)?;
+        }
+        // Only remove the folder if nothing else is in there.
+        if install_path.exists() && fs::read_dir(&install_path)?.next().is_none() {
+            fs::remove_dir(&install_path)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "update")]
+/// Replace the towboot binaries of an existing install or image, keeping everything else.
+struct UpdateCommand {
+    /// just compare the installed binaries to the bundled ones
+    #[argh(switch)]
+    check: bool,
+
+    /// whether this is a removable install (meaning /EFI/BOOT/)
+    #[argh(switch)]
+    removable: bool,
+
+    /// the operating system's name
+    /// This is the folder name inside /EFI that was used when installing.
+    #[argh(option)]
+    name: Option<String>,
+
+    #[argh(positional)]
+    /// the root of the mounted ESP or an image
+    target: PathBuf,
+}
+
+impl UpdateCommand {
+    fn r#do(&self) -> Result<(), Box<dyn Error>> {
+        let mut outdated = false;
+        if self.target.is_dir() {
+            check_install_kind(self.removable, None, self.name.as_deref())?;
+            let install_path = get_install_path(
+                &self.target, self.removable, self.name.as_deref(),
+            );
+            for file in ["BOOTIA32.efi", "BOOTX64.efi", "BOOTAA64.efi", INSTALLED_BINARY] {
+                let path = Path::join(&install_path, file);
+                if !path.exists() {
+                    continue;
+                }
+                let installed = fs::read(&path)?;
+                // the name doesn't tell the architecture of non-removable installs
+                let Some(bundled) = get_binary_arch(&installed)
+                    .and_then(|arch| bundled_binary(arch).ok())
+                else {
+                    warn!("{}: unknown architecture, not updating it", path.display());
+                    continue;
                 };
-                config_path.push("towboot.toml");
-                fs::write(&config_path, toml::to_vec(&config)?)?;
-            } else {
-                // Exit if the options were just -help.
-                return Ok(())
+                if compare_binaries(&path, &installed, bundled) {
+                    outdated = true;
+                    if !self.check {
+                        info!("updating {}", path.display());
+                        fs::write(&path, bundled)?;
+                    }
+                }
+            }
+        } else {
+            let mut image = Image::open(&self.target)?;
+            for (file, bundled) in [
+                (IA32_BOOT_PATH, towboot_ia32::TOWBOOT),
+                (X64_BOOT_PATH, towboot_x64::TOWBOOT),
+                (AA64_BOOT_PATH, towboot_aa64::TOWBOOT),
+            ] {
+                let path = Path::new(file);
+                let Some(installed) = image.read_file(path)? else {
+                    continue;
+                };
+                if compare_binaries(path, &installed, bundled) {
+                    outdated = true;
+                    if !self.check {
+                        info!("updating {}", path.display());
+                        image.add_data(bundled, path)?;
+                    }
+                }
             }
         }
-        // add towboot itself
-        // TODO: rename this maybe for non-removable installs?
-        fs::write(Path::join(&install_path, "BOOTIA32.efi"), towboot_ia32::TOWBOOT)?;
-        fs::write(Path::join(&install_path, "BOOTX64.efi"), towboot_x64::TOWBOOT)?;
-        if self.register {
-            assert!(!self.removable);
-            todo!("registration with the firmware is not supported, yet");
+        if self.check && outdated {
+            return Err("the installed binaries differ from the bundled ones".into());
         }
         Ok(())
     }
 }
 
+/// Get the bundled towboot binary for the given architecture.
+fn bundled_binary(arch: Arch) -> Result<&'static [u8], Box<dyn Error>> {
+    match arch {
+        Arch::I686 => Ok(towboot_ia32::TOWBOOT),
+        Arch::X86_64 => Ok(towboot_x64::TOWBOOT),
+        Arch::Aarch64 => Ok(towboot_aa64::TOWBOOT),
+        Arch::Riscv64 => Err("towbootctl has no towboot binary for riscv64".into()),
+    }
+}
+
+/// Compare an installed towboot binary to the bundled one.
+///
+/// Returns whether they differ.
+fn compare_binaries(path: &Path, installed: &[u8], bundled: &[u8]) -> bool {
+    let version = |binary: &[u8]| get_towboot_version(binary)
+        .unwrap_or_else(|| "unknown".to_string());
+    let outdated = installed != bundled;
+    info!(
+        "{}: installed version is {}, bundled version is {}{}",
+        path.display(), version(installed), version(bundled),
+        if outdated { " (differs)" } else { " (identical)" },
+    );
+    outdated
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "validate")]
+/// Check a configuration file and list its entries.
+struct ValidateCommand {
+    #[argh(positional, default = "PathBuf::from(\"towboot.toml\")")]
+    /// the configuration file to check
+    config: PathBuf,
+}
+
+impl ValidateCommand {
+    fn r#do(&self) -> Result<(), Box<dyn Error>> {
+        let mut config = config::read_file(&self.config.to_string_lossy())?;
+        config.validate()?;
+        for (key, entry) in &config.entries {
+            let default = if *key == config.default { " (default)" } else { "" };
+            println!("[{key}] {entry}{default}");
+            if let Some(description) = &entry.description {
+                println!("    {description}");
+            }
+        }
+        let config_dir = self.config.parent().unwrap_or(Path::new("."));
+        // paths with wildcards have to match at least one file
+        let missing: Vec<_> = config.needed_files().into_iter()
+            .map(|file| config_dir.join(file.as_str()))
+            .filter(|path| !config::expand_path(path)
+                .is_ok_and(|files| !files.is_empty() && files.iter().all(|f| f.exists())))
+            .collect();
+        for path in &missing {
+            println!("{} is missing", path.display());
+        }
+        if missing.is_empty() {
+            info!("{} is valid", self.config.display());
+            Ok(())
+        } else {
+            Err(format!("{} references missing files", self.config.display()).into())
+        }
+    }
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "verify")]
+/// Check the files inside an image against its manifest.
+struct VerifyCommand {
+    /// use this manifest instead of the one inside the image
+    #[argh(option)]
+    manifest: Option<PathBuf>,
+
+    #[argh(positional)]
+    /// the image to check
+    image: PathBuf,
+}
+
+impl VerifyCommand {
+    fn r#do(&self) -> Result<(), Box<dyn Error>> {
+        let image = Image::open(&self.image)?;
+        let manifest = match &self.manifest {
+            Some(path) => fs::read_to_string(path)?,
+            None => String::from_utf8(
+                image.read_file(Path::new(manifest::MANIFEST_PATH))?
+                    .ok_or("the image does not contain a manifest")?
+            )?,
+        };
+        let problems = manifest::verify(&image, &manifest)?;
+        for problem in &problems {
+            println!("{problem}");
+        }
+        if problems.is_empty() {
+            info!("{} matches its manifest", self.image.display());
+            Ok(())
+        } else {
+            Err(format!("{} doesn't match its manifest", self.image.display()).into())
+        }
+    }
+}
+
+/// Get information about this build for the release information of images.
+fn build_info() -> release::BuildInfo<'static> {
+    release::BuildInfo {
+        version: built_info::GIT_VERSION.unwrap_or(built_info::PKG_VERSION),
+        git_commit: built_info::GIT_COMMIT_HASH,
+        git_dirty: built_info::GIT_DIRTY.unwrap_or(false),
+        built: built_info::BUILT_TIME_UTC,
+    }
+}
+
 #[derive(Debug, FromArgs)]
 #[argh(subcommand, name = "version")]
 /// Display information about this application.
@@ -176,17 +915,66 @@ impl VersionCommand {
     }
 }
 
-/// This gets started from the command line.
-fn main() -> Result<(), Box<dyn Error>> {
+/// Collects the structured fields of a log record.
+struct Fields(serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = value.to_u64().map(serde_json::Value::from)
+            .or_else(|| value.to_i64().map(serde_json::Value::from))
+            .or_else(|| value.to_bool().map(serde_json::Value::from))
+            .unwrap_or_else(|| value.to_string().into());
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Set up the logger.
+///
+/// The JSON format contains a timestamp, the subcommand and the structured
+/// fields of each record (such as the file that has been copied).
+fn init_logger(format: LogFormat, subcommand: &'static str) {
     if env::var("RUST_LOG").is_err() {
         unsafe { env::set_var("RUST_LOG", "info"); }
     }
-    env_logger::init();
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(move |buf, record| {
+            let mut fields = Fields(serde_json::Map::new());
+            // this can't fail, as the visitor doesn't
+            let _ = record.key_values().visit(&mut fields);
+            let line = serde_json::json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "subcommand": subcommand,
+                "message": record.args().to_string(),
+                "fields": fields.0,
+            });
+            writeln!(buf, "{line}")
+        });
+    }
+    builder.init();
+}
+
+/// This gets started from the command line.
+fn main() -> Result<(), Box<dyn Error>> {
     let args: Cli = from_env();
+    init_logger(args.log_format, args.command.name());
     match args.command {
+        Command::Bench(bench_command) => bench_command.r#do(),
         Command::BootImage(boot_image_command) => boot_image_command.r#do(),
+        Command::Doctor(doctor_command) => doctor_command.r#do(),
+        Command::Firmware(firmware_command) => firmware_command.r#do(),
         Command::Image(image_command) => image_command.r#do(),
+        Command::Inspect(inspect_command) => inspect_command.r#do(),
         Command::Install(install_command) => install_command.r#do(),
+        Command::Partition(partition_command) => partition_command.r#do(),
+        Command::Schema(schema_command) => schema_command.r#do(),
+        Command::Uninstall(uninstall_command) => uninstall_command.r#do(),
+        Command::Update(update_command) => update_command.r#do(),
+        Command::Validate(validate_command) => validate_command.r#do(),
+        Command::Verify(verify_command) => verify_command.r#do(),
         Command::Version(version_command) => version_command.r#do(),
     }
 }
@@ -0,0 +1,71 @@
+//! This module creates and checks manifests of the files inside an image.
+//!
+//! A manifest is a JSON file listing every file with its size and SHA-256.
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use super::Image;
+
+/// Where the manifest is placed inside the image
+pub const MANIFEST_PATH: &str = "manifest.json";
+
+/// Calculate the SHA-256 of the given data as a hex string.
+pub(crate) fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Read a file from the image.
+fn read(image: &Image, path: &str) -> Result<Option<Vec<u8>>> {
+    image.read_file(Path::new(path)).map_err(|e| anyhow!("failed to read {path}: {e}"))
+}
+
+/// Create a manifest of all files inside the image (except the manifest itself).
+pub fn create(image: &Image) -> Result<String> {
+    let mut files = Vec::new();
+    for path in image.files().map_err(|e| anyhow!("failed to list files: {e}"))? {
+        if path == MANIFEST_PATH {
+            continue;
+        }
+        let data = read(image, &path)?.ok_or_else(|| anyhow!("{path} vanished"))?;
+        files.push(json!({ "path": path, "size": data.len(), "sha256": sha256(&data) }));
+    }
+    Ok(serde_json::to_string_pretty(&json!({ "files": files }))?)
+}
+
+/// Check the image against the manifest.
+///
+/// Returns a list of all differences; it is empty if the image matches.
+pub fn verify(image: &Image, manifest: &str) -> Result<Vec<String>> {
+    let manifest: Value = serde_json::from_str(manifest)?;
+    let entries = manifest["files"].as_array()
+        .ok_or_else(|| anyhow!("the manifest does not list any files"))?;
+    let mut problems = Vec::new();
+    let mut listed = Vec::new();
+    for entry in entries {
+        let (Some(path), Some(size), Some(hash)) = (
+            entry["path"].as_str(), entry["size"].as_u64(), entry["sha256"].as_str(),
+        ) else {
+            return Err(anyhow!("invalid manifest entry {entry}"));
+        };
+        listed.push(path);
+        match read(image, path)? {
+            None => problems.push(format!("{path} is missing")),
+            Some(data) if data.len() as u64 != size => problems.push(format!(
+                "{path} has a size of {} bytes instead of {size}", data.len(),
+            )),
+            Some(data) if sha256(&data) != hash => problems.push(format!(
+                "{path} has a different checksum",
+            )),
+            Some(_) => (),
+        }
+    }
+    for path in image.files().map_err(|e| anyhow!("failed to list files: {e}"))? {
+        if path != MANIFEST_PATH && !listed.contains(&path.as_str()) {
+            problems.push(format!("{path} is not listed in the manifest"));
+        }
+    }
+    Ok(problems)
+}
@@ -0,0 +1,106 @@
+//! This module contains the definition of additional partitions in images.
+//!
+//! They are placed after the EFI System Partition and can be filled with the
+//! contents of an existing file (e.g. a root filesystem).
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use gpt::partition_types::{self, Type};
+use uuid::Uuid;
+
+use super::Error;
+use super::error::Result;
+
+/// An additional partition in an image
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// the name of the partition
+    pub name: String,
+    /// the type of the partition
+    pub part_type: Type,
+    /// the size of the partition in bytes (or the size of `from` if not given)
+    pub size: Option<u64>,
+    /// the unique GUID of the partition (random if not given)
+    pub guid: Option<Uuid>,
+    /// a file to copy into the partition
+    pub from: Option<PathBuf>,
+}
+
+impl Partition {
+    /// Get the size of the partition, falling back to the size of its contents.
+    pub(crate) fn size(&self) -> Result<u64> {
+        match (self.size, &self.from) {
+            (Some(size), _) => Ok(size),
+            (None, Some(from)) => Ok(
+                fs::metadata(from).map_err(|e| Error::file(from, e))?.len()
+            ),
+            (None, None) => Err(Error::Image(format!(
+                "partition {} needs a size or a file to copy from", self.name,
+            ))),
+        }
+    }
+}
+
+/// Parse a size such as `4096`, `512K`, `100M` or `1G`.
+fn parse_size(size: &str) -> Result<u64> {
+    let (number, factor) = match size.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&size[..i], 1024),
+        Some((i, 'M' | 'm')) => (&size[..i], 1024 * 1024),
+        Some((i, 'G' | 'g')) => (&size[..i], 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+    number.parse::<u64>().ok()
+        .and_then(|n| n.checked_mul(factor))
+        .ok_or_else(|| Error::Image(format!("invalid size {size}")))
+}
+
+/// Parse a partition type: either a well-known name or a GUID.
+fn parse_type(part_type: &str) -> Result<Type> {
+    match part_type {
+        "efi" => Ok(partition_types::EFI),
+        "linux" => Ok(partition_types::LINUX_FS),
+        "swap" => Ok(partition_types::LINUX_SWAP),
+        "data" => Ok(partition_types::BASIC),
+        _ => Type::from_str(part_type)
+            .map_err(|_| Error::Image(format!("invalid partition type {part_type}"))),
+    }
+}
+
+/// Parse a GUID, such as `01234567-89ab-cdef-0123-456789abcdef`.
+pub(crate) fn parse_guid(guid: &str) -> Result<Uuid> {
+    Uuid::parse_str(guid).map_err(|_| Error::Image(format!("invalid GUID {guid}")))
+}
+
+impl FromStr for Partition {
+    type Err = Error;
+
+    /// Parse a definition of the form `name=data,type=linux,size=1G,from=./rootfs.img`.
+    ///
+    /// The type defaults to `data`; `size` or `from` have to be given.
+    /// The partition's GUID can be set via `guid=`.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut name = None;
+        let mut part_type = partition_types::BASIC;
+        let mut size = None;
+        let mut from = None;
+        let mut guid = None;
+        for option in s.split(',') {
+            let (key, value) = option.split_once('=')
+                .ok_or_else(|| Error::Image(format!("invalid partition option {option}")))?;
+            match key {
+                "name" => name = Some(value.to_string()),
+                "type" => part_type = parse_type(value)?,
+                "size" => size = Some(parse_size(value)?),
+                "from" => from = Some(PathBuf::from(value)),
+                "guid" => guid = Some(parse_guid(value)?),
+                _ => return Err(Error::Image(format!("unknown partition option {key}"))),
+            }
+        }
+        let name = name.ok_or_else(|| Error::image("a partition needs a name"))?;
+        if size.is_none() && from.is_none() {
+            return Err(Error::Image(format!("partition {name} needs a size or a file")));
+        }
+        Ok(Self { name, part_type, size, guid, from })
+    }
+}
@@ -0,0 +1,197 @@
+//! This module allows booting with QEMU.
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Result, anyhow};
+use log::info;
+use tempfile::{NamedTempFile, TempPath};
+
+use super::{
+    AA64_BOOT_PATH, Arch, BootOptions, DiskBus, IA32_BOOT_PATH, Pin, RISCV64_BOOT_PATH,
+    X64_BOOT_PATH, firmware,
+};
+
+/// Get the command to boot the image with QEMU.
+///
+/// If a TPM is wanted, `tpm_socket` is the control socket of swtpm.
+/// The returned temporary files have to be kept until QEMU exits.
+pub fn qemu(
+    image: &Path, options: &BootOptions, tpm_socket: Option<&Path>,
+) -> Result<(Command, Vec<TempPath>)> {
+    info!("getting firmware");
+    let needs_vars = options.nvram.is_some() || options.secure_boot;
+    let (firmware_path, vars_template_path) = match (options.firmware_arch(), needs_vars) {
+        // a local build for Secure Boot comes with its own variable store (as nvram)
+        (arch, true) if options.secure_boot && matches!(options.firmware, Pin::Local(_)) => (
+            firmware::firmware(arch, &options.firmware)?, None,
+        ),
+        (arch, false) => (firmware::firmware(arch, &options.firmware)?, None),
+        (Arch::I686, true) => firmware::ia32_split(&options.firmware)
+            .map(|(c, v)| (c, Some(v)))?,
+        (Arch::X86_64, true) => firmware::x64_split(&options.firmware)
+            .map(|(c, v)| (c, Some(v)))?,
+        (arch @ (Arch::Aarch64 | Arch::Riscv64), true) => return Err(anyhow!(
+            "can't persist UEFI variables on {arch:?}, yet",
+        )),
+    };
+    // without a given variable store, Secure Boot gets a fresh one
+    let temp_vars = (options.secure_boot && options.nvram.is_none())
+        .then(|| NamedTempFile::new().map(NamedTempFile::into_temp_path))
+        .transpose()?;
+    let mut qemu = Command::new(match options.arch {
+        Arch::I686 => "qemu-system-i386",
+        Arch::X86_64 => "qemu-system-x86_64",
+        Arch::Aarch64 => "qemu-system-aarch64",
+        Arch::Riscv64 => "qemu-system-riscv64",
+    });
+    qemu
+        .arg("-m").arg(options.memory().to_string())
+        .arg("-smp").arg(options.cpus().to_string());
+    if let Some(directory) = &options.netboot {
+        // the firmware boots from the network if there is no disk
+        let mut netdev = OsString::from("user,id=net0,tftp=");
+        netdev.push(directory);
+        netdev.push(format!(",bootfile={}", match options.firmware_arch() {
+            Arch::I686 => IA32_BOOT_PATH,
+            Arch::X86_64 => X64_BOOT_PATH,
+            Arch::Aarch64 => AA64_BOOT_PATH,
+            Arch::Riscv64 => RISCV64_BOOT_PATH,
+        }));
+        qemu
+            .arg("-netdev").arg(netdev)
+            .arg("-device").arg("virtio-net-pci,netdev=net0");
+    } else {
+        attach_disk(&mut qemu, image, options);
+    }
+    if let Some(serial_log) = &options.serial_log {
+        let mut chardev = OsString::from("stdio,id=serial0,logfile=");
+        chardev.push(serial_log);
+        qemu
+            .arg("-chardev").arg(chardev)
+            .arg("-serial").arg("chardev:serial0");
+    } else {
+        qemu.arg("-serial").arg("stdio");
+    }
+    if let Some(nvram) = options.nvram.as_deref().or(temp_vars.as_deref()) {
+        if temp_vars.is_some() || !nvram.exists() {
+            let vars_template_path = vars_template_path.ok_or_else(
+                || anyhow!("{} does not exist", nvram.display())
+            )?;
+            if options.secure_boot {
+                info!("enrolling the default keys in {}", nvram.display());
+                firmware::enroll_keys(&vars_template_path, nvram)?;
+            } else {
+                info!("creating {} to store UEFI variables", nvram.display());
+                fs::copy(vars_template_path, nvram)?;
+            }
+        }
+        let mut code_drive = OsString::from(
+            "if=pflash,format=raw,unit=0,readonly=on,file="
+        );
+        code_drive.push(firmware_path);
+        let mut vars_drive = OsString::from(
+            "if=pflash,format=raw,unit=1,file="
+        );
+        vars_drive.push(nvram);
+        qemu.arg("-drive").arg(code_drive).arg("-drive").arg(vars_drive);
+    } else if options.arch == Arch::Riscv64 {
+        // the default BIOS is OpenSBI which then starts edk2 from flash
+        let mut code_drive = OsString::from(
+            "if=pflash,format=raw,unit=0,readonly=on,file="
+        );
+        code_drive.push(firmware_path);
+        qemu.arg("-drive").arg(code_drive);
+    } else {
+        qemu.arg("-bios").arg(firmware_path);
+    }
+    if options.arch == Arch::Aarch64 {
+        qemu
+            .arg("-machine").arg(if options.kvm { "virt,accel=kvm" } else { "virt" })
+            .arg("-cpu").arg(if options.kvm { "host" } else { "max" });
+    } else if options.arch == Arch::Riscv64 {
+        qemu.arg("-machine").arg(if options.kvm { "virt,accel=kvm" } else { "virt" });
+    } else if options.secure_boot && matches!(options.firmware, Pin::Local(_)) {
+        // the variable store must only be writable from SMM
+        // (the builds from edk2-nightly don't support SMM, though)
+        qemu
+            .arg("-machine").arg(if options.kvm { "q35,smm=on,accel=kvm" } else { "q35,smm=on" })
+            .arg("-global").arg("driver=cfi.pflash01,property=secure,value=on");
+    } else if options.kvm {
+        qemu.arg("-machine").arg("pc,accel=kvm");
+    }
+    if options.gdb {
+        info!("The machine starts paused, waiting for GDB to attach to localhost:1234.");
+        qemu.arg("-s").arg("-S");
+    }
+    if options.headless {
+        qemu.arg("-display").arg("none");
+    } else if !options.arch.is_x86() {
+        // the virt machine has neither a display nor a keyboard by default
+        qemu
+            .arg("-device").arg("ramfb")
+            .arg("-device").arg("qemu-xhci")
+            .arg("-device").arg("usb-kbd");
+    }
+    if options.debug_exit {
+        qemu.arg("-device").arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
+    }
+    if let Some(firmware_log) = &options.firmware_log {
+        // OVMF writes its debug output to this port
+        let mut chardev = OsString::from("file:");
+        chardev.push(firmware_log);
+        qemu
+            .arg("-debugcon").arg(chardev)
+            .arg("-global").arg("isa-debugcon.iobase=0x402");
+    }
+    if let Some(tpm_socket) = tpm_socket {
+        let mut chardev = OsString::from("socket,id=chrtpm,path=");
+        chardev.push(tpm_socket);
+        qemu
+            .arg("-chardev").arg(chardev)
+            .arg("-tpmdev").arg("emulator,id=tpm0,chardev=chrtpm")
+            .arg("-device").arg(if options.arch.is_x86() {
+                "tpm-tis,tpmdev=tpm0"
+            } else {
+                "tpm-tis-device,tpmdev=tpm0"
+            });
+    }
+    if let Some(port) = options.qmp {
+        qemu.arg("-qmp").arg(format!("tcp:127.0.0.1:{port},server=on,wait=off"));
+    }
+    Ok((qemu, temp_vars.into_iter().collect()))
+}
+
+/// Attach the image as a disk.
+fn attach_disk(qemu: &mut Command, image: &Path, options: &BootOptions) {
+    // the virt machine has no IDE controller
+    let disk_bus = options.disk_bus.unwrap_or(
+        if options.arch.is_x86() { DiskBus::Ide } else { DiskBus::Virtio }
+    );
+    let mut drive = OsString::from(match disk_bus {
+        DiskBus::Ide => "if=ide,format=raw,file=",
+        DiskBus::Virtio => "if=virtio,format=raw,file=",
+        // the others need a device
+        DiskBus::Ahci | DiskBus::Nvme | DiskBus::Usb => "if=none,id=disk0,format=raw,file=",
+    });
+    drive.push(image);
+    qemu.arg("-drive").arg(drive);
+    match disk_bus {
+        DiskBus::Ide | DiskBus::Virtio => (),
+        DiskBus::Ahci => {
+            qemu
+                .arg("-device").arg("ahci,id=ahci")
+                .arg("-device").arg("ide-hd,drive=disk0,bus=ahci.0");
+        },
+        DiskBus::Nvme => {
+            qemu.arg("-device").arg("nvme,drive=disk0,serial=towboot");
+        },
+        DiskBus::Usb => {
+            // firmwares treat removable media differently, so look like a USB stick
+            qemu
+                .arg("-device").arg("qemu-xhci,id=xhci")
+                .arg("-device").arg("usb-storage,drive=disk0,bus=xhci.0,removable=on");
+        },
+    }
+}
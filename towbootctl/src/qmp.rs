@@ -0,0 +1,119 @@
+//! This module allows controlling a running QEMU via QMP.
+//!
+//! See <https://www.qemu.org/docs/master/interop/qmp-spec.html> for the protocol.
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use log::{debug, trace};
+use serde_json::{Value, json};
+
+/// How long to wait for QEMU to open the socket
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection to a QMP server.
+pub struct Qmp {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Qmp {
+    /// Connect to QEMU listening on the given port on localhost.
+    ///
+    /// As QEMU might not have opened the socket yet, this retries for a while.
+    pub fn connect(port: u16) -> Result<Self> {
+        let start = Instant::now();
+        let stream = loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => break stream,
+                Err(e) if start.elapsed() < CONNECT_TIMEOUT => {
+                    trace!("failed to connect to QMP: {e}, retrying");
+                    sleep(Duration::from_millis(100));
+                },
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let mut qmp = Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        let greeting = qmp.read()?;
+        debug!("connected to {}", greeting["QMP"]["version"]);
+        qmp.execute("qmp_capabilities", None)?;
+        Ok(qmp)
+    }
+
+    /// Read the next message.
+    fn read(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("QMP connection closed"));
+        }
+        trace!("QMP: < {}", line.trim_end());
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Execute a command, returning its result.
+    ///
+    /// Events that arrive in the meantime are ignored.
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let mut message = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            message["arguments"] = arguments;
+        }
+        trace!("QMP: > {message}");
+        writeln!(self.writer, "{message}")?;
+        loop {
+            let mut response = self.read()?;
+            if let Some(error) = response.get("error") {
+                return Err(anyhow!(
+                    "{command} failed: {}", error["desc"].as_str().unwrap_or("unknown error"),
+                ));
+            }
+            if let Some(result) = response.get_mut("return") {
+                return Ok(result.take());
+            }
+        }
+    }
+
+    /// Save a screenshot of the display to the given path (in PPM format).
+    pub fn screendump(&mut self, path: &Path) -> Result<()> {
+        let path = std::path::absolute(path)?;
+        self.execute("screendump", Some(json!({ "filename": path })))?;
+        Ok(())
+    }
+
+    /// Press the given keys at once and release them again.
+    ///
+    /// The keys are QEMU key codes, such as `ret`, `down` or `ctrl`.
+    pub fn send_key(&mut self, keys: &[&str]) -> Result<()> {
+        let keys: Vec<Value> = keys.iter()
+            .map(|k| json!({ "type": "qcode", "data": k }))
+            .collect();
+        self.execute("send-key", Some(json!({ "keys": keys })))?;
+        Ok(())
+    }
+
+    /// Get the state the virtual machine is in, such as `running` or `paused`.
+    pub fn query_status(&mut self) -> Result<String> {
+        let status = self.execute("query-status", None)?;
+        status["status"].as_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("invalid response to query-status"))
+    }
+
+    /// Ask the guest to power off via ACPI.
+    pub fn system_powerdown(&mut self) -> Result<()> {
+        self.execute("system_powerdown", None)?;
+        Ok(())
+    }
+
+    /// Stop QEMU immediately.
+    pub fn quit(&mut self) -> Result<()> {
+        self.execute("quit", None)?;
+        Ok(())
+    }
+}
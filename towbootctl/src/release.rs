@@ -0,0 +1,88 @@
+//! This module describes which build of towboot is inside an image.
+//!
+//! Images contain a JSON file with the versions of towbootctl and towboot,
+//! the git commit they have been built from and when this happened.
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use serde_json::{Value, json};
+
+use super::{AA64_BOOT_PATH, IA32_BOOT_PATH, Image, X64_BOOT_PATH, get_towboot_version};
+
+/// Where the release information is placed inside the image
+pub const RELEASE_PATH: &str = "towboot-release.json";
+
+/// The towboot binaries inside an image
+const BINARIES: [(&str, &str); 3] = [
+    ("ia32", IA32_BOOT_PATH), ("x64", X64_BOOT_PATH), ("aa64", AA64_BOOT_PATH),
+];
+
+/// Information about the build of towbootctl that creates the image.
+pub struct BuildInfo<'a> {
+    /// the version (as given by `git describe`, if available)
+    pub version: &'a str,
+    /// the hash of the git commit
+    pub git_commit: Option<&'a str>,
+    /// whether there were uncommitted changes
+    pub git_dirty: bool,
+    /// when towbootctl (and the bundled towboot binaries) have been built
+    pub built: &'a str,
+}
+
+/// Get the versions of the towboot binaries inside the image.
+fn towboot_versions(image: &Image) -> Result<Value> {
+    let mut versions = serde_json::Map::new();
+    for (arch, path) in BINARIES {
+        let binary = image.read_file(Path::new(path))
+            .map_err(|e| anyhow!("failed to read {path}: {e}"))?;
+        if let Some(binary) = binary {
+            versions.insert(arch.to_string(), json!(get_towboot_version(&binary)));
+        }
+    }
+    Ok(Value::Object(versions))
+}
+
+/// Create the release information for the image.
+pub fn create(image: &Image, build: &BuildInfo) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&json!({
+        "towbootctl": {
+            "version": build.version,
+            "git_commit": build.git_commit,
+            "git_dirty": build.git_dirty,
+            "built": build.built,
+        },
+        "towboot": towboot_versions(image)?,
+    }))?)
+}
+
+/// Describe the build of towboot inside the image in a human-readable way.
+///
+/// This also works for images without release information,
+/// but then only the versions of the towboot binaries are known.
+pub fn describe(image: &Image) -> Result<String> {
+    let mut description = String::new();
+    let release = image.read_file(Path::new(RELEASE_PATH))
+        .map_err(|e| anyhow!("failed to read {RELEASE_PATH}: {e}"))?;
+    match release {
+        Some(release) => {
+            let release: Value = serde_json::from_slice(&release)?;
+            let towbootctl = &release["towbootctl"];
+            let field = |name: &str| towbootctl[name].as_str().unwrap_or("unknown").to_string();
+            writeln!(description, "created by towbootctl {}", field("version"))?;
+            writeln!(
+                description, "  from commit {}{}", field("git_commit"),
+                if towbootctl["git_dirty"].as_bool() == Some(true) { " (dirty)" } else { "" },
+            )?;
+            writeln!(description, "  built at {}", field("built"))?;
+        },
+        None => writeln!(description, "the image contains no release information")?,
+    }
+    // the binaries might have been updated since
+    for (arch, version) in towboot_versions(image)?.as_object().unwrap() {
+        writeln!(
+            description, "towboot for {arch}: {}", version.as_str().unwrap_or("unknown version"),
+        )?;
+    }
+    Ok(description)
+}
@@ -0,0 +1,66 @@
+//! This module emulates a TPM with swtpm.
+//!
+//! Every virtual machine gets its own TPM with a fresh state.
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use log::debug;
+use tempfile::{TempDir, tempdir};
+
+/// How long to wait for swtpm to create its socket
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A running swtpm; it is stopped when this is dropped.
+pub struct Swtpm {
+    process: Child,
+    state: TempDir,
+}
+
+impl Swtpm {
+    /// Start a TPM 2.0 with a temporary state directory.
+    pub fn spawn() -> Result<Self> {
+        let state = tempdir()?;
+        let socket = state.path().join("swtpm-sock");
+        let mut tpm_state = OsString::from("dir=");
+        tpm_state.push(state.path());
+        let mut ctrl = OsString::from("type=unixio,path=");
+        ctrl.push(&socket);
+        let mut command = Command::new("swtpm");
+        command
+            .arg("socket").arg("--tpm2")
+            .arg("--tpmstate").arg(tpm_state)
+            .arg("--ctrl").arg(ctrl)
+            .stdout(Stdio::null());
+        debug!("running {command:?}");
+        let mut swtpm = Self { process: command.spawn()?, state };
+        let start = Instant::now();
+        while !socket.exists() {
+            if let Some(status) = swtpm.process.try_wait()? {
+                return Err(anyhow!("swtpm exited with {status}"));
+            }
+            if start.elapsed() > SOCKET_TIMEOUT {
+                return Err(anyhow!("swtpm didn't create its socket in time"));
+            }
+            sleep(Duration::from_millis(10));
+        }
+        Ok(swtpm)
+    }
+
+    /// Get the socket QEMU connects to.
+    pub fn socket(&self) -> PathBuf {
+        self.state.path().join("swtpm-sock")
+    }
+}
+
+impl Drop for Swtpm {
+    fn drop(&mut self) {
+        if let Ok(None) = self.process.try_wait() {
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! This module allows booting with VirtualBox.
+//!
+//! VirtualBox can't boot raw images directly, so we convert the image first.
+//! The virtual machine and its disk are kept next to the image and get
+//! recreated on every boot.
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{Result, anyhow};
+use log::{debug, info};
+
+/// Run VBoxManage with the given arguments.
+fn vboxmanage<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(args: I) -> Result<()> {
+    let mut command = Command::new("VBoxManage");
+    command.args(args);
+    debug!("running {command:?}");
+    let status = command.status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("{command:?} failed with {status}"))
+    }
+}
+
+/// Create a throwaway virtual machine for the image and return the command to start it.
+///
+/// The memory is given in MiB.
+pub fn virtualbox(
+    image: &Path, x86_64: bool, headless: bool, serial_log: Option<&Path>,
+    memory: u32, cpus: u32,
+) -> Result<Command> {
+    let name = format!(
+        "towboot-{}", image.file_stem().and_then(OsStr::to_str).unwrap_or("image"),
+    );
+    let mut folder = image.as_os_str().to_owned();
+    folder.push(".virtualbox");
+    let folder = Path::new(&folder);
+    // remove the machine from the last boot; this fails if there is none
+    Command::new("VBoxManage")
+        .args(["unregistervm", name.as_str(), "--delete"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if folder.exists() {
+        fs::remove_dir_all(folder)?;
+    }
+    fs::create_dir_all(folder)?;
+    info!("creating virtual machine {name} in {}", folder.display());
+    let disk = folder.join(format!("{name}.vdi"));
+    vboxmanage([
+        OsStr::new("convertfromraw"), image.as_os_str(), disk.as_os_str(),
+        OsStr::new("--format"), OsStr::new("VDI"),
+    ])?;
+    vboxmanage([
+        OsStr::new("createvm"), OsStr::new(&name), OsStr::new("--register"),
+        OsStr::new("--basefolder"), folder.as_os_str(),
+        OsStr::new("--ostype"), OsStr::new(if x86_64 { "Other_64" } else { "Other" }),
+    ])?;
+    vboxmanage([
+        "modifyvm", name.as_str(), "--firmware", if x86_64 { "efi64" } else { "efi32" },
+        "--memory", &memory.to_string(), "--cpus", &cpus.to_string(),
+    ])?;
+    if let Some(serial_log) = serial_log {
+        let serial_log = std::path::absolute(serial_log)?;
+        vboxmanage([
+            OsStr::new("modifyvm"), OsStr::new(&name),
+            OsStr::new("--uart1"), OsStr::new("0x3F8"), OsStr::new("4"),
+            OsStr::new("--uartmode1"), OsStr::new("file"), serial_log.as_os_str(),
+        ])?;
+    }
+    vboxmanage(["storagectl", name.as_str(), "--name", "SATA", "--add", "sata"])?;
+    vboxmanage([
+        OsStr::new("storageattach"), OsStr::new(&name),
+        OsStr::new("--storagectl"), OsStr::new("SATA"), OsStr::new("--port"), OsStr::new("0"),
+        OsStr::new("--type"), OsStr::new("hdd"), OsStr::new("--medium"), disk.as_os_str(),
+    ])?;
+    // these run in the foreground (as opposed to `VBoxManage startvm`)
+    let mut command = Command::new(if headless { "VBoxHeadless" } else { "VirtualBoxVM" });
+    command.arg("--startvm").arg(name);
+    Ok(command)
+}
@@ -0,0 +1,166 @@
+//! This module contains a handle to a virtual machine started by [`super::boot_image`].
+use std::io::{self, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{JoinHandle, sleep, spawn};
+use std::time::{Duration, Instant};
+
+use tempfile::TempPath;
+
+use super::swtpm::Swtpm;
+use super::wait_with_timeout;
+
+/// The code guests (like the test kernels) write to isa-debug-exit when they succeed
+///
+/// QEMU turns this into an exit status of 33.
+pub const DEBUG_EXIT_SUCCESS: i32 = 0x10;
+
+/// A virtual machine that is about to be or has been started.
+///
+/// The temporary files the virtual machine needs are kept as long as this exists.
+/// The virtual machine is killed when this is dropped.
+pub struct BootedVm {
+    command: Command,
+    process: Option<Child>,
+    /// the output collected so far (if it is captured)
+    output: Arc<Mutex<Vec<u8>>>,
+    reader: Option<JoinHandle<()>>,
+    status: Option<ExitStatus>,
+    _temp_files: Vec<TempPath>,
+    // this is dropped after the virtual machine has been killed
+    _swtpm: Option<Swtpm>,
+}
+
+impl BootedVm {
+    pub(crate) fn new(command: Command, temp_files: Vec<TempPath>) -> Self {
+        Self {
+            command,
+            process: None,
+            output: Arc::new(Mutex::new(Vec::new())),
+            reader: None,
+            status: None,
+            _temp_files: temp_files,
+            _swtpm: None,
+        }
+    }
+
+    /// Keep the TPM running as long as the virtual machine.
+    pub(crate) fn with_swtpm(mut self, swtpm: Option<Swtpm>) -> Self {
+        self._swtpm = swtpm;
+        self
+    }
+
+    /// Get the command that starts the virtual machine (e.g. to add arguments).
+    pub fn command(&mut self) -> &mut Command {
+        &mut self.command
+    }
+
+    /// Start the virtual machine.
+    pub fn spawn(&mut self) -> io::Result<()> {
+        self.process = Some(self.command.spawn()?);
+        Ok(())
+    }
+
+    /// Start the virtual machine, collecting its output (that is, the serial port).
+    ///
+    /// Use [`BootedVm::output`] to get what has been collected so far.
+    pub fn spawn_captured(&mut self) -> io::Result<()> {
+        let mut process = self.command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdout = process.stdout.take().unwrap();
+        let output = self.output.clone();
+        self.reader = Some(spawn(move || {
+            let mut buf = [0; 1024];
+            while let Ok(length) = stdout.read(&mut buf) {
+                if length == 0 {
+                    break;
+                }
+                output.lock().unwrap().extend_from_slice(&buf[..length]);
+            }
+        }));
+        self.process = Some(process);
+        Ok(())
+    }
+
+    /// Get the output collected so far.
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.output.lock().unwrap()).into_owned()
+    }
+
+    /// Wait until the output contains the given text.
+    ///
+    /// Returns false if it didn't appear before the timeout.
+    pub fn wait_for_output(&self, text: &str, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if self.output().contains(text) {
+                return true;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        self.output().contains(text)
+    }
+
+    fn process(&mut self) -> io::Result<&mut Child> {
+        self.process.as_mut().ok_or_else(
+            || io::Error::other("the virtual machine has not been started")
+        )
+    }
+
+    /// Wait for the collection of the output to finish.
+    fn join_reader(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+
+    /// Wait for the virtual machine to exit.
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        let status = self.process()?.wait()?;
+        self.status = Some(status);
+        self.join_reader();
+        Ok(status)
+    }
+
+    /// Wait for the virtual machine to exit, killing it when the timeout is reached.
+    ///
+    /// Returns None if it had to be killed.
+    pub fn wait_with_timeout(&mut self, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+        let status = wait_with_timeout(self.process()?, timeout)?;
+        self.status = status;
+        self.join_reader();
+        Ok(status)
+    }
+
+    /// Stop the virtual machine immediately.
+    pub fn kill(&mut self) -> io::Result<()> {
+        let process = self.process()?;
+        process.kill()?;
+        process.wait()?;
+        self.join_reader();
+        Ok(())
+    }
+
+    /// Get the exit code the guest wrote to QEMU's isa-debug-exit device.
+    ///
+    /// QEMU exits with `(code << 1) | 1` then, so this returns None if the
+    /// virtual machine hasn't exited (yet) or exited differently.
+    /// As QEMU also exits with 1 on its own errors, a code of 0 can't be told
+    /// apart from a failure; guests should use [`DEBUG_EXIT_SUCCESS`] instead.
+    pub fn debug_exit_code(&self) -> Option<i32> {
+        self.status?.code().filter(|code| code & 1 == 1).map(|code| code >> 1)
+    }
+}
+
+impl Drop for BootedVm {
+    fn drop(&mut self) {
+        if let Some(process) = &mut self.process {
+            if let Ok(None) = process.try_wait() {
+                let _ = process.kill();
+                let _ = process.wait();
+            }
+        }
+    }
+}
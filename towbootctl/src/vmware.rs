@@ -0,0 +1,86 @@
+//! This module allows booting with VMware Workstation, Player or Fusion.
+//!
+//! The image is used directly through a VMDK descriptor; the virtual machine
+//! is kept next to the image and gets recreated on every boot.
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Result;
+use log::info;
+
+/// Generate a VMDK descriptor that points to the raw image.
+fn vmdk(image: &Path) -> Result<String> {
+    let sectors = fs::metadata(image)?.len() / 512;
+    let cylinders = (sectors / (16 * 63)).min(16383);
+    Ok(format!("# Disk DescriptorFile
+version=1
+CID=fffffffe
+parentCID=ffffffff
+createType=\"monolithicFlat\"
+
+# Extent description
+RW {sectors} FLAT \"{}\" 0
+
+# The Disk Data Base
+ddb.virtualHWVersion = \"4\"
+ddb.adapterType = \"ide\"
+ddb.geometry.cylinders = \"{cylinders}\"
+ddb.geometry.heads = \"16\"
+ddb.geometry.sectors = \"63\"
+", image.display()))
+}
+
+/// Generate an appropriate vmx file.
+fn vmx(disk: &Path, x86_64: bool, serial_log: Option<&Path>, memory: u32, cpus: u32) -> String {
+    let guest_os = if x86_64 { "other-64" } else { "other" };
+    let disk = disk.display();
+    let serial = match serial_log {
+        Some(path) => format!("serial0.present = \"TRUE\"
+serial0.fileType = \"file\"
+serial0.fileName = \"{}\"
+", path.display()),
+        None => String::new(),
+    };
+    format!(".encoding = \"UTF-8\"
+config.version = \"8\"
+virtualHW.version = \"14\"
+displayName = \"towboot\"
+guestOS = \"{guest_os}\"
+firmware = \"efi\"
+memsize = \"{memory}\"
+numvcpus = \"{cpus}\"
+sata0.present = \"TRUE\"
+sata0:0.present = \"TRUE\"
+sata0:0.fileName = \"{disk}\"
+{serial}")
+}
+
+/// Create a throwaway virtual machine for the image and return the command to start it.
+///
+/// Note that `vmrun` returns as soon as the machine has been started.
+/// The memory is given in MiB.
+pub fn vmware(
+    image: &Path, x86_64: bool, headless: bool, serial_log: Option<&Path>,
+    memory: u32, cpus: u32,
+) -> Result<Command> {
+    let image = std::path::absolute(image)?;
+    let mut folder = image.as_os_str().to_owned();
+    folder.push(".vmware");
+    let folder = Path::new(&folder);
+    if folder.exists() {
+        fs::remove_dir_all(folder)?;
+    }
+    fs::create_dir_all(folder)?;
+    info!("creating virtual machine in {}", folder.display());
+    let disk = folder.join("towboot.vmdk");
+    fs::write(&disk, vmdk(&image)?)?;
+    let config = folder.join("towboot.vmx");
+    let serial_log = serial_log.map(std::path::absolute).transpose()?;
+    fs::write(&config, vmx(&disk, x86_64, serial_log.as_deref(), memory, cpus))?;
+    let mut command = Command::new("vmrun");
+    command
+        .arg("start").arg(config)
+        .arg(if headless { "nogui" } else { "gui" });
+    Ok(command)
+}
@@ -1,13 +1,20 @@
 #![feature(exit_status_error)]
 use std::env;
 use std::error::Error;
-use std::path::PathBuf;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::Duration;
 
 use argh::{FromArgs, from_env};
-use log::info;
+use log::{error, info};
+use sha2::{Digest, Sha256};
 
-use towbootctl::{BootImageCommand, create_image};
+use towbootctl::{
+    Arch, BootImageCommand, BootOptions, DEBUG_EXIT_SUCCESS, DEFAULT_PARTITION_NAME, boot_image,
+    create_image,
+};
 
 #[derive(Debug, FromArgs)]
 /// Top-level command.
@@ -21,6 +28,8 @@ struct Cli {
 enum Command {
     Build(Build),
     BootImage(BootImageCommand),
+    Dist(Dist),
+    Test(Test),
 }
 
 #[derive(Debug, FromArgs)]
@@ -39,6 +48,10 @@ struct Build {
     #[argh(switch)]
     no_x86_64: bool,
 
+    /// do not include aarch64 build
+    #[argh(switch)]
+    no_aarch64: bool,
+
     /// where to place the image
     #[argh(option, default = "PathBuf::from(\"image.img\")")]
     target: PathBuf,
@@ -72,6 +85,13 @@ impl Build {
                 .arg("x86_64-unknown-uefi")
                 .status()?.exit_ok()?;
         }
+        if !self.no_aarch64 {
+            info!("building for aarch64, pass --no-aarch64 to skip this");
+            build_command
+                .arg("--target")
+                .arg("aarch64-unknown-uefi")
+                .status()?.exit_ok()?;
+        }
         let build = match self.release {
             true => "release",
             false => "debug",
@@ -82,11 +102,269 @@ impl Build {
         let x86_64: Option<PathBuf> = (!self.no_x86_64).then_some(
             ["target", "x86_64-unknown-uefi", build, "towboot.efi"].into_iter().collect()
         );
-        create_image(&self.target, &self.runtime_args, i686.as_deref(), x86_64.as_deref())?;
+        let aarch64: Option<PathBuf> = (!self.no_aarch64).then_some(
+            ["target", "aarch64-unknown-uefi", build, "towboot.efi"].into_iter().collect()
+        );
+        create_image(
+            &self.target, &self.runtime_args, i686.as_deref(), x86_64.as_deref(),
+            aarch64.as_deref(), DEFAULT_PARTITION_NAME, None, None,
+        )?;
         Ok(())
     }
 }
 
+/// The UEFI targets towboot is built for and how they're called in releases
+const UEFI_TARGETS: [(&str, &str); 3] = [
+    ("i686-unknown-uefi", "i686"),
+    ("x86_64-unknown-uefi", "x86_64"),
+    ("aarch64-unknown-uefi", "aarch64"),
+];
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "dist")]
+/// Build and package everything needed for a release.
+struct Dist {
+    /// build towbootctl for this target (can be given multiple times, defaults to the host)
+    #[argh(option)]
+    host_target: Vec<String>,
+
+    /// the version to put in the file names (defaults to `git describe`)
+    #[argh(option)]
+    version: Option<String>,
+
+    /// sign the checksums with GnuPG
+    #[argh(switch)]
+    sign: bool,
+
+    /// where to place the archives
+    #[argh(option, default = "PathBuf::from(\"dist\")")]
+    target: PathBuf,
+}
+
+impl Dist {
+    fn r#do(self) -> Result<(), Box<dyn Error>> {
+        let version = match self.version {
+            Some(v) => v,
+            None => {
+                let output = process::Command::new("git")
+                    .args(["describe", "--always", "--tags"])
+                    .output()?;
+                output.status.exit_ok()?;
+                String::from_utf8(output.stdout)?.trim().to_string()
+            },
+        };
+        let name = format!("towboot-{version}");
+        let directory = self.target.join(&name);
+        fs::create_dir_all(&directory)?;
+        let mut files = Vec::new();
+        for (target, arch) in UEFI_TARGETS {
+            info!("building towboot for {arch}");
+            process::Command::new("cargo")
+                .args(["build", "--package", "towboot", "--release", "--target", target])
+                .status()?.exit_ok()?;
+            let file_name = format!("towboot-{version}-{arch}.efi");
+            fs::copy(
+                ["target", target, "release", "towboot.efi"].into_iter().collect::<PathBuf>(),
+                directory.join(&file_name),
+            )?;
+            files.push(file_name);
+        }
+        let host_targets = if self.host_target.is_empty() {
+            vec![host_target()?]
+        } else {
+            self.host_target
+        };
+        for target in host_targets {
+            info!("building towbootctl for {target}");
+            process::Command::new("cargo")
+                .args([
+                    "build", "--package", "towbootctl", "--features=binary", "--release",
+                    "--target", &target,
+                ])
+                .status()?.exit_ok()?;
+            let extension = if target.contains("windows") { ".exe" } else { "" };
+            let file_name = format!("towbootctl-{version}-{target}{extension}");
+            fs::copy(
+                ["target", &target, "release", &format!("towbootctl{extension}")]
+                    .into_iter().collect::<PathBuf>(),
+                directory.join(&file_name),
+            )?;
+            files.push(file_name);
+        }
+        info!("computing checksums");
+        let mut checksums = String::new();
+        for file_name in &files {
+            let hash = Sha256::digest(fs::read(directory.join(file_name))?);
+            writeln!(checksums, "{hash:x}  {file_name}")?;
+        }
+        let checksum_path = directory.join("SHA256SUMS");
+        fs::write(&checksum_path, checksums)?;
+        if self.sign {
+            info!("signing the checksums");
+            process::Command::new("gpg")
+                .args(["--armor", "--detach-sign"])
+                .arg(&checksum_path)
+                .status()?.exit_ok()?;
+        }
+        let archive = self.target.join(format!("{name}.tar.gz"));
+        info!("creating {}", archive.display());
+        process::Command::new("tar")
+            .arg("-czf").arg(&archive)
+            .arg("-C").arg(&self.target)
+            .arg(Path::new(&name))
+            .status()?.exit_ok()?;
+        Ok(())
+    }
+}
+
+/// The test kernels and the firmware architectures they support
+const TEST_KERNELS: [(&str, &[Arch]); 6] = [
+    ("multiboot1", &[Arch::I686, Arch::X86_64]),
+    ("multiboot2", &[Arch::I686, Arch::X86_64]),
+    ("multiboot2_x64", &[Arch::X86_64]),
+    ("framebuffer", &[Arch::I686, Arch::X86_64]),
+    ("efi", &[Arch::I686, Arch::X86_64]),
+    ("efi_x64", &[Arch::X86_64]),
+];
+
+/// The machine and firmware architectures to test
+const TEST_MACHINES: [(Arch, Arch); 3] = [
+    (Arch::I686, Arch::I686),
+    (Arch::X86_64, Arch::I686),
+    (Arch::X86_64, Arch::X86_64),
+];
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "test")]
+/// Run the integration tests.
+struct Test {
+    /// boot each test kernel on each combination of machine, firmware and KVM
+    /// instead of running `cargo test`
+    #[argh(switch)]
+    matrix: bool,
+
+    /// how many seconds each case of the matrix may take
+    #[argh(option, default = "30")]
+    timeout: u64,
+
+    /// do not test with KVM
+    #[argh(switch)]
+    no_kvm: bool,
+
+    /// do not test without KVM
+    #[argh(switch)]
+    no_tcg: bool,
+}
+
+impl Test {
+    fn r#do(self) -> Result<(), Box<dyn Error>> {
+        if !self.matrix {
+            process::Command::new("cargo")
+                .args(["test", "--package", "tests"])
+                .status()?.exit_ok()?;
+            return Ok(());
+        }
+        for (target, arch) in &UEFI_TARGETS[..2] {
+            info!("building towboot for {arch}");
+            process::Command::new("cargo")
+                .args(["build", "--package", "towboot", "--target", *target])
+                .status()?.exit_ok()?;
+        }
+        let output_dir = Path::new("target").join("xtask-test");
+        fs::create_dir_all(&output_dir)?;
+        let kvm_modes: Vec<bool> = [true, false].into_iter()
+            .filter(|kvm| if *kvm { !self.no_kvm } else { !self.no_tcg })
+            .collect();
+        let mut results = Vec::new();
+        for (kernel, firmware_archs) in TEST_KERNELS {
+            let folder = Path::new("tests").join(kernel);
+            info!("building {kernel}");
+            process::Command::new("make")
+                .current_dir(&folder)
+                .status()?.exit_ok()?;
+            for (machine, firmware) in TEST_MACHINES {
+                if !firmware_archs.contains(&firmware) {
+                    continue;
+                }
+                let towboot: PathBuf = [
+                    "target", match firmware {
+                        Arch::I686 => "i686-unknown-uefi",
+                        _ => "x86_64-unknown-uefi",
+                    }, "debug", "towboot.efi",
+                ].into_iter().collect();
+                let image = output_dir.join(format!("{kernel}-{firmware:?}.img"));
+                create_image(
+                    &image, &[
+                        "-config".to_string(),
+                        folder.join("towboot.toml").to_str().unwrap().to_string(),
+                    ],
+                    (firmware == Arch::I686).then_some(towboot.as_path()),
+                    (firmware == Arch::X86_64).then_some(towboot.as_path()),
+                    None, DEFAULT_PARTITION_NAME, None, None,
+                )?;
+                for kvm in &kvm_modes {
+                    let case = format!(
+                        "{kernel} on {machine:?} with {firmware:?} firmware ({})",
+                        if *kvm { "KVM" } else { "TCG" },
+                    );
+                    info!("running {case}");
+                    let result = self.run_case(&image, machine, firmware, *kvm);
+                    if let Err(e) = &result {
+                        error!("{case} failed: {e}");
+                    }
+                    results.push((case, result.is_ok()));
+                }
+            }
+        }
+        println!();
+        let width = results.iter().map(|(case, _)| case.len()).max().unwrap_or_default();
+        for (case, passed) in &results {
+            println!("{case:width$}  {}", if *passed { "ok" } else { "FAILED" });
+        }
+        let failed = results.iter().filter(|(_, passed)| !passed).count();
+        println!("\n{} passed, {failed} failed", results.len() - failed);
+        if failed > 0 {
+            return Err(format!("{failed} cases failed").into());
+        }
+        Ok(())
+    }
+
+    /// Boot a test image and check whether the kernel ran successfully.
+    fn run_case(
+        &self, image: &Path, machine: Arch, firmware: Arch, kvm: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut vm = boot_image(image, &BootOptions {
+            arch: machine,
+            firmware_arch: Some(firmware),
+            kvm,
+            headless: true,
+            debug_exit: true,
+            ..Default::default()
+        })?;
+        vm.spawn_captured()?;
+        if vm.wait_with_timeout(Duration::from_secs(self.timeout))?.is_none() {
+            return Err("timed out".into());
+        }
+        let output = vm.output();
+        if !output.ends_with("Halted.") || vm.debug_exit_code() != Some(DEBUG_EXIT_SUCCESS) {
+            return Err(format!(
+                "the kernel exited with {:?}, its output was:\n{output}", vm.debug_exit_code(),
+            ).into());
+        }
+        Ok(())
+    }
+}
+
+/// Get the target triple of the host.
+fn host_target() -> Result<String, Box<dyn Error>> {
+    let output = process::Command::new("rustc").arg("-vV").output()?;
+    output.status.exit_ok()?;
+    String::from_utf8(output.stdout)?.lines()
+        .find_map(|l| l.strip_prefix("host: "))
+        .map(ToString::to_string)
+        .ok_or_else(|| "failed to determine the host target".into())
+}
+
 /// This gets started from the command line.
 fn main() -> Result<(), Box<dyn Error>> {
     if env::var("RUST_LOG").is_err() {
@@ -97,5 +375,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     match args.command {
         Command::Build(build) => build.r#do(),
         Command::BootImage(boot_image) => boot_image.r#do(),
+        Command::Dist(dist) => dist.r#do(),
+        Command::Test(test) => test.r#do(),
     }
 }